@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE device_credentials;
+ALTER TABLE lights RENAME COLUMN signature TO secret;
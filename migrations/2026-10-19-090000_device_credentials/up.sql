@@ -0,0 +1,10 @@
+-- The old `secret` column holds the manufacturer signature, not a credential
+ALTER TABLE lights RENAME COLUMN secret TO signature;
+CREATE TABLE device_credentials (
+    id SERIAL PRIMARY KEY,
+    device_id uuid NOT NULL,
+    secret_key VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ
+);
+CREATE INDEX device_credentials_device_id_idx ON device_credentials (device_id);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE device_transfers (
+    id uuid PRIMARY KEY,
+    device_id uuid NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    from_user_id INT NOT NULL,
+    to_email VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resolved_at TIMESTAMPTZ
+);
+-- A device can only have one transfer in flight
+CREATE UNIQUE INDEX device_transfers_pending_idx ON device_transfers (device_id)
+WHERE status = 'pending';
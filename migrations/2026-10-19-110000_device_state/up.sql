@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE devices
+ADD COLUMN state VARCHAR NOT NULL DEFAULT 'active',
+    ADD COLUMN removal_requested_at TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE device_group_members;
+DROP TABLE device_groups;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE device_groups (
+    id uuid PRIMARY KEY,
+    user_id INT NOT NULL,
+    name TEXT NOT NULL,
+    expose_to_google BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE TABLE device_group_members (
+    group_id uuid NOT NULL REFERENCES device_groups (id) ON DELETE CASCADE,
+    device_id uuid NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    PRIMARY KEY (group_id, device_id)
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+UPDATE devices
+SET traits = array_remove(traits, 'action.devices.traits.LightEffects');
+DELETE FROM traits
+WHERE trait = 'action.devices.traits.LightEffects';
+ALTER TABLE lights DROP COLUMN native_effects;
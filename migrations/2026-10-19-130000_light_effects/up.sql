@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- Devices that can't run effects themselves get them stepped by the backend
+ALTER TABLE lights
+ADD COLUMN native_effects BOOLEAN NOT NULL DEFAULT FALSE;
+insert into traits
+values(
+        DEFAULT,
+        'light_rgb',
+        'action.devices.traits.LightEffects'
+    );
+insert into traits
+values(
+        DEFAULT,
+        'light_non_rgb',
+        'action.devices.traits.LightEffects'
+    );
+-- Devices registered before got their traits copied without it
+UPDATE devices
+SET traits = array_append(traits, 'action.devices.traits.LightEffects')
+WHERE type IN ('light_rgb', 'light_non_rgb')
+    AND NOT ('action.devices.traits.LightEffects' = ANY(traits));
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Server side state of the session cookies, the cookie JWT carries the id as jti
+CREATE TABLE sessions (
+    id uuid PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    user_agent VARCHAR,
+    ip_address VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
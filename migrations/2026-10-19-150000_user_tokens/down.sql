@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE user_tokens;
+ALTER TABLE users DROP COLUMN email_verified_at;
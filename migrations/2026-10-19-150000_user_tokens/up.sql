@@ -0,0 +1,14 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD COLUMN email_verified_at TIMESTAMPTZ;
+-- Single use tokens sent by email, only their sha256 is stored
+CREATE TABLE user_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    purpose VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+CREATE INDEX user_tokens_user_id_idx ON user_tokens (user_id);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- A new address only replaces the current one once it has been verified
+ALTER TABLE users
+ADD COLUMN pending_email VARCHAR;
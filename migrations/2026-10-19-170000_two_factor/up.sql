@@ -0,0 +1,22 @@
+-- Your SQL goes here
+-- enabled_at is NULL while the user hasn't confirmed a first code yet
+CREATE TABLE totp_credentials (
+    user_id INT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    enabled_at TIMESTAMPTZ,
+    -- Time step of the last accepted code, a code can't be used twice
+    last_used_step BIGINT
+);
+-- Only the sha256 of the codes is stored
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    used_at TIMESTAMPTZ
+);
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+-- Sessions started with the second factor, OAuth linking requires one
+ALTER TABLE sessions
+ADD COLUMN two_factor BOOLEAN NOT NULL DEFAULT FALSE;
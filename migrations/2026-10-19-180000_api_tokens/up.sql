@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- Long lived tokens for scripts, sent as "Authorization: Bearer". Only the
+-- sha256 of the token is stored, scope is space separated like in OAuth.
+CREATE TABLE api_tokens (
+    id uuid PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    scope VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- OAuth clients used to be hard-coded. Confidential clients have the sha256 of
+-- their secret, public ones none. Admins manage the clients, grant the flag
+-- with UPDATE users SET is_admin = true WHERE email = '...'.
+CREATE TABLE oauth_clients (
+    id VARCHAR PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    secret_hash VARCHAR,
+    redirect_uris TEXT[] NOT NULL,
+    scope VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    disabled_at TIMESTAMPTZ
+);
+ALTER TABLE users
+ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
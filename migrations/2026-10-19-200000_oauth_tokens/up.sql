@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- Access and refresh tokens of OAuth grants, so they survive restarts. Only
+-- the sha256 of the tokens is stored, refreshing replaces both of them.
+CREATE TABLE oauth_tokens (
+    id uuid PRIMARY KEY,
+    client_id VARCHAR NOT NULL REFERENCES oauth_clients (id) ON DELETE CASCADE,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    scope VARCHAR NOT NULL,
+    redirect_uri VARCHAR NOT NULL,
+    access_token_hash VARCHAR NOT NULL UNIQUE,
+    refresh_token_hash VARCHAR UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ
+);
+CREATE INDEX oauth_tokens_user_id_idx ON oauth_tokens (user_id);
+CREATE INDEX oauth_tokens_client_id_idx ON oauth_tokens (client_id);
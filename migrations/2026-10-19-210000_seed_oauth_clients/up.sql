@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- LocalClient was one of the hard-coded clients, the example linked from
+-- /oauth/ uses it. It is public and only redirects to localhost. GoogleHome
+-- isn't seeded since its secret was in the source, admins create it again
+-- with "client_id": "GoogleHome" and the Actions console's redirect URI.
+INSERT INTO oauth_clients (id, name, secret_hash, redirect_uris, scope)
+VALUES (
+    'LocalClient',
+    'Local example client',
+    NULL,
+    ARRAY['http://localhost:8000/oauth/getToken'],
+    'devices:read devices:control devices:manage profile'
+)
+ON CONFLICT (id) DO NOTHING;
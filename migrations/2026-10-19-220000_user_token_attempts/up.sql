@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Wrong codes sent with a login challenge, it is used up after a few of them
+ALTER TABLE user_tokens
+ADD COLUMN failed_attempts INT NOT NULL DEFAULT 0;
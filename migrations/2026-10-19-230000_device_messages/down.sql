@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE device_telemetry;
+DROP TABLE device_message_nonces;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- Nonces of the signed device messages, a message whose nonce was already seen
+-- is a replay. Only kept for as long as the timestamp check would accept it.
+CREATE TABLE device_message_nonces (
+    device_id uuid NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    nonce VARCHAR NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (device_id, nonce)
+);
+CREATE INDEX device_message_nonces_received_at_idx ON device_message_nonces (received_at);
+CREATE TABLE device_telemetry (
+    id BIGSERIAL PRIMARY KEY,
+    device_id uuid NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    data JSONB NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX device_telemetry_device_id_idx ON device_telemetry (device_id, received_at);
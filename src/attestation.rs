@@ -0,0 +1,274 @@
+// Verification of the manufacturer signature every device ships with.
+//
+// The backend only ever holds public keys. Signing keys live on the factory
+// line (see src/bin/device_factory.rs) and are identified by a key id, so new
+// keys can be added and old ones retired without re-flashing devices.
+//
+// A signature has the form "<kid>:<base64url signature>". Signatures without
+// a key id predate rotation and are checked against every configured key.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose, Engine};
+use openssl::{
+	error::ErrorStack,
+	hash::MessageDigest,
+	pkey::{Id, PKey, Public},
+	sign::Verifier,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const KEYS_DIR_VAR: &str = "DEVICE_ATTESTATION_KEYS";
+pub const KID_SEPARATOR: char = ':';
+
+// The data covered by the manufacturer signature
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceIdentity {
+	pub id: Uuid,
+	pub type_: String,
+}
+
+impl DeviceIdentity {
+	pub fn signing_payload(&self) -> Vec<u8> {
+		serde_json::to_vec(self).expect("device identity is always serializable")
+	}
+}
+
+#[derive(Debug)]
+pub enum AttestationError {
+	NotConfigured,
+	NoKeys(PathBuf),
+	KeyFile(PathBuf, io::Error),
+	InvalidKey(String, ErrorStack),
+	UnsupportedKeyType(String),
+	UnknownKeyId(String),
+	MalformedSignature,
+	InvalidSignature,
+	Crypto(ErrorStack),
+}
+
+impl fmt::Display for AttestationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AttestationError::NotConfigured => write!(f, "{} is not set", KEYS_DIR_VAR),
+			AttestationError::NoKeys(dir) => {
+				write!(f, "no attestation keys found in {}", dir.display())
+			}
+			AttestationError::KeyFile(path, err) => {
+				write!(f, "failed to read {}: {}", path.display(), err)
+			}
+			AttestationError::InvalidKey(kid, err) => write!(f, "invalid key '{}': {}", kid, err),
+			AttestationError::UnsupportedKeyType(kid) => {
+				write!(f, "key '{}' is neither RSA nor Ed25519", kid)
+			}
+			AttestationError::UnknownKeyId(kid) => write!(f, "unknown key id '{}'", kid),
+			AttestationError::MalformedSignature => write!(f, "malformed signature"),
+			AttestationError::InvalidSignature => write!(f, "invalid signature"),
+			AttestationError::Crypto(err) => write!(f, "crypto error: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for AttestationError {}
+
+impl From<ErrorStack> for AttestationError {
+	fn from(err: ErrorStack) -> Self {
+		AttestationError::Crypto(err)
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+	RsaSha256,
+	Ed25519,
+}
+
+impl Algorithm {
+	pub fn of_key<T>(kid: &str, key: &PKey<T>) -> Result<Algorithm, AttestationError> {
+		match key.id() {
+			Id::RSA => Ok(Algorithm::RsaSha256),
+			Id::ED25519 => Ok(Algorithm::Ed25519),
+			_ => Err(AttestationError::UnsupportedKeyType(kid.to_string())),
+		}
+	}
+}
+
+pub struct VerifyingKey {
+	pub kid: String,
+	pub algorithm: Algorithm,
+	key: PKey<Public>,
+}
+
+impl VerifyingKey {
+	pub fn from_pem(kid: &str, pem: &[u8]) -> Result<Self, AttestationError> {
+		let key = PKey::public_key_from_pem(pem)
+			.map_err(|err| AttestationError::InvalidKey(kid.to_string(), err))?;
+		Ok(VerifyingKey {
+			kid: kid.to_string(),
+			algorithm: Algorithm::of_key(kid, &key)?,
+			key,
+		})
+	}
+
+	fn verify(&self, payload: &[u8], signature: &[u8]) -> Result<bool, AttestationError> {
+		match self.algorithm {
+			Algorithm::RsaSha256 => {
+				let mut verifier = Verifier::new(MessageDigest::sha256(), &self.key)?;
+				verifier.update(payload)?;
+				Ok(verifier.verify(signature)?)
+			}
+			Algorithm::Ed25519 => {
+				let mut verifier = Verifier::new_without_digest(&self.key)?;
+				Ok(verifier.verify_oneshot(signature, payload)?)
+			}
+		}
+	}
+}
+
+pub struct AttestationKeys {
+	keys: HashMap<String, VerifyingKey>,
+}
+
+impl AttestationKeys {
+	pub fn new(keys: Vec<VerifyingKey>) -> Self {
+		AttestationKeys {
+			keys: keys.into_iter().map(|key| (key.kid.clone(), key)).collect(),
+		}
+	}
+
+	// Loads every `<kid>.pem` public key from the directory in DEVICE_ATTESTATION_KEYS
+	pub fn from_env() -> Result<Self, AttestationError> {
+		match dotenv::var(KEYS_DIR_VAR) {
+			Ok(dir) => AttestationKeys::from_dir(Path::new(&dir)),
+			Err(_) => Err(AttestationError::NotConfigured),
+		}
+	}
+
+	pub fn from_dir(dir: &Path) -> Result<Self, AttestationError> {
+		let entries =
+			fs::read_dir(dir).map_err(|err| AttestationError::KeyFile(dir.to_path_buf(), err))?;
+		let mut keys = vec![];
+		for entry in entries {
+			let path = entry
+				.map_err(|err| AttestationError::KeyFile(dir.to_path_buf(), err))?
+				.path();
+			if path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+				continue;
+			}
+			let kid = match path.file_stem().and_then(|stem| stem.to_str()) {
+				Some(kid) => kid.to_string(),
+				None => continue,
+			};
+			let pem = fs::read(&path).map_err(|err| AttestationError::KeyFile(path.clone(), err))?;
+			keys.push(VerifyingKey::from_pem(&kid, &pem)?);
+		}
+		if keys.is_empty() {
+			return Err(AttestationError::NoKeys(dir.to_path_buf()));
+		}
+		Ok(AttestationKeys::new(keys))
+	}
+
+	pub fn verify(&self, identity: &DeviceIdentity, signature: &str) -> Result<(), AttestationError> {
+		let payload = identity.signing_payload();
+		let (kid, encoded) = match signature.split_once(KID_SEPARATOR) {
+			Some((kid, encoded)) => (Some(kid), encoded),
+			None => (None, signature),
+		};
+		let signature = general_purpose::URL_SAFE
+			.decode(encoded)
+			.map_err(|_| AttestationError::MalformedSignature)?;
+		let candidates: Vec<&VerifyingKey> = match kid {
+			Some(kid) => match self.keys.get(kid) {
+				Some(key) => vec![key],
+				None => return Err(AttestationError::UnknownKeyId(kid.to_string())),
+			},
+			None => self.keys.values().collect(),
+		};
+		for key in candidates {
+			if key.verify(&payload, &signature)? {
+				return Ok(());
+			}
+		}
+		Err(AttestationError::InvalidSignature)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::attestation_signing::SigningKey;
+
+	fn identity() -> DeviceIdentity {
+		DeviceIdentity {
+			id: Uuid::new_v4(),
+			type_: "light".to_string(),
+		}
+	}
+
+	fn keys_for(signing_keys: &[&SigningKey]) -> AttestationKeys {
+		let public_key = |key: &&SigningKey| {
+			VerifyingKey::from_pem(&key.kid, &key.public_key_pem().unwrap()).unwrap()
+		};
+		AttestationKeys::new(signing_keys.iter().map(public_key).collect())
+	}
+
+	#[test]
+	fn signatures_of_every_configured_key_verify() {
+		let ed25519 = SigningKey::generate("2026-ed", Algorithm::Ed25519).unwrap();
+		let rsa = SigningKey::generate("2026-rsa", Algorithm::RsaSha256).unwrap();
+		let keys = keys_for(&[&ed25519, &rsa]);
+		let identity = identity();
+		for key in [&ed25519, &rsa] {
+			let signature = key.sign(&identity).unwrap();
+			assert!(keys.verify(&identity, &signature).is_ok());
+			// Without the kid every key is tried
+			let (_, legacy) = signature.split_once(KID_SEPARATOR).unwrap();
+			assert!(keys.verify(&identity, legacy).is_ok());
+		}
+	}
+
+	// What device_factory keygen writes and sign reads
+	#[test]
+	fn signing_keys_survive_the_pem_round_trip() {
+		let key = SigningKey::generate("2026", Algorithm::RsaSha256).unwrap();
+		let loaded = SigningKey::from_pem("2026", &key.private_key_pem().unwrap()).unwrap();
+		assert_eq!(loaded.algorithm, Algorithm::RsaSha256);
+		let identity = identity();
+		let signature = loaded.sign(&identity).unwrap();
+		assert!(keys_for(&[&key]).verify(&identity, &signature).is_ok());
+	}
+
+	#[test]
+	fn invalid_signatures_are_rejected() {
+		let key = SigningKey::generate("2026", Algorithm::Ed25519).unwrap();
+		let keys = keys_for(&[&key]);
+		let identity = identity();
+		let signature = key.sign(&identity).unwrap();
+
+		// Signed for another device
+		let other = DeviceIdentity {
+			id: Uuid::new_v4(),
+			..identity.clone()
+		};
+		assert!(matches!(keys.verify(&other, &signature), Err(AttestationError::InvalidSignature)));
+		// Signed with a key that isn't the configured one
+		let impostor = SigningKey::generate("2026", Algorithm::Ed25519).unwrap();
+		let forged = impostor.sign(&identity).unwrap();
+		assert!(matches!(keys.verify(&identity, &forged), Err(AttestationError::InvalidSignature)));
+
+		let unknown = signature.replacen("2026", "2025", 1);
+		assert!(matches!(
+			keys.verify(&identity, &unknown),
+			Err(AttestationError::UnknownKeyId(kid)) if kid == "2025"
+		));
+		assert!(matches!(
+			keys.verify(&identity, "2026:not base64!"),
+			Err(AttestationError::MalformedSignature)
+		));
+	}
+}
@@ -0,0 +1,71 @@
+// Signing of device identities on the factory line, see
+// src/bin/device_factory.rs. Kept apart from attestation.rs so the backend,
+// which only ever verifies, doesn't carry private key handling.
+use base64::{engine::general_purpose, Engine};
+use openssl::{
+	hash::MessageDigest,
+	pkey::{PKey, Private},
+	rsa::Rsa,
+	sign::Signer,
+};
+
+use crate::attestation::{Algorithm, AttestationError, DeviceIdentity, KID_SEPARATOR};
+
+pub struct SigningKey {
+	pub kid: String,
+	pub algorithm: Algorithm,
+	key: PKey<Private>,
+}
+
+impl SigningKey {
+	pub fn generate(kid: &str, algorithm: Algorithm) -> Result<Self, AttestationError> {
+		let key = match algorithm {
+			Algorithm::RsaSha256 => PKey::from_rsa(Rsa::generate(2048)?)?,
+			Algorithm::Ed25519 => PKey::generate_ed25519()?,
+		};
+		Ok(SigningKey {
+			kid: kid.to_string(),
+			algorithm,
+			key,
+		})
+	}
+
+	pub fn from_pem(kid: &str, pem: &[u8]) -> Result<Self, AttestationError> {
+		let key = PKey::private_key_from_pem(pem)
+			.map_err(|err| AttestationError::InvalidKey(kid.to_string(), err))?;
+		Ok(SigningKey {
+			kid: kid.to_string(),
+			algorithm: Algorithm::of_key(kid, &key)?,
+			key,
+		})
+	}
+
+	pub fn private_key_pem(&self) -> Result<Vec<u8>, AttestationError> {
+		Ok(self.key.private_key_to_pem_pkcs8()?)
+	}
+
+	pub fn public_key_pem(&self) -> Result<Vec<u8>, AttestationError> {
+		Ok(self.key.public_key_to_pem()?)
+	}
+
+	pub fn sign(&self, identity: &DeviceIdentity) -> Result<String, AttestationError> {
+		let payload = identity.signing_payload();
+		let signature = match self.algorithm {
+			Algorithm::RsaSha256 => {
+				let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+				signer.update(&payload)?;
+				signer.sign_to_vec()?
+			}
+			Algorithm::Ed25519 => {
+				let mut signer = Signer::new_without_digest(&self.key)?;
+				signer.sign_oneshot_to_vec(&payload)?
+			}
+		};
+		Ok(format!(
+			"{}{}{}",
+			self.kid,
+			KID_SEPARATOR,
+			general_purpose::URL_SAFE.encode(signature)
+		))
+	}
+}
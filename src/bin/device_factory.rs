@@ -0,0 +1,123 @@
+// Factory line tool for generating signing keys and signed device identities.
+//
+//   device_factory keygen --kid <kid> [--algorithm ed25519|rsa] [--out <dir>]
+//   device_factory sign --key <private.pem> --kid <kid> --type <device type> [--id <uuid>] [--count <n>]
+//
+// `keygen` writes `<kid>.private.pem` (keep it on the factory line) and
+// `<kid>.pem` (copy it into the backend's DEVICE_ATTESTATION_KEYS directory).
+// `sign` prints one JSON identity per line, ready to be flashed onto a device.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use uuid::Uuid;
+
+// The verifying half is the backend's, only the shared types are used here
+#[allow(dead_code)]
+#[path = "../attestation.rs"]
+mod attestation;
+#[path = "../attestation_signing.rs"]
+mod attestation_signing;
+
+use attestation::{Algorithm, DeviceIdentity};
+use attestation_signing::SigningKey;
+
+const USAGE: &str = "usage:
+  device_factory keygen --kid <kid> [--algorithm ed25519|rsa] [--out <dir>]
+  device_factory sign --key <private.pem> --kid <kid> --type <device type> [--id <uuid>] [--count <n>]";
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	let result = match args.split_first() {
+		Some((command, rest)) => match (command.as_str(), parse_flags(rest)) {
+			("keygen", Ok(flags)) => keygen(&flags),
+			("sign", Ok(flags)) => sign(&flags),
+			(_, Err(err)) => Err(err),
+			_ => Err(USAGE.to_string()),
+		},
+		None => Err(USAGE.to_string()),
+	};
+	if let Err(err) = result {
+		eprintln!("{}", err);
+		process::exit(1);
+	}
+}
+
+fn parse_flags(args: &[String]) -> Result<HashMap<String, String>, String> {
+	let mut flags = HashMap::new();
+	let mut iter = args.iter();
+	while let Some(flag) = iter.next() {
+		let name = match flag.strip_prefix("--") {
+			Some(name) => name,
+			None => return Err(format!("unexpected argument '{}'\n{}", flag, USAGE)),
+		};
+		match iter.next() {
+			Some(value) => flags.insert(name.to_string(), value.clone()),
+			None => return Err(format!("missing value for --{}\n{}", name, USAGE)),
+		};
+	}
+	Ok(flags)
+}
+
+fn required<'a>(flags: &'a HashMap<String, String>, name: &str) -> Result<&'a str, String> {
+	flags
+		.get(name)
+		.map(|value| value.as_str())
+		.ok_or(format!("--{} is required\n{}", name, USAGE))
+}
+
+fn keygen(flags: &HashMap<String, String>) -> Result<(), String> {
+	let kid = required(flags, "kid")?;
+	let algorithm = match flags.get("algorithm").map(|alg| alg.as_str()) {
+		None | Some("ed25519") => Algorithm::Ed25519,
+		Some("rsa") => Algorithm::RsaSha256,
+		Some(other) => return Err(format!("unknown algorithm '{}'", other)),
+	};
+	let out = Path::new(flags.get("out").map(|out| out.as_str()).unwrap_or("."));
+	let key = SigningKey::generate(kid, algorithm).map_err(|err| err.to_string())?;
+	let private_path = out.join(format!("{}.private.pem", kid));
+	let public_path = out.join(format!("{}.pem", kid));
+	if private_path.exists() || public_path.exists() {
+		return Err(format!("a key with id '{}' already exists in {}", kid, out.display()));
+	}
+	fs::write(&private_path, key.private_key_pem().map_err(|err| err.to_string())?)
+		.map_err(|err| err.to_string())?;
+	fs::write(&public_path, key.public_key_pem().map_err(|err| err.to_string())?)
+		.map_err(|err| err.to_string())?;
+	println!("wrote {} and {}", private_path.display(), public_path.display());
+	Ok(())
+}
+
+fn sign(flags: &HashMap<String, String>) -> Result<(), String> {
+	let kid = required(flags, "kid")?;
+	let type_ = required(flags, "type")?;
+	let pem = fs::read(required(flags, "key")?).map_err(|err| err.to_string())?;
+	let key = SigningKey::from_pem(kid, &pem).map_err(|err| err.to_string())?;
+	let count = match flags.get("count") {
+		Some(count) => count
+			.parse::<u32>()
+			.map_err(|_| format!("invalid count '{}'", count))?,
+		None => 1,
+	};
+	let fixed_id = match flags.get("id") {
+		Some(id) => Some(Uuid::parse_str(id).map_err(|_| format!("invalid id '{}'", id))?),
+		None => None,
+	};
+	if fixed_id.is_some() && count != 1 {
+		return Err("--id can only be used with a single device".to_string());
+	}
+	for _ in 0..count {
+		let identity = DeviceIdentity {
+			id: fixed_id.unwrap_or_else(Uuid::new_v4),
+			type_: type_.to_string(),
+		};
+		let secret = key.sign(&identity).map_err(|err| err.to_string())?;
+		println!(
+			"{}",
+			serde_json::json!({"id":identity.id,"type_":identity.type_,"secret":secret})
+		);
+	}
+	Ok(())
+}
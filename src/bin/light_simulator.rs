@@ -0,0 +1,209 @@
+// Stands in for the CoAP gateway and the lights behind it, so the backend and
+// the frontend can be developed without hardware. Point COAP_IP/COAP_PORT of
+// the backend at it.
+//
+// SIMULATOR_ADDR       where to listen, 0.0.0.0:5683 by default
+// SIMULATOR_LIGHTS     how many lights exist from the start (their ids are printed)
+// SIMULATOR_LATENCY_MS delay before every answer
+// SIMULATOR_OFFLINE_CHANCE chance (0 to 1) per light and second to go offline
+// SIMULATOR_OFFLINE_SECS   longest time a light stays offline
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use coap::Server;
+use coap_lite::{CoapRequest, CoapResponse, RequestType as Method, ResponseType};
+use rand_core::{OsRng, RngCore};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+struct VirtualLight {
+	// Last state the backend sent, None until the first command
+	state: Option<Value>,
+	offline_until: Option<Instant>,
+}
+
+impl VirtualLight {
+	fn new() -> Self {
+		VirtualLight {
+			state: None,
+			offline_until: None,
+		}
+	}
+
+	fn is_online(&self) -> bool {
+		self.offline_until.is_none()
+	}
+}
+
+type Lights = Arc<Mutex<HashMap<Uuid, VirtualLight>>>;
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+	env::var(name)
+		.ok()
+		.and_then(|value| value.parse().ok())
+		.unwrap_or(default)
+}
+
+// Uniform in [0, 1)
+fn random() -> f64 {
+	OsRng.next_u32() as f64 / (u32::MAX as f64 + 1.0)
+}
+
+fn reply(request: CoapRequest<SocketAddr>, status: ResponseType) -> Option<CoapResponse> {
+	reply_with(request, status, vec![])
+}
+
+fn reply_with(
+	request: CoapRequest<SocketAddr>,
+	status: ResponseType,
+	payload: Vec<u8>,
+) -> Option<CoapResponse> {
+	let mut response = request.response?;
+	response.set_status(status);
+	response.message.payload = payload;
+	Some(response)
+}
+
+// None means the request goes unanswered, which is what an offline light does
+fn handle(lights: &Lights, request: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+	let path = request.get_path();
+	let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+	let method = *request.get_method();
+	match (method, segments.as_slice()) {
+		(Method::Put, ["lights", "create"]) => {
+			let device_id = String::from_utf8(request.message.payload.clone())
+				.ok()
+				.and_then(|device_id| Uuid::parse_str(device_id.trim()).ok());
+			match device_id {
+				Some(device_id) => {
+					lights
+						.lock()
+						.unwrap()
+						.entry(device_id)
+						.or_insert_with(VirtualLight::new);
+					println!("{}: created", device_id);
+					reply(request, ResponseType::Created)
+				}
+				None => reply(request, ResponseType::BadRequest),
+			}
+		}
+		(Method::Put, ["lights", "remove", device_id]) => {
+			let removed = Uuid::parse_str(device_id)
+				.ok()
+				.and_then(|device_id| lights.lock().unwrap().remove(&device_id));
+			match removed {
+				Some(_) => {
+					println!("{}: removed", device_id);
+					reply(request, ResponseType::Deleted)
+				}
+				None => reply(request, ResponseType::NotFound),
+			}
+		}
+		(Method::Get, ["lights", "is_online", device_id]) => {
+			let online = Uuid::parse_str(device_id).ok().and_then(|device_id| {
+				lights.lock().unwrap().get(&device_id).map(VirtualLight::is_online)
+			});
+			match online {
+				Some(online) => {
+					let body = json!({ "isOnline": online }).to_string();
+					reply_with(request, ResponseType::Content, body.into_bytes())
+				}
+				None => reply(request, ResponseType::NotFound),
+			}
+		}
+		(Method::Put, ["lights", device_id]) => {
+			let device_id = match Uuid::parse_str(device_id) {
+				Ok(device_id) => device_id,
+				Err(_) => return reply(request, ResponseType::NotFound),
+			};
+			let state: Value = match serde_json::from_slice(&request.message.payload) {
+				Ok(state) => state,
+				Err(_) => return reply(request, ResponseType::BadRequest),
+			};
+			let mut lights = lights.lock().unwrap();
+			let light = match lights.get_mut(&device_id) {
+				Some(light) => light,
+				None => return reply(request, ResponseType::NotFound),
+			};
+			if !light.is_online() {
+				println!("{}: offline, dropped {}", device_id, state);
+				return None;
+			}
+			if state["removed"].as_bool() == Some(true) {
+				println!("{}: wiped", device_id);
+			} else {
+				println!("{}: {}", device_id, state);
+			}
+			light.state = Some(state);
+			reply(request, ResponseType::Changed)
+		}
+		_ => reply(request, ResponseType::NotFound),
+	}
+}
+
+// Every second each light may drop offline, and lights whose offline period
+// is over come back
+async fn flicker(lights: Lights, offline_chance: f64, offline_secs: u64) {
+	loop {
+		sleep(Duration::from_secs(1)).await;
+		let now = Instant::now();
+		for (device_id, light) in lights.lock().unwrap().iter_mut() {
+			match light.offline_until {
+				Some(until) if until <= now => {
+					light.offline_until = None;
+					println!("{}: back online", device_id);
+				}
+				Some(_) => {}
+				None if random() < offline_chance => {
+					let secs = 1 + (random() * offline_secs as f64) as u64;
+					light.offline_until = Some(now + Duration::from_secs(secs));
+					println!("{}: offline for {}s", device_id, secs);
+				}
+				None => {}
+			}
+		}
+	}
+}
+
+fn main() {
+	dotenv::dotenv().ok();
+	let addr: String = env_or("SIMULATOR_ADDR", "0.0.0.0:5683".to_string());
+	let light_count: usize = env_or("SIMULATOR_LIGHTS", 0);
+	let latency = Duration::from_millis(env_or("SIMULATOR_LATENCY_MS", 0));
+	let offline_chance: f64 = env_or("SIMULATOR_OFFLINE_CHANCE", 0.0);
+	let offline_secs: u64 = env_or("SIMULATOR_OFFLINE_SECS", 10).max(1);
+
+	let lights: Lights = Arc::new(Mutex::new(HashMap::new()));
+	for _ in 0..light_count {
+		let device_id = Uuid::new_v4();
+		lights.lock().unwrap().insert(device_id, VirtualLight::new());
+		println!("{}: created", device_id);
+	}
+
+	let rt = Runtime::new().unwrap();
+	rt.block_on(async move {
+		if offline_chance > 0.0 {
+			tokio::spawn(flicker(lights.clone(), offline_chance, offline_secs));
+		}
+		let mut server = Server::new(addr.as_str()).expect("failed to bind SIMULATOR_ADDR");
+		println!("simulating lights on {}", addr);
+		server
+			.run(move |request| {
+				let lights = lights.clone();
+				async move {
+					if !latency.is_zero() {
+						sleep(latency).await;
+					}
+					handle(&lights, request)
+				}
+			})
+			.await
+			.expect("CoAP server failed");
+	});
+}
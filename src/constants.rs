@@ -1,3 +1,28 @@
 #![allow(dead_code)]
 pub const NON_RGB_LIGHT: &str = "light_non_rgb";
 pub const RGB_LIGHT: &str = "light_rgb";
+
+pub const DEVICE_ACTIVE: &str = "active";
+pub const DEVICE_PENDING_REMOVAL: &str = "pending_removal";
+
+pub const TRANSFER_PENDING: &str = "pending";
+pub const TRANSFER_ACCEPTED: &str = "accepted";
+pub const TRANSFER_DECLINED: &str = "declined";
+pub const TRANSFER_CANCELLED: &str = "cancelled";
+pub const TRANSFER_EXPIRED: &str = "expired";
+
+pub const TOKEN_VERIFY_EMAIL: &str = "verify_email";
+pub const TOKEN_RESET_PASSWORD: &str = "reset_password";
+pub const TOKEN_CHANGE_EMAIL: &str = "change_email";
+pub const TOKEN_LOGIN_CHALLENGE: &str = "login_challenge";
+
+// Scopes of API tokens and OAuth grants, the device scopes mean the same for
+// both
+pub const SCOPE_DEVICES_READ: &str = "devices:read";
+pub const SCOPE_DEVICES_CONTROL: &str = "devices:control";
+pub const SCOPE_DEVICES_MANAGE: &str = "devices:manage";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const API_TOKEN_SCOPES: [&str; 3] =
+	[SCOPE_DEVICES_READ, SCOPE_DEVICES_CONTROL, SCOPE_DEVICES_MANAGE];
+pub const OAUTH_SCOPES: [&str; 4] =
+	[SCOPE_DEVICES_READ, SCOPE_DEVICES_CONTROL, SCOPE_DEVICES_MANAGE, SCOPE_PROFILE];
@@ -0,0 +1,96 @@
+// Removing a device for good: the device is told to wipe its credentials and
+// only once it acknowledges that are its rows deleted. A device that doesn't
+// answer in time stays in the "pending_removal" state and the removal is
+// retried on the next request and on startup.
+//
+// Tables that reference a device (e.g. device_transfers) do so with
+// ON DELETE CASCADE, so deleting the device row cleans them up as well.
+use diesel::prelude::*;
+use diesel::PgConnection;
+use schemars::JsonSchema;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::effects::EffectRunner;
+use crate::models::device::Device;
+use crate::models::device_credential::DeviceCredential;
+use crate::models::light::{Light, LightState};
+use crate::models::values::{Brightness, Rgb24};
+use crate::services::gateway::{with_timeout, DeviceGateway, GatewayError};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DecommissionStatus {
+	Removed,
+	PendingRemoval,
+}
+
+// Asks the device to wipe itself and the gateway to forget it, true when both
+// acknowledged in time
+pub async fn wipe_device(
+	gateway: &dyn DeviceGateway,
+	light: Option<&Light>,
+	device_id: Uuid,
+) -> bool {
+	let light_state = match light {
+		Some(light) => LightState {
+			removed: true,
+			..LightState::from_light(light)
+		},
+		None => LightState {
+			is_on: false,
+			brightness: Brightness::MIN,
+			color: Rgb24::BLACK,
+			removed: true,
+			transition_ms: None,
+			effect: None,
+		},
+	};
+	if with_timeout(gateway.command(device_id, light_state)).await.is_err() {
+		return false;
+	}
+	// The gateway may already have forgotten about the device
+	matches!(
+		with_timeout(gateway.remove(device_id)).await,
+		Ok(()) | Err(GatewayError::UnknownDevice)
+	)
+}
+
+// Safe to call repeatedly, a device that is already pending removal is simply
+// retried
+pub fn decommission(
+	device: &Device,
+	gateway: &dyn DeviceGateway,
+	effects: &EffectRunner,
+	conn: &mut PgConnection,
+) -> QueryResult<DecommissionStatus> {
+	Device::mark_pending_removal(device.id, conn)?;
+	// From here on the device can't talk to us anymore, whatever the outcome
+	DeviceCredential::revoke_all(device.id, conn)?;
+	// A running effect would keep sending states and undo the wipe
+	effects.cancel(device.id);
+	let light = Light::get_device_by_id(device.id, conn)?;
+	let rt = Runtime::new().unwrap();
+	if !rt.block_on(wipe_device(gateway, light.as_ref(), device.id)) {
+		return Ok(DecommissionStatus::PendingRemoval);
+	}
+	conn.transaction(|local_conn| {
+		Light::remove_device(device.id, local_conn)?;
+		Device::remove_device(device.id, local_conn)?;
+		Ok(DecommissionStatus::Removed)
+	})
+}
+
+pub fn retry_pending_removals(
+	devices: Vec<Device>,
+	gateway: &dyn DeviceGateway,
+	effects: &EffectRunner,
+	conn: &mut PgConnection,
+) {
+	for device in devices {
+		match decommission(&device, gateway, effects, conn) {
+			Ok(status) => println!("decommission of {}: {:?}", device.id, status),
+			Err(err) => println!("decommission of {} failed: {}", device.id, err),
+		}
+	}
+}
@@ -0,0 +1,356 @@
+// Transitions and effects for lights that can't run them on their own.
+//
+// Lights with `native_effects` get the transition/effect fields in the state we
+// send and handle them themselves. For every other light the backend computes
+// the intermediate states and sends them one by one from a background thread,
+// until the effect ends or the light receives another command.
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::models::light::{LightEffect, LightState};
+use crate::models::values::{Brightness, Rgb24};
+use crate::services::gateway::{with_timeout, Gateway};
+
+const STEP_MS: u64 = 100;
+// Don't flood the gateway, even for fast strobes
+const MIN_STEP_MS: u64 = 25;
+const DEFAULT_CYCLE_COLORS: [(u8, u8, u8); 6] = [
+	(255, 0, 0),
+	(255, 255, 0),
+	(0, 255, 0),
+	(0, 255, 255),
+	(0, 0, 255),
+	(255, 0, 255),
+];
+
+struct RunningEffect {
+	cancelled: Arc<AtomicBool>,
+	label: String,
+}
+
+pub struct EffectRunner {
+	running: Arc<Mutex<HashMap<Uuid, RunningEffect>>>,
+	gateway: Gateway,
+}
+
+// Name of what the light is doing, used for reporting the active effect
+pub fn effect_label(target: &LightState) -> Option<String> {
+	match (&target.effect, target.transition_ms) {
+		(Some(effect), _) => Some(effect.name().to_string()),
+		(None, Some(transition_ms)) if transition_ms > 0 => Some(
+			if target.is_on {
+				"fade_on"
+			} else {
+				"fade_off"
+			}
+			.to_string(),
+		),
+		_ => None,
+	}
+}
+
+impl EffectRunner {
+	pub fn new(gateway: Gateway) -> Self {
+		EffectRunner {
+			running: Arc::new(Mutex::new(HashMap::new())),
+			gateway,
+		}
+	}
+
+	// Stops whatever runs on the light, every new command for a light has to
+	// go through here first
+	pub fn cancel(&self, light_id: Uuid) {
+		if let Some(effect) = self.running.lock().unwrap().remove(&light_id) {
+			effect.cancelled.store(true, Ordering::SeqCst);
+		}
+	}
+
+	pub fn active_effect(&self, light_id: Uuid) -> Option<String> {
+		self.running
+			.lock()
+			.unwrap()
+			.get(&light_id)
+			.map(|effect| effect.label.clone())
+	}
+
+	// Keeps track of an effect the light runs natively
+	pub fn record_native(&self, light_id: Uuid, target: &LightState) {
+		self.cancel(light_id);
+		if let Some(label) = effect_label(target) {
+			self.running.lock().unwrap().insert(
+				light_id,
+				RunningEffect {
+					cancelled: Arc::new(AtomicBool::new(false)),
+					label,
+				},
+			);
+		}
+	}
+
+	// What to send right away to a light that doesn't support effects
+	pub fn first_frame(from: &LightState, target: &LightState) -> LightState {
+		frame(from, target, 0).0
+	}
+
+	// Plays the rest of the transition/effect, starting one step after the
+	// first frame
+	pub fn start(&self, light_id: Uuid, from: LightState, target: LightState) {
+		let label = match effect_label(&target) {
+			Some(label) => label,
+			None => return,
+		};
+		self.cancel(light_id);
+		let cancelled = Arc::new(AtomicBool::new(false));
+		self.running.lock().unwrap().insert(
+			light_id,
+			RunningEffect {
+				cancelled: cancelled.clone(),
+				label,
+			},
+		);
+		let running = self.running.clone();
+		let gateway = self.gateway.clone();
+		let step = Duration::from_millis(step_ms(&target));
+		thread::spawn(move || {
+			let rt = Runtime::new().unwrap();
+			let started = Instant::now();
+			loop {
+				thread::sleep(step);
+				if cancelled.load(Ordering::SeqCst) {
+					return;
+				}
+				let (state, done) = frame(&from, &target, started.elapsed().as_millis() as u64);
+				let delivered = rt
+					.block_on(with_timeout(gateway.command(light_id, state)))
+					.is_ok();
+				// A light that went away can't be animated anymore
+				if done || !delivered {
+					break;
+				}
+			}
+			let mut running = running.lock().unwrap();
+			if let Some(effect) = running.get(&light_id) {
+				if Arc::ptr_eq(&effect.cancelled, &cancelled) {
+					running.remove(&light_id);
+				}
+			}
+		});
+	}
+}
+
+fn step_ms(target: &LightState) -> u64 {
+	match &target.effect {
+		Some(LightEffect::Strobe { frequency_hz }) => {
+			(500 / (*frequency_hz).max(1) as u64).clamp(MIN_STEP_MS, STEP_MS)
+		}
+		_ => STEP_MS,
+	}
+}
+
+fn lerp(from: i32, to: i32, progress: f64) -> i32 {
+	from + ((to - from) as f64 * progress).round() as i32
+}
+
+fn lerp_brightness(from: Brightness, to: Brightness, progress: f64) -> Brightness {
+	Brightness::saturating(lerp(from.raw(), to.raw(), progress))
+}
+
+fn lerp_color(from: Rgb24, to: Rgb24, progress: f64) -> Rgb24 {
+	let (from_red, from_green, from_blue) = from.channels();
+	let (to_red, to_green, to_blue) = to.channels();
+	let channel = |from: u8, to: u8| lerp(from as i32, to as i32, progress).clamp(0, 255) as u8;
+	Rgb24::from_channels(
+		channel(from_red, to_red),
+		channel(from_green, to_green),
+		channel(from_blue, to_blue),
+	)
+}
+
+fn plain(state: &LightState) -> LightState {
+	LightState {
+		transition_ms: None,
+		effect: None,
+		..state.clone()
+	}
+}
+
+// The state at `elapsed_ms` after the command, and whether it is the last one
+fn frame(from: &LightState, target: &LightState, elapsed_ms: u64) -> (LightState, bool) {
+	let transition_ms = target.transition_ms.unwrap_or(0) as u64;
+	if elapsed_ms < transition_ms {
+		let progress = elapsed_ms as f64 / transition_ms as f64;
+		// A light that is off fades from (or to) zero brightness
+		let from_brightness = if from.is_on {
+			from.brightness
+		} else {
+			Brightness::MIN
+		};
+		let to_brightness = if target.is_on {
+			target.brightness
+		} else {
+			Brightness::MIN
+		};
+		let brightness = lerp_brightness(from_brightness, to_brightness, progress);
+		let state = LightState {
+			is_on: brightness > Brightness::MIN,
+			brightness: brightness,
+			color: lerp_color(from.color, target.color, progress),
+			..plain(target)
+		};
+		return (state, false);
+	}
+	let elapsed_ms = elapsed_ms - transition_ms;
+	match &target.effect {
+		None => (plain(target), true),
+		Some(LightEffect::Breathe {
+			period_ms,
+			min_brightness,
+		}) => {
+			let period_ms = (*period_ms).max(1) as u64;
+			let phase = (elapsed_ms % period_ms) as f64 / period_ms as f64;
+			let dim = (1.0 - (2.0 * PI * phase).cos()) / 2.0;
+			let state = LightState {
+				is_on: true,
+				brightness: lerp_brightness(target.brightness, *min_brightness, dim),
+				..plain(target)
+			};
+			(state, false)
+		}
+		Some(LightEffect::Strobe { frequency_hz }) => {
+			let period_ms = (1000 / (*frequency_hz).max(1) as u64).max(1);
+			let state = LightState {
+				is_on: elapsed_ms % period_ms < period_ms / 2,
+				..plain(target)
+			};
+			(state, false)
+		}
+		Some(LightEffect::ColorCycle { period_ms, colors }) => {
+			let colors: Vec<Rgb24> = if colors.is_empty() {
+				DEFAULT_CYCLE_COLORS
+					.iter()
+					.map(|(red, green, blue)| Rgb24::from_channels(*red, *green, *blue))
+					.collect()
+			} else {
+				colors.clone()
+			};
+			let period_ms = (*period_ms).max(1) as u64;
+			let position =
+				(elapsed_ms % period_ms) as f64 / period_ms as f64 * colors.len() as f64;
+			let index = position.floor() as usize % colors.len();
+			let next = (index + 1) % colors.len();
+			let state = LightState {
+				is_on: true,
+				color: lerp_color(colors[index], colors[next], position.fract()),
+				..plain(target)
+			};
+			(state, false)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn state(is_on: bool, brightness: u8) -> LightState {
+		LightState {
+			is_on,
+			brightness: Brightness::saturating(brightness as i32),
+			color: Rgb24::from_channels(255, 0, 0),
+			removed: false,
+			transition_ms: None,
+			effect: None,
+		}
+	}
+
+	#[test]
+	fn lights_that_are_off_fade_in_from_zero() {
+		let target = LightState {
+			transition_ms: Some(1000),
+			..state(true, 255)
+		};
+		let (first, last) = frame(&state(false, 200), &target, 0);
+		assert!(!first.is_on && !last);
+		assert_eq!(first.brightness, Brightness::MIN);
+		let (half, _) = frame(&state(false, 200), &target, 500);
+		assert!(half.is_on);
+		assert_eq!(half.brightness.raw(), 128);
+		assert!(half.transition_ms.is_none());
+		let (end, last) = frame(&state(false, 200), &target, 1000);
+		assert!(end.is_on && last);
+		assert_eq!(end.brightness, Brightness::MAX);
+	}
+
+	#[test]
+	fn colors_fade_channel_by_channel() {
+		let target = LightState {
+			color: Rgb24::from_channels(0, 0, 255),
+			transition_ms: Some(100),
+			..state(true, 255)
+		};
+		let (half, _) = frame(&state(true, 255), &target, 50);
+		assert_eq!(half.color, Rgb24::from_channels(127, 0, 128));
+	}
+
+	#[test]
+	fn effects_start_once_the_transition_is_over() {
+		let target = LightState {
+			transition_ms: Some(1000),
+			effect: Some(LightEffect::Strobe { frequency_hz: 5 }),
+			..state(true, 255)
+		};
+		// 200ms per flash, on for the first half
+		for (elapsed_ms, is_on) in [(1000, true), (1099, true), (1100, false), (1200, true)] {
+			let (strobe, last) = frame(&state(true, 255), &target, elapsed_ms);
+			assert_eq!(strobe.is_on, is_on, "{}ms", elapsed_ms);
+			assert!(!last);
+		}
+	}
+
+	#[test]
+	fn breathing_dims_to_the_minimum_halfway() {
+		let target = LightState {
+			effect: Some(LightEffect::Breathe {
+				period_ms: 1000,
+				min_brightness: Brightness::saturating(55),
+			}),
+			..state(true, 255)
+		};
+		assert_eq!(frame(&target, &target, 0).0.brightness.raw(), 255);
+		assert_eq!(frame(&target, &target, 500).0.brightness.raw(), 55);
+		assert_eq!(frame(&target, &target, 1000).0.brightness.raw(), 255);
+	}
+
+	#[test]
+	fn color_cycles_wrap_around() {
+		let colors = vec![Rgb24::from_channels(255, 0, 0), Rgb24::from_channels(0, 0, 255)];
+		let target = LightState {
+			effect: Some(LightEffect::ColorCycle {
+				period_ms: 1000,
+				colors: colors.clone(),
+			}),
+			..state(true, 255)
+		};
+		assert_eq!(frame(&target, &target, 0).0.color, colors[0]);
+		assert_eq!(frame(&target, &target, 500).0.color, colors[1]);
+		assert_eq!(frame(&target, &target, 1000).0.color, colors[0]);
+	}
+
+	#[test]
+	fn fast_strobes_are_throttled() {
+		let strobe = |frequency_hz| LightState {
+			effect: Some(LightEffect::Strobe { frequency_hz }),
+			..state(true, 255)
+		};
+		assert_eq!(step_ms(&strobe(1)), STEP_MS);
+		assert_eq!(step_ms(&strobe(100)), MIN_STEP_MS);
+		assert_eq!(step_ms(&state(true, 255)), STEP_MS);
+	}
+}
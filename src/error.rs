@@ -0,0 +1,198 @@
+// Errors of the services and the REST API. Kept free of Rocket so the services
+// and models can return them, routes/error.rs turns them into responses
+// {"success":false,"error":{"code":..,"message":..}} with a matching status code.
+// The codes are part of the API, clients are expected to match on them.
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::models::values::FieldError;
+
+#[derive(Debug, Clone)]
+pub enum ApiError {
+	Unauthorized,
+	InvalidCredentials,
+	InvalidCode,
+	TwoFactorRequired,
+	InsufficientScope,
+	Forbidden,
+	AdminRequired,
+	InvalidSignature,
+	MessageExpired,
+	MessageReplayed,
+	NotOwner,
+	AttestationFailed(&'static str),
+	DeviceNotFound,
+	DeviceAlreadyRegistered,
+	GroupNotFound,
+	TransferNotFound,
+	UserNotFound,
+	SessionNotFound,
+	ApiTokenNotFound,
+	OAuthClientNotFound,
+	NotFound,
+	EmailTaken,
+	AlreadyOwner,
+	TransferPending,
+	TransferExpired,
+	TransferNotPending,
+	TwoFactorEnabled,
+	TwoFactorNotEnabled,
+	PreconditionFailed,
+	InvalidToken,
+	EmailUnavailable,
+	BadRequest,
+	InvalidInput(Vec<FieldError>),
+	// Seconds until the client may try again
+	RateLimited(u64),
+	DeviceUnreachable,
+	DeviceTimeout,
+	ServiceUnavailable,
+	Internal,
+}
+
+impl ApiError {
+	pub fn invalid_field(field: &str, error: &str) -> Self {
+		ApiError::InvalidInput(vec![FieldError {
+			field: field.to_string(),
+			error: error.to_string(),
+		}])
+	}
+
+	pub fn code(&self) -> &'static str {
+		match self {
+			ApiError::Unauthorized => "unauthorized",
+			ApiError::InvalidCredentials => "invalid_credentials",
+			ApiError::InvalidCode => "invalid_code",
+			ApiError::TwoFactorRequired => "two_factor_required",
+			ApiError::InsufficientScope => "insufficient_scope",
+			ApiError::Forbidden => "forbidden",
+			ApiError::AdminRequired => "admin_required",
+			ApiError::InvalidSignature => "invalid_signature",
+			ApiError::MessageExpired => "message_expired",
+			ApiError::MessageReplayed => "message_replayed",
+			ApiError::NotOwner => "not_owner",
+			ApiError::AttestationFailed(_) => "attestation_failed",
+			ApiError::DeviceNotFound => "device_not_found",
+			ApiError::DeviceAlreadyRegistered => "device_already_registered",
+			ApiError::GroupNotFound => "group_not_found",
+			ApiError::TransferNotFound => "transfer_not_found",
+			ApiError::UserNotFound => "user_not_found",
+			ApiError::SessionNotFound => "session_not_found",
+			ApiError::ApiTokenNotFound => "api_token_not_found",
+			ApiError::OAuthClientNotFound => "oauth_client_not_found",
+			ApiError::NotFound => "not_found",
+			ApiError::EmailTaken => "email_taken",
+			ApiError::AlreadyOwner => "already_owner",
+			ApiError::TransferPending => "transfer_pending",
+			ApiError::TransferExpired => "transfer_expired",
+			ApiError::TransferNotPending => "transfer_not_pending",
+			ApiError::TwoFactorEnabled => "two_factor_enabled",
+			ApiError::TwoFactorNotEnabled => "two_factor_not_enabled",
+			ApiError::PreconditionFailed => "precondition_failed",
+			ApiError::InvalidToken => "invalid_token",
+			ApiError::EmailUnavailable => "email_unavailable",
+			ApiError::BadRequest => "bad_request",
+			ApiError::InvalidInput(_) => "invalid_input",
+			ApiError::RateLimited(_) => "rate_limited",
+			ApiError::DeviceUnreachable => "device_unreachable",
+			ApiError::DeviceTimeout => "device_timeout",
+			ApiError::ServiceUnavailable => "service_unavailable",
+			ApiError::Internal => "internal_error",
+		}
+	}
+
+	pub fn message(&self) -> &'static str {
+		match self {
+			ApiError::Unauthorized => "You need to be logged in",
+			ApiError::InvalidCredentials => "Invalid email or password",
+			ApiError::InvalidCode => "Invalid two-factor code",
+			ApiError::TwoFactorRequired => "Log in with your second factor first",
+			ApiError::InsufficientScope => "The API token is not allowed to do this",
+			ApiError::Forbidden => "You are not allowed to do this",
+			ApiError::AdminRequired => "Only admins can do this",
+			ApiError::InvalidSignature => "Invalid device signature",
+			ApiError::MessageExpired => "Message expired",
+			ApiError::MessageReplayed => "Message was already received",
+			ApiError::NotOwner => "You are not the owner of the device",
+			ApiError::AttestationFailed(reason) => reason,
+			ApiError::DeviceNotFound => "Device does not exist",
+			ApiError::DeviceAlreadyRegistered => "Device is already registered",
+			ApiError::GroupNotFound => "Group does not exist",
+			ApiError::TransferNotFound => "Transfer does not exist",
+			ApiError::UserNotFound => "User does not exist",
+			ApiError::SessionNotFound => "Session does not exist",
+			ApiError::ApiTokenNotFound => "API token does not exist",
+			ApiError::OAuthClientNotFound => "OAuth client does not exist",
+			ApiError::NotFound => "Not found",
+			ApiError::EmailTaken => "User with this email already exists",
+			ApiError::AlreadyOwner => "You already own this device",
+			ApiError::TransferPending => "Device is already being transferred",
+			ApiError::TransferExpired => "Transfer has expired",
+			ApiError::TransferNotPending => "Transfer is no longer pending",
+			ApiError::TwoFactorEnabled => "Two-factor authentication is already enabled",
+			ApiError::TwoFactorNotEnabled => "Two-factor authentication is not enabled",
+			ApiError::PreconditionFailed => "Resource was changed since it was last read",
+			ApiError::InvalidToken => "Link is invalid or has expired",
+			ApiError::EmailUnavailable => "Email could not be sent",
+			ApiError::BadRequest => "Malformed request",
+			ApiError::InvalidInput(_) => "Invalid input",
+			ApiError::RateLimited(_) => "Too many requests, try again later",
+			ApiError::DeviceUnreachable => "Device could not be reached",
+			ApiError::DeviceTimeout => "Device did not respond in time",
+			ApiError::ServiceUnavailable => "Service is temporarily unavailable",
+			ApiError::Internal => "Something went wrong",
+		}
+	}
+
+	fn detail(&self) -> Value {
+		match self {
+			ApiError::InvalidInput(fields) => {
+				json!({"code":self.code(),"message":self.message(),"fields":fields})
+			}
+			_ => json!({"code":self.code(),"message":self.message()}),
+		}
+	}
+
+	pub fn body(&self) -> Value {
+		json!({"success":false,"error":self.detail()})
+	}
+}
+
+// Lets per item failures (e.g. in bulk updates) use the same shape
+impl Serialize for ApiError {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		self.detail().serialize(serializer)
+	}
+}
+
+// The "error" object of the envelope, for the OpenAPI document
+impl JsonSchema for ApiError {
+	fn schema_name() -> String {
+		"ApiError".to_string()
+	}
+	fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+		let fields = serde_json::to_value(gen.subschema_for::<Vec<FieldError>>())
+			.expect("serializable schema");
+		serde_json::from_value(json!({
+			"type": "object",
+			"required": ["code", "message"],
+			"properties": {
+				"code": {"type": "string"},
+				"message": {"type": "string"},
+				"fields": fields
+			}
+		}))
+		.expect("valid JSON schema")
+	}
+}
+
+impl From<diesel::result::Error> for ApiError {
+	fn from(err: diesel::result::Error) -> Self {
+		println!("database error: {}", err);
+		ApiError::Internal
+	}
+}
+
@@ -0,0 +1,51 @@
+// Where the frontend is, read once at startup so a missing FRONTEND_ADDR stops
+// the server instead of failing requests. The links in the emails and the
+// OAuth login page point there, OAUTH_LOGIN_URL overrides the latter.
+use std::env;
+
+pub struct Frontend {
+	addr: String,
+	login_url: String,
+}
+
+impl Frontend {
+	pub fn new(addr: &str, login_url: Option<String>) -> Self {
+		let addr = addr.trim_end_matches('/').to_string();
+		let login_url = login_url.unwrap_or_else(|| format!("{}/login", addr));
+		Frontend { addr, login_url }
+	}
+
+	pub fn from_env() -> Self {
+		let addr = env::var("FRONTEND_ADDR").expect("set FRONTEND_ADDR");
+		Frontend::new(&addr, env::var("OAUTH_LOGIN_URL").ok())
+	}
+
+	pub fn addr(&self) -> &str {
+		&self.addr
+	}
+
+	// The frontend calls the API with the token
+	pub fn link(&self, path: &str, token: &str) -> String {
+		format!("{}/{}?token={}", self.addr, path, token)
+	}
+
+	pub fn login_url(&self) -> &str {
+		&self.login_url
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frontend_links_point_to_the_frontend() {
+		let frontend = Frontend::new("http://localhost:3000/", None);
+		let link = frontend.link("verify-email", "abc");
+		assert_eq!(link, "http://localhost:3000/verify-email?token=abc");
+		assert_eq!(frontend.login_url(), "http://localhost:3000/login");
+		let login_url = Some("https://login.example".to_string());
+		let custom = Frontend::new("http://localhost:3000", login_url);
+		assert_eq!(custom.login_url(), "https://login.example");
+	}
+}
@@ -37,8 +37,17 @@ pub struct Execution {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Params {
 	pub on: Option<bool>,
-	pub color: Option<Color>,
-	pub brightness: Option<i32>,
+	pub color: Option<ColorParams>,
+	// Percent
+	pub brightness: Option<i64>,
+	// Seconds, used by the Sleep and Wake effects
+	pub duration: Option<u32>,
+}
+// Google sends either an RGB value or a color temperature
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ColorParams {
+	pub spectrumRGB: Option<i64>,
+	pub temperature: Option<i64>,
 }
 //Structs used to respond to SYNC requests
 #[derive(Clone, Serialize, Deserialize)]
@@ -80,6 +89,8 @@ pub struct DeviceAttributes {
 	pub colorModel: Option<String>,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub colorTemperatureRange: Option<HashMap<String, i32>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub supportedEffects: Option<Vec<String>>,
 }
 // Structs used to respond to QUERY requests
 
@@ -92,6 +103,8 @@ pub struct LightState {
 	pub brightness: Option<i32>,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub color: Option<Color>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub activeLightEffect: Option<String>,
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub struct HeaterState {
@@ -0,0 +1,118 @@
+// Google Home Graph client, used to ask Google to re-run SYNC for a user when
+// their set of devices changed on our side.
+//
+// Authenticates with a service account key file pointed to by
+// GOOGLE_SERVICE_ACCOUNT_FILE. When it isn't set the calls are skipped, which is
+// what we want for local development.
+
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use tokio::runtime::Runtime;
+
+const SERVICE_ACCOUNT_VAR: &str = "GOOGLE_SERVICE_ACCOUNT_FILE";
+const HOMEGRAPH_SCOPE: &str = "https://www.googleapis.com/auth/homegraph";
+const REQUEST_SYNC_URL: &str = "https://homegraph.googleapis.com/v1/devices:requestSync";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+	client_email: String,
+	private_key: String,
+	token_uri: String,
+}
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+	iss: &'a str,
+	scope: &'a str,
+	aud: &'a str,
+	iat: i64,
+	exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	access_token: String,
+}
+
+fn load_service_account() -> Result<Option<ServiceAccount>, Box<dyn std::error::Error>> {
+	let path = match env::var(SERVICE_ACCOUNT_VAR) {
+		Ok(path) => path,
+		Err(_) => return Ok(None),
+	};
+	let account = serde_json::from_str::<ServiceAccount>(&fs::read_to_string(path)?)?;
+	Ok(Some(account))
+}
+
+async fn fetch_access_token(
+	client: &reqwest::Client,
+	account: &ServiceAccount,
+) -> Result<String, Box<dyn std::error::Error>> {
+	let now = chrono::Utc::now().timestamp();
+	let claims = AssertionClaims {
+		iss: &account.client_email,
+		scope: HOMEGRAPH_SCOPE,
+		aud: &account.token_uri,
+		iat: now,
+		exp: now + 3600,
+	};
+	let assertion = encode(
+		&Header::new(Algorithm::RS256),
+		&claims,
+		&EncodingKey::from_rsa_pem(account.private_key.as_bytes())?,
+	)?;
+	let token = client
+		.post(&account.token_uri)
+		.form(&[
+			("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+			("assertion", assertion.as_str()),
+		])
+		.send()
+		.await?
+		.error_for_status()?
+		.json::<TokenResponse>()
+		.await?;
+	Ok(token.access_token)
+}
+
+async fn send_request_sync(user_ids: &[i32]) -> Result<(), Box<dyn std::error::Error>> {
+	let account = match load_service_account()? {
+		Some(account) => account,
+		None => {
+			println!("{} not set, skipping requestSync", SERVICE_ACCOUNT_VAR);
+			return Ok(());
+		}
+	};
+	let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+	let access_token = fetch_access_token(&client, &account).await?;
+	for user_id in user_ids {
+		// agentUserId has to match the one we return from SYNC
+		client
+			.post(REQUEST_SYNC_URL)
+			.bearer_auth(&access_token)
+			.json(&json!({"agentUserId":user_id.to_string(),"async":true}))
+			.send()
+			.await?
+			.error_for_status()?;
+	}
+	Ok(())
+}
+
+// Runs in the background so the request doesn't wait on Google. Failures are
+// only logged, Google will eventually re-sync on its own.
+pub fn request_sync(user_ids: &[i32]) {
+	let user_ids = user_ids.to_vec();
+	thread::spawn(move || {
+		let result = match Runtime::new() {
+			Ok(rt) => rt.block_on(send_request_sync(&user_ids)),
+			Err(err) => Err(err.into()),
+		};
+		if let Err(err) = result {
+			println!("requestSync failed for {:?}: {}", user_ids, err);
+		}
+	});
+}
@@ -0,0 +1,275 @@
+// Keys for the JWTs we issue: session cookies and OAuth access tokens.
+//
+// JWT_KEYS points to a directory of `<kid>.pem` files, RSA (RS256) or Ed25519
+// (EdDSA). The private key named by JWT_SIGNING_KID signs new tokens, every key
+// in the directory, private or public, is accepted when verifying. Tokens carry
+// the kid in their header.
+//
+// To rotate, add the new private key and point JWT_SIGNING_KID at it. Replace
+// the old private key with its public key and delete that once the tokens it
+// signed have expired.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose, Engine};
+use jsonwebtoken::{
+	decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use openssl::{
+	error::ErrorStack,
+	pkey::{HasPublic, Id, PKey, Private},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+pub const KEYS_DIR_VAR: &str = "JWT_KEYS";
+pub const SIGNING_KID_VAR: &str = "JWT_SIGNING_KID";
+
+#[derive(Debug)]
+pub enum KeyRingError {
+	NotConfigured(&'static str),
+	KeyFile(PathBuf, io::Error),
+	InvalidKey(String, String),
+	UnsupportedKeyType(String),
+	// JWT_SIGNING_KID names a key that isn't there or has no private part
+	NoSigningKey(String),
+	Crypto(ErrorStack),
+}
+
+impl fmt::Display for KeyRingError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			KeyRingError::NotConfigured(var) => write!(f, "{} is not set", var),
+			KeyRingError::KeyFile(path, err) => {
+				write!(f, "failed to read {}: {}", path.display(), err)
+			}
+			KeyRingError::InvalidKey(kid, err) => write!(f, "invalid key '{}': {}", kid, err),
+			KeyRingError::UnsupportedKeyType(kid) => {
+				write!(f, "key '{}' is neither RSA nor Ed25519", kid)
+			}
+			KeyRingError::NoSigningKey(kid) => write!(f, "no private key with id '{}'", kid),
+			KeyRingError::Crypto(err) => write!(f, "crypto error: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for KeyRingError {}
+
+impl From<ErrorStack> for KeyRingError {
+	fn from(err: ErrorStack) -> Self {
+		KeyRingError::Crypto(err)
+	}
+}
+
+fn invalid_key(kid: &str) -> impl Fn(jsonwebtoken::errors::Error) -> KeyRingError + '_ {
+	move |err| KeyRingError::InvalidKey(kid.to_string(), err.to_string())
+}
+
+fn base64url(bytes: &[u8]) -> String {
+	general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[derive(Clone)]
+struct VerificationKey {
+	algorithm: Algorithm,
+	key: DecodingKey,
+	// Entry in the JWKS document
+	jwk: Value,
+}
+
+impl VerificationKey {
+	fn new<T: HasPublic>(kid: &str, key: &PKey<T>) -> Result<Self, KeyRingError> {
+		match key.id() {
+			Id::RSA => {
+				let rsa = key.rsa()?;
+				let n = base64url(&rsa.n().to_vec());
+				let e = base64url(&rsa.e().to_vec());
+				Ok(VerificationKey {
+					algorithm: Algorithm::RS256,
+					key: DecodingKey::from_rsa_components(&n, &e).map_err(invalid_key(kid))?,
+					jwk: json!({"kty":"RSA","use":"sig","alg":"RS256","kid":kid,"n":n,"e":e}),
+				})
+			}
+			Id::ED25519 => {
+				let x = base64url(&key.raw_public_key()?);
+				Ok(VerificationKey {
+					algorithm: Algorithm::EdDSA,
+					key: DecodingKey::from_ed_components(&x).map_err(invalid_key(kid))?,
+					jwk: json!({
+						"kty":"OKP","crv":"Ed25519","use":"sig","alg":"EdDSA","kid":kid,"x":x
+					}),
+				})
+			}
+			_ => Err(KeyRingError::UnsupportedKeyType(kid.to_string())),
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct KeyRing {
+	signing_kid: String,
+	encoding_key: EncodingKey,
+	verification: HashMap<String, VerificationKey>,
+}
+
+impl KeyRing {
+	pub fn new(kid: &str, signing_key: PKey<Private>) -> Result<Self, KeyRingError> {
+		let verification = VerificationKey::new(kid, &signing_key)?;
+		let pem = signing_key.private_key_to_pem_pkcs8()?;
+		let encoding_key = match verification.algorithm {
+			Algorithm::RS256 => EncodingKey::from_rsa_pem(&pem),
+			_ => EncodingKey::from_ed_pem(&pem),
+		}
+		.map_err(invalid_key(kid))?;
+		Ok(KeyRing {
+			signing_kid: kid.to_string(),
+			encoding_key,
+			verification: HashMap::from([(kid.to_string(), verification)]),
+		})
+	}
+
+	// A fresh key for tests, the server always loads its keys from JWT_KEYS
+	#[cfg(test)]
+	pub fn generate(kid: &str, algorithm: Algorithm) -> Result<Self, KeyRingError> {
+		let key = match algorithm {
+			Algorithm::RS256 => PKey::from_rsa(openssl::rsa::Rsa::generate(2048)?)?,
+			Algorithm::EdDSA => PKey::generate_ed25519()?,
+			_ => return Err(KeyRingError::UnsupportedKeyType(kid.to_string())),
+		};
+		KeyRing::new(kid, key)
+	}
+
+	pub fn from_env() -> Result<Self, KeyRingError> {
+		let dir =
+			dotenv::var(KEYS_DIR_VAR).map_err(|_| KeyRingError::NotConfigured(KEYS_DIR_VAR))?;
+		let signing_kid = dotenv::var(SIGNING_KID_VAR)
+			.map_err(|_| KeyRingError::NotConfigured(SIGNING_KID_VAR))?;
+		KeyRing::from_dir(Path::new(&dir), &signing_kid)
+	}
+
+	pub fn from_dir(dir: &Path, signing_kid: &str) -> Result<Self, KeyRingError> {
+		let entries =
+			fs::read_dir(dir).map_err(|err| KeyRingError::KeyFile(dir.to_path_buf(), err))?;
+		let mut pems = vec![];
+		for entry in entries {
+			let path = entry
+				.map_err(|err| KeyRingError::KeyFile(dir.to_path_buf(), err))?
+				.path();
+			if path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+				continue;
+			}
+			let kid = match path.file_stem().and_then(|stem| stem.to_str()) {
+				Some(kid) => kid.to_string(),
+				None => continue,
+			};
+			let pem = fs::read(&path).map_err(|err| KeyRingError::KeyFile(path.clone(), err))?;
+			pems.push((kid, pem));
+		}
+		let signing_key = pems
+			.iter()
+			.find(|(kid, _)| kid == signing_kid)
+			.and_then(|(_, pem)| PKey::private_key_from_pem(pem).ok())
+			.ok_or_else(|| KeyRingError::NoSigningKey(signing_kid.to_string()))?;
+		let mut keys = KeyRing::new(signing_kid, signing_key)?;
+		for (kid, pem) in pems.iter().filter(|(kid, _)| kid != signing_kid) {
+			keys.add_verification_key(kid, pem)?;
+		}
+		Ok(keys)
+	}
+
+	// Accepts tokens signed with another key, the PEM may be a private or a
+	// public key
+	pub fn add_verification_key(&mut self, kid: &str, pem: &[u8]) -> Result<(), KeyRingError> {
+		let key = match PKey::private_key_from_pem(pem) {
+			Ok(key) => VerificationKey::new(kid, &key)?,
+			Err(_) => {
+				let key = PKey::public_key_from_pem(pem)
+					.map_err(|err| KeyRingError::InvalidKey(kid.to_string(), err.to_string()))?;
+				VerificationKey::new(kid, &key)?
+			}
+		};
+		self.verification.insert(kid.to_string(), key);
+		Ok(())
+	}
+
+	pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+		let algorithm = self.verification[&self.signing_kid].algorithm;
+		let header = Header {
+			kid: Some(self.signing_kid.clone()),
+			..Header::new(algorithm)
+		};
+		encode(&header, claims, &self.encoding_key).expect("claims are always serializable")
+	}
+
+	// None for tokens without a kid, with an unknown one, with a bad signature
+	// or that expired
+	pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+		let kid = decode_header(token).ok()?.kid?;
+		let key = self.verification.get(&kid)?;
+		decode::<T>(token, &key.key, &Validation::new(key.algorithm))
+			.ok()
+			.map(|data| data.claims)
+	}
+
+	// What /.well-known/jwks.json serves
+	pub fn jwks(&self) -> Value {
+		let mut keys: Vec<&VerificationKey> = self.verification.values().collect();
+		keys.sort_by_key(|key| key.jwk["kid"].as_str().unwrap_or_default().to_string());
+		json!({ "keys": keys.iter().map(|key| key.jwk.clone()).collect::<Vec<Value>>() })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::{jwt_from_id, Claims};
+
+	fn test_keys() -> KeyRing {
+		KeyRing::generate("test", Algorithm::EdDSA).expect("generate a signing key")
+	}
+
+	// Tokens signed with a key that was rotated out keep working as long as its
+	// public key is configured
+	#[test]
+	fn tokens_survive_key_rotation() {
+		let old_key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+		let old = KeyRing::new("2023-01", old_key.clone()).unwrap();
+		let mut current = test_keys();
+		let expires = (chrono::Utc::now().timestamp() + 60) as usize;
+		let token = jwt_from_id(&old, "7".to_string(), expires);
+
+		assert!(current.verify::<Claims>(&token).is_none());
+		current
+			.add_verification_key("2023-01", &old_key.public_key_to_pem().unwrap())
+			.unwrap();
+		assert_eq!(current.verify::<Claims>(&token).unwrap().sub, "7");
+		// New tokens are signed with the current key only
+		let token = jwt_from_id(&current, "7".to_string(), expires);
+		assert!(old.verify::<Claims>(&token).is_none());
+
+		let jwks = current.jwks();
+		let kids: Vec<&str> = jwks["keys"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|key| key["kid"].as_str().unwrap())
+			.collect();
+		assert_eq!(kids, ["2023-01", "test"]);
+	}
+
+	// Sessions signed with the old shared secret have no kid and are rejected
+	#[test]
+	fn tokens_without_kid_are_rejected() {
+		let claims = Claims {
+			sub: "1".to_string(),
+			exp: (chrono::Utc::now().timestamp() + 60) as usize,
+		};
+		let token =
+			encode(&Header::default(), &claims, &EncodingKey::from_secret(b"hewwo-uwu")).unwrap();
+		assert!(test_keys().verify::<Claims>(&token).is_none());
+	}
+}
@@ -14,56 +14,200 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
+use attestation::{AttestationError, AttestationKeys};
 use dotenv::dotenv;
+use effects::EffectRunner;
+use frontend::Frontend;
+use keyring::{KeyRing, KeyRingError};
+use openapi::OpenApi;
+use rate_limit::{LoginThrottle, RateLimiter, TrustedProxies};
 use google_routes::static_rocket_route_info_for_fullfilment;
 use oath_routes::{
     static_rocket_route_info_for_authorize, static_rocket_route_info_for_authorize_consent,
     static_rocket_route_info_for_get_token, static_rocket_route_info_for_protected_resource,
-    static_rocket_route_info_for_refresh, static_rocket_route_info_for_token, MyState,
+    static_rocket_route_info_for_refresh, static_rocket_route_info_for_register_client,
+    static_rocket_route_info_for_return_to_authorize, static_rocket_route_info_for_token, MyState,
 };
 use rocket::http::Method;
+use rocket::Rocket;
 use rocket_cors::{AllowedOrigins, Cors, CorsOptions};
+use services::gateway::Gateway;
+use routes::etag::ETAG_HEADER;
 use routes::{
+    api_token::{
+        static_rocket_route_info_for_create_api_token,
+        static_rocket_route_info_for_list_api_tokens,
+        static_rocket_route_info_for_revoke_api_token,
+    },
     device::{
-        static_rocket_route_info_for_check_device_online, static_rocket_route_info_for_get_devices,
+        static_rocket_route_info_for_bulk_update,
+        static_rocket_route_info_for_check_device_online,
+        static_rocket_route_info_for_get_devices,
         static_rocket_route_info_for_get_full_devices,
-        static_rocket_route_info_for_register_device, static_rocket_route_info_for_remove_device,
-        static_rocket_route_info_for_rename_device, static_rocket_route_info_for_set_brightness,
-        static_rocket_route_info_for_set_color, static_rocket_route_info_for_set_on,
+        static_rocket_route_info_for_register_device,
+        static_rocket_route_info_for_remove_device,
+        static_rocket_route_info_for_rename_device,
+        static_rocket_route_info_for_rotate_device_key,
+        static_rocket_route_info_for_set_brightness,
+        static_rocket_route_info_for_set_color,
+        static_rocket_route_info_for_set_on,
+    },
+    device_messages::{
+        static_rocket_route_info_for_device_rotate_key,
+        static_rocket_route_info_for_report_state, static_rocket_route_info_for_report_telemetry,
+    },
+    docs::{static_rocket_route_info_for_docs, static_rocket_route_info_for_openapi},
+    keys::static_rocket_route_info_for_jwks,
+    oauth_client::{
+        static_rocket_route_info_for_create_oauth_client,
+        static_rocket_route_info_for_disable_oauth_client,
+        static_rocket_route_info_for_list_oauth_clients,
+        static_rocket_route_info_for_rotate_oauth_client_secret,
+    },
+    session::{
+        static_rocket_route_info_for_list_sessions,
+        static_rocket_route_info_for_logout_everywhere,
+        static_rocket_route_info_for_revoke_session,
+    },
+    group::{
+        static_rocket_route_info_for_add_to_group, static_rocket_route_info_for_create_group,
+        static_rocket_route_info_for_get_groups, static_rocket_route_info_for_remove_from_group,
+        static_rocket_route_info_for_remove_group, static_rocket_route_info_for_set_group_state,
+        static_rocket_route_info_for_update_group,
+    },
+    two_factor::{
+        static_rocket_route_info_for_disable_two_factor,
+        static_rocket_route_info_for_enable_two_factor,
+        static_rocket_route_info_for_regenerate_recovery_codes,
+        static_rocket_route_info_for_setup_two_factor,
+    },
+    transfer::{
+        static_rocket_route_info_for_accept_transfer, static_rocket_route_info_for_cancel_transfer,
+        static_rocket_route_info_for_get_transfers, static_rocket_route_info_for_transfer_device,
     },
     user::{
         static_rocket_route_info_for_get_me, static_rocket_route_info_for_login,
         static_rocket_route_info_for_logout, static_rocket_route_info_for_register,
+        static_rocket_route_info_for_request_password_reset,
+        static_rocket_route_info_for_resend_verification,
+        static_rocket_route_info_for_reset_password, static_rocket_route_info_for_verify_email,
+        static_rocket_route_info_for_change_password,
+        static_rocket_route_info_for_confirm_email_change,
+        static_rocket_route_info_for_delete_account, static_rocket_route_info_for_update_profile,
+        static_rocket_route_info_for_login_two_factor,
+    },
+    v2::{
+        static_rocket_route_info_for_delete_device, static_rocket_route_info_for_get_device,
+        static_rocket_route_info_for_get_device_state, static_rocket_route_info_for_list_devices,
+        static_rocket_route_info_for_patch_device, static_rocket_route_info_for_put_device_state,
+    },
+    error::{
+        static_rocket_catch_info_for_bad_request, static_rocket_catch_info_for_internal_error,
+        static_rocket_catch_info_for_not_found, static_rocket_catch_info_for_service_unavailable,
+        static_rocket_catch_info_for_forbidden, static_rocket_catch_info_for_unauthorized,
+        static_rocket_catch_info_for_unprocessable_entity,
     },
 };
 
 //use routes::*;
 use std::env;
+use std::fmt;
+use std::process;
 
+mod attestation;
+#[cfg(test)]
+mod attestation_signing;
 mod db;
+mod decommission;
+mod effects;
+mod error;
+mod frontend;
+mod homegraph;
+mod keyring;
 mod models;
 #[path = "routes/oauth.rs"]
 mod oath_routes;
+mod openapi;
 
 #[path = "routes/google.rs"]
 mod google_routes;
+mod rate_limit;
 mod schema;
+mod services;
+mod totp;
 
 pub mod constants;
 pub mod routes;
 
 pub mod utils;
 
-pub const JWT_SECRET: &str = "hewwo-uwu";
+#[cfg(test)]
+mod tests;
+
+// Keys the server can't run without
+enum StartupError {
+    AttestationKeys(AttestationError),
+    JwtKeys(KeyRingError),
+}
 
-fn rocket() -> rocket::Rocket {
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::AttestationKeys(err) => {
+                write!(f, "failed to load device attestation keys: {}", err)
+            }
+            StartupError::JwtKeys(err) => write!(f, "failed to load JWT signing keys: {}", err),
+        }
+    }
+}
+
+impl From<AttestationError> for StartupError {
+    fn from(err: AttestationError) -> Self {
+        StartupError::AttestationKeys(err)
+    }
+}
+
+impl From<KeyRingError> for StartupError {
+    fn from(err: KeyRingError) -> Self {
+        StartupError::JwtKeys(err)
+    }
+}
+
+fn rocket(gateway: Gateway, effects: EffectRunner) -> Result<Rocket, StartupError> {
     dotenv().ok();
     let database_url = env::var("DATABASE_URL").expect("set DATABASE_URL");
 
     let pool = db::init_pool(database_url);
-    rocket::ignite()
+    let oauth = MyState::new(pool.clone());
+    let attestation_keys = AttestationKeys::from_env()?;
+    // Without them no session can be signed or checked, so a missing or broken
+    // JWT_KEYS directory stops the server here, see keyring.rs for the layout
+    let keys = KeyRing::from_env()?;
+    let frontend = Frontend::from_env();
+    let cors = make_cors(&frontend);
+    let rocket = mount_routes(rocket::ignite());
+    let spec = openapi::spec(rocket.routes());
+    Ok(rocket
         .manage(pool)
-        .manage(MyState::preconfigured())
+        .manage(attestation_keys)
+        .manage(keys)
+        .manage(services::mailer::from_env())
+        .manage(frontend)
+        .manage(LoginThrottle::from_env())
+        .manage(TrustedProxies::from_env())
+        .manage(gateway)
+        .manage(effects)
+        .manage(oauth)
+        .manage(OpenApi(spec))
+        .mount("/", rocket_cors::catch_all_options_routes())
+        .manage(cors.clone())
+        .attach(cors)
+        .attach(RateLimiter::from_env()))
+}
+
+// Everything served by the API, also what the OpenAPI document is built from
+fn mount_routes(rocket: Rocket) -> Rocket {
+    rocket
         .mount(
             "/api/v1/",
             routes![
@@ -72,36 +216,96 @@ fn rocket() -> rocket::Rocket {
                 get_devices,
                 register_device,
                 login,
+                login_two_factor,
                 fullfilment,
                 logout,
+                list_sessions,
+                revoke_session,
+                logout_everywhere,
+                list_api_tokens,
+                create_api_token,
+                revoke_api_token,
+                list_oauth_clients,
+                create_oauth_client,
+                rotate_oauth_client_secret,
+                disable_oauth_client,
+                verify_email,
+                resend_verification,
+                request_password_reset,
+                reset_password,
+                change_password,
+                update_profile,
+                confirm_email_change,
+                delete_account,
+                setup_two_factor,
+                enable_two_factor,
+                disable_two_factor,
+                regenerate_recovery_codes,
                 set_brightness,
                 set_color,
                 set_on,
+                bulk_update,
                 get_full_devices,
                 check_device_online,
                 rename_device,
                 remove_device,
+                rotate_device_key,
+                report_state,
+                report_telemetry,
+                device_rotate_key,
+                transfer_device,
+                get_transfers,
+                accept_transfer,
+                cancel_transfer,
+                get_groups,
+                create_group,
+                update_group,
+                remove_group,
+                add_to_group,
+                remove_from_group,
+                set_group_state,
+                openapi,
+                docs,
             ],
         )
+        .mount(
+            "/api/v2/",
+            routes![
+                list_devices,
+                get_device,
+                patch_device,
+                delete_device,
+                get_device_state,
+                put_device_state,
+            ],
+        )
+        .mount("/", routes![jwks])
         .mount(
             "/oauth",
             routes![
                 token,
                 authorize,
                 authorize_consent,
+                return_to_authorize,
                 protected_resource,
                 refresh,
+                register_client,
                 get_token,
             ],
         )
-        .mount("/", rocket_cors::catch_all_options_routes())
-        .manage(make_cors())
-        .attach(make_cors())
+        .register(catchers![
+            bad_request,
+            unauthorized,
+            forbidden,
+            not_found,
+            unprocessable_entity,
+            internal_error,
+            service_unavailable,
+        ])
 }
-fn make_cors() -> Cors {
-    let frontend_addr = env::var("FRONTEND_ADDR").expect("set FRONTEND_ADDR");
+fn make_cors(frontend: &Frontend) -> Cors {
     let allowed_origins = AllowedOrigins::some_exact(&[
-        frontend_addr,
+        frontend.addr().to_owned(),
         "http://localhost:3000".to_owned(),
         //"http://localhost:8000",
         "http://sikora-laptop.local:3000".to_owned(),
@@ -112,16 +316,25 @@ fn make_cors() -> Cors {
 
     CorsOptions {
         allowed_origins,
-        allowed_methods: vec![Method::Get, Method::Post, Method::Options]
-            .into_iter()
-            .map(From::from)
-            .collect(), // 1.
+        allowed_methods: vec![
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete,
+            Method::Options,
+        ]
+        .into_iter()
+        .map(From::from)
+        .collect(), // 1.
         // allowed_headers: AllowedHeaders::some(&[
         // 	"Authorization",
         // 	"Accept",
         // 	"Access-Control-Allow-Origin",
         // ]),
         allow_credentials: true,
+        // Needed by the v2 API for If-Match
+        expose_headers: [ETAG_HEADER].iter().map(ToString::to_string).collect(),
         ..Default::default()
     }
     .to_cors()
@@ -129,6 +342,16 @@ fn make_cors() -> Cors {
 }
 
 fn main() {
-    utils::handle_startup();
-    rocket().launch();
+    let gateway = services::gateway::from_env();
+    let effects = EffectRunner::new(gateway.clone());
+    utils::handle_startup(&*gateway, &effects);
+    match rocket(gateway, effects) {
+        Ok(server) => {
+            server.launch();
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,172 @@
+use crate::schema::api_tokens;
+use crate::schema::api_tokens::dsl::api_tokens as all_tokens;
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rand_core::{OsRng, RngCore};
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+use super::user_token::hash_token;
+
+// Makes the tokens easy to recognize, e.g. by secret scanners
+pub const API_TOKEN_PREFIX: &str = "diy_";
+// last_used_at is only written once per this interval, not on every request
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Queryable, Clone, Selectable)]
+#[table_name = "api_tokens"]
+pub struct ApiToken {
+	pub id: Uuid,
+	pub user_id: i32,
+	pub name: String,
+	pub token_hash: String,
+	// Space separated, see API_TOKEN_SCOPES
+	pub scope: String,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: Option<DateTime<Utc>>,
+	pub last_used_at: Option<DateTime<Utc>>,
+	pub revoked_at: Option<DateTime<Utc>>,
+}
+
+// What the user gets to see of a token, the token itself only once when it
+// is created
+#[derive(Serialize, JsonSchema)]
+pub struct ApiTokenInfo {
+	pub id: Uuid,
+	pub name: String,
+	pub scopes: Vec<String>,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: Option<DateTime<Utc>>,
+	pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateApiToken {
+	pub name: String,
+	pub scopes: Vec<String>,
+	// Never expires when left out
+	pub expires_in_days: Option<u32>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ApiTokenId {
+	pub token_id: Uuid,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_tokens"]
+struct NewApiToken {
+	id: Uuid,
+	user_id: i32,
+	name: String,
+	token_hash: String,
+	scope: String,
+	expires_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+	pub fn has_scope(&self, scope: &str) -> bool {
+		self.scope.split_whitespace().any(|granted| granted == scope)
+	}
+
+	pub fn info(&self) -> ApiTokenInfo {
+		ApiTokenInfo {
+			id: self.id,
+			name: self.name.clone(),
+			scopes: self.scope.split_whitespace().map(str::to_string).collect(),
+			created_at: self.created_at,
+			expires_at: self.expires_at,
+			last_used_at: self.last_used_at,
+		}
+	}
+
+	// Returns the token to hand to the user together with the stored row
+	pub fn create(
+		user_id: i32,
+		name: &str,
+		scopes: &[String],
+		valid_for: Option<Duration>,
+		conn: &mut PgConnection,
+	) -> QueryResult<(ApiToken, String)> {
+		let mut bytes = [0u8; 32];
+		OsRng.fill_bytes(&mut bytes);
+		let token = format!(
+			"{}{}",
+			API_TOKEN_PREFIX,
+			general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+		);
+		let api_token = diesel::insert_into(api_tokens::table)
+			.values(&NewApiToken {
+				id: Uuid::new_v4(),
+				user_id,
+				name: name.to_string(),
+				token_hash: hash_token(&token),
+				scope: scopes.join(" "),
+				expires_at: valid_for.map(|valid_for| Utc::now() + valid_for),
+			})
+			.get_result::<ApiToken>(conn)?;
+		Ok((api_token, token))
+	}
+
+	// The token when it is neither revoked nor expired
+	pub fn authenticate(token: &str, conn: &mut PgConnection) -> QueryResult<Option<ApiToken>> {
+		all_tokens
+			.filter(api_tokens::token_hash.eq(hash_token(token)))
+			.filter(api_tokens::revoked_at.is_null())
+			.filter(
+				api_tokens::expires_at
+					.is_null()
+					.or(api_tokens::expires_at.gt(Utc::now())),
+			)
+			.first::<ApiToken>(conn)
+			.optional()
+	}
+
+	pub fn get_active_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<ApiToken>> {
+		all_tokens
+			.filter(api_tokens::user_id.eq(user_id))
+			.filter(api_tokens::revoked_at.is_null())
+			.filter(
+				api_tokens::expires_at
+					.is_null()
+					.or(api_tokens::expires_at.gt(Utc::now())),
+			)
+			.order(api_tokens::created_at.desc())
+			.load::<ApiToken>(conn)
+	}
+
+	pub fn touch(id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		let now = Utc::now();
+		let stale = now - Duration::seconds(TOUCH_INTERVAL_SECONDS);
+		diesel::update(all_tokens)
+			.filter(api_tokens::id.eq(id))
+			.filter(
+				api_tokens::last_used_at
+					.is_null()
+					.or(api_tokens::last_used_at.lt(stale)),
+			)
+			.set(api_tokens::last_used_at.eq(now))
+			.execute(conn)
+	}
+
+	// Number of tokens revoked, 0 when the user has no such active token
+	pub fn revoke(id: Uuid, user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_tokens)
+			.filter(api_tokens::id.eq(id))
+			.filter(api_tokens::user_id.eq(user_id))
+			.filter(api_tokens::revoked_at.is_null())
+			.set(api_tokens::revoked_at.eq(Utc::now()))
+			.execute(conn)
+	}
+
+	// When the password changes or the user logs out everywhere
+	pub fn revoke_all(user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_tokens)
+			.filter(api_tokens::user_id.eq(user_id))
+			.filter(api_tokens::revoked_at.is_null())
+			.set(api_tokens::revoked_at.eq(Utc::now()))
+			.execute(conn)
+	}
+}
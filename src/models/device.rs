@@ -1,12 +1,19 @@
+use crate::constants::DEVICE_PENDING_REMOVAL;
+use crate::models::light::LightEffect;
+use crate::models::values::{Brightness, Kelvin, Rgb24};
 use crate::routes::device;
+use crate::error::ApiError;
 use crate::schema::devices;
 use crate::schema::devices::dsl::devices as all_devices;
 use diesel;
 use diesel::pg::PgConnection;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
+use diesel::sql_types::{Nullable, Timestamptz};
+use schemars::JsonSchema;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, Selectable)]
+#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, Selectable, JsonSchema)]
 #[diesel(belongs_to(User))]
 #[table_name = "devices"]
 pub struct Device {
@@ -17,83 +24,142 @@ pub struct Device {
 	pub name: String,
 	pub nicknames: Vec<Option<String>>,
 	pub traits: Vec<Option<String>>,
+	pub state: String,
+	pub removal_requested_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct DeviceData {
 	pub device_id: Uuid,
-	pub brightness: Option<i32>,
-	pub color: Option<i32>,
+	pub brightness: Option<Brightness>,
+	pub color: Option<Rgb24>,
+	// Picks the color for RGB lights, ignored when color is set
+	pub color_temperature: Option<Kelvin>,
 	pub is_on: Option<bool>,
+	pub transition_ms: Option<u32>,
+	pub effect: Option<LightEffect>,
+}
+
+// One target state applied to many devices at once
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct BulkDeviceData {
+	#[serde(default)]
+	pub device_ids: Vec<Uuid>,
+	// The members of the group are updated as well, see bulk_update
+	pub group_id: Option<Uuid>,
+	pub brightness: Option<Brightness>,
+	pub color: Option<Rgb24>,
+	pub color_temperature: Option<Kelvin>,
+	pub is_on: Option<bool>,
+	pub transition_ms: Option<u32>,
+	pub effect: Option<LightEffect>,
+}
+
+impl BulkDeviceData {
+	pub fn for_device(&self, device_id: Uuid) -> DeviceData {
+		DeviceData {
+			device_id,
+			brightness: self.brightness,
+			color: self.color,
+			color_temperature: self.color_temperature,
+			is_on: self.is_on,
+			transition_ms: self.transition_ms,
+			effect: self.effect.clone(),
+		}
+	}
+}
+
+impl From<DeviceData> for BulkDeviceData {
+	fn from(device_data: DeviceData) -> Self {
+		BulkDeviceData {
+			device_ids: vec![device_data.device_id],
+			group_id: None,
+			brightness: device_data.brightness,
+			color: device_data.color,
+			color_temperature: device_data.color_temperature,
+			is_on: device_data.is_on,
+			transition_ms: device_data.transition_ms,
+			effect: device_data.effect,
+		}
+	}
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct BulkResult {
+	pub device_id: Uuid,
+	pub success: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<ApiError>,
 }
 
 // this is to insert users to database
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct NewDevice {
 	pub id: Uuid,
 	pub type_: String,
 	pub secret: String,
 	pub name: String,
 }
-#[derive(Serialize, Deserialize)]
-pub struct DeviceSignature {
-	pub id: Uuid,
-	pub type_: String,
-}
 
 impl Device {
-	pub fn get_all_devices(conn: &mut PgConnection) -> Vec<Device> {
-		all_devices
-			.order(devices::id.desc())
-			.load::<Device>(conn)
-			.expect("error!")
+	pub fn get_all_devices(conn: &mut PgConnection) -> QueryResult<Vec<Device>> {
+		all_devices.order(devices::id.desc()).load::<Device>(conn)
 	}
 
-	pub fn insert_device(device: Device, conn: &mut PgConnection) -> bool {
+	pub fn insert_device(device: Device, conn: &mut PgConnection) -> QueryResult<usize> {
 		diesel::insert_into(devices::table)
 			.values(&device)
 			.execute(conn)
-			.is_ok()
 	}
-	pub fn remove_device(device_id: Uuid, conn: &mut PgConnection) -> bool {
-		let s = diesel::delete(all_devices)
+	pub fn remove_device(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+		let removed = diesel::delete(all_devices)
 			.filter(devices::id.eq(device_id))
-			.execute(conn)
-			.unwrap();
-		return s > 0;
+			.execute(conn)?;
+		Ok(removed > 0)
 	}
-	pub fn get_devices_by_user(user_id: i32, conn: &mut PgConnection) -> Vec<Device> {
+	pub fn get_devices_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<Device>> {
 		diesel::query_dsl::methods::FilterDsl::filter(all_devices, devices::user_id.eq(user_id))
 			.load::<Device>(conn)
-			.expect("error!")
 	}
-	pub fn get_device_by_id(device_id: Uuid, conn: &mut PgConnection) -> Option<Device> {
-		let device =
-			diesel::query_dsl::methods::FilterDsl::filter(all_devices, devices::id.eq(device_id))
-				.load::<Device>(conn)
-				.expect("msg");
-		if device.first().is_none() {
-			return None;
-		}
-		Some(device.first().unwrap().clone())
+	pub fn get_device_by_id(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<Device>> {
+		diesel::query_dsl::methods::FilterDsl::filter(all_devices, devices::id.eq(device_id))
+			.first::<Device>(conn)
+			.optional()
 	}
-	pub fn get_device_owner(device_id: Uuid, conn: &mut PgConnection) -> Option<i32> {
-		let device =
-			diesel::query_dsl::methods::FilterDsl::filter(all_devices, devices::id.eq(device_id))
-				.load::<Device>(conn)
-				.expect("msg");
-
-		if device.first().is_none() {
-			return None;
-		}
-		Some(device.first().unwrap().user_id)
+	// Same as get_device_by_id, but keeps the row locked until the transaction ends
+	pub fn lock_device(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<Device>> {
+		diesel::query_dsl::methods::FilterDsl::filter(all_devices, devices::id.eq(device_id))
+			.for_update()
+			.first::<Device>(conn)
+			.optional()
+	}
+	pub fn get_device_owner(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<i32>> {
+		Ok(Device::get_device_by_id(device_id, conn)?.map(|device| device.user_id))
 	}
-	pub fn update_device_name(id: Uuid, new_name: &str, conn: &mut PgConnection) -> Device {
-		let device_after_update = diesel::update(devices::table)
+	pub fn update_device_name(
+		id: Uuid,
+		new_name: &str,
+		conn: &mut PgConnection,
+	) -> QueryResult<Device> {
+		diesel::update(devices::table)
 			.set(devices::name.eq(new_name))
 			.filter(devices::id.eq(id))
-			.get_result::<Device>(conn);
-		// todo implement error handling
-		return device_after_update.unwrap();
+			.get_result::<Device>(conn)
+	}
+	// Keeps the time of the first request so repeated calls don't reset it
+	pub fn mark_pending_removal(id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(devices::table)
+			.set((
+				devices::state.eq(DEVICE_PENDING_REMOVAL),
+				devices::removal_requested_at.eq(diesel::dsl::sql::<
+					Nullable<Timestamptz>,
+				>("COALESCE(removal_requested_at, NOW())")),
+			))
+			.filter(devices::id.eq(id))
+			.execute(conn)
+	}
+	pub fn get_devices_by_state(state: &str, conn: &mut PgConnection) -> QueryResult<Vec<Device>> {
+		diesel::query_dsl::methods::FilterDsl::filter(all_devices, devices::state.eq(state))
+			.load::<Device>(conn)
 	}
 }
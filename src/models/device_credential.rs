@@ -0,0 +1,87 @@
+use crate::schema::device_credentials;
+use crate::schema::device_credentials::dsl::device_credentials as all_credentials;
+use crate::utils::generate_device_key;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use uuid::Uuid;
+
+// How long a rotated-out key keeps working, so a device that missed the
+// rotation response can still reach us and retry
+const ROTATION_GRACE_MINUTES: i64 = 10;
+
+#[derive(Queryable, Clone, Selectable)]
+#[table_name = "device_credentials"]
+pub struct DeviceCredential {
+	pub id: i32,
+	pub device_id: Uuid,
+	pub secret_key: String,
+	pub created_at: DateTime<Utc>,
+	pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "device_credentials"]
+struct NewDeviceCredential {
+	device_id: Uuid,
+	secret_key: String,
+}
+
+impl DeviceCredential {
+	// Creates a new key for the device, older keys are left untouched
+	pub fn issue(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<DeviceCredential> {
+		diesel::insert_into(device_credentials::table)
+			.values(&NewDeviceCredential {
+				device_id,
+				secret_key: generate_device_key(),
+			})
+			.get_result::<DeviceCredential>(conn)
+	}
+
+	// Issues a new key and lets the current ones expire after a grace period
+	pub fn rotate(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<DeviceCredential> {
+		conn.transaction(|local_conn| {
+			let grace_end = Utc::now() + Duration::minutes(ROTATION_GRACE_MINUTES);
+			diesel::update(all_credentials)
+				.filter(device_credentials::device_id.eq(device_id))
+				.filter(
+					device_credentials::revoked_at
+						.is_null()
+						.or(device_credentials::revoked_at.gt(grace_end)),
+				)
+				.set(device_credentials::revoked_at.eq(grace_end))
+				.execute(local_conn)?;
+			DeviceCredential::issue(device_id, local_conn)
+		})
+	}
+
+	// Immediately invalidates every key of the device
+	pub fn revoke_all(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		let now = Utc::now();
+		diesel::update(all_credentials)
+			.filter(device_credentials::device_id.eq(device_id))
+			.filter(
+				device_credentials::revoked_at
+					.is_null()
+					.or(device_credentials::revoked_at.gt(now)),
+			)
+			.set(device_credentials::revoked_at.eq(now))
+			.execute(conn)
+	}
+
+	// Keys that are currently accepted for the device, newest first
+	pub fn get_valid_keys(
+		device_id: Uuid,
+		conn: &mut PgConnection,
+	) -> QueryResult<Vec<DeviceCredential>> {
+		all_credentials
+			.filter(device_credentials::device_id.eq(device_id))
+			.filter(
+				device_credentials::revoked_at
+					.is_null()
+					.or(device_credentials::revoked_at.gt(Utc::now())),
+			)
+			.order(device_credentials::created_at.desc())
+			.load::<DeviceCredential>(conn)
+	}
+}
@@ -0,0 +1,44 @@
+use crate::schema::device_message_nonces;
+use crate::schema::device_message_nonces::dsl::device_message_nonces as all_nonces;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use uuid::Uuid;
+
+#[derive(Insertable)]
+#[table_name = "device_message_nonces"]
+struct NewDeviceNonce<'a> {
+	device_id: Uuid,
+	nonce: &'a str,
+}
+
+pub struct DeviceNonce;
+
+impl DeviceNonce {
+	// False when the device already sent a message with this nonce. Nonces older
+	// than keep_for are dropped first, messages that old are turned away by their
+	// timestamp anyway.
+	pub fn claim(
+		device_id: Uuid,
+		nonce: &str,
+		keep_for: Duration,
+		conn: &mut PgConnection,
+	) -> QueryResult<bool> {
+		diesel::delete(all_nonces)
+			.filter(device_message_nonces::device_id.eq(device_id))
+			.filter(device_message_nonces::received_at.lt(Utc::now() - keep_for))
+			.execute(conn)?;
+		let inserted = diesel::insert_into(device_message_nonces::table)
+			.values(&NewDeviceNonce { device_id, nonce })
+			.on_conflict_do_nothing()
+			.execute(conn)?;
+		Ok(inserted == 1)
+	}
+
+	// Forgets every nonce of the device, for when its keys are replaced
+	pub fn clear(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::delete(all_nonces)
+			.filter(device_message_nonces::device_id.eq(device_id))
+			.execute(conn)
+	}
+}
@@ -0,0 +1,150 @@
+use crate::constants::{DEVICE_ACTIVE, TRANSFER_ACCEPTED, TRANSFER_EXPIRED, TRANSFER_PENDING};
+use crate::models::device_credential::DeviceCredential;
+use crate::models::device_nonce::DeviceNonce;
+use crate::models::group::DeviceGroup;
+use crate::schema::device_transfers::dsl::device_transfers as all_transfers;
+use crate::schema::{device_transfers, devices, lights};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+// Pending transfers that were not accepted within this time can't be accepted anymore
+const TRANSFER_VALID_DAYS: i64 = 7;
+
+#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, Selectable, JsonSchema)]
+#[table_name = "device_transfers"]
+pub struct DeviceTransfer {
+	pub id: Uuid,
+	pub device_id: Uuid,
+	pub from_user_id: i32,
+	pub to_email: String,
+	pub status: String,
+	pub created_at: DateTime<Utc>,
+	pub resolved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct TransferRequest {
+	pub device_id: Uuid,
+	pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct TransferAction {
+	pub transfer_id: Uuid,
+}
+
+impl DeviceTransfer {
+	// An expired transfer would still count as the one in flight for
+	// device_transfers_pending_idx, so it is resolved first
+	pub fn create(
+		device_id: Uuid,
+		from_user_id: i32,
+		to_email: String,
+		conn: &mut PgConnection,
+	) -> QueryResult<DeviceTransfer> {
+		conn.transaction(|local_conn| {
+			DeviceTransfer::expire(device_id, local_conn)?;
+			diesel::insert_into(device_transfers::table)
+				.values(&DeviceTransfer {
+					id: Uuid::new_v4(),
+					device_id,
+					from_user_id,
+					to_email,
+					status: TRANSFER_PENDING.to_string(),
+					created_at: Utc::now(),
+					resolved_at: None,
+				})
+				.get_result::<DeviceTransfer>(local_conn)
+		})
+	}
+
+	// Pending transfers of the device that can't be accepted anymore
+	pub fn expire(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		let valid_since = Utc::now() - Duration::days(TRANSFER_VALID_DAYS);
+		diesel::update(all_transfers)
+			.filter(device_transfers::device_id.eq(device_id))
+			.filter(device_transfers::status.eq(TRANSFER_PENDING))
+			.filter(device_transfers::created_at.lt(valid_since))
+			.set((
+				device_transfers::status.eq(TRANSFER_EXPIRED),
+				device_transfers::resolved_at.eq(Some(Utc::now())),
+			))
+			.execute(conn)
+	}
+
+	pub fn get_by_id(id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<DeviceTransfer>> {
+		all_transfers
+			.filter(device_transfers::id.eq(id))
+			.first::<DeviceTransfer>(conn)
+			.optional()
+	}
+
+	pub fn get_incoming(email: &str, conn: &mut PgConnection) -> QueryResult<Vec<DeviceTransfer>> {
+		all_transfers
+			.filter(device_transfers::to_email.eq(email))
+			.filter(device_transfers::status.eq(TRANSFER_PENDING))
+			.order(device_transfers::created_at.desc())
+			.load::<DeviceTransfer>(conn)
+	}
+
+	pub fn get_outgoing(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<DeviceTransfer>> {
+		all_transfers
+			.filter(device_transfers::from_user_id.eq(user_id))
+			.filter(device_transfers::status.eq(TRANSFER_PENDING))
+			.order(device_transfers::created_at.desc())
+			.load::<DeviceTransfer>(conn)
+	}
+
+	pub fn is_expired(&self) -> bool {
+		self.created_at + Duration::days(TRANSFER_VALID_DAYS) < Utc::now()
+	}
+
+	// Marks a pending transfer as declined/cancelled, returns false when it was
+	// already resolved
+	pub fn resolve(id: Uuid, status: &str, conn: &mut PgConnection) -> QueryResult<bool> {
+		let updated = diesel::update(all_transfers)
+			.filter(device_transfers::id.eq(id))
+			.filter(device_transfers::status.eq(TRANSFER_PENDING))
+			.set((
+				device_transfers::status.eq(status),
+				device_transfers::resolved_at.eq(Some(Utc::now())),
+			))
+			.execute(conn)?;
+		Ok(updated > 0)
+	}
+
+	// Moves the device and its light to the new owner in one transaction. The
+	// previous owner's device key stops working, the new owner has to provision
+	// the device with a fresh one.
+	pub fn accept(&self, to_user_id: i32, conn: &mut PgConnection) -> QueryResult<()> {
+		conn.transaction(|local_conn| {
+			if !DeviceTransfer::resolve(self.id, TRANSFER_ACCEPTED, local_conn)? {
+				return Err(diesel::result::Error::RollbackTransaction);
+			}
+			let moved = diesel::update(devices::table)
+				.filter(devices::id.eq(self.device_id))
+				.filter(devices::user_id.eq(self.from_user_id))
+				.filter(devices::state.eq(DEVICE_ACTIVE))
+				.set(devices::user_id.eq(to_user_id))
+				.execute(local_conn)?;
+			// The device changed hands or is being decommissioned since the
+			// transfer was started
+			if moved == 0 {
+				return Err(diesel::result::Error::NotFound);
+			}
+			diesel::update(lights::table)
+				.filter(lights::light_id.eq(self.device_id))
+				.filter(lights::user_id.eq(self.from_user_id))
+				.set(lights::user_id.eq(to_user_id))
+				.execute(local_conn)?;
+			// The groups belong to the previous owner
+			DeviceGroup::remove_device(self.device_id, local_conn)?;
+			DeviceCredential::revoke_all(self.device_id, local_conn)?;
+			DeviceNonce::clear(self.device_id, local_conn)?;
+			Ok(())
+		})
+	}
+}
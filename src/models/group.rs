@@ -0,0 +1,239 @@
+use crate::constants::RGB_LIGHT;
+use crate::schema::device_group_members::dsl::device_group_members as all_members;
+use crate::schema::device_groups::dsl::device_groups as all_groups;
+use crate::schema::{device_group_members, device_groups};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+use super::device::BulkDeviceData;
+use super::light::{FullLight, LightEffect};
+use super::values::{Brightness, Kelvin, Rgb24};
+
+// Named set of devices that can be controlled as one virtual light
+#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, Selectable, JsonSchema)]
+#[table_name = "device_groups"]
+pub struct DeviceGroup {
+	pub id: Uuid,
+	pub user_id: i32,
+	pub name: String,
+	pub expose_to_google: bool,
+}
+
+#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, Selectable)]
+#[table_name = "device_group_members"]
+pub struct GroupMember {
+	pub group_id: Uuid,
+	pub device_id: Uuid,
+}
+
+// A group together with the combined state of its members
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct FullGroup {
+	pub id: Uuid,
+	pub name: String,
+	pub expose_to_google: bool,
+	pub device_ids: Vec<Uuid>,
+	// On when any member is on
+	pub is_on: bool,
+	// Average brightness of the members
+	pub brightness: i32,
+	// Color of the first member that is on (or of the first member)
+	pub rgb: i32,
+	// Only when every member can show colors
+	pub supports_color: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct NewGroup {
+	pub name: String,
+	#[serde(default)]
+	pub device_ids: Vec<Uuid>,
+	#[serde(default)]
+	pub expose_to_google: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UpdateGroup {
+	pub group_id: Uuid,
+	pub name: Option<String>,
+	pub expose_to_google: Option<bool>,
+}
+
+// DeviceData for a whole group
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct GroupData {
+	pub group_id: Uuid,
+	pub brightness: Option<Brightness>,
+	pub color: Option<Rgb24>,
+	pub color_temperature: Option<Kelvin>,
+	pub is_on: Option<bool>,
+	pub transition_ms: Option<u32>,
+	pub effect: Option<LightEffect>,
+}
+
+impl GroupData {
+	pub fn to_bulk(&self) -> BulkDeviceData {
+		BulkDeviceData {
+			device_ids: vec![],
+			group_id: Some(self.group_id),
+			brightness: self.brightness,
+			color: self.color,
+			color_temperature: self.color_temperature,
+			is_on: self.is_on,
+			transition_ms: self.transition_ms,
+			effect: self.effect.clone(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct GroupMembers {
+	pub group_id: Uuid,
+	pub device_ids: Vec<Uuid>,
+}
+
+impl DeviceGroup {
+	pub fn insert_group(group: &DeviceGroup, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::insert_into(device_groups::table)
+			.values(group)
+			.execute(conn)
+	}
+
+	pub fn get_group_by_id(id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<DeviceGroup>> {
+		all_groups
+			.filter(device_groups::id.eq(id))
+			.first::<DeviceGroup>(conn)
+			.optional()
+	}
+
+	pub fn get_groups_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<DeviceGroup>> {
+		all_groups
+			.filter(device_groups::user_id.eq(user_id))
+			.order(device_groups::name.asc())
+			.load::<DeviceGroup>(conn)
+	}
+
+	pub fn update_group(
+		id: Uuid,
+		name: &str,
+		expose_to_google: bool,
+		conn: &mut PgConnection,
+	) -> QueryResult<DeviceGroup> {
+		diesel::update(device_groups::table)
+			.set((
+				device_groups::name.eq(name),
+				device_groups::expose_to_google.eq(expose_to_google),
+			))
+			.filter(device_groups::id.eq(id))
+			.get_result::<DeviceGroup>(conn)
+	}
+
+	pub fn remove_groups_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::delete(all_groups)
+			.filter(device_groups::user_id.eq(user_id))
+			.execute(conn)
+	}
+
+	// Memberships go away with the group (ON DELETE CASCADE)
+	pub fn remove_group(id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+		let removed = diesel::delete(all_groups)
+			.filter(device_groups::id.eq(id))
+			.execute(conn)?;
+		Ok(removed > 0)
+	}
+
+	pub fn add_members(id: Uuid, device_ids: &[Uuid], conn: &mut PgConnection) -> QueryResult<usize> {
+		let members: Vec<GroupMember> = device_ids
+			.iter()
+			.map(|device_id| GroupMember {
+				group_id: id,
+				device_id: *device_id,
+			})
+			.collect();
+		diesel::insert_into(device_group_members::table)
+			.values(&members)
+			.on_conflict_do_nothing()
+			.execute(conn)
+	}
+
+	pub fn remove_members(
+		id: Uuid,
+		device_ids: &[Uuid],
+		conn: &mut PgConnection,
+	) -> QueryResult<usize> {
+		diesel::delete(all_members)
+			.filter(device_group_members::group_id.eq(id))
+			.filter(device_group_members::device_id.eq_any(device_ids))
+			.execute(conn)
+	}
+
+	// Takes the device out of every group, e.g. when it changes hands
+	pub fn remove_device(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::delete(all_members)
+			.filter(device_group_members::device_id.eq(device_id))
+			.execute(conn)
+	}
+
+	pub fn get_member_ids(id: Uuid, conn: &mut PgConnection) -> QueryResult<Vec<Uuid>> {
+		all_members
+			.filter(device_group_members::group_id.eq(id))
+			.select(device_group_members::device_id)
+			.load::<Uuid>(conn)
+	}
+
+	// Every membership of every group owned by the user
+	pub fn get_members_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<GroupMember>> {
+		all_members
+			.inner_join(all_groups)
+			.filter(device_groups::user_id.eq(user_id))
+			.select(GroupMember::as_select())
+			.load::<GroupMember>(conn)
+	}
+
+	pub fn aggregate(&self, members: &[GroupMember], lights: &[FullLight]) -> FullGroup {
+		let member_lights: Vec<&FullLight> = lights
+			.iter()
+			.filter(|light| {
+				members
+					.iter()
+					.any(|member| member.group_id == self.id && member.device_id == light.id)
+			})
+			.collect();
+		let brightness = if member_lights.is_empty() {
+			0
+		} else {
+			member_lights.iter().map(|light| light.brightness).sum::<i32>()
+				/ member_lights.len() as i32
+		};
+		let color_source = member_lights
+			.iter()
+			.find(|light| light.is_on)
+			.or(member_lights.first());
+		FullGroup {
+			id: self.id,
+			name: self.name.clone(),
+			expose_to_google: self.expose_to_google,
+			device_ids: member_lights.iter().map(|light| light.id).collect(),
+			is_on: member_lights.iter().any(|light| light.is_on),
+			brightness,
+			rgb: color_source.map(|light| light.rgb).unwrap_or(0),
+			supports_color: !member_lights.is_empty()
+				&& member_lights.iter().all(|light| light.type_ == RGB_LIGHT),
+		}
+	}
+
+	pub fn get_full_groups_by_user(
+		user_id: i32,
+		lights: &[FullLight],
+		conn: &mut PgConnection,
+	) -> QueryResult<Vec<FullGroup>> {
+		let groups = DeviceGroup::get_groups_by_user(user_id, conn)?;
+		let members = DeviceGroup::get_members_by_user(user_id, conn)?;
+		Ok(groups
+			.iter()
+			.map(|group| group.aggregate(&members, lights))
+			.collect())
+	}
+}
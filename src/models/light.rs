@@ -1,12 +1,15 @@
 use crate::schema::devices::dsl::devices as all_devices;
 use crate::schema::lights::dsl::lights as all_lights;
 use crate::schema::traits::dsl::traits as all_traits;
+use crate::constants::DEVICE_ACTIVE;
 use crate::schema::{devices, lights, traits};
 use diesel::prelude::*;
 use diesel::PgConnection;
+use schemars::JsonSchema;
 use uuid::Uuid;
 
-use super::device::Device;
+use super::device::{Device, DeviceData};
+use super::values::{Brightness, Rgb24};
 
 #[derive(Serialize, Deserialize, Queryable, Insertable, Clone, Selectable)]
 #[diesel(belongs_to(User))]
@@ -17,15 +20,80 @@ pub struct Light {
 	pub brightness: i32,
 	pub is_on: bool,
 	pub user_id: i32,
-	pub secret: String,
+	pub signature: String,
+	// Whether the device runs transitions and effects itself
+	pub native_effects: bool,
+}
+
+// Effects that keep running until the light receives another command
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LightEffect {
+	// Brightness goes up and down between min_brightness and the set brightness
+	Breathe {
+		period_ms: u32,
+		min_brightness: Brightness,
+	},
+	// Light flashes on and off
+	Strobe { frequency_hz: u32 },
+	// Goes through the colors, spending period_ms on the whole cycle
+	ColorCycle { period_ms: u32, colors: Vec<Rgb24> },
+}
+
+impl LightEffect {
+	pub fn name(&self) -> &'static str {
+		match self {
+			LightEffect::Breathe { .. } => "breathe",
+			LightEffect::Strobe { .. } => "strobe",
+			LightEffect::ColorCycle { .. } => "color_cycle",
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LightState {
 	pub is_on: bool,
-	pub brightness: i32,
-	pub color: i32,
+	pub brightness: Brightness,
+	pub color: Rgb24,
 	pub removed:bool,
+	// Fade from the current state to this one over the given time
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub transition_ms: Option<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub effect: Option<LightEffect>,
+}
+
+impl LightState {
+	// The state the light is currently in, without any effect
+	pub fn from_light(light: &Light) -> Self {
+		LightState {
+			is_on: light.is_on,
+			brightness: Brightness::saturating(light.brightness),
+			color: Rgb24::saturating(light.rgb),
+			removed: false,
+			transition_ms: None,
+			effect: None,
+		}
+	}
+	// The light's current state with the requested changes applied
+	pub fn merged(light: &Light, device_data: &DeviceData) -> Self {
+		LightState {
+			is_on: device_data.is_on.unwrap_or(light.is_on),
+			brightness: device_data
+				.brightness
+				.unwrap_or(Brightness::saturating(light.brightness)),
+			color: device_data
+				.color
+				.or(device_data.color_temperature.map(|kelvin| kelvin.to_rgb()))
+				.unwrap_or(Rgb24::saturating(light.rgb)),
+			removed: false,
+			transition_ms: device_data.transition_ms,
+			effect: device_data.effect.clone(),
+		}
+	}
+	pub fn has_effect(&self) -> bool {
+		self.transition_ms.unwrap_or(0) > 0 || self.effect.is_some()
+	}
 }
 
 #[derive(Serialize, Deserialize, Queryable, Insertable, Clone)]
@@ -35,7 +103,7 @@ pub struct Trait {
 	pub device_type: String,
 	pub trait_: String,
 }
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct FullLight {
 	pub id: Uuid,
 	pub type_: String,
@@ -44,6 +112,7 @@ pub struct FullLight {
 	pub rgb: i32,
 	pub brightness: i32,
 	pub is_on: bool,
+	pub state: String,
 }
 
 // trait BasicDevice {
@@ -60,144 +129,161 @@ pub struct FullLight {
 // }
 
 impl Trait {
-	pub fn get_traits_for_device_type(device_type: String, conn: &mut PgConnection) -> Vec<Trait> {
+	pub fn get_traits_for_device_type(
+		device_type: String,
+		conn: &mut PgConnection,
+	) -> QueryResult<Vec<Trait>> {
 		diesel::QueryDsl::filter(all_traits, traits::device_type.eq(device_type))
 			.load::<Trait>(conn)
-			.expect("error!")
 	}
 }
 
 impl Light {
-	fn new(_light_id: Uuid, secret: String, user_id: i32) -> Self {
+	fn new(_light_id: Uuid, signature: String, user_id: i32) -> Self {
 		return Self {
 			light_id: _light_id,
 			rgb: 255 * 255 * 255,
 			brightness: 255,
 			is_on: true,
-			secret: secret,
+			signature: signature,
 			user_id,
+			native_effects: false,
 		};
 	}
-	pub fn remove_device(light_id: Uuid, conn: &mut PgConnection) -> bool {
-		let s = diesel::delete(all_lights)
+	pub fn remove_device(light_id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+		let removed = diesel::delete(all_lights)
 			.filter(lights::light_id.eq(light_id))
-			.execute(conn)
-			.unwrap();
-		return s > 0;
+			.execute(conn)?;
+		Ok(removed > 0)
 	}
 	pub fn insert_device(
 		_light_id: Uuid,
 		conn: &mut PgConnection,
-		secret: String,
+		signature: String,
 		user_id: i32,
-	) -> bool {
+	) -> QueryResult<usize> {
 		diesel::insert_into(lights::table)
-			.values(&Light::new(_light_id, secret, user_id))
+			.values(&Light::new(_light_id, signature, user_id))
 			.execute(conn)
-			.is_ok()
 	}
 	pub fn update_device_brightness(
 		_light_id: Uuid,
 		brightness: i32,
 		conn: &mut PgConnection,
-		_secret: String,
+		_signature: String,
 		_user_id: i32,
-	) -> Light {
-		let light_after_update = diesel::update(lights::table)
+	) -> QueryResult<Light> {
+		diesel::update(lights::table)
 			.set(lights::brightness.eq(brightness))
 			.filter(lights::light_id.eq(_light_id))
-			.get_result::<Light>(conn);
-		// todo implement error handling
-		return light_after_update.unwrap();
+			.get_result::<Light>(conn)
 	}
 	pub fn update_device_color(
 		_light_id: Uuid,
 		color: i32,
 		conn: &mut PgConnection,
-		_secret: String,
+		_signature: String,
 		_user_id: i32,
-	) -> Light {
-		let light_after_update = diesel::update(lights::table)
-			.set(lights::brightness.eq(color))
+	) -> QueryResult<Light> {
+		diesel::update(lights::table)
+			.set(lights::rgb.eq(color))
 			.filter(lights::light_id.eq(_light_id))
-			.get_result::<Light>(conn);
-		// todo implement error handling
-		return light_after_update.unwrap();
+			.get_result::<Light>(conn)
 	}
 	pub fn update_device_is_on(
 		_light_id: Uuid,
 		is_on: bool,
 		conn: &mut PgConnection,
-		_secret: String,
+		_signature: String,
 		_user_id: i32,
-	) -> Light {
-		let light_after_update = diesel::update(lights::table)
+	) -> QueryResult<Light> {
+		diesel::update(lights::table)
 			.set(lights::is_on.eq(is_on))
 			.filter(lights::light_id.eq(_light_id))
-			.get_result::<Light>(conn);
-		// todo implement error handling
-		return light_after_update.unwrap();
+			.get_result::<Light>(conn)
 	}
-	pub fn get_devices_by_user(user_id: i32, conn: &mut PgConnection) -> Vec<Light> {
+	pub fn set_native_effects(
+		light_id: Uuid,
+		native_effects: bool,
+		conn: &mut PgConnection,
+	) -> QueryResult<usize> {
+		diesel::update(lights::table)
+			.set(lights::native_effects.eq(native_effects))
+			.filter(lights::light_id.eq(light_id))
+			.execute(conn)
+	}
+	pub fn get_devices_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<Light>> {
 		diesel::query_dsl::methods::FilterDsl::filter(all_lights, lights::user_id.eq(user_id))
 			.load::<Light>(conn)
-			.expect("error!")
-	}
-	pub fn get_device_by_id(device_id: Uuid, conn: &mut PgConnection) -> Option<Light> {
-		let light = diesel::query_dsl::methods::FilterDsl::filter(
-			all_lights,
-			lights::light_id.eq(device_id),
-		)
-		.load::<Light>(conn)
-		.expect("error");
-		return light.first().cloned();
-	}
-	pub fn get_full_device_data_by_user(user_id: i32, conn: &mut PgConnection) -> Vec<FullLight> {
-		// .select(lights::columns::rgb)
-		// 	.select(lights::columns::is_on)
-		// 	.select(lights::columns::secret)
-		// 	.select(lights::columns::brightness)
+	}
+	// Leaves out devices that are being decommissioned, they can't be controlled
+	pub fn get_active_devices_by_user(
+		user_id: i32,
+		conn: &mut PgConnection,
+	) -> QueryResult<Vec<Light>> {
+		all_lights
+			.inner_join(all_devices.on(devices::id.eq(lights::light_id)))
+			.filter(lights::user_id.eq(user_id))
+			.filter(devices::state.eq(DEVICE_ACTIVE))
+			.select(Light::as_select())
+			.load::<Light>(conn)
+	}
+	pub fn get_device_by_id(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<Light>> {
+		diesel::query_dsl::methods::FilterDsl::filter(all_lights, lights::light_id.eq(device_id))
+			.first::<Light>(conn)
+			.optional()
+	}
+	// Same as get_device_by_id, but keeps the row locked until the transaction ends
+	pub fn lock_device(device_id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<Light>> {
+		diesel::query_dsl::methods::FilterDsl::filter(all_lights, lights::light_id.eq(device_id))
+			.for_update()
+			.first::<Light>(conn)
+			.optional()
+	}
+	pub fn get_full_device_data_by_user(
+		user_id: i32,
+		conn: &mut PgConnection,
+	) -> QueryResult<Vec<FullLight>> {
 		let lights =
 			diesel::query_dsl::methods::FilterDsl::filter(all_lights, devices::user_id.eq(user_id))
 				.left_join(all_devices.on(devices::id.eq(lights::light_id)))
 				.select((Light::as_select(), Option::<Device>::as_select()))
-				.load::<(Light, Option<Device>)>(conn)
-				.expect("error");
+				.load::<(Light, Option<Device>)>(conn)?;
 
 		let data: Vec<FullLight> = lights
-			.iter()
-			.map(|dev| {
-				let device_info = dev.1.clone().unwrap();
-				FullLight {
+			.into_iter()
+			// A light always has a device row, skip it rather than fail if not
+			.filter_map(|(light, device)| {
+				let device_info = device?;
+				Some(FullLight {
 					id: device_info.id,
 					type_: device_info.type_,
-					brightness: dev.0.brightness,
+					brightness: light.brightness,
 					name: device_info.name,
 					nicknames: device_info.nicknames,
-					rgb: dev.0.rgb,
-					is_on: dev.0.is_on,
-				}
+					rgb: light.rgb,
+					is_on: light.is_on,
+					state: device_info.state,
+				})
 			})
 			.collect();
-		return data;
+		Ok(data)
 	}
 
 	pub fn update_device(
 		light_id: Uuid,
 		light_state: &LightState,
 		db_conn: &mut PgConnection,
-		_secret: String,
+		_signature: String,
 		_user_id: i32,
-	) -> Light {
-		let light_after_update = diesel::update(lights::table)
+	) -> QueryResult<Light> {
+		diesel::update(lights::table)
 			.set((
 				lights::is_on.eq(light_state.is_on),
-				lights::brightness.eq(light_state.brightness),
-				lights::rgb.eq(light_state.color),
+				lights::brightness.eq(light_state.brightness.raw()),
+				lights::rgb.eq(light_state.color.value()),
 			))
 			.filter(lights::light_id.eq(light_id))
-			.get_result::<Light>(db_conn);
-		// todo implement error handling
-		return light_after_update.unwrap();
+			.get_result::<Light>(db_conn)
 	}
 }
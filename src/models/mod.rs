@@ -1,3 +1,15 @@
+pub mod api_token;
 pub mod device;
+pub mod device_credential;
+pub mod device_nonce;
+pub mod device_transfer;
+pub mod group;
 pub mod light;
+pub mod oauth_client;
+pub mod oauth_token;
+pub mod session;
+pub mod telemetry;
+pub mod two_factor;
 pub mod user;
+pub mod user_token;
+pub mod values;
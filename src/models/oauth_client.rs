@@ -0,0 +1,172 @@
+use crate::schema::oauth_clients;
+use crate::schema::oauth_clients::dsl::oauth_clients as all_clients;
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rand_core::{OsRng, RngCore};
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+use super::user_token::hash_token;
+
+// Clients allowed to use the authorization flow, see oauth.rs
+#[derive(Queryable, Clone, Selectable)]
+#[table_name = "oauth_clients"]
+pub struct OAuthClient {
+	pub id: String,
+	pub name: String,
+	// sha256 of the secret, None for public clients
+	pub secret_hash: Option<String>,
+	pub redirect_uris: Vec<Option<String>>,
+	// Space separated, what grants of the client are limited to
+	pub scope: String,
+	pub created_at: DateTime<Utc>,
+	pub disabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct OAuthClientInfo {
+	pub client_id: String,
+	pub name: String,
+	pub confidential: bool,
+	pub redirect_uris: Vec<String>,
+	pub scopes: Vec<String>,
+	pub created_at: DateTime<Utc>,
+	pub disabled: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateOAuthClient {
+	// A random UUID when left out. Lets integrations that were set up with the
+	// old hard-coded clients (e.g. GoogleHome) keep their client_id.
+	pub client_id: Option<String>,
+	pub name: String,
+	pub redirect_uris: Vec<String>,
+	pub scopes: Vec<String>,
+	// Confidential clients get a secret for the token endpoint
+	pub confidential: bool,
+}
+
+// RFC 7591 client metadata, the fields we support
+#[derive(Deserialize, JsonSchema)]
+pub struct ClientRegistration {
+	pub client_name: Option<String>,
+	pub redirect_uris: Vec<String>,
+	// Space separated, all scopes when left out
+	pub scope: Option<String>,
+	// "client_secret_basic" (the default) or "none" for public clients
+	pub token_endpoint_auth_method: Option<String>,
+	pub grant_types: Option<Vec<String>>,
+	pub response_types: Option<Vec<String>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "oauth_clients"]
+struct NewOAuthClient<'a> {
+	id: String,
+	name: &'a str,
+	secret_hash: Option<String>,
+	redirect_uris: Vec<Option<String>>,
+	scope: String,
+}
+
+// Only handed out once, the table keeps its hash
+pub fn generate_secret() -> String {
+	let mut bytes = [0u8; 32];
+	OsRng.fill_bytes(&mut bytes);
+	general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+impl OAuthClient {
+	pub fn is_confidential(&self) -> bool {
+		self.secret_hash.is_some()
+	}
+
+	pub fn redirect_uris(&self) -> impl Iterator<Item = &str> {
+		self.redirect_uris.iter().flatten().map(String::as_str)
+	}
+
+	pub fn check_secret(&self, secret: &str) -> bool {
+		match &self.secret_hash {
+			Some(secret_hash) => {
+				openssl::memcmp::eq(hash_token(secret).as_bytes(), secret_hash.as_bytes())
+			}
+			None => false,
+		}
+	}
+
+	pub fn info(&self) -> OAuthClientInfo {
+		OAuthClientInfo {
+			client_id: self.id.clone(),
+			name: self.name.clone(),
+			confidential: self.is_confidential(),
+			redirect_uris: self.redirect_uris().map(str::to_string).collect(),
+			scopes: self.scope.split_whitespace().map(str::to_string).collect(),
+			created_at: self.created_at,
+			disabled: self.disabled_at.is_some(),
+		}
+	}
+
+	// Returns the client and its secret, None for public clients
+	pub fn create(
+		id: Option<&str>,
+		name: &str,
+		redirect_uris: &[String],
+		scopes: &[String],
+		confidential: bool,
+		conn: &mut PgConnection,
+	) -> QueryResult<(OAuthClient, Option<String>)> {
+		let secret = if confidential { Some(generate_secret()) } else { None };
+		let client = diesel::insert_into(oauth_clients::table)
+			.values(&NewOAuthClient {
+				id: id.map_or_else(|| Uuid::new_v4().to_string(), str::to_string),
+				name,
+				secret_hash: secret.as_deref().map(hash_token),
+				redirect_uris: redirect_uris.iter().cloned().map(Some).collect(),
+				scope: scopes.join(" "),
+			})
+			.get_result::<OAuthClient>(conn)?;
+		Ok((client, secret))
+	}
+
+	pub fn get(id: &str, conn: &mut PgConnection) -> QueryResult<Option<OAuthClient>> {
+		all_clients
+			.filter(oauth_clients::id.eq(id))
+			.first::<OAuthClient>(conn)
+			.optional()
+	}
+
+	// Disabled clients can't start or finish the flow
+	pub fn get_enabled(id: &str, conn: &mut PgConnection) -> QueryResult<Option<OAuthClient>> {
+		all_clients
+			.filter(oauth_clients::id.eq(id))
+			.filter(oauth_clients::disabled_at.is_null())
+			.first::<OAuthClient>(conn)
+			.optional()
+	}
+
+	pub fn get_all(conn: &mut PgConnection) -> QueryResult<Vec<OAuthClient>> {
+		all_clients.order(oauth_clients::created_at.desc()).load::<OAuthClient>(conn)
+	}
+
+	// Only for confidential clients, the old secret stops working right away
+	pub fn rotate_secret(id: &str, conn: &mut PgConnection) -> QueryResult<Option<String>> {
+		let secret = generate_secret();
+		let updated = diesel::update(all_clients)
+			.filter(oauth_clients::id.eq(id))
+			.filter(oauth_clients::secret_hash.is_not_null())
+			.set(oauth_clients::secret_hash.eq(hash_token(&secret)))
+			.execute(conn)?;
+		Ok(if updated > 0 { Some(secret) } else { None })
+	}
+
+	// There is no way back, the tokens of the client are revoked as well
+	pub fn disable(id: &str, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_clients)
+			.filter(oauth_clients::id.eq(id))
+			.filter(oauth_clients::disabled_at.is_null())
+			.set(oauth_clients::disabled_at.eq(Utc::now()))
+			.execute(conn)
+	}
+}
@@ -0,0 +1,156 @@
+use crate::schema::oauth_tokens;
+use crate::schema::oauth_tokens::dsl::oauth_tokens as all_tokens;
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rand_core::{OsRng, RngCore};
+use uuid::Uuid;
+
+use super::user_token::hash_token;
+
+// Tell access tokens apart from API tokens (API_TOKEN_PREFIX) in the
+// Authorization header
+pub const OAUTH_ACCESS_PREFIX: &str = "diyo_";
+const OAUTH_REFRESH_PREFIX: &str = "diyr_";
+
+// One row per grant, see the DbIssuer in oauth.rs
+#[derive(Queryable, Clone, Selectable)]
+#[table_name = "oauth_tokens"]
+pub struct OAuthToken {
+	pub id: Uuid,
+	pub client_id: String,
+	pub user_id: i32,
+	// Space separated, a subset of the client's scopes
+	pub scope: String,
+	pub redirect_uri: String,
+	pub access_token_hash: String,
+	pub refresh_token_hash: Option<String>,
+	// Of the access token, refresh tokens don't expire
+	pub expires_at: DateTime<Utc>,
+	pub created_at: DateTime<Utc>,
+	pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "oauth_tokens"]
+struct NewOAuthToken<'a> {
+	id: Uuid,
+	client_id: &'a str,
+	user_id: i32,
+	scope: &'a str,
+	redirect_uri: &'a str,
+	access_token_hash: String,
+	refresh_token_hash: Option<String>,
+	expires_at: DateTime<Utc>,
+}
+
+fn generate_token(prefix: &str) -> String {
+	let mut bytes = [0u8; 32];
+	OsRng.fill_bytes(&mut bytes);
+	format!("{}{}", prefix, general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+}
+
+// The tokens to hand to the client, they are only stored hashed
+pub struct IssuedTokens {
+	pub access: String,
+	pub refresh: String,
+}
+
+impl IssuedTokens {
+	fn generate() -> Self {
+		IssuedTokens {
+			access: generate_token(OAUTH_ACCESS_PREFIX),
+			refresh: generate_token(OAUTH_REFRESH_PREFIX),
+		}
+	}
+}
+
+impl OAuthToken {
+	pub fn has_scope(&self, scope: &str) -> bool {
+		self.scope.split_whitespace().any(|granted| granted == scope)
+	}
+
+	pub fn issue(
+		client_id: &str,
+		user_id: i32,
+		scope: &str,
+		redirect_uri: &str,
+		expires_at: DateTime<Utc>,
+		conn: &mut PgConnection,
+	) -> QueryResult<(OAuthToken, IssuedTokens)> {
+		let tokens = IssuedTokens::generate();
+		let token = diesel::insert_into(oauth_tokens::table)
+			.values(&NewOAuthToken {
+				id: Uuid::new_v4(),
+				client_id,
+				user_id,
+				scope,
+				redirect_uri,
+				access_token_hash: hash_token(&tokens.access),
+				refresh_token_hash: Some(hash_token(&tokens.refresh)),
+				expires_at,
+			})
+			.get_result::<OAuthToken>(conn)?;
+		Ok((token, tokens))
+	}
+
+	// Both tokens of the grant are replaced, the old ones stop working. None
+	// when the refresh token is unknown or revoked.
+	pub fn refresh(
+		refresh_token: &str,
+		scope: &str,
+		expires_at: DateTime<Utc>,
+		conn: &mut PgConnection,
+	) -> QueryResult<Option<(OAuthToken, IssuedTokens)>> {
+		let tokens = IssuedTokens::generate();
+		let token = diesel::update(all_tokens)
+			.filter(oauth_tokens::refresh_token_hash.eq(hash_token(refresh_token)))
+			.filter(oauth_tokens::revoked_at.is_null())
+			.set((
+				oauth_tokens::scope.eq(scope),
+				oauth_tokens::access_token_hash.eq(hash_token(&tokens.access)),
+				oauth_tokens::refresh_token_hash.eq(hash_token(&tokens.refresh)),
+				oauth_tokens::expires_at.eq(expires_at),
+			))
+			.get_result::<OAuthToken>(conn)
+			.optional()?;
+		Ok(token.map(|token| (token, tokens)))
+	}
+
+	// Expired access tokens are returned as well, the caller checks expires_at
+	pub fn get_by_access(token: &str, conn: &mut PgConnection) -> QueryResult<Option<OAuthToken>> {
+		all_tokens
+			.filter(oauth_tokens::access_token_hash.eq(hash_token(token)))
+			.filter(oauth_tokens::revoked_at.is_null())
+			.first::<OAuthToken>(conn)
+			.optional()
+	}
+
+	pub fn get_by_refresh(token: &str, conn: &mut PgConnection) -> QueryResult<Option<OAuthToken>> {
+		all_tokens
+			.filter(oauth_tokens::refresh_token_hash.eq(hash_token(token)))
+			.filter(oauth_tokens::revoked_at.is_null())
+			.first::<OAuthToken>(conn)
+			.optional()
+	}
+
+	// The access token when it is neither revoked nor expired
+	pub fn authenticate(token: &str, conn: &mut PgConnection) -> QueryResult<Option<OAuthToken>> {
+		all_tokens
+			.filter(oauth_tokens::access_token_hash.eq(hash_token(token)))
+			.filter(oauth_tokens::revoked_at.is_null())
+			.filter(oauth_tokens::expires_at.gt(Utc::now()))
+			.first::<OAuthToken>(conn)
+			.optional()
+	}
+
+	// When the client is disabled
+	pub fn revoke_by_client(client_id: &str, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_tokens)
+			.filter(oauth_tokens::client_id.eq(client_id))
+			.filter(oauth_tokens::revoked_at.is_null())
+			.set(oauth_tokens::revoked_at.eq(Utc::now()))
+			.execute(conn)
+	}
+}
@@ -0,0 +1,130 @@
+use crate::schema::sessions;
+use crate::schema::sessions::dsl::sessions as all_sessions;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+// A session ends after this long without a request
+pub const SESSION_IDLE_DAYS: i64 = 30;
+// last_seen_at is only written once per this interval, not on every request
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Serialize, Queryable, Clone, Selectable, JsonSchema)]
+#[table_name = "sessions"]
+pub struct Session {
+	pub id: Uuid,
+	#[serde(skip)]
+	pub user_id: i32,
+	pub user_agent: Option<String>,
+	pub ip_address: Option<String>,
+	pub created_at: DateTime<Utc>,
+	pub last_seen_at: DateTime<Utc>,
+	pub expires_at: DateTime<Utc>,
+	#[serde(skip)]
+	pub revoked_at: Option<DateTime<Utc>>,
+	// Started with the second factor, see services/two_factor.rs
+	pub two_factor: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "sessions"]
+struct NewSession {
+	id: Uuid,
+	user_id: i32,
+	user_agent: Option<String>,
+	ip_address: Option<String>,
+	expires_at: DateTime<Utc>,
+}
+
+impl Session {
+	pub fn start(
+		user_id: i32,
+		user_agent: Option<String>,
+		ip_address: Option<String>,
+		conn: &mut PgConnection,
+	) -> QueryResult<Session> {
+		diesel::insert_into(sessions::table)
+			.values(&NewSession {
+				id: Uuid::new_v4(),
+				user_id,
+				user_agent,
+				ip_address,
+				expires_at: Utc::now() + Duration::days(SESSION_IDLE_DAYS),
+			})
+			.get_result::<Session>(conn)
+	}
+
+	pub fn mark_two_factor(id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_sessions)
+			.filter(sessions::id.eq(id))
+			.set(sessions::two_factor.eq(true))
+			.execute(conn)
+	}
+
+	// The session when it belongs to the user and is neither revoked nor expired
+	pub fn get_active(
+		id: Uuid,
+		user_id: i32,
+		conn: &mut PgConnection,
+	) -> QueryResult<Option<Session>> {
+		all_sessions
+			.filter(sessions::id.eq(id))
+			.filter(sessions::user_id.eq(user_id))
+			.filter(sessions::revoked_at.is_null())
+			.filter(sessions::expires_at.gt(Utc::now()))
+			.first::<Session>(conn)
+			.optional()
+	}
+
+	pub fn get_active_by_user(user_id: i32, conn: &mut PgConnection) -> QueryResult<Vec<Session>> {
+		all_sessions
+			.filter(sessions::user_id.eq(user_id))
+			.filter(sessions::revoked_at.is_null())
+			.filter(sessions::expires_at.gt(Utc::now()))
+			.order(sessions::last_seen_at.desc())
+			.load::<Session>(conn)
+	}
+
+	// Sliding expiration, every use pushes the end of the session back
+	pub fn touch(id: Uuid, conn: &mut PgConnection) -> QueryResult<usize> {
+		let now = Utc::now();
+		diesel::update(all_sessions)
+			.filter(sessions::id.eq(id))
+			.filter(sessions::last_seen_at.lt(now - Duration::seconds(TOUCH_INTERVAL_SECONDS)))
+			.set((
+				sessions::last_seen_at.eq(now),
+				sessions::expires_at.eq(now + Duration::days(SESSION_IDLE_DAYS)),
+			))
+			.execute(conn)
+	}
+
+	// Number of sessions revoked, 0 when the user has no such active session
+	pub fn revoke(id: Uuid, user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_sessions)
+			.filter(sessions::id.eq(id))
+			.filter(sessions::user_id.eq(user_id))
+			.filter(sessions::revoked_at.is_null())
+			.set(sessions::revoked_at.eq(Utc::now()))
+			.execute(conn)
+	}
+
+	// Every session but the one the user is using
+	pub fn revoke_others(id: Uuid, user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_sessions)
+			.filter(sessions::user_id.eq(user_id))
+			.filter(sessions::id.ne(id))
+			.filter(sessions::revoked_at.is_null())
+			.set(sessions::revoked_at.eq(Utc::now()))
+			.execute(conn)
+	}
+
+	pub fn revoke_all(user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_sessions)
+			.filter(sessions::user_id.eq(user_id))
+			.filter(sessions::revoked_at.is_null())
+			.set(sessions::revoked_at.eq(Utc::now()))
+			.execute(conn)
+	}
+}
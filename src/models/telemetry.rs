@@ -0,0 +1,23 @@
+use crate::schema::device_telemetry;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Insertable)]
+#[table_name = "device_telemetry"]
+struct NewTelemetry<'a> {
+	device_id: Uuid,
+	data: &'a Value,
+}
+
+// The reports sent to /device/telemetry, the data is whatever the device sent
+pub struct Telemetry;
+
+impl Telemetry {
+	pub fn insert(device_id: Uuid, data: &Value, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::insert_into(device_telemetry::table)
+			.values(&NewTelemetry { device_id, data })
+			.execute(conn)
+	}
+}
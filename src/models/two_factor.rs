@@ -0,0 +1,163 @@
+use crate::schema::recovery_codes::dsl::recovery_codes as all_codes;
+use crate::schema::totp_credentials::dsl::totp_credentials as all_credentials;
+use crate::schema::{recovery_codes, totp_credentials};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use schemars::JsonSchema;
+
+use super::user_token::hash_token;
+
+#[derive(Queryable, Clone, Selectable)]
+#[table_name = "totp_credentials"]
+pub struct TotpCredential {
+	pub user_id: i32,
+	// base32, as shown to the user
+	pub secret: String,
+	pub created_at: DateTime<Utc>,
+	pub enabled_at: Option<DateTime<Utc>>,
+	pub last_used_step: Option<i64>,
+}
+
+// Shown once when enrolling, the user adds it to an authenticator app
+#[derive(Serialize, JsonSchema)]
+pub struct TotpSetup {
+	pub secret: String,
+	pub otpauth_uri: String,
+}
+
+// A code from the authenticator app or one of the recovery codes
+#[derive(Deserialize, JsonSchema)]
+pub struct TwoFactorCode {
+	pub code: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DisableTwoFactor {
+	pub password: String,
+	pub code: String,
+}
+
+// Second step of the login, the challenge is what /login returned
+#[derive(Deserialize, JsonSchema)]
+pub struct LoginChallenge {
+	pub challenge: String,
+	pub code: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+struct NewRecoveryCode {
+	user_id: i32,
+	code_hash: String,
+}
+
+impl TotpCredential {
+	pub fn get(user_id: i32, conn: &mut PgConnection) -> QueryResult<Option<TotpCredential>> {
+		all_credentials
+			.filter(totp_credentials::user_id.eq(user_id))
+			.first::<TotpCredential>(conn)
+			.optional()
+	}
+
+	pub fn is_enabled(user_id: i32, conn: &mut PgConnection) -> QueryResult<bool> {
+		Ok(TotpCredential::get(user_id, conn)?.map_or(false, |totp| totp.enabled_at.is_some()))
+	}
+
+	// Replaces an enrollment that was never confirmed, check that 2FA isn't
+	// enabled first
+	pub fn start_enrollment(
+		user_id: i32,
+		secret: &str,
+		conn: &mut PgConnection,
+	) -> QueryResult<usize> {
+		diesel::insert_into(totp_credentials::table)
+			.values((
+				totp_credentials::user_id.eq(user_id),
+				totp_credentials::secret.eq(secret),
+			))
+			.on_conflict(totp_credentials::user_id)
+			.do_update()
+			.set((
+				totp_credentials::secret.eq(secret),
+				totp_credentials::created_at.eq(Utc::now()),
+				totp_credentials::last_used_step.eq(None::<i64>),
+			))
+			.execute(conn)
+	}
+
+	pub fn enable(user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_credentials)
+			.filter(totp_credentials::user_id.eq(user_id))
+			.set(totp_credentials::enabled_at.eq(Utc::now()))
+			.execute(conn)
+	}
+
+	// False when a code of this or a later step was already accepted, which
+	// stops a code that was seen by someone else from being used again
+	pub fn use_step(user_id: i32, step: i64, conn: &mut PgConnection) -> QueryResult<bool> {
+		let updated = diesel::update(all_credentials)
+			.filter(totp_credentials::user_id.eq(user_id))
+			.filter(
+				totp_credentials::last_used_step
+					.is_null()
+					.or(totp_credentials::last_used_step.lt(step)),
+			)
+			.set(totp_credentials::last_used_step.eq(step))
+			.execute(conn)?;
+		Ok(updated > 0)
+	}
+
+	pub fn remove(user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::delete(all_credentials)
+			.filter(totp_credentials::user_id.eq(user_id))
+			.execute(conn)
+	}
+}
+
+pub struct RecoveryCode;
+
+impl RecoveryCode {
+	// The previous codes stop working
+	pub fn replace_all(user_id: i32, codes: &[String], conn: &mut PgConnection) -> QueryResult<()> {
+		conn.transaction(|local_conn| {
+			RecoveryCode::remove_all(user_id, local_conn)?;
+			let new_codes: Vec<NewRecoveryCode> = codes
+				.iter()
+				.map(|code| NewRecoveryCode {
+					user_id,
+					code_hash: hash_token(code),
+				})
+				.collect();
+			diesel::insert_into(recovery_codes::table)
+				.values(&new_codes)
+				.execute(local_conn)?;
+			Ok(())
+		})
+	}
+
+	// Each code works once
+	pub fn consume(user_id: i32, code: &str, conn: &mut PgConnection) -> QueryResult<bool> {
+		let used = diesel::update(all_codes)
+			.filter(recovery_codes::user_id.eq(user_id))
+			.filter(recovery_codes::code_hash.eq(hash_token(code)))
+			.filter(recovery_codes::used_at.is_null())
+			.set(recovery_codes::used_at.eq(Utc::now()))
+			.execute(conn)?;
+		Ok(used > 0)
+	}
+
+	pub fn remaining(user_id: i32, conn: &mut PgConnection) -> QueryResult<i64> {
+		all_codes
+			.filter(recovery_codes::user_id.eq(user_id))
+			.filter(recovery_codes::used_at.is_null())
+			.count()
+			.get_result(conn)
+	}
+
+	pub fn remove_all(user_id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::delete(all_codes)
+			.filter(recovery_codes::user_id.eq(user_id))
+			.execute(conn)
+	}
+}
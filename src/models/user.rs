@@ -1,7 +1,9 @@
 use crate::schema::users;
 use crate::schema::users::dsl::users as all_users;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use diesel::PgConnection;
+use schemars::JsonSchema;
 
 #[derive(Serialize, Queryable)]
 pub struct User {
@@ -10,13 +12,21 @@ pub struct User {
 	pub password: String,
 	pub first_name: String,
 	pub last_name: String,
+	pub email_verified_at: Option<DateTime<Utc>>,
+	pub pending_email: Option<String>,
+	// Manages the OAuth clients, see services/oauth_client.rs
+	pub is_admin: bool,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Me {
 	pub id: i32,
 	pub email: String,
 	pub first_name: String,
 	pub last_name: String,
+	pub email_verified: bool,
+	// Set while a change of the email address waits for verification
+	pub pending_email: Option<String>,
+	pub two_factor_enabled: bool,
 }
 // decode request data
 #[derive(Deserialize)]
@@ -24,12 +34,12 @@ pub struct UserData {
 	pub email: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct LoginUser {
 	pub email: String,
 	pub password: String,
 }
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct RegisterUser {
 	pub email: String,
 	pub password: String,
@@ -38,6 +48,44 @@ pub struct RegisterUser {
 	pub last_name: String,
 }
 
+// The token from a verification or password reset email
+#[derive(Deserialize, JsonSchema)]
+pub struct EmailToken {
+	pub token: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PasswordResetRequest {
+	pub email: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ResetPassword {
+	pub token: String,
+	pub password: String,
+	pub rep_password: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChangePassword {
+	pub current_password: String,
+	pub password: String,
+	pub rep_password: String,
+}
+
+// Fields that are left out stay as they are
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdateProfile {
+	pub first_name: Option<String>,
+	pub last_name: Option<String>,
+	pub email: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DeleteAccount {
+	pub password: String,
+}
+
 // this is to insert users to database
 #[derive(Serialize, Deserialize, Insertable, Clone, Selectable)]
 #[table_name = "users"]
@@ -58,11 +106,8 @@ impl std::convert::From<RegisterUser> for NewUser {
 	}
 }
 impl User {
-	pub fn get_all_users(conn: &mut PgConnection) -> Vec<User> {
-		all_users
-			.order(users::id.desc())
-			.load::<User>(conn)
-			.expect("error!")
+	pub fn get_all_users(conn: &mut PgConnection) -> QueryResult<Vec<User>> {
+		all_users.order(users::id.desc()).load::<User>(conn)
 	}
 
 	pub fn insert_user(
@@ -74,14 +119,75 @@ impl User {
 			.execute(conn)
 	}
 
-	pub fn get_user_by_email(email: String, conn: &mut PgConnection) -> Vec<User> {
+	pub fn get_user_by_email(email: String, conn: &mut PgConnection) -> QueryResult<Option<User>> {
 		diesel::query_dsl::methods::FilterDsl::filter(all_users, users::email.eq(email))
-			.load::<User>(conn)
-			.expect("error!")
+			.first::<User>(conn)
+			.optional()
 	}
-	pub fn get_user_by_id(id: i32, conn: &mut PgConnection) -> Vec<User> {
+	pub fn get_user_by_id(id: i32, conn: &mut PgConnection) -> QueryResult<Option<User>> {
 		diesel::query_dsl::methods::FilterDsl::filter(all_users, users::id.eq(id))
-			.load::<User>(conn)
-			.expect("error!")
+			.first::<User>(conn)
+			.optional()
+	}
+
+	pub fn mark_email_verified(id: i32, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_users)
+			.filter(users::id.eq(id))
+			.filter(users::email_verified_at.is_null())
+			.set(users::email_verified_at.eq(Utc::now()))
+			.execute(conn)
+	}
+
+	pub fn update_name(
+		id: i32,
+		first_name: &str,
+		last_name: &str,
+		conn: &mut PgConnection,
+	) -> QueryResult<usize> {
+		diesel::update(all_users)
+			.filter(users::id.eq(id))
+			.set((users::first_name.eq(first_name), users::last_name.eq(last_name)))
+			.execute(conn)
+	}
+
+	pub fn set_pending_email(id: i32, email: &str, conn: &mut PgConnection) -> QueryResult<usize> {
+		diesel::update(all_users)
+			.filter(users::id.eq(id))
+			.set(users::pending_email.eq(email))
+			.execute(conn)
+	}
+
+	// Replaces the email with the pending one, which is verified by now. Returns
+	// the new address, None when no change was pending.
+	pub fn apply_pending_email(id: i32, conn: &mut PgConnection) -> QueryResult<Option<String>> {
+		diesel::update(all_users)
+			.filter(users::id.eq(id))
+			.filter(users::pending_email.is_not_null())
+			.set((
+				users::email.eq(users::pending_email.assume_not_null()),
+				users::pending_email.eq(None::<String>),
+				users::email_verified_at.eq(Utc::now()),
+			))
+			.returning(users::email)
+			.get_result::<String>(conn)
+			.optional()
+	}
+
+	// Sessions and email tokens go with the user (ON DELETE CASCADE)
+	pub fn delete_user(id: i32, conn: &mut PgConnection) -> QueryResult<bool> {
+		let deleted = diesel::delete(all_users).filter(users::id.eq(id)).execute(conn)?;
+		Ok(deleted > 0)
+	}
+
+	// Takes the argon2 hash, not the password
+	pub fn update_password(
+		id: i32,
+		password: String,
+		conn: &mut PgConnection,
+	) -> QueryResult<usize> {
+		diesel::update(all_users)
+			.filter(users::id.eq(id))
+			.set(users::password.eq(password))
+			.execute(conn)
 	}
 }
@@ -0,0 +1,128 @@
+use crate::schema::user_tokens;
+use crate::schema::user_tokens::dsl::user_tokens as all_tokens;
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use openssl::sha::sha256;
+use rand_core::{OsRng, RngCore};
+
+// Tokens for the links in verification and password reset emails. The token
+// itself only ever exists in the email, a leaked table can't be used to take
+// over accounts.
+#[derive(Queryable, Clone, Selectable)]
+#[table_name = "user_tokens"]
+pub struct UserToken {
+	pub id: i32,
+	pub user_id: i32,
+	pub purpose: String,
+	pub token_hash: String,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: DateTime<Utc>,
+	pub used_at: Option<DateTime<Utc>>,
+	pub failed_attempts: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_tokens"]
+struct NewUserToken {
+	user_id: i32,
+	purpose: String,
+	token_hash: String,
+	expires_at: DateTime<Utc>,
+}
+
+// Also used for recovery codes, see models/two_factor.rs
+pub fn hash_token(token: &str) -> String {
+	general_purpose::URL_SAFE_NO_PAD.encode(sha256(token.as_bytes()))
+}
+
+impl UserToken {
+	// Returns the token to send, earlier unused tokens for the same purpose stop
+	// working
+	pub fn issue(
+		user_id: i32,
+		purpose: &str,
+		valid_for: Duration,
+		conn: &mut PgConnection,
+	) -> QueryResult<String> {
+		let mut bytes = [0u8; 32];
+		OsRng.fill_bytes(&mut bytes);
+		let token = general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+		conn.transaction(|local_conn| {
+			diesel::update(all_tokens)
+				.filter(user_tokens::user_id.eq(user_id))
+				.filter(user_tokens::purpose.eq(purpose))
+				.filter(user_tokens::used_at.is_null())
+				.set(user_tokens::used_at.eq(Utc::now()))
+				.execute(local_conn)?;
+			diesel::insert_into(user_tokens::table)
+				.values(&NewUserToken {
+					user_id,
+					purpose: purpose.to_string(),
+					token_hash: hash_token(&token),
+					expires_at: Utc::now() + valid_for,
+				})
+				.execute(local_conn)
+		})?;
+		Ok(token)
+	}
+
+	// Whose the token is without using it up, for tokens that are only consumed
+	// once a further check passed
+	pub fn peek(token: &str, purpose: &str, conn: &mut PgConnection) -> QueryResult<Option<i32>> {
+		all_tokens
+			.filter(user_tokens::token_hash.eq(hash_token(token)))
+			.filter(user_tokens::purpose.eq(purpose))
+			.filter(user_tokens::used_at.is_null())
+			.filter(user_tokens::expires_at.gt(Utc::now()))
+			.select(user_tokens::user_id)
+			.first::<i32>(conn)
+			.optional()
+	}
+
+	// Marks the token as used and returns whose it was. None for unknown,
+	// expired and already used tokens.
+	pub fn consume(
+		token: &str,
+		purpose: &str,
+		conn: &mut PgConnection,
+	) -> QueryResult<Option<i32>> {
+		diesel::update(all_tokens)
+			.filter(user_tokens::token_hash.eq(hash_token(token)))
+			.filter(user_tokens::purpose.eq(purpose))
+			.filter(user_tokens::used_at.is_null())
+			.filter(user_tokens::expires_at.gt(Utc::now()))
+			.set(user_tokens::used_at.eq(Utc::now()))
+			.returning(user_tokens::user_id)
+			.get_result::<i32>(conn)
+			.optional()
+	}
+
+	// Counts a wrong answer to a peeked token, the token is used up once it got
+	// max_attempts of them
+	pub fn record_failure(
+		token: &str,
+		purpose: &str,
+		max_attempts: i32,
+		conn: &mut PgConnection,
+	) -> QueryResult<()> {
+		let token_hash = hash_token(token);
+		let attempts = diesel::update(all_tokens)
+			.filter(user_tokens::token_hash.eq(&token_hash))
+			.filter(user_tokens::purpose.eq(purpose))
+			.filter(user_tokens::used_at.is_null())
+			.set(user_tokens::failed_attempts.eq(user_tokens::failed_attempts + 1))
+			.returning(user_tokens::failed_attempts)
+			.get_result::<i32>(conn)
+			.optional()?;
+		if attempts.map_or(false, |attempts| attempts >= max_attempts) {
+			diesel::update(all_tokens)
+				.filter(user_tokens::token_hash.eq(&token_hash))
+				.filter(user_tokens::used_at.is_null())
+				.set(user_tokens::used_at.eq(Utc::now()))
+				.execute(conn)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,326 @@
+// Validated values for the state of a light. Anything coming from a user (REST
+// or Google) goes through these before it can reach a device or the database.
+use std::convert::TryFrom;
+use std::fmt;
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+
+use serde_json::Value;
+
+pub const MAX_RGB: i64 = 0xFFFFFF;
+pub const MIN_KELVIN: i64 = 1000;
+pub const MAX_KELVIN: i64 = 12000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueError(String);
+
+impl fmt::Display for ValueError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BrightnessScale {
+	// 0-255, what the devices and the database use
+	Raw,
+	// 0-100, what Google uses
+	Percent,
+}
+
+// Either a plain number on the raw scale or a value with an explicit scale,
+// e.g. {"value": 40, "scale": "percent"}
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum BrightnessInput {
+	Raw(i64),
+	Scaled { value: i64, scale: BrightnessScale },
+}
+
+// Stored on the raw 0-255 scale
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "BrightnessInput", into = "i32")]
+pub struct Brightness(u8);
+
+impl Brightness {
+	pub const MIN: Brightness = Brightness(0);
+	pub const MAX: Brightness = Brightness(255);
+
+	pub fn new(value: i64, scale: BrightnessScale) -> Result<Self, ValueError> {
+		match scale {
+			BrightnessScale::Raw if (0..=255).contains(&value) => Ok(Brightness(value as u8)),
+			BrightnessScale::Raw => Err(ValueError(
+				"brightness must be between 0 and 255".to_string(),
+			)),
+			BrightnessScale::Percent if (0..=100).contains(&value) => {
+				Ok(Brightness((value as f64 * 2.55).round() as u8))
+			}
+			BrightnessScale::Percent => Err(ValueError(
+				"brightness must be between 0 and 100 percent".to_string(),
+			)),
+		}
+	}
+	pub fn from_raw(value: i64) -> Result<Self, ValueError> {
+		Brightness::new(value, BrightnessScale::Raw)
+	}
+	pub fn from_percent(value: i64) -> Result<Self, ValueError> {
+		Brightness::new(value, BrightnessScale::Percent)
+	}
+	// For values we already trust, e.g. rows written before validation existed
+	pub fn saturating(value: i32) -> Self {
+		Brightness(value.clamp(0, 255) as u8)
+	}
+	pub fn raw(self) -> i32 {
+		self.0 as i32
+	}
+	pub fn percent(self) -> i32 {
+		(self.0 as f64 / 2.55).round() as i32
+	}
+}
+
+impl TryFrom<BrightnessInput> for Brightness {
+	type Error = ValueError;
+	fn try_from(input: BrightnessInput) -> Result<Self, Self::Error> {
+		match input {
+			BrightnessInput::Raw(value) => Brightness::from_raw(value),
+			BrightnessInput::Scaled { value, scale } => Brightness::new(value, scale),
+		}
+	}
+}
+
+impl From<Brightness> for i32 {
+	fn from(brightness: Brightness) -> Self {
+		brightness.raw()
+	}
+}
+
+// 24 bit RGB color, 0xRRGGBB
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "i64", into = "i32")]
+pub struct Rgb24(u32);
+
+impl Rgb24 {
+	pub const BLACK: Rgb24 = Rgb24(0);
+	pub const WHITE: Rgb24 = Rgb24(0xFFFFFF);
+
+	pub fn from_channels(red: u8, green: u8, blue: u8) -> Self {
+		Rgb24(((red as u32) << 16) | ((green as u32) << 8) | blue as u32)
+	}
+	pub fn saturating(value: i32) -> Self {
+		Rgb24(value.clamp(0, MAX_RGB as i32) as u32)
+	}
+	pub fn channels(self) -> (u8, u8, u8) {
+		((self.0 >> 16) as u8, (self.0 >> 8) as u8, self.0 as u8)
+	}
+	pub fn value(self) -> i32 {
+		self.0 as i32
+	}
+}
+
+impl TryFrom<i64> for Rgb24 {
+	type Error = ValueError;
+	fn try_from(value: i64) -> Result<Self, Self::Error> {
+		if (0..=MAX_RGB).contains(&value) {
+			Ok(Rgb24(value as u32))
+		} else {
+			Err(ValueError(
+				"color must be between 0 and 0xFFFFFF".to_string(),
+			))
+		}
+	}
+}
+
+impl From<Rgb24> for i32 {
+	fn from(color: Rgb24) -> Self {
+		color.value()
+	}
+}
+
+// White color temperature, only used to pick a color for RGB lights
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "i64", into = "i32")]
+pub struct Kelvin(u16);
+
+impl Kelvin {
+	pub fn value(self) -> i32 {
+		self.0 as i32
+	}
+	// Approximation of the black body color, good enough for a light bulb
+	pub fn to_rgb(self) -> Rgb24 {
+		let temp = self.0 as f64 / 100.0;
+		let red = if temp <= 66.0 {
+			255.0
+		} else {
+			329.698727446 * (temp - 60.0).powf(-0.1332047592)
+		};
+		let green = if temp <= 66.0 {
+			99.4708025861 * temp.ln() - 161.1195681661
+		} else {
+			288.1221695283 * (temp - 60.0).powf(-0.0755148492)
+		};
+		let blue = if temp >= 66.0 {
+			255.0
+		} else if temp <= 19.0 {
+			0.0
+		} else {
+			138.5177312231 * (temp - 10.0).ln() - 305.0447927307
+		};
+		let channel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+		Rgb24::from_channels(channel(red), channel(green), channel(blue))
+	}
+}
+
+impl TryFrom<i64> for Kelvin {
+	type Error = ValueError;
+	fn try_from(value: i64) -> Result<Self, Self::Error> {
+		if (MIN_KELVIN..=MAX_KELVIN).contains(&value) {
+			Ok(Kelvin(value as u16))
+		} else {
+			Err(ValueError(format!(
+				"color temperature must be between {}K and {}K",
+				MIN_KELVIN, MAX_KELVIN
+			)))
+		}
+	}
+}
+
+impl From<Kelvin> for i32 {
+	fn from(kelvin: Kelvin) -> Self {
+		kelvin.value()
+	}
+}
+
+// Transitions are in milliseconds, Google sends durations in seconds
+pub fn transition_ms_from_secs(secs: u32) -> Result<u32, ValueError> {
+	secs.checked_mul(1000).ok_or_else(|| {
+		ValueError(format!("duration must be at most {} seconds", u32::MAX / 1000))
+	})
+}
+
+// Written by hand, deriving would describe the wrapped integers instead of
+// what the API accepts
+fn schema_from(schema: Value) -> Schema {
+	serde_json::from_value(schema).expect("valid JSON schema")
+}
+
+impl JsonSchema for Brightness {
+	fn schema_name() -> String {
+		"Brightness".to_string()
+	}
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		schema_from(json!({
+			"description": "0-255, or a value with an explicit scale. Always returned on the raw scale.",
+			"oneOf": [
+				{"type": "integer", "minimum": 0, "maximum": 255},
+				{
+					"type": "object",
+					"required": ["value", "scale"],
+					"properties": {
+						"value": {"type": "integer", "minimum": 0},
+						"scale": {"type": "string", "enum": ["raw", "percent"]}
+					}
+				}
+			]
+		}))
+	}
+}
+
+impl JsonSchema for Rgb24 {
+	fn schema_name() -> String {
+		"Rgb24".to_string()
+	}
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		schema_from(json!({
+			"description": "24 bit RGB color, 0xRRGGBB",
+			"type": "integer",
+			"minimum": 0,
+			"maximum": MAX_RGB
+		}))
+	}
+}
+
+impl JsonSchema for Kelvin {
+	fn schema_name() -> String {
+		"Kelvin".to_string()
+	}
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		schema_from(json!({
+			"description": "White color temperature in kelvin",
+			"type": "integer",
+			"minimum": MIN_KELVIN,
+			"maximum": MAX_KELVIN
+		}))
+	}
+}
+
+#[derive(Serialize, Clone, Debug, JsonSchema)]
+pub struct FieldError {
+	pub field: String,
+	pub error: String,
+}
+
+fn check<T: serde::de::DeserializeOwned>(value: &Value) -> Option<String> {
+	match serde_json::from_value::<T>(value.clone()) {
+		Ok(_) => None,
+		Err(err) => Some(err.to_string()),
+	}
+}
+
+fn check_field(name: &str, value: &Value) -> Option<String> {
+	if value.is_null() {
+		return None;
+	}
+	match name {
+		"brightness" | "min_brightness" => check::<Brightness>(value),
+		"color" => check::<Rgb24>(value),
+		"colors" => check::<Vec<Rgb24>>(value),
+		"color_temperature" => check::<Kelvin>(value),
+		_ => None,
+	}
+}
+
+// Every invalid light value in a request body, with the path to it, so all of
+// them can be reported at once instead of only the first one serde runs into
+pub fn invalid_fields(body: &Value) -> Vec<FieldError> {
+	fn walk(prefix: &str, value: &Value, errors: &mut Vec<FieldError>) {
+		match value {
+			Value::Object(fields) => {
+				for (name, field) in fields.iter() {
+					let path = if prefix.is_empty() {
+						name.clone()
+					} else {
+						format!("{}.{}", prefix, name)
+					};
+					match check_field(name, field) {
+						Some(error) => errors.push(FieldError { field: path, error }),
+						None => walk(&path, field, errors),
+					}
+				}
+			}
+			Value::Array(items) => {
+				for (index, item) in items.iter().enumerate() {
+					walk(&format!("{}[{}]", prefix, index), item, errors);
+				}
+			}
+			_ => {}
+		}
+	}
+	let mut errors = vec![];
+	walk("", body, &mut errors);
+	errors
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn google_durations_are_checked_before_converting() {
+		assert_eq!(transition_ms_from_secs(1800), Ok(1_800_000));
+		assert_eq!(transition_ms_from_secs(u32::MAX / 1000), Ok(u32::MAX / 1000 * 1000));
+		assert!(transition_ms_from_secs(u32::MAX / 1000 + 1).is_err());
+	}
+}
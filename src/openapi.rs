@@ -0,0 +1,893 @@
+// OpenAPI 3 document for the REST API. The operations are taken from the routes
+// Rocket actually mounted, the table below only adds what Rocket doesn't know
+// about them: a summary, how they are authenticated and the body types.
+// Routes without an entry are left out of the document, tests.rs checks that
+// there are none.
+use rocket::http::Method;
+use rocket::Route;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::constants::{API_TOKEN_SCOPES, SCOPE_DEVICES_READ, SCOPE_PROFILE};
+use crate::decommission::DecommissionStatus;
+use crate::models::api_token::{ApiTokenId, ApiTokenInfo, CreateApiToken};
+use crate::models::device::{BulkDeviceData, BulkResult, Device, DeviceData, NewDevice};
+use crate::models::device_transfer::{DeviceTransfer, TransferAction, TransferRequest};
+use crate::models::group::{DeviceGroup, FullGroup, GroupData, GroupMembers, NewGroup, UpdateGroup};
+use crate::models::light::FullLight;
+use crate::models::oauth_client::{ClientRegistration, CreateOAuthClient, OAuthClientInfo};
+use crate::models::two_factor::{DisableTwoFactor, LoginChallenge, TotpSetup, TwoFactorCode};
+use crate::models::user::{
+	ChangePassword, DeleteAccount, EmailToken, LoginUser, Me, PasswordResetRequest, RegisterUser,
+	ResetPassword, UpdateProfile,
+};
+use crate::oath_routes::SCOPE_DESCRIPTIONS;
+use crate::routes::api_token::api_token_scope;
+use crate::routes::device::{DeleteData, RenameData, RotateKeyData};
+use crate::routes::device_messages::StateReport;
+use crate::error::ApiError;
+use crate::routes::group::GroupId;
+use crate::routes::session::{SessionId, SessionInfo};
+use crate::routes::etag::{ETAG_HEADER, IF_MATCH_HEADER, IF_NONE_MATCH_HEADER};
+use crate::routes::v2::{Deletion, DevicePatch, DeviceResource, DeviceStateResource, StateUpdate};
+use crate::routes::SESSION_STRING;
+
+pub const API_VERSION: &str = "1.0.0";
+
+// Served by the docs routes, built once at startup
+pub struct OpenApi(pub Value);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Auth {
+	None,
+	// Session cookie set by /login, or an API token or OAuth access token on
+	// the routes in API_TOKEN_ROUTES
+	Session,
+	// Signed with the device key, see device_messages.rs
+	Device,
+	// Bearer token issued by /oauth/token, granted with the scope
+	OAuth(&'static str),
+	// Bearer token from OAUTH_REGISTRATION_TOKEN
+	InitialAccessToken,
+}
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+pub struct RouteDoc {
+	// Name of the route's handler function
+	pub name: &'static str,
+	pub tag: &'static str,
+	pub summary: &'static str,
+	pub auth: Auth,
+	pub request: Option<SchemaFn>,
+	pub response: Reply,
+}
+
+pub enum Reply {
+	// Fields returned next to "success":true
+	Envelope(&'static [(&'static str, SchemaFn)]),
+	// Fields of the schema next to "success":true
+	Flattened(SchemaFn),
+	// v2 resource with an ETag, changed with If-Match (see routes/etag.rs)
+	Tagged(SchemaFn),
+	// v2 body without an ETag, changes still take If-Match
+	Plain(SchemaFn),
+	// Redirects, HTML, OAuth
+	Other,
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+	gen.subschema_for::<T>()
+}
+
+const SUCCESS: &[(&str, SchemaFn)] = &[];
+// "worked", "status":200 and "result" are the success flags of the API before
+// "success" was introduced, still sent for older clients
+const WORKED: &[(&str, SchemaFn)] = &[("worked", schema::<bool>)];
+
+pub fn route_docs() -> Vec<RouteDoc> {
+	vec![
+		RouteDoc {
+			name: "register",
+			tag: "users",
+			summary: "Create an account",
+			auth: Auth::None,
+			request: Some(schema::<RegisterUser>),
+			response: Reply::Envelope(WORKED),
+		},
+		RouteDoc {
+			name: "login",
+			tag: "users",
+			summary: "Log in and set the session cookie, or get a challenge when 2FA is enabled",
+			auth: Auth::None,
+			request: Some(schema::<LoginUser>),
+			response: Reply::Envelope(&[
+				("worked", schema::<bool>),
+				("two_factor_required", schema::<bool>),
+				("challenge", schema::<Option<String>>),
+			]),
+		},
+		RouteDoc {
+			name: "login_two_factor",
+			tag: "users",
+			summary: "Finish a 2FA login with a TOTP or recovery code and set the session cookie",
+			auth: Auth::None,
+			request: Some(schema::<LoginChallenge>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "setup_two_factor",
+			tag: "users",
+			summary: "Generate a TOTP secret, 2FA is enabled once a code for it is confirmed",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("setup", schema::<TotpSetup>)]),
+		},
+		RouteDoc {
+			name: "enable_two_factor",
+			tag: "users",
+			summary: "Confirm a code and enable 2FA, returns the recovery codes",
+			auth: Auth::Session,
+			request: Some(schema::<TwoFactorCode>),
+			response: Reply::Envelope(&[("recovery_codes", schema::<Vec<String>>)]),
+		},
+		RouteDoc {
+			name: "disable_two_factor",
+			tag: "users",
+			summary: "Turn 2FA off with the password and a code",
+			auth: Auth::Session,
+			request: Some(schema::<DisableTwoFactor>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "regenerate_recovery_codes",
+			tag: "users",
+			summary: "Replace the recovery codes",
+			auth: Auth::Session,
+			request: Some(schema::<TwoFactorCode>),
+			response: Reply::Envelope(&[("recovery_codes", schema::<Vec<String>>)]),
+		},
+		RouteDoc {
+			name: "logout",
+			tag: "users",
+			summary: "End the session and remove the session cookie",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Envelope(&[
+				("status", schema::<u16>),
+				("result", schema::<bool>),
+			]),
+		},
+		RouteDoc {
+			name: "list_sessions",
+			tag: "users",
+			summary: "Active sessions of the user, one per login",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("sessions", schema::<Vec<SessionInfo>>)]),
+		},
+		RouteDoc {
+			name: "revoke_session",
+			tag: "users",
+			summary: "End one of the user's sessions",
+			auth: Auth::Session,
+			request: Some(schema::<SessionId>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "logout_everywhere",
+			tag: "users",
+			summary: "End every session and revoke every API token of the user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[
+				("revoked", schema::<usize>),
+				("revoked_api_tokens", schema::<usize>),
+			]),
+		},
+		RouteDoc {
+			name: "list_api_tokens",
+			tag: "users",
+			summary: "Active personal API tokens of the user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("api_tokens", schema::<Vec<ApiTokenInfo>>)]),
+		},
+		RouteDoc {
+			name: "create_api_token",
+			tag: "users",
+			summary: "Create a scoped API token, the token is only shown in this response",
+			auth: Auth::Session,
+			request: Some(schema::<CreateApiToken>),
+			response: Reply::Envelope(&[
+				("api_token", schema::<ApiTokenInfo>),
+				("token", schema::<String>),
+			]),
+		},
+		RouteDoc {
+			name: "revoke_api_token",
+			tag: "users",
+			summary: "Revoke one of the user's API tokens",
+			auth: Auth::Session,
+			request: Some(schema::<ApiTokenId>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "list_oauth_clients",
+			tag: "admin",
+			summary: "Every OAuth client, admins only",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("clients", schema::<Vec<OAuthClientInfo>>)]),
+		},
+		RouteDoc {
+			name: "create_oauth_client",
+			tag: "admin",
+			summary: "Create an OAuth client, the secret is only shown in this response",
+			auth: Auth::Session,
+			request: Some(schema::<CreateOAuthClient>),
+			response: Reply::Envelope(&[
+				("client", schema::<OAuthClientInfo>),
+				("client_secret", schema::<Option<String>>),
+			]),
+		},
+		RouteDoc {
+			name: "rotate_oauth_client_secret",
+			tag: "admin",
+			summary: "Replace the secret of a confidential OAuth client",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("client_secret", schema::<String>)]),
+		},
+		RouteDoc {
+			name: "disable_oauth_client",
+			tag: "admin",
+			summary: "Disable an OAuth client and revoke its tokens",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "verify_email",
+			tag: "users",
+			summary: "Confirm the email address with the token from the verification email",
+			auth: Auth::None,
+			request: Some(schema::<EmailToken>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "resend_verification",
+			tag: "users",
+			summary: "Send the verification email again",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "request_password_reset",
+			tag: "users",
+			summary: "Email a password reset link, succeeds for unknown addresses too",
+			auth: Auth::None,
+			request: Some(schema::<PasswordResetRequest>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "reset_password",
+			tag: "users",
+			summary: "Set a new password with the token from the reset email, ends all sessions",
+			auth: Auth::None,
+			request: Some(schema::<ResetPassword>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "change_password",
+			tag: "users",
+			summary: "Change the password, ends every other session",
+			auth: Auth::Session,
+			request: Some(schema::<ChangePassword>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "update_profile",
+			tag: "users",
+			summary: "Change the name or email, a new email is used once it is confirmed",
+			auth: Auth::Session,
+			request: Some(schema::<UpdateProfile>),
+			response: Reply::Envelope(&[("user", schema::<Me>)]),
+		},
+		RouteDoc {
+			name: "confirm_email_change",
+			tag: "users",
+			summary: "Switch to the new email with the token sent to it",
+			auth: Auth::None,
+			request: Some(schema::<EmailToken>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "delete_account",
+			tag: "users",
+			summary: "Delete the account, its devices are wiped and its OAuth tokens revoked",
+			auth: Auth::Session,
+			request: Some(schema::<DeleteAccount>),
+			response: Reply::Envelope(&[("devices_pending_removal", schema::<usize>)]),
+		},
+		RouteDoc {
+			name: "get_me",
+			tag: "users",
+			summary: "The logged in user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Flattened(schema::<Me>),
+		},
+		RouteDoc {
+			name: "get_devices",
+			tag: "devices",
+			summary: "Devices of the user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[
+				("status", schema::<u16>),
+				("devices", schema::<Vec<Device>>),
+			]),
+		},
+		RouteDoc {
+			name: "get_full_devices",
+			tag: "devices",
+			summary: "Devices of the user with their current state, and their groups",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[
+				("status", schema::<u16>),
+				("lights", schema::<Vec<FullLight>>),
+				("groups", schema::<Vec<FullGroup>>),
+			]),
+		},
+		RouteDoc {
+			name: "register_device",
+			tag: "devices",
+			summary: "Register a device and issue its device key",
+			auth: Auth::Session,
+			request: Some(schema::<NewDevice>),
+			response: Reply::Envelope(&[
+				("status", schema::<u16>),
+				("result", schema::<bool>),
+				("device_key", schema::<String>),
+			]),
+		},
+		RouteDoc {
+			name: "set_on",
+			tag: "devices",
+			summary: "Turn a light on or off",
+			auth: Auth::Session,
+			request: Some(schema::<DeviceData>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "set_color",
+			tag: "devices",
+			summary: "Set the color of a light",
+			auth: Auth::Session,
+			request: Some(schema::<DeviceData>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "set_brightness",
+			tag: "devices",
+			summary: "Set the brightness of a light",
+			auth: Auth::Session,
+			request: Some(schema::<DeviceData>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "bulk_update",
+			tag: "devices",
+			summary: "Apply one state to many devices or a group",
+			auth: Auth::Session,
+			request: Some(schema::<BulkDeviceData>),
+			response: Reply::Envelope(&[("results", schema::<Vec<BulkResult>>)]),
+		},
+		RouteDoc {
+			name: "check_device_online",
+			tag: "devices",
+			summary: "Whether the device answers through the gateway",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("isOnline", schema::<bool>)]),
+		},
+		RouteDoc {
+			name: "rename_device",
+			tag: "devices",
+			summary: "Rename a device",
+			auth: Auth::Session,
+			request: Some(schema::<RenameData>),
+			response: Reply::Envelope(&[("new_name", schema::<String>)]),
+		},
+		RouteDoc {
+			name: "remove_device",
+			tag: "devices",
+			summary: "Decommission a device",
+			auth: Auth::Session,
+			request: Some(schema::<DeleteData>),
+			response: Reply::Envelope(&[("status", schema::<DecommissionStatus>)]),
+		},
+		RouteDoc {
+			name: "rotate_device_key",
+			tag: "devices",
+			summary: "Issue a new device key",
+			auth: Auth::Session,
+			request: Some(schema::<RotateKeyData>),
+			response: Reply::Envelope(&[("device_key", schema::<String>)]),
+		},
+		RouteDoc {
+			name: "report_state",
+			tag: "device messages",
+			summary: "Report the actual state of the device",
+			auth: Auth::Device,
+			request: Some(schema::<StateReport>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "report_telemetry",
+			tag: "device messages",
+			summary: "Send telemetry, any JSON object is stored as sent",
+			auth: Auth::Device,
+			request: Some(schema::<Map<String, Value>>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "device_rotate_key",
+			tag: "device messages",
+			summary: "Replace the key the request was signed with",
+			auth: Auth::Device,
+			request: None,
+			response: Reply::Envelope(&[("device_key", schema::<String>)]),
+		},
+		RouteDoc {
+			name: "transfer_device",
+			tag: "transfers",
+			summary: "Offer a device to another user",
+			auth: Auth::Session,
+			request: Some(schema::<TransferRequest>),
+			response: Reply::Envelope(&[("transfer", schema::<DeviceTransfer>)]),
+		},
+		RouteDoc {
+			name: "get_transfers",
+			tag: "transfers",
+			summary: "Pending transfers to and from the user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[
+				("incoming", schema::<Vec<DeviceTransfer>>),
+				("outgoing", schema::<Vec<DeviceTransfer>>),
+			]),
+		},
+		RouteDoc {
+			name: "accept_transfer",
+			tag: "transfers",
+			summary: "Accept a transfer addressed to the user",
+			auth: Auth::Session,
+			request: Some(schema::<TransferAction>),
+			response: Reply::Envelope(&[("device_id", schema::<Uuid>)]),
+		},
+		RouteDoc {
+			name: "cancel_transfer",
+			tag: "transfers",
+			summary: "Decline a transfer, or take back an own offer",
+			auth: Auth::Session,
+			request: Some(schema::<TransferAction>),
+			response: Reply::Envelope(&[("status", schema::<String>)]),
+		},
+		RouteDoc {
+			name: "get_groups",
+			tag: "groups",
+			summary: "Groups of the user with their combined state",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Envelope(&[("groups", schema::<Vec<FullGroup>>)]),
+		},
+		RouteDoc {
+			name: "create_group",
+			tag: "groups",
+			summary: "Create a group",
+			auth: Auth::Session,
+			request: Some(schema::<NewGroup>),
+			response: Reply::Envelope(&[("group", schema::<DeviceGroup>)]),
+		},
+		RouteDoc {
+			name: "update_group",
+			tag: "groups",
+			summary: "Rename a group or change whether Google sees it",
+			auth: Auth::Session,
+			request: Some(schema::<UpdateGroup>),
+			response: Reply::Envelope(&[("group", schema::<DeviceGroup>)]),
+		},
+		RouteDoc {
+			name: "remove_group",
+			tag: "groups",
+			summary: "Remove a group, its devices stay",
+			auth: Auth::Session,
+			request: Some(schema::<GroupId>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "add_to_group",
+			tag: "groups",
+			summary: "Add devices to a group",
+			auth: Auth::Session,
+			request: Some(schema::<GroupMembers>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "remove_from_group",
+			tag: "groups",
+			summary: "Remove devices from a group",
+			auth: Auth::Session,
+			request: Some(schema::<GroupMembers>),
+			response: Reply::Envelope(SUCCESS),
+		},
+		RouteDoc {
+			name: "set_group_state",
+			tag: "groups",
+			summary: "Apply a state to every device of a group",
+			auth: Auth::Session,
+			request: Some(schema::<GroupData>),
+			response: Reply::Envelope(&[("results", schema::<Vec<BulkResult>>)]),
+		},
+		RouteDoc {
+			name: "fullfilment",
+			tag: "google",
+			summary: "Google Smart Home intents, EXECUTE also needs devices:control",
+			auth: Auth::OAuth(SCOPE_DEVICES_READ),
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "openapi",
+			tag: "docs",
+			summary: "This document",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "docs",
+			tag: "docs",
+			summary: "Documentation rendered from this document",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "list_devices",
+			tag: "devices v2",
+			summary: "Devices of the user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Plain(schema::<Vec<DeviceResource>>),
+		},
+		RouteDoc {
+			name: "get_device",
+			tag: "devices v2",
+			summary: "A device of the user",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Tagged(schema::<DeviceResource>),
+		},
+		RouteDoc {
+			name: "patch_device",
+			tag: "devices v2",
+			summary: "Change the given fields of a device",
+			auth: Auth::Session,
+			request: Some(schema::<DevicePatch>),
+			response: Reply::Tagged(schema::<DeviceResource>),
+		},
+		RouteDoc {
+			name: "delete_device",
+			tag: "devices v2",
+			summary: "Decommission a device, 202 until the device acknowledged it",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Plain(schema::<Deletion>),
+		},
+		RouteDoc {
+			name: "get_device_state",
+			tag: "devices v2",
+			summary: "Current state of a light",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Tagged(schema::<DeviceStateResource>),
+		},
+		RouteDoc {
+			name: "put_device_state",
+			tag: "devices v2",
+			summary: "Replace the state of a light",
+			auth: Auth::Session,
+			request: Some(schema::<StateUpdate>),
+			response: Reply::Tagged(schema::<DeviceStateResource>),
+		},
+		RouteDoc {
+			name: "authorize",
+			tag: "oauth",
+			summary: "Authorization endpoint, shows the consent page or redirects to the login",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "authorize_consent",
+			tag: "oauth",
+			summary: "Answer of the consent page, needs its csrf_token",
+			auth: Auth::Session,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "return_to_authorize",
+			tag: "oauth",
+			summary: "Back to the authorization request once logged in, with the signed return_to",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "token",
+			tag: "oauth",
+			summary: "Token endpoint",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "refresh",
+			tag: "oauth",
+			summary: "Refresh an access token",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "register_client",
+			tag: "oauth",
+			summary: "Dynamic client registration (RFC 7591), answers with the client metadata",
+			auth: Auth::InitialAccessToken,
+			request: Some(schema::<ClientRegistration>),
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "protected_resource",
+			tag: "oauth",
+			summary: "Profile of the user who granted the access token",
+			auth: Auth::OAuth(SCOPE_PROFILE),
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "jwks",
+			tag: "oauth",
+			summary: "Public keys for checking the JWTs we issue",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+		RouteDoc {
+			name: "get_token",
+			tag: "oauth",
+			summary: "Page for trying out the authorization flow",
+			auth: Auth::None,
+			request: None,
+			response: Reply::Other,
+		},
+	]
+}
+
+// "/api/v1/is_online/<device_id>" -> "/api/v1/is_online/{device_id}"
+pub fn openapi_path(route: &Route) -> String {
+	route
+		.uri
+		.path()
+		.split('/')
+		.map(|segment| match dynamic_name(segment) {
+			Some(name) => format!("{{{}}}", name),
+			None => segment.to_string(),
+		})
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+fn dynamic_name(segment: &str) -> Option<&str> {
+	if segment.starts_with('<') && segment.ends_with('>') {
+		Some(segment[1..segment.len() - 1].trim_end_matches(".."))
+	} else {
+		None
+	}
+}
+
+fn header(name: &str) -> Value {
+	json!({"name": name, "in": "header", "required": false, "schema": {"type": "string"}})
+}
+
+fn parameters(route: &Route, doc: &RouteDoc) -> Vec<Value> {
+	let path = route.uri.path().split('/').filter_map(dynamic_name).map(|name| {
+		json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}})
+	});
+	let query = route
+		.uri
+		.query()
+		.unwrap_or("")
+		.split('&')
+		.filter_map(dynamic_name)
+		.map(|name| json!({"name": name, "in": "query", "required": true, "schema": {"type": "string"}}));
+	let mut parameters: Vec<Value> = path.chain(query).collect();
+	let conditional = match doc.response {
+		Reply::Tagged(_) | Reply::Plain(_) => route.method != Method::Get,
+		_ => false,
+	};
+	if conditional {
+		parameters.push(header(IF_MATCH_HEADER));
+	} else if let Reply::Tagged(_) = doc.response {
+		parameters.push(header(IF_NONE_MATCH_HEADER));
+	}
+	parameters
+}
+
+fn security(auth: Auth, name: &str) -> Value {
+	match auth {
+		Auth::None => json!([]),
+		Auth::Session => match api_token_scope(name) {
+			Some(scope) if API_TOKEN_SCOPES.contains(&scope) => {
+				json!([{"session": []}, {"apiToken": []}, {"oauth": [scope]}])
+			}
+			Some(scope) => json!([{"session": []}, {"oauth": [scope]}]),
+			None => json!([{"session": []}]),
+		},
+		Auth::Device => json!([{"deviceSignature": []}]),
+		Auth::OAuth(scope) => json!([{"oauth": [scope]}]),
+		Auth::InitialAccessToken => json!([{"initialAccessToken": []}]),
+	}
+}
+
+fn to_value(schema: Schema) -> Value {
+	serde_json::to_value(schema).expect("serializable schema")
+}
+
+fn envelope(fields: &[(&str, SchemaFn)], gen: &mut SchemaGenerator) -> Value {
+	let mut properties = Map::new();
+	properties.insert("success".to_string(), json!({"type": "boolean"}));
+	for (name, schema_fn) in fields {
+		properties.insert(name.to_string(), to_value(schema_fn(gen)));
+	}
+	json!({"type": "object", "required": ["success"], "properties": properties})
+}
+
+fn operation(route: &Route, doc: &RouteDoc, gen: &mut SchemaGenerator) -> Value {
+	let mut operation = json!({
+		"operationId": doc.name,
+		"summary": doc.summary,
+		"tags": [doc.tag],
+		"security": security(doc.auth, doc.name),
+		"parameters": parameters(route, doc),
+	});
+	if let Some(scope) = api_token_scope(doc.name) {
+		operation["x-api-token-scope"] = json!(scope);
+	}
+	if let Some(request) = doc.request {
+		let content_type = route
+			.format
+			.as_ref()
+			.map(|format| format.to_string())
+			.unwrap_or_else(|| "application/json".to_string());
+		let mut content = Map::new();
+		content.insert(content_type, json!({"schema": to_value(request(gen))}));
+		operation["requestBody"] = json!({"required": true, "content": content});
+	}
+	let mut responses = match doc.response {
+		Reply::Envelope(fields) => json!({
+			"200": {
+				"description": "Success",
+				"content": {"application/json": {"schema": envelope(fields, gen)}},
+			},
+		}),
+		Reply::Flattened(fields) => json!({
+			"200": {
+				"description": "Success",
+				"content": {"application/json": {"schema": {
+					"allOf": [envelope(&[], gen), to_value(fields(gen))],
+				}}},
+			},
+		}),
+		Reply::Tagged(resource) => json!({
+			"200": {
+				"description": "Success",
+				"headers": {ETAG_HEADER: {"schema": {"type": "string"}}},
+				"content": {"application/json": {"schema": to_value(resource(gen))}},
+			},
+			"304": {"description": "Not modified since the ETag in If-None-Match"},
+		}),
+		Reply::Plain(body) => json!({
+			"200": {
+				"description": "Success",
+				"content": {"application/json": {"schema": to_value(body(gen))}},
+			},
+		}),
+		Reply::Other => json!({"200": {"description": "Success"}}),
+	};
+	responses["default"] = json!({"$ref": "#/components/responses/Error"});
+	operation["responses"] = responses;
+	operation
+}
+
+// Every mounted route that has an entry in route_docs, the tests make sure
+// that is all of them
+pub fn spec<'a>(routes: impl Iterator<Item = &'a Route>) -> Value {
+	let docs = route_docs();
+	let mut gen = SchemaSettings::openapi3().into_generator();
+	let error = to_value(schema::<ApiError>(&mut gen));
+	let mut paths = Map::new();
+	for route in routes {
+		// Preflight requests are answered by rocket_cors
+		if route.method == Method::Options {
+			continue;
+		}
+		let doc = match docs.iter().find(|doc| Some(doc.name) == route.name) {
+			Some(doc) => doc,
+			None => continue,
+		};
+		let operation = operation(route, doc, &mut gen);
+		let path = paths.entry(openapi_path(route)).or_insert_with(|| json!({}));
+		path[route.method.as_str().to_lowercase()] = operation;
+	}
+	let schemas = serde_json::to_value(gen.take_definitions()).expect("serializable schemas");
+	let oauth_scopes: Map<String, Value> = SCOPE_DESCRIPTIONS
+		.iter()
+		.map(|(scope, description)| (scope.to_string(), json!(description)))
+		.collect();
+	json!({
+		"openapi": "3.0.3",
+		"info": {"title": "DIY IoT backend", "version": API_VERSION},
+		"paths": paths,
+		"components": {
+			"schemas": schemas,
+			"responses": {
+				"Error": {
+					"description": "Error, see the code for what went wrong",
+					"content": {"application/json": {"schema": {
+						"type": "object",
+						"required": ["success", "error"],
+						"properties": {"success": {"type": "boolean"}, "error": error},
+					}}},
+				},
+			},
+			"securitySchemes": {
+				"session": {"type": "apiKey", "in": "cookie", "name": SESSION_STRING},
+				"deviceSignature": {
+					"type": "apiKey",
+					"in": "header",
+					"name": crate::routes::device_messages::SIGNATURE_HEADER,
+					"description": "HMAC-SHA256 of <device id>.<timestamp>.<nonce>.<body> \
+						with the device key, the values are sent in X-Device-Id, \
+						X-Device-Timestamp and X-Device-Nonce. Every nonce is accepted once.",
+				},
+				"oauth": {
+					"type": "oauth2",
+					"flows": {
+						"authorizationCode": {
+							"authorizationUrl": "/oauth/authorize",
+							"tokenUrl": "/oauth/token",
+							"refreshUrl": "/oauth/refresh",
+							"scopes": oauth_scopes,
+						},
+					},
+				},
+				"initialAccessToken": {"type": "http", "scheme": "bearer"},
+				"apiToken": {
+					"type": "http",
+					"scheme": "bearer",
+					"description": "Personal API token, see x-api-token-scope for the scope",
+				},
+			},
+		},
+	})
+}
@@ -0,0 +1,453 @@
+// Throttling of the routes that can be abused: logins, registration, the OAuth
+// token endpoint and the device controls.
+//
+// RateLimiter is a fairing counting requests per client IP in fixed windows.
+// The client IP is the address the connection came from, X-Real-IP is only
+// believed when the connection came from one of TRUSTED_PROXIES.
+// The limits are "<requests>/<seconds>" and read from RATE_LIMIT_LOGIN,
+// RATE_LIMIT_REGISTER, RATE_LIMIT_OAUTH_TOKEN and RATE_LIMIT_CONTROL. A
+// request over the limit never reaches its route: it is rewritten to a path
+// nothing is mounted on and the 404 catcher answers with 429 and Retry-After.
+//
+// LoginThrottle locks an account (the email, whether it exists or not) for one
+// client IP after LOGIN_LOCKOUT_AFTER failed logins in a row for
+// LOGIN_LOCKOUT_SECONDS, doubling with every further failure. Keying on the IP
+// as well means guessing someone's password doesn't lock them out everywhere
+// right away. Only after LOGIN_ACCOUNT_LOCKOUT_AFTER failures from any IPs is
+// the account locked for all of them, so rotating IPs doesn't give an attacker
+// unlimited guesses.
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::{Data, Request};
+
+const LIMITED_PATH: &str = "/__rate_limited";
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+// Failed logins are forgotten after this long without another one
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+// The maps are cleaned up of stale entries once they grow this big
+const PRUNE_AT: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+	pub requests: u32,
+	pub window: Duration,
+}
+
+impl Limit {
+	pub fn new(requests: u32, window_secs: u64) -> Self {
+		Limit {
+			requests,
+			window: Duration::from_secs(window_secs),
+		}
+	}
+
+	fn from_env(name: &str, default: Limit) -> Limit {
+		match env::var(name) {
+			Ok(value) => value
+				.parse()
+				.unwrap_or_else(|_| panic!("{} must look like <requests>/<seconds>", name)),
+			Err(_) => default,
+		}
+	}
+}
+
+impl FromStr for Limit {
+	type Err = ();
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let (requests, seconds) = value.split_once('/').ok_or(())?;
+		let requests = requests.trim().parse().map_err(|_| ())?;
+		let seconds: u64 = seconds.trim().parse().map_err(|_| ())?;
+		if seconds == 0 {
+			return Err(());
+		}
+		Ok(Limit::new(requests, seconds))
+	}
+}
+
+pub struct Limits {
+	pub login: Limit,
+	pub register: Limit,
+	pub oauth_token: Limit,
+	pub control: Limit,
+}
+
+impl Limits {
+	pub fn from_env() -> Self {
+		Limits {
+			login: Limit::from_env("RATE_LIMIT_LOGIN", Limit::new(10, 60)),
+			register: Limit::from_env("RATE_LIMIT_REGISTER", Limit::new(5, 60 * 60)),
+			oauth_token: Limit::from_env("RATE_LIMIT_OAUTH_TOKEN", Limit::new(30, 60)),
+			control: Limit::from_env("RATE_LIMIT_CONTROL", Limit::new(120, 60)),
+		}
+	}
+}
+
+// Requests matching any of the paths share one limit. "*" matches one segment.
+struct Rule {
+	name: &'static str,
+	method: Method,
+	paths: &'static [&'static str],
+	limit: Limit,
+}
+
+impl Rule {
+	fn matches(&self, method: Method, path: &str) -> bool {
+		self.method == method && self.paths.iter().any(|pattern| path_matches(pattern, path))
+	}
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+	let pattern: Vec<&str> = pattern.trim_end_matches('/').split('/').collect();
+	let path: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+	pattern.len() == path.len()
+		&& pattern
+			.iter()
+			.zip(path.iter())
+			.all(|(expected, segment)| *expected == "*" || expected == segment)
+}
+
+// Proxies allowed to tell us the client's address, read from TRUSTED_PROXIES
+// (comma separated IPs). Without any, X-Real-IP is ignored, a client could
+// otherwise pick a new address for every request.
+#[derive(Clone, Default)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+	pub fn new(proxies: Vec<IpAddr>) -> Self {
+		TrustedProxies(proxies)
+	}
+
+	pub fn from_env() -> Self {
+		let proxies = match env::var("TRUSTED_PROXIES") {
+			Ok(value) => value
+				.split(',')
+				.map(str::trim)
+				.filter(|proxy| !proxy.is_empty())
+				.map(|proxy| {
+					proxy
+						.parse()
+						.unwrap_or_else(|_| panic!("TRUSTED_PROXIES must be IP addresses"))
+				})
+				.collect(),
+			Err(_) => vec![],
+		};
+		TrustedProxies(proxies)
+	}
+
+	pub fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+		let remote = request.remote()?.ip();
+		if self.0.contains(&remote) {
+			if let Some(real_ip) = request.real_ip() {
+				return Some(real_ip);
+			}
+		}
+		Some(remote)
+	}
+}
+
+struct Window {
+	started: Instant,
+	count: u32,
+}
+
+// Set on requests that were stopped, the 404 catcher turns it into a 429
+#[derive(Clone, Copy, Default)]
+pub struct RetryAfter(pub Option<u64>);
+
+pub struct RateLimiter {
+	rules: Vec<Rule>,
+	proxies: TrustedProxies,
+	windows: Mutex<HashMap<(&'static str, IpAddr), Window>>,
+}
+
+impl RateLimiter {
+	pub fn new(limits: Limits, proxies: TrustedProxies) -> Self {
+		RateLimiter {
+			proxies,
+			rules: vec![
+				Rule {
+					name: "login",
+					method: Method::Post,
+					paths: &["/api/v1/login", "/api/v1/login/two_factor"],
+					limit: limits.login,
+				},
+				Rule {
+					name: "register",
+					method: Method::Post,
+					paths: &["/api/v1/register"],
+					limit: limits.register,
+				},
+				Rule {
+					name: "oauth_token",
+					method: Method::Post,
+					paths: &["/oauth/token", "/oauth/refresh"],
+					limit: limits.oauth_token,
+				},
+				Rule {
+					name: "control",
+					method: Method::Post,
+					paths: &[
+						"/api/v1/set_on",
+						"/api/v1/set_color",
+						"/api/v1/set_brightness",
+						"/api/v1/bulk_update",
+						"/api/v1/set_group_state",
+					],
+					limit: limits.control,
+				},
+				Rule {
+					name: "control",
+					method: Method::Put,
+					paths: &["/api/v2/devices/*/state"],
+					limit: limits.control,
+				},
+			],
+			windows: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn from_env() -> Self {
+		RateLimiter::new(Limits::from_env(), TrustedProxies::from_env())
+	}
+
+	// Counts the request, Err with the time until the window ends when the limit
+	// was already reached
+	fn hit(
+		&self,
+		name: &'static str,
+		limit: Limit,
+		ip: IpAddr,
+		now: Instant,
+	) -> Result<(), Duration> {
+		let mut windows = self.windows.lock().unwrap();
+		if windows.len() >= PRUNE_AT {
+			windows.retain(|_, window| now.duration_since(window.started) < MAX_LOCKOUT);
+		}
+		let window = windows.entry((name, ip)).or_insert(Window {
+			started: now,
+			count: 0,
+		});
+		if now.duration_since(window.started) >= limit.window {
+			window.started = now;
+			window.count = 0;
+		}
+		if window.count >= limit.requests {
+			return Err(limit.window - now.duration_since(window.started));
+		}
+		window.count += 1;
+		Ok(())
+	}
+
+	pub(crate) fn check(
+		&self,
+		method: Method,
+		path: &str,
+		ip: IpAddr,
+		now: Instant,
+	) -> Result<(), Duration> {
+		match self.rules.iter().find(|rule| rule.matches(method, path)) {
+			Some(rule) => self.hit(rule.name, rule.limit, ip, now),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Fairing for RateLimiter {
+	fn info(&self) -> Info {
+		Info {
+			name: "Rate limiter",
+			kind: Kind::Request,
+		}
+	}
+
+	fn on_request(&self, request: &mut Request, _: &Data) {
+		// Without an address there is nothing to key the limit by
+		let ip = match self.proxies.client_ip(request) {
+			Some(ip) => ip,
+			None => return,
+		};
+		let path = request.uri().path().to_string();
+		if let Err(wait) = self.check(request.method(), &path, ip, Instant::now()) {
+			request.local_cache(|| RetryAfter(Some(retry_after(wait))));
+			request.set_uri(Origin::parse(LIMITED_PATH).expect("valid path"));
+		}
+	}
+}
+
+struct Failures {
+	count: u32,
+	last: Instant,
+	locked_until: Option<Instant>,
+}
+
+// Failures in a row per key, locking the key after lockout_after of them
+struct FailureCounter {
+	lockout_after: u32,
+	failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl FailureCounter {
+	fn new(lockout_after: u32) -> Self {
+		FailureCounter {
+			lockout_after: lockout_after.max(1),
+			failures: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+		let failures = self.failures.lock().unwrap();
+		match failures.get(key).and_then(|failures| failures.locked_until) {
+			Some(until) if until > now => Err(until - now),
+			_ => Ok(()),
+		}
+	}
+
+	fn record_failure_at(&self, key: &str, lockout: Duration, now: Instant) {
+		let mut failures = self.failures.lock().unwrap();
+		if failures.len() >= PRUNE_AT {
+			failures.retain(|_, failures| now.duration_since(failures.last) < FAILURE_MEMORY);
+		}
+		let entry = failures.entry(key.to_string()).or_insert(Failures {
+			count: 0,
+			last: now,
+			locked_until: None,
+		});
+		if now.duration_since(entry.last) >= FAILURE_MEMORY {
+			entry.count = 0;
+		}
+		entry.count += 1;
+		entry.last = now;
+		if entry.count >= self.lockout_after {
+			let doublings = (entry.count - self.lockout_after).min(16);
+			let lockout = (lockout * 2u32.pow(doublings)).min(MAX_LOCKOUT);
+			entry.locked_until = Some(now + lockout);
+		}
+	}
+
+	fn record_success(&self, key: &str) {
+		self.failures.lock().unwrap().remove(key);
+	}
+}
+
+pub struct LoginThrottle {
+	lockout: Duration,
+	per_ip: FailureCounter,
+	per_account: FailureCounter,
+}
+
+impl LoginThrottle {
+	pub fn new(lockout_after: u32, account_lockout_after: u32, lockout: Duration) -> Self {
+		LoginThrottle {
+			lockout,
+			per_ip: FailureCounter::new(lockout_after),
+			per_account: FailureCounter::new(account_lockout_after),
+		}
+	}
+
+	pub fn from_env() -> Self {
+		let lockout_after = env::var("LOGIN_LOCKOUT_AFTER")
+			.ok()
+			.and_then(|value| value.parse().ok())
+			.unwrap_or(5);
+		let account_lockout_after = env::var("LOGIN_ACCOUNT_LOCKOUT_AFTER")
+			.ok()
+			.and_then(|value| value.parse().ok())
+			.unwrap_or(20);
+		let lockout_secs = env::var("LOGIN_LOCKOUT_SECONDS")
+			.ok()
+			.and_then(|value| value.parse().ok())
+			.unwrap_or(30);
+		LoginThrottle::new(lockout_after, account_lockout_after, Duration::from_secs(lockout_secs))
+	}
+
+	// Err with the time left when the account is locked for this IP or for all
+	pub fn check(&self, account: &str, ip: Option<&str>) -> Result<(), Duration> {
+		self.check_at(account, ip, Instant::now())
+	}
+
+	pub fn record_failure(&self, account: &str, ip: Option<&str>) {
+		self.record_failure_at(account, ip, Instant::now())
+	}
+
+	pub fn record_success(&self, account: &str, ip: Option<&str>) {
+		self.per_ip.record_success(&ip_key(account, ip));
+		self.per_account.record_success(account);
+	}
+
+	fn check_at(&self, account: &str, ip: Option<&str>, now: Instant) -> Result<(), Duration> {
+		let per_ip = self.per_ip.check_at(&ip_key(account, ip), now);
+		let per_account = self.per_account.check_at(account, now);
+		match (per_ip, per_account) {
+			(Err(a), Err(b)) => Err(a.max(b)),
+			(Err(wait), _) | (_, Err(wait)) => Err(wait),
+			_ => Ok(()),
+		}
+	}
+
+	fn record_failure_at(&self, account: &str, ip: Option<&str>, now: Instant) {
+		self.per_ip.record_failure_at(&ip_key(account, ip), self.lockout, now);
+		self.per_account.record_failure_at(account, self.lockout, now);
+	}
+}
+
+fn ip_key(account: &str, ip: Option<&str>) -> String {
+	format!("{}@{}", account, ip.unwrap_or("unknown"))
+}
+
+// Whole seconds for Retry-After, rounded up and never 0
+pub fn retry_after(wait: Duration) -> u64 {
+	(wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn accounts_lock_for_longer_after_every_failure() {
+		let throttle = LoginThrottle::new(3, 100, Duration::from_secs(30));
+		let ip = Some("10.0.0.1");
+		let start = Instant::now();
+		for _ in 0..2 {
+			throttle.record_failure_at("a@example.com", ip, start);
+		}
+		assert!(throttle.check_at("a@example.com", ip, start).is_ok());
+		throttle.record_failure_at("a@example.com", ip, start);
+		let locked = throttle.check_at("a@example.com", ip, start);
+		assert_eq!(locked, Err(Duration::from_secs(30)));
+		assert!(throttle.check_at("b@example.com", ip, start).is_ok());
+
+		let later = start + Duration::from_secs(30);
+		assert!(throttle.check_at("a@example.com", ip, later).is_ok());
+		throttle.record_failure_at("a@example.com", ip, later);
+		let locked = throttle.check_at("a@example.com", ip, later);
+		assert_eq!(locked, Err(Duration::from_secs(60)));
+
+		throttle.record_success("a@example.com", ip);
+		assert!(throttle.check_at("a@example.com", ip, later).is_ok());
+	}
+
+	// Another IP gets its own few tries, but rotating IPs doesn't give
+	// unlimited guesses
+	#[test]
+	fn accounts_lock_everywhere_after_more_failures() {
+		let throttle = LoginThrottle::new(1, 3, Duration::from_secs(30));
+		let now = Instant::now();
+		throttle.record_failure_at("a@example.com", Some("10.0.0.1"), now);
+		assert!(throttle.check_at("a@example.com", Some("10.0.0.1"), now).is_err());
+		assert!(throttle.check_at("a@example.com", Some("10.0.0.2"), now).is_ok());
+
+		throttle.record_failure_at("a@example.com", Some("10.0.0.2"), now);
+		throttle.record_failure_at("a@example.com", Some("10.0.0.3"), now);
+		assert!(throttle.check_at("a@example.com", Some("10.0.0.4"), now).is_err());
+		assert!(throttle.check_at("b@example.com", Some("10.0.0.4"), now).is_ok());
+	}
+}
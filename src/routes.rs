@@ -1,30 +1,216 @@
-use crate::utils::is_user_logged_in;
+use crate::db::Conn as DbConn;
+use crate::keyring::KeyRing;
+use crate::models::api_token::{ApiToken, API_TOKEN_PREFIX};
+use crate::models::oauth_token::OAuthToken;
+use crate::models::session::Session;
+use crate::utils::claim_form_jwt;
 
 use rocket::request::{self, FromRequest};
-use rocket::{Outcome, Request};
+use rocket::{Outcome, Request, State};
 
-use rocket::http::Status;
+use rocket::http::{Cookie, Status};
+use uuid::Uuid;
+
+use self::api_token::api_token_scope;
+use self::error::ApiError;
 
 pub const SESSION_STRING: &str = "session-token";
 
+// The cookie is set on "/", the browser only drops it for the same path
+pub fn removed_session_cookie() -> Cookie<'static> {
+	Cookie::build(SESSION_STRING, "").path("/").finish()
+}
+
+pub enum Credential {
+	// The session the cookie belongs to, see models/session.rs. two_factor is
+	// whether it was started with the second factor.
+	Session { id: Uuid, two_factor: bool },
+	// A personal API token sent as "Authorization: Bearer", see api_token.rs
+	ApiToken(Uuid),
+	// An access token of an OAuth grant, see models/oauth_token.rs
+	OAuth(Uuid),
+}
+
 pub struct AuthUser {
 	pub user_id: i32,
+	pub credential: Credential,
 }
 
+impl AuthUser {
+	// Bearer tokens only reach the routes in API_TOKEN_ROUTES, so routes
+	// managing the account can rely on this
+	pub fn session_id(&self) -> Result<Uuid, ApiError> {
+		match self.credential {
+			Credential::Session { id, .. } => Ok(id),
+			Credential::ApiToken(_) | Credential::OAuth(_) => Err(ApiError::InsufficientScope),
+		}
+	}
+
+	pub fn two_factor(&self) -> bool {
+		matches!(self.credential, Credential::Session { two_factor: true, .. })
+	}
+}
+
+pub mod api_token;
 pub mod device;
+pub mod device_messages;
+pub mod docs;
+pub mod error;
+pub mod etag;
+pub mod group;
+pub mod keys;
+pub mod oauth_client;
+pub mod session;
+pub mod transfer;
+pub mod two_factor;
 pub mod user;
+pub mod v2;
+pub mod validation;
 
 #[derive(Debug)]
 pub enum UserError {
 	UserNotLoggedIn,
+	// The bearer token lacks the scope the route needs
+	ScopeMissing,
+	// The KeyRing isn't managed by Rocket
+	NoKeyRing,
+	Database,
+}
+
+// Set on requests AuthUser refused for the token's scopes, the 403 catcher
+// tells them apart from other refusals
+#[derive(Clone, Copy, Default)]
+pub struct ScopeMissing(pub bool);
+
+pub(crate) fn bearer_token<'a>(request: &'a Request) -> Option<&'a str> {
+	request
+		.headers()
+		.get_one("Authorization")
+		.and_then(|header| header.strip_prefix("Bearer "))
+		.map(str::trim)
+}
+
+// Asked for only once the credentials were parsed, malformed ones are turned
+// away without a connection
+fn db_conn(request: &Request) -> Result<DbConn, request::Outcome<AuthUser, UserError>> {
+	match request.guard::<DbConn>() {
+		Outcome::Success(conn) => Ok(conn),
+		Outcome::Failure((status, _)) => Err(Outcome::Failure((status, UserError::Database))),
+		Outcome::Forward(_) => Err(Outcome::Forward(())),
+	}
+}
+
+fn from_session(request: &Request) -> request::Outcome<AuthUser, UserError> {
+	let keys = match request.guard::<State<KeyRing>>() {
+		Outcome::Success(keys) => keys,
+		_ => return Outcome::Failure((Status::InternalServerError, UserError::NoKeyRing)),
+	};
+	let claims = request
+		.cookies()
+		.get(SESSION_STRING)
+		.and_then(|cookie| claim_form_jwt(&keys, cookie.value().to_string()));
+	// Cookies from before sessions were stored have no jti and are rejected
+	let ids = claims.and_then(|claims| {
+		let user_id = claims.sub.parse::<i32>().ok()?;
+		let session_id = Uuid::parse_str(claims.jti.as_deref()?).ok()?;
+		Some((user_id, session_id))
+	});
+	let (user_id, session_id) = match ids {
+		Some(ids) => ids,
+		None => return Outcome::Failure((Status::Unauthorized, UserError::UserNotLoggedIn)),
+	};
+	let mut conn = match db_conn(request) {
+		Ok(conn) => conn,
+		Err(outcome) => return outcome,
+	};
+	match Session::get_active(session_id, user_id, &mut conn) {
+		Ok(Some(session)) => {
+			if let Err(err) = Session::touch(session_id, &mut conn) {
+				println!("failed to update session {}: {}", session_id, err);
+			}
+			Outcome::Success(AuthUser {
+				user_id,
+				credential: Credential::Session {
+					id: session_id,
+					two_factor: session.two_factor,
+				},
+			})
+		}
+		Ok(None) => Outcome::Failure((Status::Unauthorized, UserError::UserNotLoggedIn)),
+		Err(err) => {
+			println!("database error: {}", err);
+			Outcome::Failure((Status::ServiceUnavailable, UserError::Database))
+		}
+	}
+}
+
+// Bearer tokens are only good for the routes listed in API_TOKEN_ROUTES, and
+// only with the scope listed there
+fn has_route_scope(request: &Request, has_scope: impl Fn(&str) -> bool) -> bool {
+	request
+		.route()
+		.and_then(|route| route.name)
+		.and_then(api_token_scope)
+		.map_or(false, has_scope)
+}
+
+fn from_api_token(request: &Request, token: &str) -> request::Outcome<AuthUser, UserError> {
+	let mut conn = match db_conn(request) {
+		Ok(conn) => conn,
+		Err(outcome) => return outcome,
+	};
+	let api_token = match ApiToken::authenticate(token, &mut conn) {
+		Ok(Some(api_token)) => api_token,
+		Ok(None) => return Outcome::Failure((Status::Unauthorized, UserError::UserNotLoggedIn)),
+		Err(err) => {
+			println!("database error: {}", err);
+			return Outcome::Failure((Status::ServiceUnavailable, UserError::Database));
+		}
+	};
+	if !has_route_scope(request, |scope| api_token.has_scope(scope)) {
+		request.local_cache(|| ScopeMissing(true));
+		return Outcome::Failure((Status::Forbidden, UserError::ScopeMissing));
+	}
+	if let Err(err) = ApiToken::touch(api_token.id, &mut conn) {
+		println!("failed to update API token {}: {}", api_token.id, err);
+	}
+	Outcome::Success(AuthUser {
+		user_id: api_token.user_id,
+		credential: Credential::ApiToken(api_token.id),
+	})
+}
+
+// Access tokens the OAuth clients (e.g. Google Home) got for the user
+fn from_oauth_token(request: &Request, token: &str) -> request::Outcome<AuthUser, UserError> {
+	let mut conn = match db_conn(request) {
+		Ok(conn) => conn,
+		Err(outcome) => return outcome,
+	};
+	let oauth_token = match OAuthToken::authenticate(token, &mut conn) {
+		Ok(Some(oauth_token)) => oauth_token,
+		Ok(None) => return Outcome::Failure((Status::Unauthorized, UserError::UserNotLoggedIn)),
+		Err(err) => {
+			println!("database error: {}", err);
+			return Outcome::Failure((Status::ServiceUnavailable, UserError::Database));
+		}
+	};
+	if !has_route_scope(request, |scope| oauth_token.has_scope(scope)) {
+		request.local_cache(|| ScopeMissing(true));
+		return Outcome::Failure((Status::Forbidden, UserError::ScopeMissing));
+	}
+	Outcome::Success(AuthUser {
+		user_id: oauth_token.user_id,
+		credential: Credential::OAuth(oauth_token.id),
+	})
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for AuthUser {
 	type Error = UserError;
 	fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-		match is_user_logged_in(request.cookies()) {
-			None => Outcome::Failure((Status::Unauthorized, UserError::UserNotLoggedIn)),
-			Some(x) => Outcome::Success(AuthUser { user_id: x }),
+		match bearer_token(request) {
+			Some(token) if token.starts_with(API_TOKEN_PREFIX) => from_api_token(request, token),
+			Some(token) => from_oauth_token(request, token),
+			None => from_session(request),
 		}
 	}
 }
@@ -0,0 +1,80 @@
+// Personal API tokens. A token is sent as "Authorization: Bearer <token>" and
+// AuthUser accepts it on the routes in API_TOKEN_ROUTES when the token has the
+// listed scope, every other route (these included) answers 403 to it. OAuth
+// access tokens reach the same routes, AuthUser checks the listed scope against
+// the scopes of their grant instead.
+use rocket_contrib::json::Json;
+
+use crate::constants::{
+	SCOPE_DEVICES_CONTROL, SCOPE_DEVICES_MANAGE, SCOPE_DEVICES_READ, SCOPE_PROFILE,
+};
+use crate::db::Conn as DbConn;
+use crate::models::api_token::{ApiTokenId, CreateApiToken};
+use crate::services::api_token::ApiTokenService;
+
+use super::error::ApiResult;
+use super::AuthUser;
+
+// Route name to the scope a bearer token needs for it. API tokens never have
+// the profile scope, it is only granted to OAuth clients.
+pub const API_TOKEN_ROUTES: &[(&str, &str)] = &[
+	("get_me", SCOPE_PROFILE),
+	("get_devices", SCOPE_DEVICES_READ),
+	("get_full_devices", SCOPE_DEVICES_READ),
+	("check_device_online", SCOPE_DEVICES_READ),
+	("get_groups", SCOPE_DEVICES_READ),
+	("list_devices", SCOPE_DEVICES_READ),
+	("get_device", SCOPE_DEVICES_READ),
+	("get_device_state", SCOPE_DEVICES_READ),
+	("set_on", SCOPE_DEVICES_CONTROL),
+	("set_color", SCOPE_DEVICES_CONTROL),
+	("set_brightness", SCOPE_DEVICES_CONTROL),
+	("bulk_update", SCOPE_DEVICES_CONTROL),
+	("set_group_state", SCOPE_DEVICES_CONTROL),
+	("put_device_state", SCOPE_DEVICES_CONTROL),
+	("register_device", SCOPE_DEVICES_MANAGE),
+	("rename_device", SCOPE_DEVICES_MANAGE),
+	("remove_device", SCOPE_DEVICES_MANAGE),
+	("rotate_device_key", SCOPE_DEVICES_MANAGE),
+	("patch_device", SCOPE_DEVICES_MANAGE),
+	("delete_device", SCOPE_DEVICES_MANAGE),
+	("create_group", SCOPE_DEVICES_MANAGE),
+	("update_group", SCOPE_DEVICES_MANAGE),
+	("remove_group", SCOPE_DEVICES_MANAGE),
+	("add_to_group", SCOPE_DEVICES_MANAGE),
+	("remove_from_group", SCOPE_DEVICES_MANAGE),
+	("transfer_device", SCOPE_DEVICES_MANAGE),
+	("get_transfers", SCOPE_DEVICES_MANAGE),
+	("accept_transfer", SCOPE_DEVICES_MANAGE),
+	("cancel_transfer", SCOPE_DEVICES_MANAGE),
+];
+
+pub fn api_token_scope(route_name: &str) -> Option<&'static str> {
+	API_TOKEN_ROUTES
+		.iter()
+		.find(|(name, _)| *name == route_name)
+		.map(|(_, scope)| *scope)
+}
+
+#[get("/api_tokens")]
+pub fn list_api_tokens(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let tokens = ApiTokenService::list(user.user_id, &mut conn)?;
+	Ok(Json(json!({"success":true,"api_tokens":tokens})))
+}
+
+// The only response the token itself is ever part of
+#[post("/api_tokens", format = "application/json", data = "<request>")]
+pub fn create_api_token(
+	mut conn: DbConn,
+	user: AuthUser,
+	request: Json<CreateApiToken>,
+) -> ApiResult {
+	let (info, token) = ApiTokenService::create(user.user_id, &request, &mut conn)?;
+	Ok(Json(json!({"success":true,"api_token":info,"token":token})))
+}
+
+#[post("/api_tokens/revoke", format = "application/json", data = "<token>")]
+pub fn revoke_api_token(mut conn: DbConn, user: AuthUser, token: Json<ApiTokenId>) -> ApiResult {
+	ApiTokenService::revoke(user.user_id, token.token_id, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
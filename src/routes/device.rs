@@ -1,297 +1,219 @@
+use crate::attestation::AttestationKeys;
 use crate::db::Conn as DbConn;
-use crate::models::{light::Light, light::LightState, light::Trait};
+use crate::models::light::Light;
 
-use crate::models::device::{self, Device, DeviceData, DeviceSignature, NewDevice};
-use crate::utils::{self, create_coap_device, remove_coap_device};
+use crate::models::device::{BulkDeviceData, BulkResult, Device, DeviceData, NewDevice};
+use crate::models::group::DeviceGroup;
+use crate::decommission::DecommissionStatus;
+use crate::effects::EffectRunner;
+use crate::services::control::ControlService;
+use crate::services::device::DeviceService;
+use crate::services::gateway::Gateway;
 
+use rocket::State;
 use rocket_contrib::json::Json;
-use serde_json::Value;
+use schemars::JsonSchema;
 
-use tokio::runtime::Runtime;
 use uuid::Uuid;
 
-use crate::constants::{NON_RGB_LIGHT, RGB_LIGHT};
-
+use super::error::{ApiError, ApiResult};
+use super::validation::Valid;
 use super::AuthUser;
 
 // Returns the devices owned by the user
 #[get("/devices")]
-pub fn get_devices(mut conn: DbConn, user: AuthUser) -> Json<Value> {
-    let devices = Device::get_devices_by_user(user.user_id, &mut conn);
-    return Json(json!({"status":200,"devices":devices}));
+pub fn get_devices(mut conn: DbConn, user: AuthUser) -> ApiResult {
+    let devices = Device::get_devices_by_user(user.user_id, &mut conn)?;
+    return Ok(Json(json!({"success":true,"status":200,"devices":devices})));
 }
 
 #[get("/full_devices")]
-pub fn get_full_devices(mut conn: DbConn, user: AuthUser) -> Json<Value> {
-    let lights = Light::get_full_device_data_by_user(user.user_id, &mut conn);
-    return Json(json!({"status":200,"lights":lights}));
+pub fn get_full_devices(mut conn: DbConn, user: AuthUser) -> ApiResult {
+    let lights = Light::get_full_device_data_by_user(user.user_id, &mut conn)?;
+    let groups = DeviceGroup::get_full_groups_by_user(user.user_id, &lights, &mut conn)?;
+    return Ok(Json(json!({"success":true,"status":200,"lights":lights,"groups":groups})));
 }
 
-fn update_device(user_id: i32, device_data: DeviceData, mut db_conn: DbConn) -> Json<Value> {
-    let device = Light::get_device_by_id(device_data.device_id, &mut db_conn);
-    if device.is_none() {
-        return Json(json!({"success":false,"error":"Device does not exist"}));
-    }
-    let device = device.unwrap();
-    if user_id != device.user_id {
-        return Json(json!(
-			{"success":false,"error":"You are not the owner of the device"}
-		));
-    }
-    let mut light_state = LightState {
-        brightness: device.brightness,
-        color: device.rgb,
-        is_on: device.is_on,
-        removed:false,
-    };
-    light_state.brightness = match device_data.brightness {
-        Some(brightness) => brightness,
-        None => device.brightness,
-    };
-    light_state.is_on = match device_data.is_on {
-        Some(on) => on,
-        None => device.is_on,
-    };
-    light_state.color = match device_data.color {
-        Some(color) => color,
-        None => device.rgb,
-    };
-    let rt = Runtime::new().unwrap();
-    Light::update_device(
-        device.light_id,
-        &light_state,
-        &mut db_conn,
-        device.secret,
-        device.user_id,
-    );
-    let resp = rt.block_on(utils::send_device_command(light_state, device.light_id));
-    if resp.is_err() {
-        return Json(json!(
-			{"success":false,"error":resp.unwrap_err().to_string()}
-		));
-    }
-    if &resp.unwrap().header.get_code() == "4.04" {
-        return Json(json!(
-			{"success":false,"error":"device not found"}
-		));
-    }
-
-    return Json(json!({"success":true}));
+fn update_device(
+    user_id: i32,
+    device_data: DeviceData,
+    gateway: &Gateway,
+    effects: &EffectRunner,
+    mut db_conn: DbConn,
+) -> ApiResult {
+    ControlService::set_device_state(user_id, device_data, &**gateway, effects, &mut db_conn)?;
+    Ok(Json(json!({"success":true})))
 }
 
 #[post("/set_on", format = "application/json", data = "<device_data>")]
-pub fn set_on(conn: DbConn, device_data: Json<DeviceData>, user: AuthUser) -> Json<Value> {
+pub fn set_on(
+    conn: DbConn,
+    device_data: Valid<DeviceData>,
+    gateway: State<Gateway>,
+    effects: State<EffectRunner>,
+    user: AuthUser,
+) -> ApiResult {
     let user_id = user.user_id;
-    return update_device(user_id, device_data.0, conn);
+    return update_device(user_id, device_data.0, &gateway, &effects, conn);
 }
 
 #[post("/set_color", format = "application/json", data = "<device_data>")]
-pub fn set_color(conn: DbConn, device_data: Json<DeviceData>, user: AuthUser) -> Json<Value> {
+pub fn set_color(
+    conn: DbConn,
+    device_data: Valid<DeviceData>,
+    gateway: State<Gateway>,
+    effects: State<EffectRunner>,
+    user: AuthUser,
+) -> ApiResult {
     let user_id = user.user_id;
-    return update_device(user_id, device_data.0, conn);
+    return update_device(user_id, device_data.0, &gateway, &effects, conn);
 }
 
 #[post("/set_brightness", format = "application/json", data = "<device_data>")]
-pub fn set_brightness(conn: DbConn, device_data: Json<DeviceData>, user: AuthUser) -> Json<Value> {
+pub fn set_brightness(
+    conn: DbConn,
+    device_data: Valid<DeviceData>,
+    gateway: State<Gateway>,
+    effects: State<EffectRunner>,
+    user: AuthUser,
+) -> ApiResult {
     let user_id = user.user_id;
-    return update_device(user_id, device_data.0, conn);
+    return update_device(user_id, device_data.0, &gateway, &effects, conn);
+}
+
+// Failures of single devices are reported per device, the request itself
+// only fails when the new state couldn't be saved
+pub fn bulk_response(results: Result<Vec<BulkResult>, diesel::result::Error>) -> ApiResult {
+    let results = results?;
+    let success = results.iter().all(|result| result.success);
+    Ok(Json(json!({"success":success,"results":results})))
+}
+
+// Targets the listed devices and/or every member of a group
+#[post("/bulk_update", format = "application/json", data = "<bulk_data>")]
+pub fn bulk_update(
+    mut conn: DbConn,
+    bulk_data: Valid<BulkDeviceData>,
+    gateway: State<Gateway>,
+    effects: State<EffectRunner>,
+    user: AuthUser,
+) -> ApiResult {
+    let mut device_ids = bulk_data.device_ids.clone();
+    if let Some(group_id) = bulk_data.group_id {
+        match DeviceGroup::get_group_by_id(group_id, &mut conn)? {
+            Some(group) if group.user_id == user.user_id => {
+                device_ids.append(&mut DeviceGroup::get_member_ids(group.id, &mut conn)?);
+            }
+            _ => return Err(ApiError::GroupNotFound),
+        }
+        // Devices listed on their own and in the group get one command
+        device_ids.sort();
+        device_ids.dedup();
+    }
+    bulk_response(ControlService::apply_state_to_devices(
+        user.user_id,
+        &device_ids,
+        &bulk_data,
+        &**gateway,
+        &effects,
+        &mut conn,
+    ))
 }
 
 #[post("/register_device", format = "application/json", data = "<new_device>")]
 pub fn register_device(
     mut conn: DbConn,
     new_device: Json<NewDevice>,
+    attestation_keys: State<AttestationKeys>,
+    gateway: State<Gateway>,
     user: AuthUser,
-) -> Json<Value> {
-    // Verifies that the device is signed by the private key
-    let device_data = DeviceSignature {
-        id: new_device.id,
-        type_: new_device.type_.clone(),
-    };
-    // TODO actually match the errors
-    let verified = utils::verify_secret(
-        &new_device.secret,
-        serde_json::to_string(&device_data).unwrap(),
-    );
-    if verified.is_err() {
-        return Json(json!({"error":"signature is wrong"}));
-    }
-    let verified = verified.unwrap();
-    println!("{}", verified);
-    if !verified {
-        return Json(json!({"error":"failed to authenticate device"}));
-    }
-    // Sets the device traits used for google home integrartion
-    let traits: Vec<Option<String>> =
-        Trait::get_traits_for_device_type(new_device.type_.clone(), &mut conn)
-            .iter()
-            .map(|trait_| Some(trait_.trait_.clone()))
-            .collect();
-
-    let device = Device {
-        id: new_device.id,
-        user_id: user.user_id,
-        type_: new_device.type_.clone(),
-        internal_name: new_device.type_.clone() + new_device.id.to_string().as_str(),
-        name: new_device.name.clone(),
-        nicknames: vec![],
-        traits: traits,
-    };
-    let matcher = match device.type_.as_str() {
-        RGB_LIGHT => Some(device.type_.clone()),
-        NON_RGB_LIGHT => Some(device.type_.clone()),
-        _ => None,
-    };
-    if matcher.is_none() {
-        return Json(json!({"error":"invalid device type"}));
-    }
+) -> ApiResult {
+    let device_key = DeviceService::register(
+        &new_device,
+        user.user_id,
+        &attestation_keys,
+        &**gateway,
+        &mut conn,
+    )?;
+    return Ok(Json(json!({
+        "success":true,
+        "status":200,
+        "result":true,
+        "device_key":device_key,
+    })));
+}
 
-    let transaction_status = conn.build_transaction().run(|local_conn| {
-        let mut status = Light::insert_device(
-            device.id,
-            local_conn,
-            new_device.secret.clone(),
-            device.user_id,
-        );
-        println!("{}", status);
-        if status {
-            status = Device::insert_device(device, local_conn);
-        }
-        println!("{}", status);
-        if matcher.unwrap().contains("light") {
-            let rt = Runtime::new().unwrap();
-            let coap_response = rt.block_on(create_coap_device(new_device.id, "lights"));
-            if coap_response.is_err() {
-                return Err(diesel::result::Error::RollbackTransaction);
-            }
-            if coap_response.unwrap().header.get_code() != "2.04" {
-                return Err(diesel::result::Error::RollbackTransaction);
-            }
-        }
-        if status {
-            return Ok(());
-        } else {
-            return Err(diesel::result::Error::RollbackTransaction);
-        }
-    });
+#[derive(Deserialize, JsonSchema)]
+pub struct RotateKeyData {
+    device_id: Uuid,
+}
 
-    return Json(json!({"status":200,"result":transaction_status.is_ok()}));
+// Issues a new device key, the previous one stays valid for a short grace period
+#[post("/rotate_device_key", format = "application/json", data = "<device_data>")]
+pub fn rotate_device_key(
+    mut conn: DbConn,
+    device_data: Json<RotateKeyData>,
+    user: AuthUser,
+) -> ApiResult {
+    let device = DeviceService::owned(device_data.device_id, user.user_id, &mut conn)?;
+    let device_key = DeviceService::rotate_key(&device, &mut conn)?;
+    Ok(Json(json!({"success":true,"device_key":device_key})))
 }
 
 #[get("/is_online/<device_id>", format = "application/json")]
-pub fn check_device_online(mut conn: DbConn, device_id: String, user: AuthUser) -> Json<Value> {
-    let user_id = user.user_id;
-    let device = Light::get_device_by_id(Uuid::parse_str(&device_id).unwrap(), &mut conn);
-    if device.is_none() {
-        return Json(json!({"success":false,"error":"Device does not exist"}));
-    }
-    let device = device.unwrap();
-    if user_id != device.user_id {
-        return Json(json!(
-			{"success":false,"error":"You are not the owner of the device"}
-		));
-    }
-    let rt = Runtime::new().unwrap();
-    let resp = rt.block_on(utils::check_device_online(device_id));
-    return match resp {
-        Some(value) => Json(json!({"isOnline":value,"success":true})),
-        None => Json(json!({"success":false})),
-    };
+pub fn check_device_online(
+    mut conn: DbConn,
+    device_id: String,
+    gateway: State<Gateway>,
+    user: AuthUser,
+) -> ApiResult {
+    let device_id = Uuid::parse_str(&device_id)
+        .map_err(|_| ApiError::invalid_field("device_id", "not a valid device id"))?;
+    let device = DeviceService::owned(device_id, user.user_id, &mut conn)?;
+    let is_online = DeviceService::is_online(&device, &**gateway)?;
+    Ok(Json(json!({"isOnline":is_online,"success":true})))
 }
 
-#[derive(Deserialize)]
-struct RenameData {
+#[derive(Deserialize, JsonSchema)]
+pub struct RenameData {
     device_id: Uuid,
     new_name: String,
 }
 
-#[allow(private_interfaces)]
 #[post("/rename_device", format = "application/json", data = "<device_data>")]
 pub fn rename_device(
     mut conn: DbConn,
     device_data: Json<RenameData>,
     user: AuthUser,
-) -> Json<Value> {
-    let user_id = user.user_id;
-    let device_id = device_data.device_id;
-    let device = Device::get_device_by_id(device_id, &mut conn);
-    match device {
-        Some(dev) => {
-            if dev.user_id != user_id {
-                return Json(
-                    json!({"success":false,"error":"You are not the owner of the device"}),
-                );
-            }
-            Device::update_device_name(dev.id, &device_data.new_name, &mut conn);
-            return Json(json!({"success":true,"new_name":device_data.new_name}));
-        }
-        None => {
-            return Json(json!({"success":false,"error":"Device does not exist"}));
-        }
-    }
-    // return Json(json!({}));
+) -> ApiResult {
+    let device = DeviceService::owned(device_data.device_id, user.user_id, &mut conn)?;
+    DeviceService::rename(&device, &device_data.new_name, &mut conn)?;
+    return Ok(Json(json!({"success":true,"new_name":device_data.new_name})));
 }
 
-#[derive(Deserialize)]
-struct DeleteData {
+#[derive(Deserialize, JsonSchema)]
+pub struct DeleteData {
     device_id: Uuid,
 }
 
-#[allow(private_interfaces)]
+// Decommissions the device, removing it once the device acknowledged the wipe.
+// Calling it again for a device that is pending removal retries the wipe.
 #[post("/remove_device", format = "application/json", data = "<device_data>")]
 pub fn remove_device(
     mut conn: DbConn,
     device_data: Json<DeleteData>,
+    gateway: State<Gateway>,
+    effects: State<EffectRunner>,
     user: AuthUser,
-) -> Json<Value> {
-    let user_id = user.user_id;
-    let device_id = device_data.device_id;
-    let device = Device::get_device_by_id(device_id, &mut conn);
-    let light = Light::get_device_by_id(device_id, &mut conn);
-    let device_status = match device {
-        Some(dev) => {
-            if dev.user_id != user_id {
-                Some(Json(
-                    json!({"success":false,"error":"You are not the owner of the device"}),
-                ))
-            } else {
-                if Device::remove_device(device_id, &mut conn) {
-                    None
-                } else {
-                    Some(Json(json!({"success":false,"error":"something went wrong"})))
-                }
-            }
+) -> ApiResult {
+    let device = match DeviceService::owned(device_data.device_id, user.user_id, &mut conn) {
+        Ok(device) => device,
+        // Already gone, removing it again is not an error
+        Err(ApiError::DeviceNotFound) => {
+            return Ok(Json(json!({"success":true,"status":DecommissionStatus::Removed})))
         }
-        None => Some(Json(json!({"success":false,"error":"Device does not exist"}))),
+        Err(err) => return Err(err),
     };
-    if device_status.is_some() {
-        return device_status.unwrap();
-    }
-    let mut light_id: Uuid;
-    let light_status = match light {
-        Some(light_dev) => {
-            light_id = light_dev.light_id;
-            if light_dev.user_id != user_id {
-                Some(Json(
-                    json!({"success":false,"error":"You are not the owner of the device"})
-                ))
-            } else {
-                if Light::remove_device(device_id, &mut conn) {
-                    None
-                } else {
-                    Some(Json(json!({"success":false,"error":"something went wrong"})))
-                }
-            }
-        }
-        None => { return Json(json!({"success":false,"error":"Device does not exist"})) }
-    };
-    if light_status.is_some() {
-        return light_status.unwrap();
-    }
-    let rt = Runtime::new().unwrap();
-    rt.block_on(async {
-        // TODO Check this later
-        remove_coap_device(light_id).await;
-    });
-    Json(json!({"success":true}))
+    let status = DeviceService::remove(&device, &**gateway, &effects, &mut conn)?;
+    Ok(Json(json!({"success":true,"status":status})))
 }
@@ -0,0 +1,276 @@
+// Routes called by the devices themselves (through the gateway), as opposed to
+// the user facing routes in device.rs. Every request has to be signed with the
+// device key issued at registration and every reply is signed with the same key.
+// Requests carry a nonce, a replayed request is turned away even within the
+// allowed clock skew.
+use std::io::{Cursor, Read};
+
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder};
+use rocket::{Data, Outcome, Request, Response};
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::db::Conn as DbConn;
+use crate::models::device_credential::DeviceCredential;
+use crate::models::device_nonce::DeviceNonce;
+use crate::models::light::{Light, LightState};
+use crate::models::telemetry::Telemetry;
+use crate::models::values::{Brightness, Rgb24};
+use crate::utils::{sign_device_message, verify_device_message};
+
+use super::error::{error_status, ApiError};
+
+pub const DEVICE_ID_HEADER: &str = "X-Device-Id";
+pub const TIMESTAMP_HEADER: &str = "X-Device-Timestamp";
+pub const NONCE_HEADER: &str = "X-Device-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Device-Signature";
+pub const BACKEND_SIGNATURE_HEADER: &str = "X-Backend-Signature";
+
+// Messages older (or newer) than this are rejected to limit replays
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024;
+// Any unique string, e.g. a random UUID or a counter
+const MAX_NONCE_LENGTH: usize = 64;
+
+pub struct DeviceAuthHeaders {
+	pub device_id: Uuid,
+	pub timestamp: i64,
+	pub nonce: String,
+	pub signature: String,
+}
+
+#[derive(Debug)]
+pub enum DeviceAuthError {
+	MissingHeaders,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for DeviceAuthHeaders {
+	type Error = DeviceAuthError;
+	fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+		let headers = request.headers();
+		let device_id = headers
+			.get_one(DEVICE_ID_HEADER)
+			.and_then(|id| Uuid::parse_str(id).ok());
+		let timestamp = headers
+			.get_one(TIMESTAMP_HEADER)
+			.and_then(|timestamp| timestamp.parse::<i64>().ok());
+		let nonce = headers
+			.get_one(NONCE_HEADER)
+			.filter(|nonce| !nonce.is_empty() && nonce.len() <= MAX_NONCE_LENGTH);
+		let signature = headers.get_one(SIGNATURE_HEADER);
+		match (device_id, timestamp, nonce, signature) {
+			(Some(device_id), Some(timestamp), Some(nonce), Some(signature)) => {
+				Outcome::Success(DeviceAuthHeaders {
+					device_id,
+					timestamp,
+					nonce: nonce.to_string(),
+					signature: signature.to_string(),
+				})
+			}
+			_ => Outcome::Failure((Status::Unauthorized, DeviceAuthError::MissingHeaders)),
+		}
+	}
+}
+
+// JSON reply signed with the key that authenticated the request
+pub struct SignedResponse {
+	status: Status,
+	body: String,
+	timestamp: i64,
+	nonce: String,
+	signature: String,
+}
+
+impl<'r> Responder<'r> for SignedResponse {
+	fn respond_to(self, _: &Request) -> response::Result<'r> {
+		Response::build()
+			.status(self.status)
+			.header(ContentType::JSON)
+			.raw_header(TIMESTAMP_HEADER, self.timestamp.to_string())
+			.raw_header(NONCE_HEADER, self.nonce)
+			.raw_header(BACKEND_SIGNATURE_HEADER, self.signature)
+			.sized_body(Cursor::new(self.body))
+			.ok()
+	}
+}
+
+// Errors before the device is authenticated can't be signed
+type DeviceResponse = Result<SignedResponse, ApiError>;
+
+// Reads the body and checks it against every key currently valid for the
+// device, then uses up the nonce
+fn authenticate(
+	auth: &DeviceAuthHeaders,
+	data: Data,
+	conn: &mut DbConn,
+) -> Result<(DeviceCredential, String), ApiError> {
+	let now = chrono::Utc::now().timestamp();
+	if (now - auth.timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+		return Err(ApiError::MessageExpired);
+	}
+	let mut body = String::new();
+	if data
+		.open()
+		.take(MAX_MESSAGE_SIZE)
+		.read_to_string(&mut body)
+		.is_err()
+	{
+		return Err(ApiError::BadRequest);
+	}
+	let keys = DeviceCredential::get_valid_keys(auth.device_id, conn)?;
+	let credential = keys.into_iter().find(|credential| {
+		verify_device_message(
+			&credential.secret_key,
+			auth.device_id,
+			auth.timestamp,
+			&auth.nonce,
+			&body,
+			&auth.signature,
+		)
+	});
+	let credential = match credential {
+		Some(credential) => credential,
+		None => return Err(ApiError::InvalidSignature),
+	};
+	// Twice the skew, a message may arrive that early and still be accepted that late
+	let keep_for = chrono::Duration::seconds(2 * MAX_CLOCK_SKEW_SECS);
+	if !DeviceNonce::claim(auth.device_id, &auth.nonce, keep_for, conn)? {
+		return Err(ApiError::MessageReplayed);
+	}
+	Ok((credential, body))
+}
+
+fn signed_reply(
+	credential: &DeviceCredential,
+	auth: &DeviceAuthHeaders,
+	status: Status,
+	reply: Value,
+) -> DeviceResponse {
+	let body = reply.to_string();
+	let timestamp = chrono::Utc::now().timestamp();
+	match sign_device_message(
+		&credential.secret_key,
+		credential.device_id,
+		timestamp,
+		&auth.nonce,
+		&body,
+	) {
+		Ok(signature) => Ok(SignedResponse {
+			status,
+			body,
+			timestamp,
+			nonce: auth.nonce.clone(),
+			signature,
+		}),
+		Err(_) => Err(ApiError::Internal),
+	}
+}
+
+fn signed_ok(
+	credential: &DeviceCredential,
+	auth: &DeviceAuthHeaders,
+	reply: Value,
+) -> DeviceResponse {
+	signed_reply(credential, auth, Status::Ok, reply)
+}
+
+fn signed_error(
+	credential: &DeviceCredential,
+	auth: &DeviceAuthHeaders,
+	error: ApiError,
+) -> DeviceResponse {
+	signed_reply(credential, auth, error_status(&error), error.body())
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct StateReport {
+	is_on: bool,
+	brightness: Brightness,
+	color: Rgb24,
+	// Sent by firmware that can run transitions and effects itself
+	native_effects: Option<bool>,
+}
+
+// The device reports its actual state, e.g. after a physical switch was used
+#[post("/device/state", data = "<data>")]
+pub fn report_state(mut conn: DbConn, auth: DeviceAuthHeaders, data: Data) -> DeviceResponse {
+	let (credential, body) = authenticate(&auth, data, &mut conn)?;
+	let report = match serde_json::from_str::<StateReport>(&body) {
+		Ok(report) => report,
+		Err(err) => {
+			return signed_error(
+				&credential,
+				&auth,
+				ApiError::invalid_field("body", &err.to_string()),
+			)
+		}
+	};
+	let light = match Light::get_device_by_id(auth.device_id, &mut conn) {
+		Ok(Some(light)) => light,
+		Ok(None) => return signed_error(&credential, &auth, ApiError::DeviceNotFound),
+		Err(err) => return signed_error(&credential, &auth, err.into()),
+	};
+	let light_state = LightState {
+		is_on: report.is_on,
+		brightness: report.brightness,
+		color: report.color,
+		removed: false,
+		transition_ms: None,
+		effect: None,
+	};
+	if let Some(native_effects) = report.native_effects {
+		if native_effects != light.native_effects
+			&& Light::set_native_effects(light.light_id, native_effects, &mut conn).is_err()
+		{
+			return signed_error(&credential, &auth, ApiError::Internal);
+		}
+	}
+	match Light::update_device(
+		light.light_id,
+		&light_state,
+		&mut conn,
+		light.signature,
+		light.user_id,
+	) {
+		Ok(_) => signed_ok(&credential, &auth, json!({"success":true})),
+		Err(err) => signed_error(&credential, &auth, err.into()),
+	}
+}
+
+// Kept as sent, the data isn't interpreted yet
+#[post("/device/telemetry", data = "<data>")]
+pub fn report_telemetry(mut conn: DbConn, auth: DeviceAuthHeaders, data: Data) -> DeviceResponse {
+	let (credential, body) = authenticate(&auth, data, &mut conn)?;
+	let telemetry = match serde_json::from_str::<Map<String, Value>>(&body) {
+		Ok(telemetry) => Value::Object(telemetry),
+		Err(err) => {
+			return signed_error(
+				&credential,
+				&auth,
+				ApiError::invalid_field("body", &err.to_string()),
+			)
+		}
+	};
+	match Telemetry::insert(auth.device_id, &telemetry, &mut conn) {
+		Ok(_) => signed_ok(&credential, &auth, json!({"success":true})),
+		Err(err) => signed_error(&credential, &auth, err.into()),
+	}
+}
+
+// Lets a device replace its own key, the reply carrying the new key is signed
+// with the old one
+#[post("/device/rotate_key", data = "<data>")]
+pub fn device_rotate_key(mut conn: DbConn, auth: DeviceAuthHeaders, data: Data) -> DeviceResponse {
+	let (credential, _) = authenticate(&auth, data, &mut conn)?;
+	match DeviceCredential::rotate(auth.device_id, &mut conn) {
+		Ok(new_credential) => signed_ok(
+			&credential,
+			&auth,
+			json!({"success":true,"device_key":new_credential.secret_key}),
+		),
+		Err(_) => signed_error(&credential, &auth, ApiError::Internal),
+	}
+}
@@ -0,0 +1,17 @@
+use rocket::response::content::Html;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::openapi::OpenApi;
+
+#[get("/openapi.json")]
+pub fn openapi(spec: State<OpenApi>) -> Json<Value> {
+	Json(spec.0.clone())
+}
+
+// Renders openapi.json with Redoc
+#[get("/docs")]
+pub fn docs() -> Html<&'static str> {
+	Html(include_str!("../docs.html"))
+}
@@ -0,0 +1,116 @@
+// Responses for ApiError (see error.rs). Every failure, including the ones
+// produced by request guards, is sent as its JSON body with the status code
+// from error_status().
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::rate_limit::RetryAfter;
+
+use super::validation::InvalidFields;
+use super::ScopeMissing;
+
+pub use crate::error::ApiError;
+
+pub type ApiResult = Result<Json<Value>, ApiError>;
+
+// The status code every error is sent with
+pub fn error_status(error: &ApiError) -> Status {
+	match error {
+		ApiError::Unauthorized
+		| ApiError::InvalidCredentials
+		| ApiError::InvalidCode
+		| ApiError::InvalidSignature
+		| ApiError::MessageExpired
+		| ApiError::MessageReplayed => Status::Unauthorized,
+		ApiError::NotOwner
+		| ApiError::AttestationFailed(_)
+		| ApiError::TwoFactorRequired
+		| ApiError::InsufficientScope
+		| ApiError::Forbidden
+		| ApiError::AdminRequired => Status::Forbidden,
+		ApiError::DeviceNotFound
+		| ApiError::GroupNotFound
+		| ApiError::TransferNotFound
+		| ApiError::UserNotFound
+		| ApiError::SessionNotFound
+		| ApiError::ApiTokenNotFound
+		| ApiError::OAuthClientNotFound
+		| ApiError::NotFound => Status::NotFound,
+		ApiError::EmailTaken
+		| ApiError::DeviceAlreadyRegistered
+		| ApiError::AlreadyOwner
+		| ApiError::TransferPending
+		| ApiError::TransferExpired
+		| ApiError::TransferNotPending
+		| ApiError::TwoFactorEnabled
+		| ApiError::TwoFactorNotEnabled => Status::Conflict,
+		ApiError::PreconditionFailed => Status::PreconditionFailed,
+		ApiError::BadRequest | ApiError::InvalidToken => Status::BadRequest,
+		ApiError::InvalidInput(_) => Status::UnprocessableEntity,
+		ApiError::RateLimited(_) => Status::TooManyRequests,
+		ApiError::DeviceUnreachable => Status::BadGateway,
+		ApiError::DeviceTimeout => Status::GatewayTimeout,
+		ApiError::ServiceUnavailable | ApiError::EmailUnavailable => Status::ServiceUnavailable,
+		ApiError::Internal => Status::InternalServerError,
+	}
+}
+
+impl<'r> Responder<'r> for ApiError {
+	fn respond_to(self, request: &Request) -> response::Result<'r> {
+		let mut response = Response::build_from(Json(self.body()).respond_to(request)?);
+		response.status(error_status(&self));
+		if let ApiError::RateLimited(seconds) = self {
+			response.raw_header("Retry-After", seconds.to_string());
+		}
+		response.ok()
+	}
+}
+
+#[catch(400)]
+pub fn bad_request() -> ApiError {
+	ApiError::BadRequest
+}
+
+// AuthUser and the device signature guards
+#[catch(401)]
+pub fn unauthorized() -> ApiError {
+	ApiError::Unauthorized
+}
+
+// Blames the token only when AuthUser turned it away for its scopes
+#[catch(403)]
+pub fn forbidden(request: &Request) -> ApiError {
+	if request.local_cache(ScopeMissing::default).0 {
+		ApiError::InsufficientScope
+	} else {
+		ApiError::Forbidden
+	}
+}
+
+// Also answers the requests the RateLimiter stopped, see rate_limit.rs
+#[catch(404)]
+pub fn not_found(request: &Request) -> ApiError {
+	match request.local_cache(RetryAfter::default).0 {
+		Some(seconds) => ApiError::RateLimited(seconds),
+		None => ApiError::NotFound,
+	}
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request) -> ApiError {
+	ApiError::InvalidInput(request.local_cache(InvalidFields::default).0.clone())
+}
+
+#[catch(500)]
+pub fn internal_error() -> ApiError {
+	ApiError::Internal
+}
+
+// No database connection available for the Conn guard
+#[catch(503)]
+pub fn service_unavailable() -> ApiError {
+	ApiError::ServiceUnavailable
+}
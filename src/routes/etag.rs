@@ -0,0 +1,92 @@
+// Entity tags for the v2 resources. A client changing a resource sends the ETag
+// it last saw in If-Match and gets a 412 when someone else changed the resource
+// in the meantime. GETs answer If-None-Match with 304.
+use base64::{engine::general_purpose, Engine};
+use openssl::sha::sha256;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder};
+use rocket::{Outcome, Request, Response};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::error::ApiError;
+
+pub const ETAG_HEADER: &str = "ETag";
+pub const IF_MATCH_HEADER: &str = "If-Match";
+pub const IF_NONE_MATCH_HEADER: &str = "If-None-Match";
+
+fn etag_of(body: &Value) -> String {
+	let digest = sha256(body.to_string().as_bytes());
+	format!("\"{}\"", general_purpose::URL_SAFE_NO_PAD.encode(&digest[..12]))
+}
+
+// Strong tag of the JSON representation of a resource
+pub fn etag<T: Serialize>(resource: &T) -> String {
+	etag_of(&serde_json::to_value(resource).unwrap_or(Value::Null))
+}
+
+// If-Match only accepts strong tags, If-None-Match compares weakly
+fn list_matches(header: &str, etag: &str, weak: bool) -> bool {
+	header.split(',').map(str::trim).any(|tag| {
+		let tag = if weak { tag.trim_start_matches("W/") } else { tag };
+		tag == "*" || tag == etag
+	})
+}
+
+pub struct IfMatch(Option<String>);
+
+impl IfMatch {
+	// Requests without If-Match are unconditional
+	pub fn check(&self, current: &str) -> Result<(), ApiError> {
+		match &self.0 {
+			Some(header) if !list_matches(header, current, false) => {
+				Err(ApiError::PreconditionFailed)
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfMatch {
+	type Error = ();
+	fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+		Outcome::Success(IfMatch(
+			request.headers().get_one(IF_MATCH_HEADER).map(String::from),
+		))
+	}
+}
+
+// JSON resource sent together with its ETag
+pub struct Tagged {
+	body: Value,
+	etag: String,
+}
+
+impl Tagged {
+	pub fn new<T: Serialize>(resource: &T) -> Self {
+		let body = serde_json::to_value(resource).unwrap_or(Value::Null);
+		let etag = etag_of(&body);
+		Tagged { body, etag }
+	}
+}
+
+impl<'r> Responder<'r> for Tagged {
+	fn respond_to(self, request: &Request) -> response::Result<'r> {
+		let not_modified = request.method() == Method::Get
+			&& request
+				.headers()
+				.get_one(IF_NONE_MATCH_HEADER)
+				.map_or(false, |header| list_matches(header, &self.etag, true));
+		if not_modified {
+			return Response::build()
+				.status(Status::NotModified)
+				.raw_header(ETAG_HEADER, self.etag)
+				.ok();
+		}
+		Response::build_from(Json(self.body).respond_to(request)?)
+			.raw_header(ETAG_HEADER, self.etag)
+			.ok()
+	}
+}
@@ -2,32 +2,37 @@ use crate::db::Conn as DbConn;
 use crate::google_routes::google_structs::{
 	DeviceAttributes, GoogleDevice, GoogleResponse, NameStruct, SyncPayload,
 };
-use coap_client::{ClientOptions, HostOptions, RequestOptions, TokioClient};
 use oxide_auth_rocket::{OAuthFailure, OAuthRequest, OAuthResponse};
 use rocket::http::Status;
 use rocket::response::Responder;
+use diesel::QueryResult;
 use rocket::{http::ContentType, Response, State};
+use serde::Serialize;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::io;
-use tokio::runtime::Runtime;
+use uuid::Uuid;
 
-#[path = "../constants.rs"]
-mod constants;
 #[path = "../google_structs.rs"]
 mod google_structs;
-#[path = "../jwt_issuer.rs"]
-mod jwt_issuer;
-#[path = "../utils.rs"]
-mod utils;
-use crate::models::{device::Device, light::Light};
+use crate::effects::EffectRunner;
+use crate::error::ApiError;
+use crate::models::device::{BulkDeviceData, BulkResult, Device};
+use crate::models::group::{DeviceGroup, GroupMember};
+use crate::models::light::{Light, LightEffect};
+use crate::models::values::{transition_ms_from_secs, Brightness, Kelvin, Rgb24, ValueError};
+use crate::services::control::ControlService;
+use crate::services::gateway::{DeviceGateway, Gateway};
 use crate::oath_routes::MyState;
 
 use rocket_contrib::json::Json;
 
-use self::constants::{NON_RGB_LIGHT, RGB_LIGHT};
+use crate::constants::{
+	DEVICE_ACTIVE, NON_RGB_LIGHT, RGB_LIGHT, SCOPE_DEVICES_CONTROL, SCOPE_DEVICES_READ,
+};
 use self::google_structs::{
-	Color, CommandsResponse, DeviceData, ExecutePayload, GoogleRequest, LightState, QueryPayload,
-	States,
+	Color, ColorParams, Command, CommandsResponse, DeviceData, ExecutePayload, GoogleRequest,
+	LightState, QueryPayload, States,
 };
 
 #[post("/fullfilment", format = "application/json", data = "<request>")]
@@ -35,38 +40,55 @@ pub fn fullfilment<'r>(
 	oauth: OAuthRequest<'r>,
 	state: State<MyState>,
 	request: Json<GoogleRequest>,
+	gateway: State<Gateway>,
+	effects: State<EffectRunner>,
 	conn: DbConn,
 ) -> impl Responder<'r> {
+	// EXECUTE changes the lights, the other intents only read them
+	let scope = match request.inputs.first().map(|input| input.intent.as_str()) {
+		Some("action.devices.EXECUTE") => SCOPE_DEVICES_CONTROL,
+		_ => SCOPE_DEVICES_READ,
+	};
 	let protect = state
 		.endpoint()
-		.with_scopes(vec!["default-scope".parse().unwrap()])
+		.with_scopes(vec![scope.parse().unwrap()])
 		.resource_flow()
 		.execute(oauth);
 	match protect {
 		Ok(grant) => {
-			println!("{}", grant.owner_id);
-			let user_id = grant.owner_id.parse::<i32>().unwrap();
 			let request_id = request.requestId.clone();
-
-			let input = request.inputs.first().unwrap();
+			// Our grants are issued to user ids
+			let user_id = match grant.owner_id.parse::<i32>() {
+				Ok(user_id) => user_id,
+				Err(_) => return Ok(error_reply(request_id, "authFailure")),
+			};
+			let input = match request.inputs.first() {
+				Some(input) => input,
+				None => return Ok(error_reply(request_id, "protocolError")),
+			};
 			match input.intent.as_str() {
 				"action.devices.SYNC" => {
-					let response = handle_sync(request_id, user_id, conn);
-					Ok(Json(
-						json! ({"requestId":response.requestId,"payload":response.payload}),
-					))
+					Ok(intent_reply(request_id.clone(), handle_sync(request_id, user_id, conn)))
 				}
 				"action.devices.QUERY" => {
-					let response = handle_query(request_id, user_id, conn);
-					Ok(Json(
-						json! ({"requestId":response.requestId,"payload":response.payload}),
-					))
+					let response = handle_query(request_id.clone(), user_id, &effects, conn);
+					Ok(intent_reply(request_id, response))
 				}
 				"action.devices.EXECUTE" => {
-					let response = handle_execute(request.into_inner(), user_id, conn);
-					Ok(Json(
-						json! ({"requestId":response.requestId,"payload":response.payload}),
-					))
+					let payload = input.payload.as_ref();
+					let commands = match payload.and_then(|payload| payload.commands.as_ref()) {
+						Some(commands) => commands,
+						None => return Ok(error_reply(request_id, "protocolError")),
+					};
+					let response = handle_execute(
+						request_id.clone(),
+						commands,
+						user_id,
+						&**gateway,
+						&effects,
+						conn,
+					);
+					Ok(intent_reply(request_id, response))
 				}
 				_ => {
 					let response = GoogleResponse {
@@ -97,9 +119,85 @@ pub fn fullfilment<'r>(
 	}
 }
 
-fn handle_sync(request_id: String, user_id: i32, mut conn: DbConn) -> GoogleResponse<SyncPayload> {
-	let devices: Vec<GoogleDevice> = Device::get_devices_by_user(user_id, &mut conn)
+const LIGHT_EFFECTS_TRAIT: &str = "action.devices.traits.LightEffects";
+const COLOR_LOOP_PERIOD_MS: u32 = 10000;
+// Google's default for Sleep and Wake when no duration is given
+const DEFAULT_EFFECT_DURATION_SECS: u32 = 1800;
+
+// White range offered for RGB lights, mapped to a color on our side
+const COLOR_TEMPERATURE_MIN_K: i32 = 2000;
+const COLOR_TEMPERATURE_MAX_K: i32 = 9000;
+
+fn color_temperature_range() -> Option<HashMap<String, i32>> {
+	let mut range = HashMap::new();
+	range.insert("temperatureMinK".to_string(), COLOR_TEMPERATURE_MIN_K);
+	range.insert("temperatureMaxK".to_string(), COLOR_TEMPERATURE_MAX_K);
+	Some(range)
+}
+
+fn supported_effects(traits: &[String]) -> Option<Vec<String>> {
+	if traits.iter().any(|trait_| trait_ == LIGHT_EFFECTS_TRAIT) {
+		Some(vec![
+			"colorLoop".to_string(),
+			"sleep".to_string(),
+			"wake".to_string(),
+		])
+	} else {
+		None
+	}
+}
+
+// Our effect labels mapped to the names Google knows, None for effects that
+// Google can't show
+fn google_effect(label: Option<String>) -> Option<String> {
+	match label.as_deref() {
+		Some("color_cycle") => Some("colorLoop".to_string()),
+		Some("fade_off") => Some("sleep".to_string()),
+		Some("fade_on") => Some("wake".to_string()),
+		_ => None,
+	}
+}
+
+// An intent that failed as a whole
+fn error_reply(request_id: String, error_code: &str) -> Json<Value> {
+	Json(json!({
+		"requestId":request_id,
+		"payload":{"errorCode":error_code,"status":"ERROR"},
+	}))
+}
+
+// Google retries transientError, which only helps when the database may answer
+// the next time. Anything else would fail the same way again.
+fn database_error_code(err: diesel::result::Error) -> &'static str {
+	use diesel::result::{DatabaseErrorKind, Error};
+	println!("database error: {}", err);
+	match err {
+		Error::DatabaseError(DatabaseErrorKind::ClosedConnection, _)
+		| Error::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _)
+		| Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => "transientError",
+		_ => "hardError",
+	}
+}
+
+fn intent_reply<T: Serialize>(
+	request_id: String,
+	response: QueryResult<GoogleResponse<T>>,
+) -> Json<Value> {
+	match response {
+		Ok(response) => Json(json!({"requestId":response.requestId,"payload":response.payload})),
+		Err(err) => error_reply(request_id, database_error_code(err)),
+	}
+}
+
+fn handle_sync(
+	request_id: String,
+	user_id: i32,
+	mut conn: DbConn,
+) -> QueryResult<GoogleResponse<SyncPayload>> {
+	let mut devices: Vec<GoogleDevice> = Device::get_devices_by_user(user_id, &mut conn)?
 		.iter()
+		// Devices being decommissioned are already gone as far as Google is concerned
+		.filter(|device| device.state == DEVICE_ACTIVE)
 		.filter_map(|device| {
 			let traits: Vec<String> = device
 				.traits
@@ -107,6 +205,7 @@ fn handle_sync(request_id: String, user_id: i32, mut conn: DbConn) -> GoogleResp
 				.filter(|trait_| trait_.is_some())
 				.map(|trait_| return trait_.clone().unwrap())
 				.collect();
+			let light_effects = supported_effects(&traits);
 			return match device.type_.as_str() {
 				NON_RGB_LIGHT => {
 					let device_type = "action.devices.types.LIGHT".to_string();
@@ -123,6 +222,7 @@ fn handle_sync(request_id: String, user_id: i32, mut conn: DbConn) -> GoogleResp
 						attributes: DeviceAttributes {
 							colorModel: None,
 							colorTemperatureRange: None,
+							supportedEffects: light_effects,
 						},
 					})
 				}
@@ -140,7 +240,8 @@ fn handle_sync(request_id: String, user_id: i32, mut conn: DbConn) -> GoogleResp
 						willReportState: false,
 						attributes: DeviceAttributes {
 							colorModel: Some("rgb".to_string()),
-							colorTemperatureRange: None,
+							colorTemperatureRange: color_temperature_range(),
+							supportedEffects: light_effects,
 						},
 					})
 				}
@@ -148,7 +249,46 @@ fn handle_sync(request_id: String, user_id: i32, mut conn: DbConn) -> GoogleResp
 			};
 		})
 		.collect();
-	GoogleResponse {
+	// Exposed groups show up in Google Home as one light
+	let lights = Light::get_full_device_data_by_user(user_id, &mut conn)?;
+	let groups = DeviceGroup::get_full_groups_by_user(user_id, &lights, &mut conn)?;
+	for group in groups.iter().filter(|group| group.expose_to_google) {
+		let mut traits = vec![
+			"action.devices.traits.OnOff".to_string(),
+			"action.devices.traits.Brightness".to_string(),
+		];
+		if group.supports_color {
+			traits.push("action.devices.traits.ColorSetting".to_string());
+		}
+		// Effects run per member, emulated where a member can't run them itself
+		traits.push(LIGHT_EFFECTS_TRAIT.to_string());
+		let light_effects = supported_effects(&traits);
+		devices.push(GoogleDevice {
+			id: group.id,
+			type_: "action.devices.types.LIGHT".to_string(),
+			traits: traits,
+			name: NameStruct {
+				defaultNames: vec![group.name.clone()],
+				name: group.name.clone(),
+				nicknames: vec![],
+			},
+			willReportState: false,
+			attributes: DeviceAttributes {
+				colorModel: if group.supports_color {
+					Some("rgb".to_string())
+				} else {
+					None
+				},
+				colorTemperatureRange: if group.supports_color {
+					color_temperature_range()
+				} else {
+					None
+				},
+				supportedEffects: light_effects,
+			},
+		});
+	}
+	Ok(GoogleResponse {
 		requestId: request_id.clone(),
 		payload: SyncPayload {
 			agentUserId: Some(user_id.to_string()),
@@ -156,130 +296,258 @@ fn handle_sync(request_id: String, user_id: i32, mut conn: DbConn) -> GoogleResp
 			errorCode: None,
 			status: None,
 		},
-	}
+	})
 }
 
 fn handle_query(
 	request_id: String,
 	user_id: i32,
+	effects: &EffectRunner,
 	mut conn: DbConn,
-) -> GoogleResponse<QueryPayload> {
-	println!("{}", user_id);
+) -> QueryResult<GoogleResponse<QueryPayload>> {
 	let mut devices = HashMap::new();
-	for device in Light::get_devices_by_user(user_id, &mut conn).iter() {
+	for device in Light::get_active_devices_by_user(user_id, &mut conn)?.iter() {
 		let state = LightState {
 			online: true,
 			on: Some(device.is_on),
-			brightness: Some(device.brightness),
+			brightness: Some(Brightness::saturating(device.brightness).percent()),
 			color: Some(Color {
 				spectrumRGB: device.rgb,
 			}),
+			activeLightEffect: google_effect(effects.active_effect(device.light_id)),
 		};
 		devices.insert(device.light_id.to_string(), States::Light(state));
 	}
-	GoogleResponse {
+	let lights = Light::get_full_device_data_by_user(user_id, &mut conn)?;
+	let groups = DeviceGroup::get_full_groups_by_user(user_id, &lights, &mut conn)?;
+	for group in groups.iter().filter(|group| group.expose_to_google) {
+		let state = LightState {
+			online: true,
+			on: Some(group.is_on),
+			brightness: Some(Brightness::saturating(group.brightness).percent()),
+			color: Some(Color {
+				spectrumRGB: group.rgb,
+			}),
+			// Every member runs the same effect, so any of them will do
+			activeLightEffect: google_effect(
+				group
+					.device_ids
+					.iter()
+					.find_map(|device_id| effects.active_effect(*device_id)),
+			),
+		};
+		devices.insert(group.id.to_string(), States::Light(state));
+	}
+	Ok(GoogleResponse {
 		requestId: request_id.clone(),
 		payload: QueryPayload { devices: devices },
+	})
+}
+fn color_target(color: Option<&ColorParams>) -> Result<BulkDeviceData, ValueError> {
+	let color = match color {
+		Some(color) => color,
+		None => return Ok(BulkDeviceData::default()),
+	};
+	Ok(BulkDeviceData {
+		color: color.spectrumRGB.map(Rgb24::try_from).transpose()?,
+		color_temperature: color.temperature.map(Kelvin::try_from).transpose()?,
+		..Default::default()
+	})
+}
+
+// Ids sent by Google are either device ids or ids of groups exposed to Google
+fn resolve_google_id(id: &str, groups: &[DeviceGroup], members: &[GroupMember]) -> Vec<Uuid> {
+	let id = match Uuid::parse_str(id) {
+		Ok(id) => id,
+		Err(_) => return vec![],
+	};
+	match groups
+		.iter()
+		.find(|group| group.id == id && group.expose_to_google)
+	{
+		Some(group) => members
+			.iter()
+			.filter(|member| member.group_id == group.id)
+			.map(|member| member.device_id)
+			.collect(),
+		None => vec![id],
 	}
 }
-fn handle_execute(
-	request: GoogleRequest,
-	user_id: i32,
-	mut conn: DbConn,
-) -> GoogleResponse<ExecutePayload> {
-	let mut command_outputs: Vec<CommandsResponse> = vec![];
-	let commands = request
-		.inputs
-		.first()
-		.unwrap()
-		.payload
-		.as_ref()
-		.unwrap()
-		.commands
-		.as_ref()
-		.unwrap()
-		.iter();
-	let user_devices = Device::get_devices_by_user(user_id, &mut conn);
-	for command in commands {
-		for execution in command.execution.iter() {
-			match execution.command.as_str() {
-				"action.devices.commands.OnOff" => {
-					// let output = task::block_in_place(|| handle_on_off(command.devices.as_ref()));
-					let rt = Runtime::new().unwrap();
-					let mut devices = rt.block_on(handle_on_off(
-						command.devices.as_ref(),
-						user_devices.as_ref(),
-					));
-					command_outputs.append(&mut devices);
-				}
-				_ => {}
+
+// What Google is told about a device that didn't take the command, None when it
+// did. A group only succeeds when every member did.
+fn execute_error(device_ids: &[Uuid], results: &[BulkResult]) -> Option<&'static str> {
+	if device_ids.is_empty() {
+		return Some("deviceOffline");
+	}
+	for device_id in device_ids {
+		match results.iter().find(|result| result.device_id == *device_id) {
+			Some(result) if result.success => continue,
+			Some(BulkResult { error: Some(err), .. })
+				if !matches!(err, ApiError::DeviceUnreachable | ApiError::DeviceTimeout) =>
+			{
+				return Some("hardError")
 			}
+			_ => return Some("deviceOffline"),
 		}
 	}
-	GoogleResponse {
-		requestId: request.requestId,
-		payload: ExecutePayload {
-			commands: command_outputs,
-		},
-	}
+	None
 }
 
-async fn handle_on_off(
+fn execute_responses(
 	devices: &Vec<DeviceData>,
-	user_devices: &Vec<Device>,
+	groups: &[DeviceGroup],
+	members: &[GroupMember],
+	results: &[BulkResult],
 ) -> Vec<CommandsResponse> {
 	let mut success = CommandsResponse {
 		ids: vec![],
 		status: "SUCCESS".to_string(),
 		states: Some(States::Light(LightState {
 			online: true,
-			on: Some(true),
+			on: None,
 			brightness: None,
 			color: None,
+			activeLightEffect: None,
 		})),
 		errorCode: None,
 	};
-	let mut failure = CommandsResponse {
-		ids: vec![],
+	let mut failures: Vec<CommandsResponse> = vec![];
+	for device in devices.iter() {
+		let device_ids = resolve_google_id(&device.id, groups, members);
+		let error_code = match execute_error(&device_ids, results) {
+			Some(error_code) => error_code,
+			None => {
+				success.ids.push(device.id.clone());
+				continue;
+			}
+		};
+		match failures
+			.iter_mut()
+			.find(|failure| failure.errorCode.as_deref() == Some(error_code))
+		{
+			Some(failure) => failure.ids.push(device.id.clone()),
+			None => failures.push(error_response(vec![device.id.clone()], error_code)),
+		}
+	}
+	std::iter::once(success)
+		.chain(failures)
+		.filter(|response| !response.ids.is_empty())
+		.collect()
+}
+
+fn error_response(ids: Vec<String>, error_code: &str) -> CommandsResponse {
+	CommandsResponse {
+		ids,
 		status: "ERROR".to_string(),
 		states: None,
-		errorCode: Some("deviceOffline".to_string()),
-	};
-	let mut host_opts = HostOptions::default();
-	host_opts.host = "192.168.33.108".to_string();
-	host_opts.port = 5683;
-	let mut req_opts = RequestOptions::default();
-	req_opts.non_confirmable = false;
-	let mut client = TokioClient::connect(host_opts, &ClientOptions::default()).await;
-	let device_map: Vec<String> = user_devices
-		.iter()
-		.map(|device| device.id.to_string())
-		.collect();
-	if client.is_err() {
-	} else {
-		for device in devices.iter() {
-			println!("{:?}", device);
-			if !device_map.contains(&device.id) {
-			} else {
-				let resp = client
-					.as_mut()
-					.unwrap()
-					.put(format!("/devices/{}", device.id).as_str(), None, &req_opts)
-					.await;
-				//TODO handle errors
-				if resp.is_err() {
-					//something went wrong when sending the request
-				} else {
-					// Check the response
-					if resp.unwrap_or_default().len() != 0 {
-						failure.ids.push(device.id.clone());
-					} else {
-						// println!("{}", resp_payload.unwrap());
-						success.ids.push(device.id.clone());
-					}
+		errorCode: Some(error_code.to_string()),
+	}
+}
+
+fn handle_execute(
+	request_id: String,
+	commands: &[Command],
+	user_id: i32,
+	gateway: &dyn DeviceGateway,
+	effects: &EffectRunner,
+	mut conn: DbConn,
+) -> QueryResult<GoogleResponse<ExecutePayload>> {
+	let mut command_outputs: Vec<CommandsResponse> = vec![];
+	let groups = DeviceGroup::get_groups_by_user(user_id, &mut conn)?;
+	let members = DeviceGroup::get_members_by_user(user_id, &mut conn)?;
+	for command in commands {
+		let device_ids: Vec<Uuid> = command
+			.devices
+			.iter()
+			.flat_map(|device| resolve_google_id(&device.id, &groups, &members))
+			.collect();
+		for execution in command.execution.iter() {
+			let params = &execution.params;
+			let target = match execution.command.as_str() {
+				"action.devices.commands.OnOff" => Ok(BulkDeviceData {
+					is_on: params.on,
+					..Default::default()
+				}),
+				"action.devices.commands.BrightnessAbsolute" => params
+					.brightness
+					.map(Brightness::from_percent)
+					.transpose()
+					.map(|brightness| BulkDeviceData {
+						brightness,
+						..Default::default()
+					}),
+				"action.devices.commands.ColorAbsolute" => color_target(params.color.as_ref()),
+				"action.devices.commands.ColorLoop" => Ok(BulkDeviceData {
+					is_on: Some(true),
+					effect: Some(LightEffect::ColorCycle {
+						period_ms: COLOR_LOOP_PERIOD_MS,
+						colors: vec![],
+					}),
+					..Default::default()
+				}),
+				"action.devices.commands.Sleep" => {
+					let duration = params.duration.unwrap_or(DEFAULT_EFFECT_DURATION_SECS);
+					transition_ms_from_secs(duration).map(|transition_ms| BulkDeviceData {
+						is_on: Some(false),
+						transition_ms: Some(transition_ms),
+						..Default::default()
+					})
 				}
-			}
+				"action.devices.commands.Wake" => {
+					let duration = params.duration.unwrap_or(DEFAULT_EFFECT_DURATION_SECS);
+					transition_ms_from_secs(duration).map(|transition_ms| BulkDeviceData {
+						is_on: Some(true),
+						transition_ms: Some(transition_ms),
+						..Default::default()
+					})
+				}
+				// Any command cancels the running effect, this one just doesn't
+				// change anything else
+				"action.devices.commands.StopEffect" => Ok(BulkDeviceData::default()),
+				_ => {
+					let ids = command.devices.iter().map(|device| device.id.clone()).collect();
+					command_outputs.push(error_response(ids, "functionNotSupported"));
+					continue;
+				}
+			};
+			let target = match target {
+				Ok(target) => target,
+				Err(_) => {
+					let ids = command.devices.iter().map(|device| device.id.clone()).collect();
+					command_outputs.push(error_response(ids, "valueOutOfRange"));
+					continue;
+				}
+			};
+			let results = match ControlService::apply_state_to_devices(
+				user_id,
+				&device_ids,
+				&target,
+				gateway,
+				effects,
+				&mut conn,
+			) {
+				Ok(results) => results,
+				// The commands may have reached the devices, but their state
+				// wasn't saved
+				Err(err) => {
+					let ids = command.devices.iter().map(|device| device.id.clone()).collect();
+					command_outputs.push(error_response(ids, database_error_code(err)));
+					continue;
+				}
+			};
+			command_outputs.append(&mut execute_responses(
+				&command.devices,
+				&groups,
+				&members,
+				&results,
+			));
 		}
 	}
-	return vec![success, failure];
+	Ok(GoogleResponse {
+		requestId: request_id,
+		payload: ExecutePayload {
+			commands: command_outputs,
+		},
+	})
 }
@@ -0,0 +1,137 @@
+use crate::db::Conn as DbConn;
+use crate::effects::EffectRunner;
+use crate::models::device::Device;
+use crate::models::group::{DeviceGroup, GroupData, GroupMembers, NewGroup, UpdateGroup};
+use crate::models::light::Light;
+use crate::services::control::ControlService;
+use crate::services::gateway::Gateway;
+
+use diesel::{Connection, PgConnection, QueryResult};
+use rocket::State;
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+use super::device::bulk_response;
+use super::error::{ApiError, ApiResult};
+use super::validation::Valid;
+use super::AuthUser;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GroupId {
+	group_id: Uuid,
+}
+
+// Returns the group when it exists and belongs to the user
+fn owned_group(
+	group_id: Uuid,
+	user_id: i32,
+	conn: &mut PgConnection,
+) -> Result<DeviceGroup, ApiError> {
+	match DeviceGroup::get_group_by_id(group_id, conn)? {
+		Some(group) if group.user_id == user_id => Ok(group),
+		_ => Err(ApiError::GroupNotFound),
+	}
+}
+
+// Only the user's own devices can be put into their groups
+fn all_owned(device_ids: &[Uuid], user_id: i32, conn: &mut PgConnection) -> QueryResult<bool> {
+	let owned: Vec<Uuid> = Device::get_devices_by_user(user_id, conn)?
+		.iter()
+		.map(|device| device.id)
+		.collect();
+	Ok(device_ids.iter().all(|device_id| owned.contains(device_id)))
+}
+
+fn check_name(name: &str) -> Result<(), ApiError> {
+	if name.trim().is_empty() {
+		return Err(ApiError::invalid_field("name", "Group name can't be empty"));
+	}
+	Ok(())
+}
+
+#[get("/groups")]
+pub fn get_groups(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let lights = Light::get_full_device_data_by_user(user.user_id, &mut conn)?;
+	let groups = DeviceGroup::get_full_groups_by_user(user.user_id, &lights, &mut conn)?;
+	Ok(Json(json!({"success":true,"groups":groups})))
+}
+
+#[post("/create_group", format = "application/json", data = "<new_group>")]
+pub fn create_group(mut conn: DbConn, new_group: Json<NewGroup>, user: AuthUser) -> ApiResult {
+	check_name(&new_group.name)?;
+	if !all_owned(&new_group.device_ids, user.user_id, &mut conn)? {
+		return Err(ApiError::DeviceNotFound);
+	}
+	let group = DeviceGroup {
+		id: Uuid::new_v4(),
+		user_id: user.user_id,
+		name: new_group.name.clone(),
+		expose_to_google: new_group.expose_to_google,
+	};
+	conn.transaction::<_, diesel::result::Error, _>(|local_conn| {
+		DeviceGroup::insert_group(&group, local_conn)?;
+		DeviceGroup::add_members(group.id, &new_group.device_ids, local_conn)?;
+		Ok(())
+	})?;
+	Ok(Json(json!({"success":true,"group":group})))
+}
+
+#[post("/update_group", format = "application/json", data = "<update>")]
+pub fn update_group(mut conn: DbConn, update: Json<UpdateGroup>, user: AuthUser) -> ApiResult {
+	let group = owned_group(update.group_id, user.user_id, &mut conn)?;
+	let name = update.name.clone().unwrap_or(group.name);
+	check_name(&name)?;
+	let expose_to_google = update.expose_to_google.unwrap_or(group.expose_to_google);
+	let group = DeviceGroup::update_group(group.id, &name, expose_to_google, &mut conn)?;
+	Ok(Json(json!({"success":true,"group":group})))
+}
+
+#[post("/remove_group", format = "application/json", data = "<group_data>")]
+pub fn remove_group(mut conn: DbConn, group_data: Json<GroupId>, user: AuthUser) -> ApiResult {
+	let group = owned_group(group_data.group_id, user.user_id, &mut conn)?;
+	DeviceGroup::remove_group(group.id, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+#[post("/add_to_group", format = "application/json", data = "<members>")]
+pub fn add_to_group(mut conn: DbConn, members: Json<GroupMembers>, user: AuthUser) -> ApiResult {
+	let group = owned_group(members.group_id, user.user_id, &mut conn)?;
+	if !all_owned(&members.device_ids, user.user_id, &mut conn)? {
+		return Err(ApiError::DeviceNotFound);
+	}
+	DeviceGroup::add_members(group.id, &members.device_ids, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+#[post("/remove_from_group", format = "application/json", data = "<members>")]
+pub fn remove_from_group(
+	mut conn: DbConn,
+	members: Json<GroupMembers>,
+	user: AuthUser,
+) -> ApiResult {
+	let group = owned_group(members.group_id, user.user_id, &mut conn)?;
+	DeviceGroup::remove_members(group.id, &members.device_ids, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+// Controls the group like a single light, every member gets the same change
+#[post("/set_group_state", format = "application/json", data = "<group_data>")]
+pub fn set_group_state(
+	mut conn: DbConn,
+	group_data: Valid<GroupData>,
+	gateway: State<Gateway>,
+	effects: State<EffectRunner>,
+	user: AuthUser,
+) -> ApiResult {
+	let group = owned_group(group_data.group_id, user.user_id, &mut conn)?;
+	let member_ids = DeviceGroup::get_member_ids(group.id, &mut conn)?;
+	bulk_response(ControlService::apply_state_to_devices(
+		user.user_id,
+		&member_ids,
+		&group_data.to_bulk(),
+		&**gateway,
+		&effects,
+		&mut conn,
+	))
+}
@@ -0,0 +1,11 @@
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::keyring::KeyRing;
+
+// Public keys our JWTs can be checked with, see keyring.rs
+#[get("/.well-known/jwks.json")]
+pub fn jwks(keys: State<KeyRing>) -> Json<Value> {
+	Json(keys.jwks())
+}
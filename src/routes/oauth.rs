@@ -1,78 +1,412 @@
-use std::{io, sync::Mutex};
+use std::{borrow::Cow, io, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::{PgConnection, QueryResult};
 
 use oxide_auth::{
 	endpoint::{Authorizer, Issuer, OwnerConsent, Registrar, Solicitation},
 	frontends::simple::endpoint::{FnSolicitor, Vacant},
 	primitives::{
-		prelude::{AuthMap, RandomGenerator, TokenMap},
-		registrar::{Client, ClientMap, RegisteredUrl},
+		grant::{Extensions, Grant},
+		issuer::{IssuedToken, RefreshedToken, TokenType},
+		prelude::{AuthMap, RandomGenerator},
+		registrar::{BoundClient, ClientUrl, PreGrant, RegisteredUrl, RegistrarError},
+		scope::Scope,
 	},
 };
 use oxide_auth_rocket::{Generic, OAuthFailure, OAuthRequest, OAuthResponse};
+use reqwest::Url;
 use rocket::{
-	http::{self, hyper::header::Location, Status},
-	response::{content::Html, Responder},
+	http::{self, hyper::header::Location, uri::Origin, Status},
+	request::{self, Form, FromRequest},
+	response::{self as rocket_response, content::Html, status, Responder},
 };
-use rocket::{
-	http::{ContentType, Cookies},
-	Data, Response, State,
+use rocket::{http::ContentType, Data, Outcome, Request, Response, State};
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::constants::{
+	SCOPE_DEVICES_CONTROL, SCOPE_DEVICES_MANAGE, SCOPE_DEVICES_READ, SCOPE_PROFILE,
 };
+use crate::db::{Conn as DbConn, Pool};
+use crate::frontend::Frontend;
+use crate::keyring::KeyRing;
+use crate::models::oauth_client::{ClientRegistration, OAuthClient};
+use crate::models::oauth_token::OAuthToken;
+use crate::routes::error::ApiError;
+use crate::routes::{bearer_token, AuthUser};
+use crate::services::oauth_client::{OAuthClientService, RegistrationError};
+use crate::services::two_factor::TwoFactorService;
+use crate::services::user::UserService;
+
+const AUTHORIZE_PATH: &str = "/oauth/authorize";
+// How long logging in may take before the way back stops working
+const RETURN_TO_VALID_SECONDS: i64 = 60 * 60;
+const CONSENT_VALID_SECONDS: i64 = 15 * 60;
+const ACCESS_TOKEN_VALID_SECONDS: i64 = 60 * 60;
 
-#[path = "../jwt_issuer.rs"]
-mod jwt_issuer;
-#[path = "../utils.rs"]
-mod utils;
-use crate::routes::SESSION_STRING;
+// What the consent page tells the user about the scopes in OAUTH_SCOPES
+pub const SCOPE_DESCRIPTIONS: &[(&str, &str)] = &[
+	(SCOPE_DEVICES_READ, "See your devices and their state"),
+	(SCOPE_DEVICES_CONTROL, "Turn your lights on and off and change their color and brightness"),
+	(SCOPE_DEVICES_MANAGE, "Add, rename, remove and transfer your devices"),
+	(SCOPE_PROFILE, "See your name and email address"),
+];
+
+// The registrar and issuer check out a connection per call and are built for
+// every request, only the authorization codes are shared. The lock on them is
+// held for a single call, never across a database query.
 pub struct MyState {
-	registrar: Mutex<ClientMap>,
+	pool: Pool,
 	authorizer: Mutex<AuthMap<RandomGenerator>>,
-	issuer: Mutex<TokenMap<RandomGenerator>>,
+}
+
+pub struct SharedAuthorizer<'a>(&'a Mutex<AuthMap<RandomGenerator>>);
+
+impl Authorizer for SharedAuthorizer<'_> {
+	fn authorize(&mut self, grant: Grant) -> Result<String, ()> {
+		self.0.lock().unwrap().authorize(grant)
+	}
+
+	fn extract(&mut self, token: &str) -> Result<Option<Grant>, ()> {
+		self.0.lock().unwrap().extract(token)
+	}
+}
+
+// Grants are kept in the oauth_tokens table, so they survive restarts. The
+// tokens of deleted accounts go with the account, the ones of disabled clients
+// are revoked together with the client.
+pub struct DbIssuer {
+	pool: Pool,
+}
+
+// Refresh tokens don't expire, the grant they recover only has to outlive the
+// refresh flow
+fn grant_of(token: &OAuthToken, until: DateTime<Utc>) -> Result<Grant, ()> {
+	Ok(Grant {
+		owner_id: token.user_id.to_string(),
+		client_id: token.client_id.clone(),
+		scope: token.scope.parse().map_err(|_| ())?,
+		redirect_uri: Url::parse(&token.redirect_uri).map_err(|_| ())?,
+		until,
+		extensions: Extensions::new(),
+	})
+}
+
+fn database_error(err: diesel::result::Error) {
+	println!("database error: {}", err);
+}
+
+impl DbIssuer {
+	fn with_conn<T>(&self, f: impl FnOnce(&mut PgConnection) -> QueryResult<T>) -> Result<T, ()> {
+		let mut conn = self.pool.get().map_err(|_| ())?;
+		f(&mut conn).map_err(database_error)
+	}
+}
+
+impl Issuer for DbIssuer {
+	fn issue(&mut self, grant: Grant) -> Result<IssuedToken, ()> {
+		let user_id = grant.owner_id.parse::<i32>().map_err(|_| ())?;
+		let until = Utc::now() + Duration::seconds(ACCESS_TOKEN_VALID_SECONDS);
+		let (_, tokens) = self.with_conn(|conn| {
+			OAuthToken::issue(
+				&grant.client_id,
+				user_id,
+				&grant.scope.to_string(),
+				grant.redirect_uri.as_str(),
+				until,
+				conn,
+			)
+		})?;
+		Ok(IssuedToken {
+			token: tokens.access,
+			refresh: Some(tokens.refresh),
+			until,
+			token_type: TokenType::Bearer,
+		})
+	}
+
+	fn refresh(&mut self, refresh: &str, grant: Grant) -> Result<RefreshedToken, ()> {
+		let until = Utc::now() + Duration::seconds(ACCESS_TOKEN_VALID_SECONDS);
+		let refreshed = self.with_conn(|conn| {
+			OAuthToken::refresh(refresh, &grant.scope.to_string(), until, conn)
+		})?;
+		let (_, tokens) = refreshed.ok_or(())?;
+		Ok(RefreshedToken {
+			token: tokens.access,
+			refresh: Some(tokens.refresh),
+			until,
+			token_type: TokenType::Bearer,
+		})
+	}
+
+	fn recover_token<'a>(&'a self, token: &'a str) -> Result<Option<Grant>, ()> {
+		match self.with_conn(|conn| OAuthToken::get_by_access(token, conn))? {
+			Some(token) => grant_of(&token, token.expires_at).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	fn recover_refresh<'a>(&'a self, token: &'a str) -> Result<Option<Grant>, ()> {
+		let until = Utc::now() + Duration::seconds(ACCESS_TOKEN_VALID_SECONDS);
+		match self.with_conn(|conn| OAuthToken::get_by_refresh(token, conn))? {
+			Some(token) => grant_of(&token, until).map(Some),
+			None => Ok(None),
+		}
+	}
+}
+
+// The scope of a grant is what the client asked for, limited to the scopes it
+// was registered with. Clients that don't ask get all of them.
+pub fn negotiate_scope(allowed: &Scope, requested: Option<&Scope>) -> Option<Scope> {
+	let requested = match requested {
+		Some(requested) => requested,
+		None => return Some(allowed.clone()),
+	};
+	let granted: Vec<&str> = requested
+		.iter()
+		.filter(|scope| allowed.iter().any(|allowed| allowed == *scope))
+		.collect();
+	if granted.is_empty() {
+		return None;
+	}
+	granted.join(" ").parse().ok()
+}
+
+// Clients come from the oauth_clients table, disabled ones are unknown
+pub struct DbRegistrar {
+	pool: Pool,
+}
+
+impl DbRegistrar {
+	fn client(&self, client_id: &str) -> Result<OAuthClient, RegistrarError> {
+		let mut conn = self.pool.get().map_err(|_| RegistrarError::PrimitiveError)?;
+		match OAuthClient::get_enabled(client_id, &mut conn) {
+			Ok(Some(client)) => Ok(client),
+			Ok(None) => Err(RegistrarError::Unspecified),
+			Err(err) => {
+				println!("database error: {}", err);
+				Err(RegistrarError::PrimitiveError)
+			}
+		}
+	}
+}
+
+impl Registrar for DbRegistrar {
+	// The redirect URI has to be one of the client's, clients with more than one
+	// have to name it
+	fn bound_redirect<'a>(&self, bound: ClientUrl<'a>) -> Result<BoundClient<'a>, RegistrarError> {
+		let client = self.client(&bound.client_id)?;
+		let mut registered = client.redirect_uris().filter_map(|uri| Url::parse(uri).ok());
+		let redirect_uri = match &bound.redirect_uri {
+			Some(requested) => registered.find(|uri| *uri == **requested),
+			None if client.redirect_uris().count() == 1 => registered.next(),
+			None => None,
+		}
+		.ok_or(RegistrarError::Unspecified)?;
+		Ok(BoundClient {
+			client_id: bound.client_id,
+			redirect_uri: Cow::Owned(RegisteredUrl::Semantic(redirect_uri)),
+		})
+	}
+
+	fn negotiate<'a>(
+		&self,
+		bound: BoundClient<'a>,
+		scope: Option<Scope>,
+	) -> Result<PreGrant, RegistrarError> {
+		let client = self.client(&bound.client_id)?;
+		let allowed: Scope = client.scope.parse().map_err(|_| RegistrarError::PrimitiveError)?;
+		let scope = negotiate_scope(&allowed, scope.as_ref()).ok_or(RegistrarError::Unspecified)?;
+		Ok(PreGrant {
+			client_id: bound.client_id.into_owned(),
+			redirect_uri: bound.redirect_uri.into_owned(),
+			scope,
+		})
+	}
+
+	// Confidential clients send their secret, public ones nothing
+	fn check(&self, client_id: &str, passphrase: Option<&[u8]>) -> Result<(), RegistrarError> {
+		let client = self.client(client_id)?;
+		let secret = passphrase.map(std::str::from_utf8);
+		match secret {
+			None if !client.is_confidential() => Ok(()),
+			Some(Ok(secret)) if client.check_secret(secret) => Ok(()),
+			_ => Err(RegistrarError::Unspecified),
+		}
+	}
+}
+
+// Accounts with 2FA can only grant access (e.g. link Google Home) from a
+// session that was started with the second factor
+fn missing_second_factor(user: &AuthUser, conn: &mut DbConn) -> bool {
+	if user.two_factor() {
+		return false;
+	}
+	match TwoFactorService::is_enabled(user.user_id, conn) {
+		Ok(enabled) => enabled,
+		Err(_) => true,
+	}
+}
+
+fn redirect_to<'r>(url: String) -> OAuthResponse<'r> {
+	Response::build()
+		.status(Status::from_code(302).unwrap())
+		.header(Location(url))
+		.finalize()
+		.into()
+}
+
+fn forbidden<'r>() -> OAuthResponse<'r> {
+	Response::build().status(Status::Forbidden).finalize().into()
+}
+
+// The authorize request to come back to after logging in. Neither claims have a
+// "sub", so they can't pass for session cookies signed with the same keys.
+#[derive(Serialize, Deserialize)]
+struct ReturnTo {
+	return_to: String,
+	exp: usize,
+}
+
+// CSRF token of the consent form, only good for the session it was shown to
+#[derive(Serialize, Deserialize)]
+struct ConsentToken {
+	consent_session: String,
+	exp: usize,
+}
+
+#[derive(FromForm)]
+pub struct ConsentForm {
+	csrf_token: String,
+}
+
+fn expires_in(seconds: i64) -> usize {
+	(Utc::now().timestamp() + seconds) as usize
+}
+
+// The same request without the answer of the consent form
+fn authorize_path(uri: &Origin) -> String {
+	let query: Vec<(String, String)> = uri
+		.query()
+		.and_then(|query| serde_urlencoded::from_str(query).ok())
+		.unwrap_or_default();
+	let query: Vec<(String, String)> = query
+		.into_iter()
+		.filter(|(name, _)| name != "allow" && name != "deny")
+		.collect();
+	format!("{}?{}", AUTHORIZE_PATH, serde_urlencoded::to_string(query).unwrap_or_default())
+}
+
+// The login page gets return_to as an opaque token and sends the user to
+// /oauth/return with it once they are logged in
+fn login_redirect<'r>(
+	keys: &KeyRing,
+	frontend: &Frontend,
+	uri: &Origin,
+	two_factor: bool,
+) -> OAuthResponse<'r> {
+	let return_to = return_to_token(keys, authorize_path(uri));
+	let mut query = vec![("return_to", return_to.as_str())];
+	if two_factor {
+		query.push(("two_factor", "required"));
+	}
+	let query = serde_urlencoded::to_string(query).expect("strings are encodable");
+	redirect_to(format!("{}?{}", frontend.login_url(), query))
+}
+
+pub(crate) fn return_to_token(keys: &KeyRing, path: String) -> String {
+	keys.sign(&ReturnTo {
+		return_to: path,
+		exp: expires_in(RETURN_TO_VALID_SECONDS),
+	})
+}
+
+pub(crate) fn consent_token(keys: &KeyRing, session_id: Uuid) -> String {
+	keys.sign(&ConsentToken {
+		consent_session: session_id.to_string(),
+		exp: expires_in(CONSENT_VALID_SECONDS),
+	})
+}
+
+fn consent_token_valid(keys: &KeyRing, token: &str, user: &AuthUser) -> bool {
+	match (keys.verify::<ConsentToken>(token), user.session_id()) {
+		(Some(claims), Ok(session_id)) => claims.consent_session == session_id.to_string(),
+		_ => false,
+	}
 }
 
 #[get("/authorize")]
 pub fn authorize<'r>(
 	oauth: OAuthRequest<'r>,
 	state: State<MyState>,
-	cookies: Cookies,
+	keys: State<KeyRing>,
+	frontend: State<Frontend>,
+	uri: &Origin,
+	mut conn: DbConn,
+	user: Option<AuthUser>,
 ) -> impl Responder<'r> {
-	//Result<OAuthResponse<'r>, OAuthFailure> {
-	let user_id = utils::get_user_id_from_cookie(cookies.get(SESSION_STRING));
-	println!("{:?}", user_id);
-	if user_id.is_none() {
-		//TODO Redirect to frontend to login
-		let url = "https://iot-frontend/login".to_string();
-		println!("{url}");
-		return Ok(Response::build()
-			.status(Status::from_code(302).unwrap())
-			.header(Location(url))
-			.finalize()
-			.into());
+	let (user, session_id) = match user.map(|user| (user.session_id(), user)) {
+		Some((Ok(session_id), user)) => (user, session_id),
+		_ => return Ok(login_redirect(&keys, &frontend, uri, false)),
+	};
+	if missing_second_factor(&user, &mut conn) {
+		return Ok(login_redirect(&keys, &frontend, uri, true));
 	}
-	return state
+	let csrf_token = consent_token(&keys, session_id);
+	state
 		.endpoint()
-		.with_solicitor(FnSolicitor(consent_form))
+		.with_solicitor(FnSolicitor(move |_: &mut _, solicitation: Solicitation<'_>| {
+			let name = client_name(&solicitation.pre_grant().client_id, &mut conn);
+			consent_form(solicitation, &name, &csrf_token)
+		}))
 		.authorization_flow()
 		.execute(oauth)
-		.map_err(|err| err.pack::<OAuthFailure>());
+		.map_err(|err| err.pack::<OAuthFailure>())
 }
 
-#[post("/authorize?<allow>")]
+// Where the login page sends the user back to, see login_redirect
+#[get("/return?<return_to>")]
+pub fn return_to_authorize<'r>(
+	return_to: String,
+	keys: State<KeyRing>,
+) -> Result<OAuthResponse<'r>, ApiError> {
+	match keys.verify::<ReturnTo>(&return_to) {
+		Some(claims) if claims.return_to.starts_with(&format!("{}?", AUTHORIZE_PATH)) => {
+			Ok(redirect_to(claims.return_to))
+		}
+		_ => Err(ApiError::InvalidToken),
+	}
+}
+
+#[post("/authorize?<allow>", data = "<consent>")]
 pub fn authorize_consent<'r>(
 	oauth: OAuthRequest<'r>,
 	allow: Option<bool>,
+	consent: Form<ConsentForm>,
 	state: State<MyState>,
-	cookies: Cookies,
+	keys: State<KeyRing>,
+	frontend: State<Frontend>,
+	uri: &Origin,
+	mut conn: DbConn,
+	user: Option<AuthUser>,
 ) -> Result<OAuthResponse<'r>, OAuthFailure> {
-	let allowed = allow.unwrap_or(false);
-	let user_id = utils::get_user_id_from_cookie(cookies.get(SESSION_STRING));
-	if user_id.is_none() {
-		//TODO Redirect to frontend to login
+	let user = match user {
+		Some(user) => user,
+		None => return Ok(login_redirect(&keys, &frontend, uri, false)),
+	};
+	if missing_second_factor(&user, &mut conn) {
+		return Ok(forbidden());
+	}
+	if !consent_token_valid(&keys, &consent.csrf_token, &user) {
+		return Ok(forbidden());
 	}
-	println!("{}", user_id.unwrap());
+	let allowed = allow.unwrap_or(false);
+	let user_id = user.user_id.to_string();
 	state
 		.endpoint()
 		.with_solicitor(FnSolicitor(move |_: &mut _, grant: Solicitation<'_>| {
-			consent_decision(allowed, grant, user_id.unwrap().to_string().clone())
+			consent_decision(allowed, grant, user_id.clone())
 		}))
 		.authorization_flow()
 		.execute(oauth)
@@ -107,25 +441,91 @@ pub fn refresh<'r>(
 		.map_err(|err| err.pack::<OAuthFailure>())
 }
 
+// "Authorization: Bearer" of a registration request
+pub struct InitialAccessToken(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for InitialAccessToken {
+	type Error = ();
+	fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+		Outcome::Success(InitialAccessToken(bearer_token(request).map(str::to_string)))
+	}
+}
+
+// Errors in the format of RFC 7591 section 3.2.2
+impl<'r> Responder<'r> for RegistrationError {
+	fn respond_to(self, request: &Request) -> rocket_response::Result<'r> {
+		let (status, error, description) = match self {
+			RegistrationError::InvalidToken => {
+				(Status::Unauthorized, "invalid_token", "Invalid initial access token".to_string())
+			}
+			RegistrationError::InvalidRedirectUri(description) => {
+				(Status::BadRequest, "invalid_redirect_uri", description)
+			}
+			RegistrationError::InvalidClientMetadata(description) => {
+				(Status::BadRequest, "invalid_client_metadata", description)
+			}
+			RegistrationError::Database => return Err(Status::InternalServerError),
+		};
+		status::Custom(status, Json(json!({"error":error,"error_description":description})))
+			.respond_to(request)
+	}
+}
+
+// Dynamic client registration (RFC 7591) for trusted integrations
+#[post("/register", format = "application/json", data = "<registration>")]
+pub fn register_client(
+	mut conn: DbConn,
+	token: InitialAccessToken,
+	registration: Json<ClientRegistration>,
+) -> Result<status::Custom<Json<serde_json::Value>>, RegistrationError> {
+	let (client, secret) =
+		OAuthClientService::register(token.0.as_deref(), &registration, &mut conn)?;
+	let mut response = json!({
+		"client_id": client.id,
+		"client_id_issued_at": client.created_at.timestamp(),
+		"client_name": client.name,
+		"redirect_uris": client.redirect_uris().collect::<Vec<_>>(),
+		"scope": client.scope,
+		"token_endpoint_auth_method": OAuthClientService::auth_method(&client),
+		"grant_types": ["authorization_code", "refresh_token"],
+		"response_types": ["code"],
+	});
+	if let Some(secret) = secret {
+		response["client_secret"] = json!(secret);
+		// Secrets don't expire, they are rotated by an admin
+		response["client_secret_expires_at"] = json!(0);
+	}
+	Ok(status::Custom(Status::Created, Json(response)))
+}
+
+// The profile of the user who granted the token
 #[get("/")]
 pub fn protected_resource<'r>(
 	oauth: OAuthRequest<'r>,
 	state: State<MyState>,
+	mut conn: DbConn,
 ) -> impl Responder<'r> {
+	// LocalClient is seeded by a migration, /oauth/getToken is its only
+	// redirect URI
 	const DENY_TEXT: &str = "<html>
 This page should be accessed via an oauth token from the client in the example. Click
-<a href=\"/oauth/authorize?response_type=code&client_id=LocalClient\">
+<a href=\"/oauth/authorize?response_type=code&client_id=LocalClient&scope=profile\">
 here</a> to begin the authorization process.
 </html>
 ";
 
 	let protect = state
 		.endpoint()
-		.with_scopes(vec!["default-scope".parse().unwrap()])
+		.with_scopes(vec![SCOPE_PROFILE.parse().unwrap()])
 		.resource_flow()
 		.execute(oauth);
 	match protect {
-		Ok(_grant) => Ok("Hello, world"),
+		Ok(grant) => Ok(grant
+			.owner_id
+			.parse::<i32>()
+			.map_err(|_| ApiError::Unauthorized)
+			.and_then(|user_id| UserService::me(user_id, &mut conn))
+			.map(|me| Json(json!({"success":true,"user":me})))),
 		Err(Ok(response)) => {
 			let error: OAuthResponse = Response::build_from(response.into())
 				.header(ContentType::HTML)
@@ -144,46 +544,19 @@ pub fn get_token() -> Html<String> {
 }
 
 impl MyState {
-	pub fn preconfigured() -> Self {
+	pub fn new(pool: Pool) -> Self {
 		MyState {
-			registrar: Mutex::new(
-				vec![
-					Client::public(
-						"LocalClient",
-						RegisteredUrl::Semantic(
-							"http://localhost:8000/oauth/getToken".parse().unwrap(),
-						),
-						"default-scope".parse().unwrap(),
-					),
-					Client::confidential(
-						"GoogleHome",
-						RegisteredUrl::Semantic(
-							"http://localhost:8000/oauth/getToken".parse().unwrap(),
-						),
-						"default-scope".parse().unwrap(),
-						"passphrase".as_bytes(),
-					),
-				]
-				.into_iter()
-				.collect(),
-			),
+			pool,
 			// Authorization tokens are 16 byte random keys to a memory hash map.
 			authorizer: Mutex::new(AuthMap::new(RandomGenerator::new(16))),
-			// Bearer tokens are also random generated but 256-bit tokens, since they live longer
-			// and this example is somewhat paranoid.
-			//
-			// We could also use a `TokenSigner::ephemeral` here to create signed tokens which can
-			// be read and parsed by anyone, but not maliciously created. However, they can not be
-			// revoked and thus don't offer even longer lived refresh tokens.
-			issuer: Mutex::new(TokenMap::new(RandomGenerator::new(16))),
 		}
 	}
 
 	pub fn endpoint(&self) -> Generic<impl Registrar + '_, impl Authorizer + '_, impl Issuer + '_> {
 		Generic {
-			registrar: self.registrar.lock().unwrap(),
-			authorizer: self.authorizer.lock().unwrap(),
-			issuer: self.issuer.lock().unwrap(),
+			registrar: DbRegistrar { pool: self.pool.clone() },
+			authorizer: SharedAuthorizer(&self.authorizer),
+			issuer: DbIssuer { pool: self.pool.clone() },
 			// Solicitor configured later.
 			solicitor: Vacant,
 			// Scope configured later.
@@ -194,17 +567,33 @@ impl MyState {
 	}
 }
 
+// The registrar only knows the client by its id, the user should see the name
+// the admin gave it
+fn client_name(client_id: &str, conn: &mut DbConn) -> String {
+	match OAuthClient::get_enabled(client_id, conn) {
+		Ok(Some(client)) => client.name,
+		Ok(None) => client_id.to_string(),
+		Err(err) => {
+			println!("database error: {}", err);
+			client_id.to_string()
+		}
+	}
+}
+
 fn consent_form<'r>(
-	_: &mut OAuthRequest<'r>,
 	solicitation: Solicitation,
+	client_name: &str,
+	csrf_token: &str,
 ) -> OwnerConsent<OAuthResponse<'r>> {
 	OwnerConsent::InProgress(
 		Response::build()
 			.status(http::Status::Ok)
 			.header(http::ContentType::HTML)
 			.sized_body(io::Cursor::new(consent_page_html(
-				"/oauth/authorize",
+				AUTHORIZE_PATH,
 				solicitation,
+				client_name,
+				csrf_token,
 			)))
 			.finalize()
 			.into(),
@@ -223,11 +612,48 @@ fn consent_decision<'r>(
 	}
 }
 
-pub fn consent_page_html(route: &str, solicitation: Solicitation) -> String {
+fn escape_html(text: &str) -> String {
+	text.chars()
+		.map(|c| match c {
+			'<' => "&lt;".to_string(),
+			'>' => "&gt;".to_string(),
+			'&' => "&amp;".to_string(),
+			'"' => "&quot;".to_string(),
+			'\'' => "&#39;".to_string(),
+			_ => c.to_string(),
+		})
+		.collect()
+}
+
+// One list item per scope of the grant
+fn scope_list_html(scope: &Scope) -> String {
+	scope
+		.iter()
+		.map(|scope| {
+			let description = SCOPE_DESCRIPTIONS
+				.iter()
+				.find(|(name, _)| *name == scope)
+				.map(|(_, description)| *description)
+				.unwrap_or(scope);
+			format!("    <li>{}</li>\n", escape_html(description))
+		})
+		.collect()
+}
+
+// The user the consent is for comes from the session, not from the form
+pub fn consent_page_html(
+	route: &str,
+	solicitation: Solicitation,
+	client_name: &str,
+	csrf_token: &str,
+) -> String {
 	macro_rules! template {
 		() => {
-			"<html>'{0:}' (at {1:}) is requesting permission for '{2:}'
+			"<html>'{0:}' (at {1:}) is requesting permission to:
+<ul>
+{2:}</ul>
 <form method=\"post\">
+    <input type=\"hidden\" name=\"csrf_token\" value=\"{5:}\">
     <input type=\"submit\" value=\"Accept\" formaction=\"{4:}?{3:}&allow=true\">
     <input type=\"submit\" value=\"Deny\" formaction=\"{4:}?{3:}&deny=true\">
 </form>
@@ -237,12 +663,12 @@ pub fn consent_page_html(route: &str, solicitation: Solicitation) -> String {
 
 	let grant = solicitation.pre_grant();
 	let state = solicitation.state();
-	let user_id = 2.to_string();
+	let scope = grant.scope.to_string();
 	let mut extra = vec![
 		("response_type", "code"),
 		("client_id", grant.client_id.as_str()),
 		("redirect_uri", grant.redirect_uri.as_str()),
-		("user_id", user_id.as_str()),
+		("scope", scope.as_str()),
 	];
 
 	if let Some(state) = state {
@@ -251,10 +677,62 @@ pub fn consent_page_html(route: &str, solicitation: Solicitation) -> String {
 
 	format!(
 		template!(),
-		grant.client_id,
-		grant.redirect_uri,
-		grant.scope,
-		serde_urlencoded::to_string(extra).unwrap(),
+		escape_html(client_name),
+		escape_html(grant.redirect_uri.as_str()),
+		scope_list_html(&grant.scope),
+		escape_html(&serde_urlencoded::to_string(extra).unwrap()),
 		&route,
+		escape_html(csrf_token),
 	)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::{claim_form_jwt, session_jwt};
+	use rocket::local::Client;
+
+	fn test_keys() -> KeyRing {
+		KeyRing::generate("test", jsonwebtoken::Algorithm::EdDSA).expect("generate a signing key")
+	}
+
+	#[test]
+	fn oauth_grants_get_at_most_the_registered_scopes() {
+		let allowed: Scope = "devices:read devices:control".parse().unwrap();
+		let negotiate = |requested: Option<&str>| {
+			let requested: Option<Scope> = requested.map(|scope| scope.parse().unwrap());
+			negotiate_scope(&allowed, requested.as_ref()).map(|scope| scope.to_string())
+		};
+		assert_eq!(negotiate(None), Some(allowed.to_string()));
+		assert_eq!(negotiate(Some("devices:read")), Some("devices:read".to_string()));
+		let narrowed = negotiate(Some("devices:read devices:manage"));
+		assert_eq!(narrowed, Some("devices:read".to_string()));
+		assert_eq!(negotiate(Some("devices:manage profile")), None);
+	}
+
+	#[test]
+	fn oauth_return_only_follows_signed_authorize_links() {
+		let keys = test_keys();
+		// Neither token can be used as a session cookie
+		assert!(claim_form_jwt(&keys, consent_token(&keys, Uuid::new_v4())).is_none());
+		let return_to = return_to_token(&keys, "/oauth/authorize?client_id=x".to_string());
+		assert!(claim_form_jwt(&keys, return_to.clone()).is_none());
+
+		let rocket = rocket::ignite()
+			.manage(keys.clone())
+			.mount("/oauth", routes![return_to_authorize]);
+		let client = Client::new(rocket).expect("valid rocket instance");
+		let response = client.get(format!("/oauth/return?return_to={}", return_to)).dispatch();
+		assert_eq!(response.status(), Status::Found);
+		let location = response.headers().get_one("Location");
+		assert_eq!(location, Some("/oauth/authorize?client_id=x"));
+
+		let elsewhere = return_to_token(&keys, "https://example.com/".to_string());
+		let expires = (chrono::Utc::now().timestamp() + 60) as usize;
+		let session = session_jwt(&keys, 1, Uuid::new_v4(), expires);
+		for token in [elsewhere, session, "garbage".to_string()] {
+			let response = client.get(format!("/oauth/return?return_to={}", token)).dispatch();
+			assert_eq!(response.status(), Status::BadRequest);
+		}
+	}
+}
@@ -0,0 +1,47 @@
+// Managing the OAuth clients, for admins only (users.is_admin)
+use rocket_contrib::json::Json;
+
+use crate::db::Conn as DbConn;
+use crate::models::oauth_client::CreateOAuthClient;
+use crate::services::oauth_client::OAuthClientService;
+
+use super::error::ApiResult;
+use super::AuthUser;
+
+#[get("/admin/oauth_clients")]
+pub fn list_oauth_clients(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let clients = OAuthClientService::list(user.user_id, &mut conn)?;
+	Ok(Json(json!({"success":true,"clients":clients})))
+}
+
+// The secret of confidential clients is only part of this response
+#[post("/admin/oauth_clients", format = "application/json", data = "<client>")]
+pub fn create_oauth_client(
+	mut conn: DbConn,
+	user: AuthUser,
+	client: Json<CreateOAuthClient>,
+) -> ApiResult {
+	let (client, secret) = OAuthClientService::create(user.user_id, &client, &mut conn)?;
+	Ok(Json(json!({"success":true,"client":client,"client_secret":secret})))
+}
+
+#[post("/admin/oauth_clients/<client_id>/rotate_secret")]
+pub fn rotate_oauth_client_secret(
+	mut conn: DbConn,
+	user: AuthUser,
+	client_id: String,
+) -> ApiResult {
+	let secret = OAuthClientService::rotate_secret(user.user_id, &client_id, &mut conn)?;
+	Ok(Json(json!({"success":true,"client_secret":secret})))
+}
+
+// The client can't be used anymore and the tokens it got stop working
+#[post("/admin/oauth_clients/<client_id>/disable")]
+pub fn disable_oauth_client(
+	mut conn: DbConn,
+	user: AuthUser,
+	client_id: String,
+) -> ApiResult {
+	OAuthClientService::disable(user.user_id, &client_id, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
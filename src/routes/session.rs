@@ -0,0 +1,103 @@
+// The user's sessions, one per login. Revoking a session makes its cookie
+// useless right away, see AuthUser.
+use diesel::Connection;
+use rocket::http::Cookies;
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, State};
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+use crate::db::Conn as DbConn;
+use crate::models::api_token::ApiToken;
+use crate::models::session::Session;
+use crate::rate_limit::TrustedProxies;
+
+use super::error::{ApiError, ApiResult};
+use super::{removed_session_cookie, AuthUser};
+
+const MAX_USER_AGENT_LEN: usize = 256;
+
+// What is recorded about the client when a session starts
+pub struct ClientInfo {
+	pub user_agent: Option<String>,
+	pub ip_address: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+	type Error = ();
+	fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+		let ip = match request.guard::<State<TrustedProxies>>() {
+			Outcome::Success(proxies) => proxies.client_ip(request),
+			_ => TrustedProxies::default().client_ip(request),
+		};
+		Outcome::Success(ClientInfo {
+			user_agent: request
+				.headers()
+				.get_one("User-Agent")
+				.map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect()),
+			ip_address: ip.map(|ip| ip.to_string()),
+		})
+	}
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SessionInfo {
+	#[serde(flatten)]
+	pub session: Session,
+	// Whether this is the session making the request
+	pub current: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SessionId {
+	pub session_id: Uuid,
+}
+
+#[get("/sessions")]
+pub fn list_sessions(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let current = user.session_id()?;
+	let sessions: Vec<SessionInfo> = Session::get_active_by_user(user.user_id, &mut conn)?
+		.into_iter()
+		.map(|session| SessionInfo {
+			current: session.id == current,
+			session,
+		})
+		.collect();
+	Ok(Json(json!({"success":true,"sessions":sessions})))
+}
+
+#[post("/sessions/revoke", format = "application/json", data = "<session>")]
+pub fn revoke_session(
+	mut conn: DbConn,
+	session: Json<SessionId>,
+	user: AuthUser,
+	mut cookies: Cookies,
+) -> ApiResult {
+	let current = user.session_id()?;
+	if Session::revoke(session.session_id, user.user_id, &mut conn)? == 0 {
+		return Err(ApiError::SessionNotFound);
+	}
+	if session.session_id == current {
+		cookies.remove(removed_session_cookie());
+	}
+	Ok(Json(json!({"success":true})))
+}
+
+// Ends every session of the user, including this one, and every API token
+#[post("/logout_everywhere")]
+pub fn logout_everywhere(mut conn: DbConn, user: AuthUser, mut cookies: Cookies) -> ApiResult {
+	let (revoked, revoked_api_tokens) =
+		conn.transaction::<_, diesel::result::Error, _>(|local_conn| {
+			Ok((
+				Session::revoke_all(user.user_id, local_conn)?,
+				ApiToken::revoke_all(user.user_id, local_conn)?,
+			))
+		})?;
+	cookies.remove(removed_session_cookie());
+	Ok(Json(json!({
+		"success":true,
+		"revoked":revoked,
+		"revoked_api_tokens":revoked_api_tokens,
+	})))
+}
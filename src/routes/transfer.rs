@@ -0,0 +1,101 @@
+use crate::constants::{DEVICE_ACTIVE, TRANSFER_CANCELLED, TRANSFER_DECLINED};
+use crate::db::Conn as DbConn;
+use crate::homegraph;
+use crate::models::device::Device;
+use crate::models::device_transfer::{DeviceTransfer, TransferAction, TransferRequest};
+use crate::models::user::User;
+
+use rocket_contrib::json::Json;
+
+use super::error::{ApiError, ApiResult};
+use super::AuthUser;
+
+fn my_email(user_id: i32, conn: &mut DbConn) -> Result<String, ApiError> {
+	let me = User::get_user_by_id(user_id, conn)?;
+	me.map(|me| me.email).ok_or(ApiError::UserNotFound)
+}
+
+// Starts handing a device over to the user with the given email
+#[post("/transfer_device", format = "application/json", data = "<transfer>")]
+pub fn transfer_device(
+	mut conn: DbConn,
+	transfer: Json<TransferRequest>,
+	user: AuthUser,
+) -> ApiResult {
+	let device = Device::get_device_by_id(transfer.device_id, &mut conn)?
+		.ok_or(ApiError::DeviceNotFound)?;
+	if device.user_id != user.user_id {
+		return Err(ApiError::NotOwner);
+	}
+	// A device pending removal is already gone as far as the user is concerned
+	if device.state != DEVICE_ACTIVE {
+		return Err(ApiError::DeviceNotFound);
+	}
+	if my_email(user.user_id, &mut conn)? == transfer.email {
+		return Err(ApiError::AlreadyOwner);
+	}
+	match DeviceTransfer::create(device.id, user.user_id, transfer.email.clone(), &mut conn) {
+		Ok(created) => Ok(Json(json!({"success":true,"transfer":created}))),
+		Err(diesel::result::Error::DatabaseError(
+			diesel::result::DatabaseErrorKind::UniqueViolation,
+			_,
+		)) => Err(ApiError::TransferPending),
+		Err(err) => Err(err.into()),
+	}
+}
+
+// Pending transfers addressed to the user and started by the user
+#[get("/transfers")]
+pub fn get_transfers(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let email = my_email(user.user_id, &mut conn)?;
+	let incoming = DeviceTransfer::get_incoming(&email, &mut conn)?;
+	let outgoing = DeviceTransfer::get_outgoing(user.user_id, &mut conn)?;
+	Ok(Json(json!({
+		"success":true,
+		"incoming":incoming.into_iter().filter(|transfer| !transfer.is_expired()).collect::<Vec<_>>(),
+		"outgoing":outgoing,
+	})))
+}
+
+#[post("/accept_transfer", format = "application/json", data = "<action>")]
+pub fn accept_transfer(mut conn: DbConn, action: Json<TransferAction>, user: AuthUser) -> ApiResult {
+	let transfer = DeviceTransfer::get_by_id(action.transfer_id, &mut conn)?
+		.ok_or(ApiError::TransferNotFound)?;
+	// Transfers addressed to someone else don't exist as far as the user knows
+	if my_email(user.user_id, &mut conn)? != transfer.to_email {
+		return Err(ApiError::TransferNotFound);
+	}
+	if transfer.is_expired() {
+		return Err(ApiError::TransferExpired);
+	}
+	match transfer.accept(user.user_id, &mut conn) {
+		Ok(()) => {}
+		// Someone else resolved the transfer or the device moved in the meantime
+		Err(diesel::result::Error::RollbackTransaction)
+		| Err(diesel::result::Error::NotFound) => return Err(ApiError::TransferNotPending),
+		Err(err) => return Err(err.into()),
+	}
+	// Both users' Google Home device lists changed
+	homegraph::request_sync(&[transfer.from_user_id, user.user_id]);
+	Ok(Json(json!({"success":true,"device_id":transfer.device_id})))
+}
+
+// The recipient declines, or the owner takes the offer back
+#[post("/cancel_transfer", format = "application/json", data = "<action>")]
+pub fn cancel_transfer(mut conn: DbConn, action: Json<TransferAction>, user: AuthUser) -> ApiResult {
+	let transfer = DeviceTransfer::get_by_id(action.transfer_id, &mut conn)?
+		.ok_or(ApiError::TransferNotFound)?;
+	let status = if transfer.from_user_id == user.user_id {
+		TRANSFER_CANCELLED
+	} else {
+		if my_email(user.user_id, &mut conn)? != transfer.to_email {
+			return Err(ApiError::TransferNotFound);
+		}
+		TRANSFER_DECLINED
+	};
+	if DeviceTransfer::resolve(transfer.id, status, &mut conn)? {
+		Ok(Json(json!({"success":true,"status":status})))
+	} else {
+		Err(ApiError::TransferNotPending)
+	}
+}
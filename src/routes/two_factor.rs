@@ -0,0 +1,42 @@
+use crate::db::Conn as DbConn;
+use crate::models::two_factor::{DisableTwoFactor, TwoFactorCode};
+use crate::services::two_factor::TwoFactorService;
+
+use rocket_contrib::json::Json;
+
+use super::error::ApiResult;
+use super::AuthUser;
+
+// Starts enrolling, nothing changes for the login until /two_factor/enable
+#[post("/two_factor/setup")]
+pub fn setup_two_factor(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let setup = TwoFactorService::setup(user.user_id, &mut conn)?;
+	Ok(Json(json!({"success":true,"setup":setup})))
+}
+
+#[post("/two_factor/enable", format = "application/json", data = "<code>")]
+pub fn enable_two_factor(mut conn: DbConn, user: AuthUser, code: Json<TwoFactorCode>) -> ApiResult {
+	let codes = TwoFactorService::enable(user.user_id, user.session_id()?, &code.code, &mut conn)?;
+	Ok(Json(json!({"success":true,"recovery_codes":codes})))
+}
+
+#[post("/two_factor/disable", format = "application/json", data = "<disable>")]
+pub fn disable_two_factor(
+	mut conn: DbConn,
+	user: AuthUser,
+	disable: Json<DisableTwoFactor>,
+) -> ApiResult {
+	TwoFactorService::disable(user.user_id, &disable.password, &disable.code, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+// The previous recovery codes stop working
+#[post("/two_factor/recovery_codes", format = "application/json", data = "<code>")]
+pub fn regenerate_recovery_codes(
+	mut conn: DbConn,
+	user: AuthUser,
+	code: Json<TwoFactorCode>,
+) -> ApiResult {
+	let codes = TwoFactorService::regenerate_recovery_codes(user.user_id, &code.code, &mut conn)?;
+	Ok(Json(json!({"success":true,"recovery_codes":codes})))
+}
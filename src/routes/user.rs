@@ -1,182 +1,220 @@
 use crate::db::Conn as DbConn;
-use crate::models::user::{LoginUser, Me, RegisterUser, User};
+use crate::effects::EffectRunner;
+use crate::frontend::Frontend;
+use crate::keyring::KeyRing;
+use crate::models::session::Session;
+use crate::models::two_factor::LoginChallenge;
+use crate::models::user::{
+	ChangePassword, DeleteAccount, EmailToken, LoginUser, PasswordResetRequest, RegisterUser,
+	ResetPassword, UpdateProfile,
+};
+use crate::rate_limit::LoginThrottle;
+use crate::services::gateway::Gateway;
+use crate::services::mailer::Mailer;
+use crate::services::two_factor::TwoFactorService;
+use crate::services::user::UserService;
 
-use crate::utils::jwt_from_id;
+use crate::utils::session_jwt;
 
 use rocket_contrib::json::Json;
-use serde_json::Value;
-
-use argon2::{
-	password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-	Argon2,
-};
 
 use rocket::http::{Cookie, Cookies, SameSite};
+use rocket::State;
 
-use super::{AuthUser, SESSION_STRING};
+use super::error::ApiResult;
+use super::session::ClientInfo;
+use super::{removed_session_cookie, AuthUser, SESSION_STRING};
+
+// The session itself expires when unused, see models/session.rs. The token
+// lifetime caps how long a session can be kept alive.
+// On "/" so the OAuth authorize flow and /api/v2 get it as well
+fn session_cookie(keys: &KeyRing, session: &Session) -> Cookie<'static> {
+	// TODO in prod make cookies secure
+	Cookie::build(
+		SESSION_STRING,
+		session_jwt(
+			keys,
+			session.user_id,
+			session.id,
+			(chrono::Utc::now().timestamp() + 365 * 24 * 60 * 60) as usize,
+		),
+	)
+	.path("/")
+	.same_site(SameSite::None)
+	.secure(false)
+	.http_only(true)
+	.finish()
+}
 
 #[post("/register", format = "application/json", data = "<new_user>")]
 pub fn register(
 	mut conn: DbConn,
-	mut new_user: Json<RegisterUser>,
+	new_user: Json<RegisterUser>,
+	keys: State<KeyRing>,
+	mailer: State<Mailer>,
+	frontend: State<Frontend>,
+	client: ClientInfo,
 	mut cookies: Cookies,
-) -> Json<Value> {
-	if new_user.rep_password != new_user.password {
-		return Json(json!({
-			"errors":
-				[{
-					"field":"password",
-					"message":"passwords dont match"
-				}]
-		}));
-	}
-	let password = new_user.password.as_bytes();
-	let salt = SaltString::generate(&mut OsRng);
-	let argon2 = Argon2::default();
-	let password_hash = argon2.hash_password(password, &salt);
-	if password_hash.is_err() {
-		return Json(json!({
-			"errors":
-				[{
-					"field":"password",
-					"message":"something went wrong when trying to hash a password"
-				}]
-		}));
+) -> ApiResult {
+	let user = UserService::register(&new_user, &mut conn)?;
+	// The account works without a verified address, the email can be sent again
+	// from /resend_verification
+	if let Err(err) = UserService::send_verification(&user, &**mailer, &frontend, &mut conn) {
+		println!("verification email for user {} not sent: {:?}", user.id, err);
 	}
-	new_user.password = password_hash.unwrap().to_string();
-	let status = User::insert_user(new_user.clone().into(), &mut conn);
-	if status.is_err() {
-		let error_str = status.unwrap_err().to_string();
-		return match error_str.as_str() {
-			"duplicate key value violates unique constraint \"users_email_key\"" => Json(json!({
-				"errors":
-					[{
-						"field":"email",
-						"message":"user with this email already exists"
-					}]
-			})),
-			_ => Json(json!({
-				"errors":
-					[{
-						"field":"password",
-						"message":"failed to create a user"
-					}]
-			})),
-		};
-	}
-	let email = new_user.email.clone();
-	let user_list = User::get_user_by_email(email, &mut conn);
-	let user = user_list.first();
-	return match user {
-		Some(user) => {
-			let user_id = user.id.to_string();
-			let session_cookie = Cookie::build(
-				SESSION_STRING,
-				jwt_from_id(
-					user_id.clone(),
-					(chrono::Utc::now().timestamp() + 365 * 24 * 60 * 60) as usize,
-				),
-			)
-			.path("/")
-			.same_site(SameSite::None)
-			.secure(false)
-			.http_only(true)
-			.finish();
-			// TODO in prod make cookies secure
-			cookies.add(session_cookie);
-			Json(json!({
-				"worked":true
-			}))
-		}
-		// This should be literally impossible
-		None => Json(json!({
-			"errors":
-				[{
-					"field":"password",
-					"message":"failed to create a user"
-				}]
-		})),
-	};
+	let session = Session::start(user.id, client.user_agent, client.ip_address, &mut conn)?;
+	cookies.add(session_cookie(&keys, &session));
+	// "worked" is what clients from before the error envelope look for
+	Ok(Json(json!({"success":true,"worked":true})))
 }
 
-// Login the user and send them a jwt containing their user_id
+// Login the user and send them a jwt for a new session. With 2FA enabled there
+// is no session yet, the challenge has to be sent to /login/two_factor with a
+// code.
 #[post("/login", format = "application/json", data = "<user_data>")]
-pub fn login(user_data: Json<LoginUser>, mut conn: DbConn, mut cookies: Cookies) -> Json<Value> {
-	let user = User::get_user_by_email(user_data.clone().email, &mut conn);
-	// Check if user with given email exists
-	if user.first().is_none() {
-		return Json(json!({
-			"errors":
-				[{
-					"field":"email",
-					"message":"invalid email"
-				}]
-		}));
-	}
-	// Check if password match
-	let parsed_hash = PasswordHash::new(&user.first().unwrap().password);
-	if parsed_hash.is_err() {
-		return Json(json!({
-			"errors":
-				[{
-					"field":"password",
-					"message":"invalid password"
-				}]
-		}));
-	}
-	if Argon2::default()
-		.verify_password(user_data.password.as_bytes(), &parsed_hash.unwrap())
-		.is_err()
-	{
-		return Json(json!({
-			"errors":
-				[{
-					"field":"password",
-					"message":"invalid password"
-				}]
-		}));
+pub fn login(
+	user_data: Json<LoginUser>,
+	mut conn: DbConn,
+	keys: State<KeyRing>,
+	throttle: State<LoginThrottle>,
+	client: ClientInfo,
+	mut cookies: Cookies,
+) -> ApiResult {
+	let client_ip = client.ip_address.as_deref();
+	let user = UserService::login(&user_data, client_ip, &throttle, &mut conn)?;
+	if TwoFactorService::is_enabled(user.id, &mut conn)? {
+		let challenge = TwoFactorService::start_login(user.id, &mut conn)?;
+		return Ok(Json(json!({
+			"success":true,
+			"worked":false,
+			"two_factor_required":true,
+			"challenge":challenge,
+		})));
 	}
+	let session = Session::start(user.id, client.user_agent, client.ip_address, &mut conn)?;
+	cookies.add(session_cookie(&keys, &session));
+	Ok(Json(json!({"success":true,"worked":true,"two_factor_required":false})))
+}
 
-	let user_id = user.first().unwrap().id.to_string();
-	let session_cookie = Cookie::build(
-		SESSION_STRING,
-		jwt_from_id(
-			user_id.clone(),
-			(chrono::Utc::now().timestamp() + 365 * 24 * 60 * 60) as usize,
-		),
-	)
-	.path("/api/v1")
-	.same_site(SameSite::None)
-	.secure(false)
-	.http_only(true)
-	.finish();
-	cookies.add(session_cookie);
-	return Json(json!({
-		"worked":true
-	}));
+#[post("/login/two_factor", format = "application/json", data = "<login>")]
+pub fn login_two_factor(
+	login: Json<LoginChallenge>,
+	mut conn: DbConn,
+	keys: State<KeyRing>,
+	throttle: State<LoginThrottle>,
+	client: ClientInfo,
+	mut cookies: Cookies,
+) -> ApiResult {
+	let user_id = TwoFactorService::complete_login(
+		&login.challenge,
+		&login.code,
+		client.ip_address.as_deref(),
+		&throttle,
+		&mut conn,
+	)?;
+	let session = Session::start(user_id, client.user_agent, client.ip_address, &mut conn)?;
+	Session::mark_two_factor(session.id, &mut conn)?;
+	cookies.add(session_cookie(&keys, &session));
+	Ok(Json(json!({"success":true})))
 }
+// Ends the session, logging out without one is not an error
 #[post("/logout")]
-pub fn logout(mut cookies: Cookies) -> Json<Value> {
-	cookies.remove(Cookie::named(SESSION_STRING));
-	return Json(json!({
-		"status":200,
-		"result":true,
-	}));
+pub fn logout(mut conn: DbConn, user: Option<AuthUser>, mut cookies: Cookies) -> ApiResult {
+	if let Some(user) = user {
+		Session::revoke(user.session_id()?, user.user_id, &mut conn)?;
+	}
+	cookies.remove(removed_session_cookie());
+	Ok(Json(json!({"success":true,"status":200,"result":true})))
 }
+// The user's fields stay at the top level like before the envelope
 #[get("/me", format = "application/json")]
-pub fn get_me(mut conn: DbConn, user: AuthUser) -> Json<Value> {
-	let user_id_int: i32 = user.user_id;
-	println!("{}", user_id_int);
-	let user = User::get_user_by_id(user_id_int, &mut conn);
-	if user.first().is_none() {
-		return Json(json!({"error":"invalid user_id"}));
-	}
-	let user = user.first().unwrap();
-	let return_user = Me {
-		id: user.id,
-		email: user.email.clone(),
-		first_name: user.first_name.clone(),
-		last_name: user.last_name.clone(),
-	};
-	Json(json!(return_user))
+pub fn get_me(mut conn: DbConn, user: AuthUser) -> ApiResult {
+	let me = UserService::me(user.user_id, &mut conn)?;
+	let mut body = json!(me);
+	body["success"] = json!(true);
+	Ok(Json(body))
+}
+
+#[post("/verify_email", format = "application/json", data = "<token>")]
+pub fn verify_email(mut conn: DbConn, token: Json<EmailToken>) -> ApiResult {
+	UserService::verify_email(&token.token, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+#[post("/resend_verification")]
+pub fn resend_verification(
+	mut conn: DbConn,
+	user: AuthUser,
+	mailer: State<Mailer>,
+	frontend: State<Frontend>,
+) -> ApiResult {
+	UserService::resend_verification(user.user_id, &**mailer, &frontend, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+// Succeeds for unknown addresses too
+#[post("/request_password_reset", format = "application/json", data = "<request>")]
+pub fn request_password_reset(
+	mut conn: DbConn,
+	request: Json<PasswordResetRequest>,
+	mailer: State<Mailer>,
+	frontend: State<Frontend>,
+) -> ApiResult {
+	UserService::request_password_reset(&request.email, &**mailer, &frontend, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+#[post("/reset_password", format = "application/json", data = "<reset>")]
+pub fn reset_password(mut conn: DbConn, reset: Json<ResetPassword>) -> ApiResult {
+	UserService::reset_password(&reset.token, &reset.password, &reset.rep_password, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+#[post("/change_password", format = "application/json", data = "<change>")]
+pub fn change_password(
+	mut conn: DbConn,
+	user: AuthUser,
+	change: Json<ChangePassword>,
+) -> ApiResult {
+	UserService::change_password(user.user_id, user.session_id()?, &change, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+// A changed email is only used once it is confirmed with /confirm_email_change
+#[post("/update_profile", format = "application/json", data = "<profile>")]
+pub fn update_profile(
+	mut conn: DbConn,
+	user: AuthUser,
+	profile: Json<UpdateProfile>,
+	mailer: State<Mailer>,
+	frontend: State<Frontend>,
+) -> ApiResult {
+	let me = UserService::update_profile(user.user_id, &profile, &**mailer, &frontend, &mut conn)?;
+	Ok(Json(json!({"success":true,"user":me})))
+}
+
+#[post("/confirm_email_change", format = "application/json", data = "<token>")]
+pub fn confirm_email_change(mut conn: DbConn, token: Json<EmailToken>) -> ApiResult {
+	UserService::confirm_email_change(&token.token, &mut conn)?;
+	Ok(Json(json!({"success":true})))
+}
+
+#[post("/delete_account", format = "application/json", data = "<confirmation>")]
+pub fn delete_account(
+	mut conn: DbConn,
+	user: AuthUser,
+	confirmation: Json<DeleteAccount>,
+	gateway: State<Gateway>,
+	effects: State<EffectRunner>,
+	mut cookies: Cookies,
+) -> ApiResult {
+	let pending = UserService::delete_account(
+		user.user_id,
+		&confirmation.password,
+		&**gateway,
+		&effects,
+		&mut conn,
+	)?;
+	cookies.remove(removed_session_cookie());
+	Ok(Json(json!({"success":true,"devices_pending_removal":pending})))
 }
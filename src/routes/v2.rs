@@ -0,0 +1,222 @@
+// Resource oriented device API, mounted at /api/v2. The RPC style v1 routes in
+// device.rs stay for existing clients, both go through the same functions.
+// Every resource carries an ETag, see etag.rs.
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::State;
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+use crate::db::Conn as DbConn;
+use crate::decommission::DecommissionStatus;
+use crate::effects::EffectRunner;
+use crate::models::device::{Device, DeviceData};
+use crate::models::light::{Light, LightEffect};
+use crate::models::values::{Brightness, Rgb24};
+use crate::services::control::ControlService;
+use crate::services::device::DeviceService;
+use crate::services::gateway::Gateway;
+
+use super::error::ApiError;
+use super::etag::{etag, IfMatch, Tagged};
+use super::validation::Valid;
+use super::AuthUser;
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeviceResource {
+	pub id: Uuid,
+	#[serde(rename = "type")]
+	pub type_: String,
+	pub name: String,
+	pub nicknames: Vec<String>,
+	pub traits: Vec<String>,
+	// active or pending_removal
+	pub status: String,
+}
+
+impl From<Device> for DeviceResource {
+	fn from(device: Device) -> Self {
+		DeviceResource {
+			id: device.id,
+			type_: device.type_,
+			name: device.name,
+			nicknames: device.nicknames.into_iter().flatten().collect(),
+			traits: device.traits.into_iter().flatten().collect(),
+			status: device.state,
+		}
+	}
+}
+
+// JSON merge patch, fields that are left out stay as they are
+#[derive(Deserialize, JsonSchema)]
+pub struct DevicePatch {
+	pub name: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeviceStateResource {
+	pub is_on: bool,
+	pub brightness: Brightness,
+	pub color: Rgb24,
+	// Transition or effect the light is running
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub active_effect: Option<String>,
+}
+
+impl DeviceStateResource {
+	fn new(light: &Light, effects: &EffectRunner) -> Self {
+		DeviceStateResource {
+			is_on: light.is_on,
+			brightness: Brightness::saturating(light.brightness),
+			color: Rgb24::saturating(light.rgb),
+			active_effect: effects.active_effect(light.light_id),
+		}
+	}
+}
+
+// The whole state, unlike the v1 routes nothing is taken from the current one
+#[derive(Deserialize, JsonSchema)]
+pub struct StateUpdate {
+	pub is_on: bool,
+	pub brightness: Brightness,
+	pub color: Rgb24,
+	pub transition_ms: Option<u32>,
+	pub effect: Option<LightEffect>,
+}
+
+impl StateUpdate {
+	fn for_device(&self, device_id: Uuid) -> DeviceData {
+		DeviceData {
+			device_id,
+			brightness: Some(self.brightness),
+			color: Some(self.color),
+			color_temperature: None,
+			is_on: Some(self.is_on),
+			transition_ms: self.transition_ms,
+			effect: self.effect.clone(),
+		}
+	}
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Deletion {
+	pub status: DecommissionStatus,
+}
+
+fn parse_id(device_id: &str) -> Result<Uuid, ApiError> {
+	Uuid::parse_str(device_id)
+		.map_err(|_| ApiError::invalid_field("device_id", "not a valid device id"))
+}
+
+fn check_light_owner(light: Option<Light>, user_id: i32) -> Result<Light, ApiError> {
+	match light {
+		Some(light) if light.user_id == user_id => Ok(light),
+		Some(_) => Err(ApiError::NotOwner),
+		None => Err(ApiError::DeviceNotFound),
+	}
+}
+
+#[get("/devices")]
+pub fn list_devices(
+	mut conn: DbConn,
+	user: AuthUser,
+) -> Result<Json<Vec<DeviceResource>>, ApiError> {
+	let devices = Device::get_devices_by_user(user.user_id, &mut conn)?;
+	Ok(Json(devices.into_iter().map(DeviceResource::from).collect()))
+}
+
+#[get("/devices/<device_id>")]
+pub fn get_device(
+	mut conn: DbConn,
+	device_id: String,
+	user: AuthUser,
+) -> Result<Tagged, ApiError> {
+	let device = DeviceService::owned(parse_id(&device_id)?, user.user_id, &mut conn)?;
+	Ok(Tagged::new(&DeviceResource::from(device)))
+}
+
+#[patch("/devices/<device_id>", format = "application/json", data = "<patch>")]
+pub fn patch_device(
+	mut conn: DbConn,
+	device_id: String,
+	patch: Json<DevicePatch>,
+	if_match: IfMatch,
+	user: AuthUser,
+) -> Result<Tagged, ApiError> {
+	let device_id = parse_id(&device_id)?;
+	let device = conn.build_transaction().run::<_, ApiError, _>(|local_conn| {
+		let device =
+			DeviceService::check_owner(Device::lock_device(device_id, local_conn)?, user.user_id)?;
+		if_match.check(&etag(&DeviceResource::from(device.clone())))?;
+		match &patch.name {
+			Some(name) => DeviceService::rename(&device, name, local_conn),
+			None => Ok(device),
+		}
+	})?;
+	Ok(Tagged::new(&DeviceResource::from(device)))
+}
+
+// Decommissions the device, 202 while the device hasn't acknowledged the wipe
+#[delete("/devices/<device_id>")]
+pub fn delete_device(
+	mut conn: DbConn,
+	device_id: String,
+	if_match: IfMatch,
+	gateway: State<Gateway>,
+	effects: State<EffectRunner>,
+	user: AuthUser,
+) -> Result<Custom<Json<Deletion>>, ApiError> {
+	let device_id = parse_id(&device_id)?;
+	let status = conn.build_transaction().run::<_, ApiError, _>(|local_conn| {
+		let device =
+			DeviceService::check_owner(Device::lock_device(device_id, local_conn)?, user.user_id)?;
+		if_match.check(&etag(&DeviceResource::from(device.clone())))?;
+		DeviceService::remove(&device, &**gateway, &effects, local_conn)
+	})?;
+	let code = match status {
+		DecommissionStatus::Removed => Status::Ok,
+		DecommissionStatus::PendingRemoval => Status::Accepted,
+	};
+	Ok(Custom(code, Json(Deletion { status })))
+}
+
+#[get("/devices/<device_id>/state")]
+pub fn get_device_state(
+	mut conn: DbConn,
+	device_id: String,
+	effects: State<EffectRunner>,
+	user: AuthUser,
+) -> Result<Tagged, ApiError> {
+	let light = Light::get_device_by_id(parse_id(&device_id)?, &mut conn)?;
+	let light = check_light_owner(light, user.user_id)?;
+	Ok(Tagged::new(&DeviceStateResource::new(&light, &effects)))
+}
+
+// The row stays locked while the command is sent, so a concurrent PUT with the
+// same If-Match can't slip in between
+#[put("/devices/<device_id>/state", format = "application/json", data = "<state>")]
+pub fn put_device_state(
+	mut conn: DbConn,
+	device_id: String,
+	state: Valid<StateUpdate>,
+	if_match: IfMatch,
+	gateway: State<Gateway>,
+	effects: State<EffectRunner>,
+	user: AuthUser,
+) -> Result<Tagged, ApiError> {
+	let device_id = parse_id(&device_id)?;
+	let light = conn.build_transaction().run::<_, ApiError, _>(|local_conn| {
+		let light = check_light_owner(Light::lock_device(device_id, local_conn)?, user.user_id)?;
+		if_match.check(&etag(&DeviceStateResource::new(&light, &effects)))?;
+		ControlService::set_device_state(
+			user.user_id,
+			state.for_device(device_id),
+			&**gateway,
+			&effects,
+			local_conn,
+		)?;
+		Light::get_device_by_id(device_id, local_conn)?.ok_or(ApiError::DeviceNotFound)
+	})?;
+	Ok(Tagged::new(&DeviceStateResource::new(&light, &effects)))
+}
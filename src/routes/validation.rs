@@ -0,0 +1,69 @@
+// JSON bodies carrying light values. Works like rocket_contrib's Json, except
+// that a body with invalid values is rejected with a 422 listing every invalid
+// field rather than a bare status (see the 422 catcher in error.rs).
+use std::io::Read;
+use std::ops::Deref;
+
+use rocket::data::{self, FromDataSimple};
+use rocket::http::Status;
+use rocket::{Data, Outcome, Request};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::models::values::{invalid_fields, FieldError};
+
+const LIMIT: u64 = 1 << 20;
+
+pub struct Valid<T>(pub T);
+
+impl<T> Valid<T> {
+	pub fn into_inner(self) -> T {
+		self.0
+	}
+}
+
+impl<T> Deref for Valid<T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		&self.0
+	}
+}
+
+// Kept in the request's local cache for the 422 catcher
+#[derive(Default)]
+pub struct InvalidFields(pub Vec<FieldError>);
+
+fn reject<T>(request: &Request, errors: Vec<FieldError>) -> data::Outcome<T, Vec<FieldError>> {
+	request.local_cache(|| InvalidFields(errors.clone()));
+	Outcome::Failure((Status::UnprocessableEntity, errors))
+}
+
+impl<T: DeserializeOwned> FromDataSimple for Valid<T> {
+	type Error = Vec<FieldError>;
+
+	fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+		let mut body = String::new();
+		if data.open().take(LIMIT).read_to_string(&mut body).is_err() {
+			return Outcome::Failure((Status::BadRequest, vec![]));
+		}
+		let value = match serde_json::from_str::<Value>(&body) {
+			Ok(value) => value,
+			Err(_) => return Outcome::Failure((Status::BadRequest, vec![])),
+		};
+		let errors = invalid_fields(&value);
+		if !errors.is_empty() {
+			return reject(request, errors);
+		}
+		match serde_json::from_value::<T>(value) {
+			Ok(parsed) => Outcome::Success(Valid(parsed)),
+			// Missing fields, wrong types and so on
+			Err(err) => reject(
+				request,
+				vec![FieldError {
+					field: "body".to_string(),
+					error: err.to_string(),
+				}],
+			),
+		}
+	}
+}
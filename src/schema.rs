@@ -1,5 +1,74 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+	api_tokens (id) {
+		id -> Uuid,
+		user_id -> Int4,
+		name -> Varchar,
+		token_hash -> Varchar,
+		scope -> Varchar,
+		created_at -> Timestamptz,
+		expires_at -> Nullable<Timestamptz>,
+		last_used_at -> Nullable<Timestamptz>,
+		revoked_at -> Nullable<Timestamptz>,
+	}
+}
+
+diesel::table! {
+	device_credentials (id) {
+		id -> Int4,
+		device_id -> Uuid,
+		secret_key -> Varchar,
+		created_at -> Timestamptz,
+		revoked_at -> Nullable<Timestamptz>,
+	}
+}
+
+diesel::table! {
+	device_group_members (group_id, device_id) {
+		group_id -> Uuid,
+		device_id -> Uuid,
+	}
+}
+
+diesel::table! {
+	device_groups (id) {
+		id -> Uuid,
+		user_id -> Int4,
+		name -> Text,
+		expose_to_google -> Bool,
+	}
+}
+
+diesel::table! {
+	device_message_nonces (device_id, nonce) {
+		device_id -> Uuid,
+		nonce -> Varchar,
+		received_at -> Timestamptz,
+	}
+}
+
+diesel::table! {
+	device_telemetry (id) {
+		id -> Int8,
+		device_id -> Uuid,
+		data -> Jsonb,
+		received_at -> Timestamptz,
+	}
+}
+
+diesel::table! {
+	device_transfers (id) {
+		id -> Uuid,
+		device_id -> Uuid,
+		from_user_id -> Int4,
+		to_email -> Varchar,
+		status -> Varchar,
+		created_at -> Timestamptz,
+		resolved_at -> Nullable<Timestamptz>,
+	}
+}
+
 diesel::table! {
 	devices (id) {
 		id -> Uuid,
@@ -10,6 +79,8 @@ diesel::table! {
 		name -> Text,
 		nicknames -> Array<Nullable<Text>>,
 		traits -> Array<Nullable<Text>>,
+		state -> Varchar,
+		removal_requested_at -> Nullable<Timestamptz>,
 	}
 }
 
@@ -19,8 +90,70 @@ diesel::table! {
 		rgb -> Int4,
 		brightness -> Int4,
 		is_on -> Bool,
+		user_id -> Int4,
+		signature -> Varchar,
+		native_effects -> Bool,
+	}
+}
+
+diesel::table! {
+	oauth_clients (id) {
+		id -> Varchar,
+		name -> Varchar,
+		secret_hash -> Nullable<Varchar>,
+		redirect_uris -> Array<Nullable<Text>>,
+		scope -> Varchar,
+		created_at -> Timestamptz,
+		disabled_at -> Nullable<Timestamptz>,
+	}
+}
+
+diesel::table! {
+	oauth_tokens (id) {
+		id -> Uuid,
+		client_id -> Varchar,
+		user_id -> Int4,
+		scope -> Varchar,
+		redirect_uri -> Varchar,
+		access_token_hash -> Varchar,
+		refresh_token_hash -> Nullable<Varchar>,
+		expires_at -> Timestamptz,
+		created_at -> Timestamptz,
+		revoked_at -> Nullable<Timestamptz>,
+	}
+}
+
+diesel::table! {
+	recovery_codes (id) {
+		id -> Int4,
+		user_id -> Int4,
+		code_hash -> Varchar,
+		created_at -> Timestamptz,
+		used_at -> Nullable<Timestamptz>,
+	}
+}
+
+diesel::table! {
+	sessions (id) {
+		id -> Uuid,
+		user_id -> Int4,
+		user_agent -> Nullable<Varchar>,
+		ip_address -> Nullable<Varchar>,
+		created_at -> Timestamptz,
+		last_seen_at -> Timestamptz,
+		expires_at -> Timestamptz,
+		revoked_at -> Nullable<Timestamptz>,
+		two_factor -> Bool,
+	}
+}
+
+diesel::table! {
+	totp_credentials (user_id) {
 		user_id -> Int4,
 		secret -> Varchar,
+		created_at -> Timestamptz,
+		enabled_at -> Nullable<Timestamptz>,
+		last_used_step -> Nullable<Int8>,
 	}
 }
 
@@ -33,6 +166,19 @@ diesel::table! {
 	}
 }
 
+diesel::table! {
+	user_tokens (id) {
+		id -> Int4,
+		user_id -> Int4,
+		purpose -> Varchar,
+		token_hash -> Varchar,
+		created_at -> Timestamptz,
+		expires_at -> Timestamptz,
+		used_at -> Nullable<Timestamptz>,
+		failed_attempts -> Int4,
+	}
+}
+
 diesel::table! {
 	users (id) {
 		id -> Int4,
@@ -40,7 +186,41 @@ diesel::table! {
 		password -> Varchar,
 		first_name -> Varchar,
 		last_name -> Varchar,
+		email_verified_at -> Nullable<Timestamptz>,
+		pending_email -> Nullable<Varchar>,
+		is_admin -> Bool,
 	}
 }
 
-diesel::allow_tables_to_appear_in_same_query!(devices, lights, traits, users,);
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(device_group_members -> device_groups (group_id));
+diesel::joinable!(device_group_members -> devices (device_id));
+diesel::joinable!(device_message_nonces -> devices (device_id));
+diesel::joinable!(device_telemetry -> devices (device_id));
+diesel::joinable!(device_transfers -> devices (device_id));
+diesel::joinable!(oauth_tokens -> oauth_clients (client_id));
+diesel::joinable!(oauth_tokens -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(totp_credentials -> users (user_id));
+diesel::joinable!(user_tokens -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+	api_tokens,
+	device_credentials,
+	device_group_members,
+	device_groups,
+	device_message_nonces,
+	device_telemetry,
+	device_transfers,
+	devices,
+	lights,
+	oauth_clients,
+	oauth_tokens,
+	recovery_codes,
+	sessions,
+	totp_credentials,
+	traits,
+	user_tokens,
+	users,
+);
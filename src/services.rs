@@ -0,0 +1,11 @@
+// Business logic shared by the REST routes and the Google fulfillment. Nothing
+// in here knows about Rocket: the functions take a database connection and a
+// DeviceGateway, so they can be run against a mock gateway in tests.
+pub mod api_token;
+pub mod control;
+pub mod device;
+pub mod gateway;
+pub mod mailer;
+pub mod oauth_client;
+pub mod two_factor;
+pub mod user;
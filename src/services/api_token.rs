@@ -0,0 +1,72 @@
+// Personal API tokens for scripts and home automation, sent as
+// "Authorization: Bearer". Each token carries scopes limiting the routes it is
+// accepted on, see API_TOKEN_ROUTES in routes/api_token.rs.
+use chrono::Duration;
+use diesel::PgConnection;
+use uuid::Uuid;
+
+use crate::constants::API_TOKEN_SCOPES;
+use crate::models::api_token::{ApiToken, ApiTokenInfo, CreateApiToken};
+use crate::error::ApiError;
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_TOKENS_PER_USER: usize = 50;
+const MAX_VALID_DAYS: u32 = 3650;
+
+pub struct ApiTokenService;
+
+impl ApiTokenService {
+	pub fn list(user_id: i32, conn: &mut PgConnection) -> Result<Vec<ApiTokenInfo>, ApiError> {
+		Ok(ApiToken::get_active_by_user(user_id, conn)?
+			.iter()
+			.map(ApiToken::info)
+			.collect())
+	}
+
+	// The token itself is only ever returned here, only its hash is stored
+	pub fn create(
+		user_id: i32,
+		request: &CreateApiToken,
+		conn: &mut PgConnection,
+	) -> Result<(ApiTokenInfo, String), ApiError> {
+		let name = request.name.trim();
+		if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+			return Err(ApiError::invalid_field("name", "name must be 1 to 64 characters"));
+		}
+		if request.scopes.is_empty() {
+			return Err(ApiError::invalid_field("scopes", "at least one scope is needed"));
+		}
+		if let Some(scope) = request
+			.scopes
+			.iter()
+			.find(|scope| !API_TOKEN_SCOPES.contains(&scope.as_str()))
+		{
+			return Err(ApiError::invalid_field("scopes", &format!("unknown scope {}", scope)));
+		}
+		let valid_for = match request.expires_in_days {
+			Some(days) if days == 0 || days > MAX_VALID_DAYS => {
+				return Err(ApiError::invalid_field(
+					"expires_in_days",
+					"must be between 1 and 3650 days",
+				))
+			}
+			Some(days) => Some(Duration::days(days.into())),
+			None => None,
+		};
+		if ApiToken::get_active_by_user(user_id, conn)?.len() >= MAX_TOKENS_PER_USER {
+			return Err(ApiError::invalid_field("name", "too many API tokens, revoke some first"));
+		}
+		let mut scopes = request.scopes.clone();
+		scopes.sort();
+		scopes.dedup();
+		let (api_token, token) = ApiToken::create(user_id, name, &scopes, valid_for, conn)?;
+		Ok((api_token.info(), token))
+	}
+
+	pub fn revoke(user_id: i32, token_id: Uuid, conn: &mut PgConnection) -> Result<(), ApiError> {
+		if ApiToken::revoke(token_id, user_id, conn)? == 0 {
+			return Err(ApiError::ApiTokenNotFound);
+		}
+		Ok(())
+	}
+}
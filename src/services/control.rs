@@ -0,0 +1,141 @@
+// Changing the state of lights, for the REST routes, groups and Google
+use diesel::{Connection, PgConnection, QueryResult};
+use futures::future::join_all;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::effects::EffectRunner;
+use crate::models::device::{BulkDeviceData, BulkResult, DeviceData};
+use crate::models::light::{Light, LightState};
+use crate::error::ApiError;
+
+use super::gateway::{with_timeout, DeviceGateway};
+
+pub struct ControlService;
+
+impl ControlService {
+	// Sends the states concurrently. Whatever ran on a light before is replaced,
+	// transitions and effects are stepped by the backend for lights that can't
+	// run them, the first step is what gets sent here.
+	pub fn send_commands(
+		targets: &[(Light, LightState)],
+		gateway: &dyn DeviceGateway,
+		effects: &EffectRunner,
+	) -> Vec<BulkResult> {
+		let rt = Runtime::new().unwrap();
+		let responses = rt.block_on(join_all(targets.iter().map(|(light, light_state)| {
+			effects.cancel(light.light_id);
+			let command = if light_state.has_effect() && !light.native_effects {
+				EffectRunner::first_frame(&LightState::from_light(light), light_state)
+			} else {
+				light_state.clone()
+			};
+			with_timeout(gateway.command(light.light_id, command))
+		})));
+		targets
+			.iter()
+			.zip(responses)
+			.map(|((light, _), response)| BulkResult {
+				device_id: light.light_id,
+				success: response.is_ok(),
+				error: response.err().map(ApiError::from),
+			})
+			.collect()
+	}
+
+	// Applies one target state to many devices, the state of every device that
+	// accepted it is saved in one transaction. Devices the user doesn't own and
+	// devices pending removal are reported as not existing.
+	pub fn apply_state_to_devices(
+		user_id: i32,
+		device_ids: &[Uuid],
+		target: &BulkDeviceData,
+		gateway: &dyn DeviceGateway,
+		effects: &EffectRunner,
+		conn: &mut PgConnection,
+	) -> QueryResult<Vec<BulkResult>> {
+		let owned_lights = Light::get_active_devices_by_user(user_id, conn)?;
+		let mut results: Vec<BulkResult> = vec![];
+		let mut targets: Vec<(Light, LightState)> = vec![];
+		for device_id in device_ids.iter() {
+			if targets.iter().any(|(light, _)| light.light_id == *device_id) {
+				continue;
+			}
+			match owned_lights.iter().find(|light| light.light_id == *device_id) {
+				Some(light) => {
+					let light_state = LightState::merged(light, &target.for_device(*device_id));
+					targets.push((light.clone(), light_state));
+				}
+				None => results.push(BulkResult {
+					device_id: *device_id,
+					success: false,
+					error: Some(ApiError::DeviceNotFound),
+				}),
+			}
+		}
+
+		let sent = ControlService::send_commands(&targets, gateway, effects);
+		let accepted: Vec<&(Light, LightState)> = targets
+			.iter()
+			.zip(sent.iter())
+			.filter(|(_, result)| result.success)
+			.map(|(target, _)| target)
+			.collect();
+		results.extend(sent.iter().cloned());
+
+		conn.transaction::<_, diesel::result::Error, _>(|local_conn| {
+			for (light, light_state) in accepted.iter() {
+				Light::update_device(
+					light.light_id,
+					light_state,
+					local_conn,
+					light.signature.clone(),
+					light.user_id,
+				)?;
+			}
+			Ok(())
+		})?;
+		for (light, light_state) in accepted {
+			if light.native_effects {
+				effects.record_native(light.light_id, light_state);
+			} else {
+				effects.start(
+					light.light_id,
+					LightState::from_light(light),
+					light_state.clone(),
+				);
+			}
+		}
+		Ok(results)
+	}
+
+	// Sets the state of one of the user's lights
+	pub fn set_device_state(
+		user_id: i32,
+		device_data: DeviceData,
+		gateway: &dyn DeviceGateway,
+		effects: &EffectRunner,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let device = Light::get_device_by_id(device_data.device_id, conn)?
+			.ok_or(ApiError::DeviceNotFound)?;
+		if user_id != device.user_id {
+			return Err(ApiError::NotOwner);
+		}
+		let results = ControlService::apply_state_to_devices(
+			user_id,
+			&[device.light_id],
+			&device_data.into(),
+			gateway,
+			effects,
+			conn,
+		)?;
+		match results.into_iter().next() {
+			Some(BulkResult { success: true, .. }) => Ok(()),
+			Some(BulkResult {
+				error: Some(error), ..
+			}) => Err(error),
+			_ => Err(ApiError::Internal),
+		}
+	}
+}
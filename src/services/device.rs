@@ -0,0 +1,135 @@
+// Registering, renaming and removing devices
+use diesel::PgConnection;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::attestation::{AttestationError, AttestationKeys, DeviceIdentity};
+use crate::constants::{DEVICE_ACTIVE, NON_RGB_LIGHT, RGB_LIGHT};
+use crate::decommission::{decommission, DecommissionStatus};
+use crate::effects::EffectRunner;
+use crate::models::device::{Device, NewDevice};
+use crate::models::device_credential::DeviceCredential;
+use crate::models::light::{Light, Trait};
+use crate::error::ApiError;
+
+use super::gateway::{with_timeout, DeviceGateway};
+
+pub struct DeviceService;
+
+impl DeviceService {
+	// The device when it exists and belongs to the user
+	pub fn check_owner(device: Option<Device>, user_id: i32) -> Result<Device, ApiError> {
+		match device {
+			Some(device) if device.user_id == user_id => Ok(device),
+			Some(_) => Err(ApiError::NotOwner),
+			None => Err(ApiError::DeviceNotFound),
+		}
+	}
+
+	pub fn owned(
+		device_id: Uuid,
+		user_id: i32,
+		conn: &mut PgConnection,
+	) -> Result<Device, ApiError> {
+		DeviceService::check_owner(Device::get_device_by_id(device_id, conn)?, user_id)
+	}
+
+	// Adds the device to the user's account and returns its device key, which
+	// is handed out only this once. The device has to be signed by one of the
+	// manufacturer keys.
+	pub fn register(
+		new_device: &NewDevice,
+		user_id: i32,
+		attestation_keys: &AttestationKeys,
+		gateway: &dyn DeviceGateway,
+		conn: &mut PgConnection,
+	) -> Result<String, ApiError> {
+		let identity = DeviceIdentity {
+			id: new_device.id,
+			type_: new_device.type_.clone(),
+		};
+		if let Err(err) = attestation_keys.verify(&identity, &new_device.secret) {
+			let error = match err {
+				AttestationError::MalformedSignature => "signature is wrong",
+				AttestationError::UnknownKeyId(_) => "device was signed with an unknown key",
+				AttestationError::InvalidSignature => "failed to authenticate device",
+				_ => "failed to verify device signature",
+			};
+			println!("device {} failed attestation: {}", new_device.id, err);
+			return Err(ApiError::AttestationFailed(error));
+		}
+		if ![RGB_LIGHT, NON_RGB_LIGHT].contains(&new_device.type_.as_str()) {
+			return Err(ApiError::invalid_field("type_", "invalid device type"));
+		}
+		if Device::get_device_by_id(new_device.id, conn)?.is_some() {
+			return Err(ApiError::DeviceAlreadyRegistered);
+		}
+		// Sets the device traits used for google home integrartion
+		let traits: Vec<Option<String>> =
+			Trait::get_traits_for_device_type(new_device.type_.clone(), conn)?
+				.iter()
+				.map(|trait_| Some(trait_.trait_.clone()))
+				.collect();
+		let device = Device {
+			id: new_device.id,
+			user_id,
+			type_: new_device.type_.clone(),
+			internal_name: new_device.type_.clone() + new_device.id.to_string().as_str(),
+			name: new_device.name.clone(),
+			nicknames: vec![],
+			traits,
+			state: DEVICE_ACTIVE.to_string(),
+			removal_requested_at: None,
+		};
+		let credential = conn.build_transaction().run::<_, ApiError, _>(|local_conn| {
+			Light::insert_device(device.id, local_conn, new_device.secret.clone(), user_id)?;
+			Device::insert_device(device, local_conn)?;
+			Ok(DeviceCredential::issue(new_device.id, local_conn)?)
+		})?;
+		// Not inside the transaction, it would stay open for as long as the
+		// gateway takes. The device is removed again when the gateway refuses it.
+		let rt = Runtime::new().unwrap();
+		let created = rt.block_on(with_timeout(gateway.create(new_device.id, &new_device.type_)));
+		if let Err(err) = created {
+			conn.transaction::<_, ApiError, _>(|local_conn| {
+				DeviceCredential::revoke_all(new_device.id, local_conn)?;
+				Light::remove_device(new_device.id, local_conn)?;
+				Device::remove_device(new_device.id, local_conn)?;
+				Ok(())
+			})?;
+			return Err(err.into());
+		}
+		Ok(credential.secret_key)
+	}
+
+	pub fn rename(
+		device: &Device,
+		new_name: &str,
+		conn: &mut PgConnection,
+	) -> Result<Device, ApiError> {
+		if new_name.trim().is_empty() {
+			return Err(ApiError::invalid_field("name", "Device name can't be empty"));
+		}
+		Ok(Device::update_device_name(device.id, new_name, conn)?)
+	}
+
+	// Issues a new device key, the previous one stays valid for a short grace period
+	pub fn rotate_key(device: &Device, conn: &mut PgConnection) -> Result<String, ApiError> {
+		Ok(DeviceCredential::rotate(device.id, conn)?.secret_key)
+	}
+
+	pub fn is_online(device: &Device, gateway: &dyn DeviceGateway) -> Result<bool, ApiError> {
+		let rt = Runtime::new().unwrap();
+		Ok(rt.block_on(with_timeout(gateway.is_online(device.id)))?)
+	}
+
+	// See decommission.rs
+	pub fn remove(
+		device: &Device,
+		gateway: &dyn DeviceGateway,
+		effects: &EffectRunner,
+		conn: &mut PgConnection,
+	) -> Result<DecommissionStatus, ApiError> {
+		Ok(decommission(device, gateway, effects, conn)?)
+	}
+}
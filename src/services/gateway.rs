@@ -0,0 +1,80 @@
+// The gateway relays our commands to the devices. Services only talk to devices
+// through DeviceGateway, the routes get the implementation from Rocket's managed
+// state. DEVICE_GATEWAY picks it: "coap" (the default) for the real gateway,
+// "simulator" for in-memory lights.
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::models::light::LightState;
+use crate::error::ApiError;
+
+pub mod coap;
+pub mod simulator;
+
+use self::coap::CoapGateway;
+use self::simulator::SimulatedGateway;
+
+const DEFAULT_ACK_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GatewayError {
+	// The gateway doesn't know the device
+	UnknownDevice,
+	// The gateway or the device couldn't be reached, or refused the request
+	Unreachable,
+	// No answer within ack_timeout()
+	Timeout,
+}
+
+impl From<GatewayError> for ApiError {
+	fn from(err: GatewayError) -> Self {
+		match err {
+			GatewayError::Timeout => ApiError::DeviceTimeout,
+			GatewayError::UnknownDevice | GatewayError::Unreachable => ApiError::DeviceUnreachable,
+		}
+	}
+}
+
+pub type GatewayResult<T> = Result<T, GatewayError>;
+
+pub trait DeviceGateway: Send + Sync {
+	// Makes the gateway accept messages for the device
+	fn create(&self, device_id: Uuid, device_type: &str) -> BoxFuture<'_, GatewayResult<()>>;
+	fn remove(&self, device_id: Uuid) -> BoxFuture<'_, GatewayResult<()>>;
+	// Resolves once the device acknowledged the state
+	fn command(&self, device_id: Uuid, state: LightState) -> BoxFuture<'_, GatewayResult<()>>;
+	fn is_online(&self, device_id: Uuid) -> BoxFuture<'_, GatewayResult<bool>>;
+}
+
+// What the routes and the effect runner share
+pub type Gateway = Arc<dyn DeviceGateway>;
+
+// How long a device (through the gateway) gets to answer a command
+pub fn ack_timeout() -> Duration {
+	let millis = env::var("DEVICE_ACK_TIMEOUT_MS")
+		.ok()
+		.and_then(|millis| millis.parse().ok())
+		.unwrap_or(DEFAULT_ACK_TIMEOUT_MS);
+	Duration::from_millis(millis)
+}
+
+// Bounds any gateway call by ack_timeout()
+pub async fn with_timeout<T>(call: BoxFuture<'_, GatewayResult<T>>) -> GatewayResult<T> {
+	match timeout(ack_timeout(), call).await {
+		Ok(result) => result,
+		Err(_) => Err(GatewayError::Timeout),
+	}
+}
+
+pub fn from_env() -> Gateway {
+	match env::var("DEVICE_GATEWAY").as_deref() {
+		Ok("coap") | Err(_) => Arc::new(CoapGateway::from_env()),
+		Ok("simulator") => Arc::new(SimulatedGateway::from_env()),
+		Ok(other) => panic!("unknown DEVICE_GATEWAY {}", other),
+	}
+}
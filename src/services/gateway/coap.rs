@@ -0,0 +1,107 @@
+// The CoAP gateway at COAP_IP:COAP_PORT. Every request opens its own client,
+// the gateway answers once the device acknowledged.
+use std::env;
+
+use coap_client::{backend::Tokio, ClientOptions, HostOptions, RequestOptions, TokioClient};
+use coap_lite::Packet;
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::models::light::LightState;
+
+use super::{DeviceGateway, GatewayError, GatewayResult};
+
+type Client = coap_client::Client<std::io::Error, Tokio>;
+
+pub struct CoapGateway {
+	host: String,
+	port: u16,
+}
+
+// 4.04 is how the gateway says it doesn't know the device
+fn acknowledged(response: Result<Packet, coap_client::Error<std::io::Error>>) -> GatewayResult<()> {
+	let packet = response.map_err(|_| GatewayError::Unreachable)?;
+	let code = packet.header.get_code();
+	if code == "4.04" {
+		Err(GatewayError::UnknownDevice)
+	} else if code.starts_with("2.") {
+		Ok(())
+	} else {
+		Err(GatewayError::Unreachable)
+	}
+}
+
+fn request_options() -> RequestOptions {
+	let mut req_opts = RequestOptions::default();
+	req_opts.non_confirmable = false;
+	req_opts
+}
+
+impl CoapGateway {
+	pub fn new(host: String, port: u16) -> Self {
+		CoapGateway { host, port }
+	}
+
+	pub fn from_env() -> Self {
+		let host = env::var("COAP_IP").expect("set COAP_IP");
+		let port = env::var("COAP_PORT").expect("set COAP_PORT");
+		CoapGateway::new(host, port.parse().expect("COAP_PORT must be a port number"))
+	}
+
+	async fn client(&self) -> GatewayResult<Client> {
+		let mut host_opts = HostOptions::default();
+		host_opts.host = self.host.clone();
+		host_opts.port = self.port;
+		TokioClient::connect(host_opts, &ClientOptions::default())
+			.await
+			.map_err(|_| GatewayError::Unreachable)
+	}
+
+	async fn put(&self, path: String, payload: Option<Vec<u8>>) -> GatewayResult<()> {
+		let mut client = self.client().await?;
+		acknowledged(
+			client
+				.put_and_get_packet(&path, payload.as_deref(), &request_options())
+				.await,
+		)
+	}
+}
+
+impl DeviceGateway for CoapGateway {
+	// Every device type we support is a light
+	fn create(&self, device_id: Uuid, _device_type: &str) -> BoxFuture<'_, GatewayResult<()>> {
+		self.put(
+			"/lights/create".to_string(),
+			Some(device_id.to_string().into_bytes()),
+		)
+		.boxed()
+	}
+
+	fn remove(&self, device_id: Uuid) -> BoxFuture<'_, GatewayResult<()>> {
+		self.put(format!("/lights/remove/{}", device_id), None).boxed()
+	}
+
+	fn command(&self, device_id: Uuid, state: LightState) -> BoxFuture<'_, GatewayResult<()>> {
+		let payload = serde_json::to_vec(&state).expect("light state is always valid JSON");
+		self.put(format!("/lights/{}", device_id), Some(payload)).boxed()
+	}
+
+	fn is_online(&self, device_id: Uuid) -> BoxFuture<'_, GatewayResult<bool>> {
+		async move {
+			let mut client = self.client().await?;
+			let response = client
+				.get(
+					&format!("/lights/is_online/{}", device_id),
+					&request_options(),
+				)
+				.await
+				.map_err(|_| GatewayError::Unreachable)?;
+			serde_json::from_slice::<Value>(&response)
+				.ok()
+				.and_then(|body| body["isOnline"].as_bool())
+				.ok_or(GatewayError::Unreachable)
+		}
+		.boxed()
+	}
+}
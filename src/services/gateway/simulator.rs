@@ -0,0 +1,152 @@
+// Virtual lights kept in memory, for local development and tests. It answers
+// like the CoAP gateway does: devices have to be created before they accept
+// commands, and a light that is offline never acknowledges anything.
+//
+// SIMULATOR_LATENCY_MS delays every answer and SIMULATOR_FAILURE_RATE (0 to 1)
+// is the share of requests that fail as if the gateway was unreachable.
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt};
+use rand_core::{OsRng, RngCore};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::models::light::LightState;
+
+use super::{DeviceGateway, GatewayError, GatewayResult};
+
+#[derive(Clone)]
+pub struct SimulatedLight {
+	pub device_type: String,
+	pub online: bool,
+	// The last state the light acknowledged
+	pub state: Option<LightState>,
+}
+
+pub struct SimulatedGateway {
+	lights: Mutex<HashMap<Uuid, SimulatedLight>>,
+	latency: Duration,
+	failure_rate: f64,
+}
+
+impl SimulatedGateway {
+	pub fn new(latency: Duration, failure_rate: f64) -> Self {
+		SimulatedGateway {
+			lights: Mutex::new(HashMap::new()),
+			latency,
+			failure_rate: failure_rate.clamp(0.0, 1.0),
+		}
+	}
+
+	pub fn from_env() -> Self {
+		let latency_ms = env::var("SIMULATOR_LATENCY_MS")
+			.ok()
+			.and_then(|latency_ms| latency_ms.parse().ok())
+			.unwrap_or(0);
+		let failure_rate = env::var("SIMULATOR_FAILURE_RATE")
+			.ok()
+			.and_then(|failure_rate| failure_rate.parse().ok())
+			.unwrap_or(0.0);
+		SimulatedGateway::new(Duration::from_millis(latency_ms), failure_rate)
+	}
+
+	pub fn light(&self, device_id: Uuid) -> Option<SimulatedLight> {
+		self.lights.lock().unwrap().get(&device_id).cloned()
+	}
+
+	// Unplugs or plugs in a light, false when there is no such light
+	pub fn set_online(&self, device_id: Uuid, online: bool) -> bool {
+		match self.lights.lock().unwrap().get_mut(&device_id) {
+			Some(light) => {
+				light.online = online;
+				true
+			}
+			None => false,
+		}
+	}
+
+	// Waits out the latency, then fails at the configured rate
+	async fn network(&self) -> GatewayResult<()> {
+		if !self.latency.is_zero() {
+			sleep(self.latency).await;
+		}
+		let roll = OsRng.next_u32() as f64 / u32::MAX as f64;
+		if roll < self.failure_rate {
+			return Err(GatewayError::Unreachable);
+		}
+		Ok(())
+	}
+}
+
+impl DeviceGateway for SimulatedGateway {
+	fn create(&self, device_id: Uuid, device_type: &str) -> BoxFuture<'_, GatewayResult<()>> {
+		let device_type = device_type.to_string();
+		async move {
+			self.network().await?;
+			self.lights.lock().unwrap().insert(
+				device_id,
+				SimulatedLight {
+					device_type,
+					online: true,
+					state: None,
+				},
+			);
+			Ok(())
+		}
+		.boxed()
+	}
+
+	fn remove(&self, device_id: Uuid) -> BoxFuture<'_, GatewayResult<()>> {
+		async move {
+			self.network().await?;
+			match self.lights.lock().unwrap().remove(&device_id) {
+				Some(_) => Ok(()),
+				None => Err(GatewayError::UnknownDevice),
+			}
+		}
+		.boxed()
+	}
+
+	fn command(&self, device_id: Uuid, state: LightState) -> BoxFuture<'_, GatewayResult<()>> {
+		async move {
+			self.network().await?;
+			match self.lights.lock().unwrap().get_mut(&device_id) {
+				Some(light) if light.online => {
+					light.state = Some(state);
+					Ok(())
+				}
+				Some(_) => Err(GatewayError::Timeout),
+				None => Err(GatewayError::UnknownDevice),
+			}
+		}
+		.boxed()
+	}
+
+	fn is_online(&self, device_id: Uuid) -> BoxFuture<'_, GatewayResult<bool>> {
+		async move {
+			self.network().await?;
+			self.light(device_id)
+				.map(|light| light.online)
+				.ok_or(GatewayError::UnknownDevice)
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::runtime::Runtime;
+
+	#[test]
+	fn simulator_fails_at_the_configured_rate() {
+		let rt = Runtime::new().unwrap();
+		let gateway = SimulatedGateway::new(Duration::ZERO, 1.0);
+		let id = Uuid::new_v4();
+		assert_eq!(rt.block_on(gateway.create(id, "light")), Err(GatewayError::Unreachable));
+		assert!(gateway.light(id).is_none());
+	}
+}
@@ -0,0 +1,158 @@
+// Outgoing email. MAILER picks the transport: "log" (the default) prints the
+// emails, or writes them to MAIL_DIR when that is set, "smtp" sends them
+// through SMTP_HOST with SMTP_USERNAME/SMTP_PASSWORD. MAIL_FROM is the sender.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::Utc;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+const DEFAULT_FROM: &str = "DIY IoT <no-reply@localhost>";
+
+pub struct Email {
+	pub to: String,
+	pub subject: String,
+	pub body: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "failed to send email: {}", self.0)
+	}
+}
+
+pub trait MailTransport: Send + Sync {
+	fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+// What the routes get from Rocket's managed state
+pub type Mailer = Arc<dyn MailTransport>;
+
+pub fn from_env() -> Mailer {
+	match env::var("MAILER").as_deref() {
+		Ok("log") | Err(_) => {
+			Arc::new(LogMailer::new(env::var("MAIL_DIR").ok().map(PathBuf::from)))
+		}
+		Ok("smtp") => Arc::new(SmtpMailer::from_env()),
+		Ok(other) => panic!("unknown MAILER {}", other),
+	}
+}
+
+fn sender() -> String {
+	env::var("MAIL_FROM").unwrap_or_else(|_| DEFAULT_FROM.to_string())
+}
+
+// For local development, nothing leaves the machine
+pub struct LogMailer {
+	dir: Option<PathBuf>,
+}
+
+impl LogMailer {
+	pub fn new(dir: Option<PathBuf>) -> Self {
+		LogMailer { dir }
+	}
+}
+
+// The address comes from the request, only plain characters make it into the
+// file name so it can't point outside of MAIL_DIR
+fn file_name(address: &str) -> String {
+	address
+		.chars()
+		.map(|c| match c {
+			'a'..='z' | 'A'..='Z' | '0'..='9' | '@' | '.' | '-' | '_' | '+' => c,
+			_ => '_',
+		})
+		.take(64)
+		.collect()
+}
+
+impl MailTransport for LogMailer {
+	fn send(&self, email: &Email) -> Result<(), MailError> {
+		let text = format!(
+			"From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+			sender(),
+			email.to,
+			email.subject,
+			email.body
+		);
+		match &self.dir {
+			Some(dir) => {
+				let millis = Utc::now().timestamp_millis();
+				let path = dir.join(format!("{}-{}.eml", millis, file_name(&email.to)));
+				fs::write(&path, text).map_err(|err| MailError(err.to_string()))
+			}
+			None => {
+				println!("{}", text);
+				Ok(())
+			}
+		}
+	}
+}
+
+pub struct SmtpMailer {
+	transport: SmtpTransport,
+	from: String,
+}
+
+impl SmtpMailer {
+	pub fn from_env() -> Self {
+		let host = env::var("SMTP_HOST").expect("set SMTP_HOST");
+		let mut transport = SmtpTransport::relay(&host).expect("invalid SMTP_HOST");
+		let username = env::var("SMTP_USERNAME");
+		let password = env::var("SMTP_PASSWORD");
+		if let (Ok(username), Ok(password)) = (username, password) {
+			transport = transport.credentials(Credentials::new(username, password));
+		}
+		SmtpMailer {
+			transport: transport.build(),
+			from: sender(),
+		}
+	}
+}
+
+impl MailTransport for SmtpMailer {
+	fn send(&self, email: &Email) -> Result<(), MailError> {
+		let message = Message::builder()
+			.from(self.from.parse().map_err(|_| MailError("invalid MAIL_FROM".to_string()))?)
+			.to(email.to.parse().map_err(|_| MailError(format!("invalid address {}", email.to)))?)
+			.subject(email.subject.clone())
+			.body(email.body.clone())
+			.map_err(|err| MailError(err.to_string()))?;
+		self.transport
+			.send(&message)
+			.map(|_| ())
+			.map_err(|err| MailError(err.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use uuid::Uuid;
+
+	#[test]
+	fn logged_emails_stay_in_mail_dir() {
+		let dir = env::temp_dir().join(format!("mail-{}", Uuid::new_v4()));
+		std::fs::create_dir(&dir).unwrap();
+		let email = Email {
+			to: "../../x/y@example.com".to_string(),
+			subject: "Hi".to_string(),
+			body: String::new(),
+		};
+		LogMailer::new(Some(dir.clone())).send(&email).expect("written");
+		let names: Vec<String> = std::fs::read_dir(&dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+			.collect();
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(names.len(), 1);
+		assert!(names[0].ends_with("-.._.._x_y@example.com.eml"), "{}", names[0]);
+	}
+}
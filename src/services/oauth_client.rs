@@ -0,0 +1,219 @@
+// OAuth clients live in the database. Admins create them, rotate the secrets
+// of confidential ones and disable them; trusted integrations can register
+// themselves (RFC 7591) with the token in OAUTH_REGISTRATION_TOKEN.
+use diesel::{Connection, PgConnection};
+use reqwest::Url;
+use std::env;
+
+use crate::constants::OAUTH_SCOPES;
+use crate::models::oauth_client::{
+	ClientRegistration, CreateOAuthClient, OAuthClient, OAuthClientInfo,
+};
+use crate::models::oauth_token::OAuthToken;
+use crate::models::user::User;
+use crate::error::ApiError;
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_REDIRECT_URIS: usize = 10;
+const AUTH_METHOD_SECRET: &str = "client_secret_basic";
+const AUTH_METHOD_NONE: &str = "none";
+
+// RFC 7591 section 3.2.2, the description is shown to the integration
+#[derive(Debug)]
+pub enum RegistrationError {
+	InvalidToken,
+	InvalidRedirectUri(String),
+	InvalidClientMetadata(String),
+	Database,
+}
+
+impl From<diesel::result::Error> for RegistrationError {
+	fn from(err: diesel::result::Error) -> Self {
+		println!("database error: {}", err);
+		RegistrationError::Database
+	}
+}
+
+// Redirects go to https, or to http on the machine itself for development
+fn check_redirect_uris(redirect_uris: &[String]) -> Result<(), String> {
+	if redirect_uris.is_empty() || redirect_uris.len() > MAX_REDIRECT_URIS {
+		return Err("between 1 and 10 redirect URIs are needed".to_string());
+	}
+	for redirect_uri in redirect_uris {
+		let url = Url::parse(redirect_uri).map_err(|_| format!("{} is not a URL", redirect_uri))?;
+		let local = matches!(url.host_str(), Some("localhost") | Some("127.0.0.1"));
+		if url.scheme() != "https" && !(url.scheme() == "http" && local) {
+			return Err(format!("{} has to use https", redirect_uri));
+		}
+		if url.fragment().is_some() {
+			return Err(format!("{} can't have a fragment", redirect_uri));
+		}
+	}
+	Ok(())
+}
+
+fn check_scopes(scopes: &[String]) -> Result<(), String> {
+	if scopes.is_empty() {
+		return Err("at least one scope is needed".to_string());
+	}
+	match scopes.iter().find(|scope| !OAUTH_SCOPES.contains(&scope.as_str())) {
+		Some(scope) => Err(format!("unknown scope {}", scope)),
+		None => Ok(()),
+	}
+}
+
+fn check_client_id(client_id: &str) -> Result<(), String> {
+	let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+	if client_id.is_empty() || client_id.len() > MAX_NAME_LEN || !client_id.chars().all(allowed) {
+		return Err("client_id must be 1 to 64 letters, digits, '-', '_' or '.'".to_string());
+	}
+	Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+	if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+		return Err("name must be 1 to 64 characters".to_string());
+	}
+	Ok(())
+}
+
+pub struct OAuthClientService;
+
+impl OAuthClientService {
+	pub fn ensure_admin(user_id: i32, conn: &mut PgConnection) -> Result<(), ApiError> {
+		match User::get_user_by_id(user_id, conn)? {
+			Some(user) if user.is_admin => Ok(()),
+			_ => Err(ApiError::AdminRequired),
+		}
+	}
+
+	pub fn list(admin_id: i32, conn: &mut PgConnection) -> Result<Vec<OAuthClientInfo>, ApiError> {
+		OAuthClientService::ensure_admin(admin_id, conn)?;
+		Ok(OAuthClient::get_all(conn)?.iter().map(OAuthClient::info).collect())
+	}
+
+	// The secret is only ever returned here and by rotate_secret
+	pub fn create(
+		admin_id: i32,
+		request: &CreateOAuthClient,
+		conn: &mut PgConnection,
+	) -> Result<(OAuthClientInfo, Option<String>), ApiError> {
+		OAuthClientService::ensure_admin(admin_id, conn)?;
+		let name = request.name.trim();
+		check_name(name).map_err(|err| ApiError::invalid_field("name", &err))?;
+		check_redirect_uris(&request.redirect_uris)
+			.map_err(|err| ApiError::invalid_field("redirect_uris", &err))?;
+		check_scopes(&request.scopes).map_err(|err| ApiError::invalid_field("scopes", &err))?;
+		if let Some(client_id) = &request.client_id {
+			check_client_id(client_id).map_err(|err| ApiError::invalid_field("client_id", &err))?;
+			// Disabled clients keep their id, their grants reference it
+			if OAuthClient::get(client_id, conn)?.is_some() {
+				return Err(ApiError::invalid_field("client_id", "client_id is already taken"));
+			}
+		}
+		let (client, secret) = OAuthClient::create(
+			request.client_id.as_deref(),
+			name,
+			&request.redirect_uris,
+			&request.scopes,
+			request.confidential,
+			conn,
+		)?;
+		Ok((client.info(), secret))
+	}
+
+	pub fn rotate_secret(
+		admin_id: i32,
+		client_id: &str,
+		conn: &mut PgConnection,
+	) -> Result<String, ApiError> {
+		OAuthClientService::ensure_admin(admin_id, conn)?;
+		let client = OAuthClient::get(client_id, conn)?.ok_or(ApiError::OAuthClientNotFound)?;
+		if !client.is_confidential() {
+			return Err(ApiError::invalid_field("client_id", "public clients have no secret"));
+		}
+		OAuthClient::rotate_secret(client_id, conn)?.ok_or(ApiError::OAuthClientNotFound)
+	}
+
+	pub fn disable(
+		admin_id: i32,
+		client_id: &str,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		OAuthClientService::ensure_admin(admin_id, conn)?;
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			if OAuthClient::disable(client_id, local_conn)? == 0 {
+				return Err(ApiError::OAuthClientNotFound);
+			}
+			OAuthToken::revoke_by_client(client_id, local_conn)?;
+			Ok(())
+		})
+	}
+
+	// Registration is off unless OAUTH_REGISTRATION_TOKEN is set
+	fn check_registration_token(token: Option<&str>) -> bool {
+		match (env::var("OAUTH_REGISTRATION_TOKEN"), token) {
+			(Ok(expected), Some(token)) if !expected.is_empty() => {
+				expected.len() == token.len()
+					&& openssl::memcmp::eq(expected.as_bytes(), token.as_bytes())
+			}
+			_ => false,
+		}
+	}
+
+	// token is the initial access token the integration was given
+	pub fn register(
+		token: Option<&str>,
+		registration: &ClientRegistration,
+		conn: &mut PgConnection,
+	) -> Result<(OAuthClient, Option<String>), RegistrationError> {
+		if !OAuthClientService::check_registration_token(token) {
+			return Err(RegistrationError::InvalidToken);
+		}
+		check_redirect_uris(&registration.redirect_uris)
+			.map_err(RegistrationError::InvalidRedirectUri)?;
+		let name = registration.client_name.as_deref().unwrap_or("").trim();
+		check_name(name).map_err(RegistrationError::InvalidClientMetadata)?;
+		let scopes: Vec<String> = match &registration.scope {
+			Some(scope) => scope.split_whitespace().map(str::to_string).collect(),
+			None => OAUTH_SCOPES.iter().map(|scope| scope.to_string()).collect(),
+		};
+		check_scopes(&scopes).map_err(RegistrationError::InvalidClientMetadata)?;
+		let confidential = match registration.token_endpoint_auth_method.as_deref() {
+			None | Some(AUTH_METHOD_SECRET) => true,
+			Some(AUTH_METHOD_NONE) => false,
+			Some(method) => {
+				return Err(RegistrationError::InvalidClientMetadata(format!(
+					"token_endpoint_auth_method {} is not supported",
+					method
+				)))
+			}
+		};
+		let supported = |values: &Option<Vec<String>>, allowed: &[&str]| {
+			values
+				.iter()
+				.flatten()
+				.all(|value| allowed.contains(&value.as_str()))
+		};
+		if !supported(&registration.grant_types, &["authorization_code", "refresh_token"]) {
+			return Err(RegistrationError::InvalidClientMetadata(
+				"only the authorization_code and refresh_token grants are supported".to_string(),
+			));
+		}
+		if !supported(&registration.response_types, &["code"]) {
+			return Err(RegistrationError::InvalidClientMetadata(
+				"only the code response type is supported".to_string(),
+			));
+		}
+		let redirect_uris = &registration.redirect_uris;
+		Ok(OAuthClient::create(None, name, redirect_uris, &scopes, confidential, conn)?)
+	}
+
+	pub fn auth_method(client: &OAuthClient) -> &'static str {
+		if client.is_confidential() {
+			AUTH_METHOD_SECRET
+		} else {
+			AUTH_METHOD_NONE
+		}
+	}
+}
@@ -0,0 +1,196 @@
+// TOTP second factor. Once enabled, /login only hands out a challenge and the
+// session is started by /login/two_factor with a code from the authenticator
+// app or one of the recovery codes.
+use chrono::{Duration, Utc};
+use diesel::{Connection, PgConnection};
+use rand_core::{OsRng, RngCore};
+use std::env;
+use uuid::Uuid;
+
+use crate::constants::TOKEN_LOGIN_CHALLENGE;
+use crate::models::session::Session;
+use crate::models::two_factor::{RecoveryCode, TotpCredential, TotpSetup};
+use crate::models::user::User;
+use crate::models::user_token::UserToken;
+use crate::rate_limit::{retry_after, LoginThrottle};
+use crate::error::ApiError;
+use crate::totp;
+
+use super::user::UserService;
+
+const DEFAULT_ISSUER: &str = "DIY IoT";
+const RECOVERY_CODE_COUNT: usize = 10;
+const LOGIN_CHALLENGE_VALID_MINUTES: i64 = 5;
+// Wrong codes per challenge, the password has to be entered again after that
+const LOGIN_CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+
+// "abcde-fghij", from the same alphabet as TOTP secrets
+fn generate_recovery_code() -> String {
+	let mut bytes = [0u8; 7];
+	OsRng.fill_bytes(&mut bytes);
+	let code = totp::base32_encode(&bytes).to_lowercase();
+	format!("{}-{}", &code[..5], &code[5..10])
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+	(0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect()
+}
+
+// Users type the codes with or without the dash, in any case
+fn normalize_recovery_code(code: &str) -> String {
+	let code: String = code
+		.chars()
+		.filter(|c| c.is_ascii_alphanumeric())
+		.collect::<String>()
+		.to_lowercase();
+	if code.len() == 10 {
+		format!("{}-{}", &code[..5], &code[5..])
+	} else {
+		code
+	}
+}
+
+pub struct TwoFactorService;
+
+impl TwoFactorService {
+	pub fn is_enabled(user_id: i32, conn: &mut PgConnection) -> Result<bool, ApiError> {
+		Ok(TotpCredential::is_enabled(user_id, conn)?)
+	}
+
+	// A new secret, 2FA is only enabled once a code for it was entered
+	pub fn setup(user_id: i32, conn: &mut PgConnection) -> Result<TotpSetup, ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		if TotpCredential::is_enabled(user_id, conn)? {
+			return Err(ApiError::TwoFactorEnabled);
+		}
+		let secret = totp::generate_secret();
+		let encoded = totp::base32_encode(&secret);
+		TotpCredential::start_enrollment(user_id, &encoded, conn)?;
+		let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| DEFAULT_ISSUER.to_string());
+		Ok(TotpSetup {
+			otpauth_uri: totp::otpauth_uri(&secret, &issuer, &user.email),
+			secret: encoded,
+		})
+	}
+
+	// Returns the recovery codes, they are never shown again. The session used to
+	// enable 2FA counts as one started with it, every other session is ended.
+	pub fn enable(
+		user_id: i32,
+		session_id: Uuid,
+		code: &str,
+		conn: &mut PgConnection,
+	) -> Result<Vec<String>, ApiError> {
+		let credential = match TotpCredential::get(user_id, conn)? {
+			Some(credential) if credential.enabled_at.is_some() => {
+				return Err(ApiError::TwoFactorEnabled)
+			}
+			Some(credential) => credential,
+			None => return Err(ApiError::TwoFactorNotEnabled),
+		};
+		TwoFactorService::check_totp(&credential, code, conn)?;
+		let codes = generate_recovery_codes();
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			TotpCredential::enable(user_id, local_conn)?;
+			RecoveryCode::replace_all(user_id, &codes, local_conn)?;
+			Session::mark_two_factor(session_id, local_conn)?;
+			Session::revoke_others(session_id, user_id, local_conn)?;
+			Ok(())
+		})?;
+		Ok(codes)
+	}
+
+	fn check_totp(
+		credential: &TotpCredential,
+		code: &str,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let secret = totp::base32_decode(&credential.secret).ok_or(ApiError::Internal)?;
+		match totp::verify(&secret, code, Utc::now().timestamp()) {
+			Some(step) if TotpCredential::use_step(credential.user_id, step, conn)? => Ok(()),
+			_ => Err(ApiError::InvalidCode),
+		}
+	}
+
+	// A TOTP code, or a recovery code which is used up by this
+	pub fn check_code(user_id: i32, code: &str, conn: &mut PgConnection) -> Result<(), ApiError> {
+		let credential = TotpCredential::get(user_id, conn)?
+			.filter(|credential| credential.enabled_at.is_some())
+			.ok_or(ApiError::TwoFactorNotEnabled)?;
+		if TwoFactorService::check_totp(&credential, code, conn).is_ok() {
+			return Ok(());
+		}
+		if RecoveryCode::consume(user_id, &normalize_recovery_code(code), conn)? {
+			return Ok(());
+		}
+		Err(ApiError::InvalidCode)
+	}
+
+	pub fn disable(
+		user_id: i32,
+		password: &str,
+		code: &str,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		UserService::check_password(&user, password)?;
+		TwoFactorService::check_code(user_id, code, conn)?;
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			TotpCredential::remove(user_id, local_conn)?;
+			RecoveryCode::remove_all(user_id, local_conn)?;
+			Ok(())
+		})
+	}
+
+	pub fn regenerate_recovery_codes(
+		user_id: i32,
+		code: &str,
+		conn: &mut PgConnection,
+	) -> Result<Vec<String>, ApiError> {
+		TwoFactorService::check_code(user_id, code, conn)?;
+		let codes = generate_recovery_codes();
+		RecoveryCode::replace_all(user_id, &codes, conn)?;
+		Ok(codes)
+	}
+
+	// After the password was checked, what the client sends back together with
+	// the code
+	pub fn start_login(user_id: i32, conn: &mut PgConnection) -> Result<String, ApiError> {
+		Ok(UserToken::issue(
+			user_id,
+			TOKEN_LOGIN_CHALLENGE,
+			Duration::minutes(LOGIN_CHALLENGE_VALID_MINUTES),
+			conn,
+		)?)
+	}
+
+	// The user the challenge was for, once the code is right. A wrong code
+	// leaves the challenge valid so the user can try again, until the challenge
+	// got LOGIN_CHALLENGE_MAX_ATTEMPTS of them or the throttle locks the account.
+	pub fn complete_login(
+		challenge: &str,
+		code: &str,
+		client_ip: Option<&str>,
+		throttle: &LoginThrottle,
+		conn: &mut PgConnection,
+	) -> Result<i32, ApiError> {
+		let user_id =
+			UserToken::peek(challenge, TOKEN_LOGIN_CHALLENGE, conn)?.ok_or(ApiError::InvalidToken)?;
+		let account = format!("two_factor:{}", user_id);
+		throttle
+			.check(&account, client_ip)
+			.map_err(|wait| ApiError::RateLimited(retry_after(wait)))?;
+		if let Err(err) = TwoFactorService::check_code(user_id, code, conn) {
+			throttle.record_failure(&account, client_ip);
+			UserToken::record_failure(
+				challenge,
+				TOKEN_LOGIN_CHALLENGE,
+				LOGIN_CHALLENGE_MAX_ATTEMPTS,
+				conn,
+			)?;
+			return Err(err);
+		}
+		throttle.record_success(&account, client_ip);
+		UserToken::consume(challenge, TOKEN_LOGIN_CHALLENGE, conn)?.ok_or(ApiError::InvalidToken)
+	}
+}
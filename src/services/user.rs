@@ -0,0 +1,403 @@
+// Accounts and passwords, the session cookie is left to the routes
+use argon2::{
+	password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+	Argon2,
+};
+use chrono::Duration;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::{Connection, PgConnection};
+use std::sync::OnceLock;
+
+use uuid::Uuid;
+
+use crate::constants::{
+	TOKEN_CHANGE_EMAIL, TOKEN_RESET_PASSWORD, TOKEN_VERIFY_EMAIL, TRANSFER_CANCELLED,
+	TRANSFER_DECLINED,
+};
+use crate::decommission::DecommissionStatus;
+use crate::effects::EffectRunner;
+use crate::frontend::Frontend;
+use crate::models::api_token::ApiToken;
+use crate::models::device::Device;
+use crate::models::device_transfer::DeviceTransfer;
+use crate::models::group::DeviceGroup;
+use crate::models::session::Session;
+use crate::models::two_factor::TotpCredential;
+use crate::models::user::{
+	ChangePassword, LoginUser, Me, NewUser, RegisterUser, UpdateProfile, User,
+};
+use crate::models::user_token::UserToken;
+use crate::rate_limit::{retry_after, LoginThrottle};
+use crate::error::ApiError;
+
+use super::device::DeviceService;
+use super::gateway::DeviceGateway;
+use super::mailer::{Email, MailTransport};
+
+const VERIFY_EMAIL_VALID_HOURS: i64 = 48;
+const RESET_PASSWORD_VALID_MINUTES: i64 = 30;
+
+pub struct UserService;
+
+impl UserService {
+	pub fn hash_password(password: &str) -> Result<String, ApiError> {
+		let salt = SaltString::generate(&mut OsRng);
+		Argon2::default()
+			.hash_password(password.as_bytes(), &salt)
+			.map(|hash| hash.to_string())
+			.map_err(|_| ApiError::Internal)
+	}
+
+	pub fn register(new_user: &RegisterUser, conn: &mut PgConnection) -> Result<User, ApiError> {
+		UserService::check_new_password(&new_user.password, &new_user.rep_password)?;
+		let new_user = NewUser {
+			password: UserService::hash_password(&new_user.password)?,
+			..new_user.clone().into()
+		};
+		match User::insert_user(new_user.clone(), conn) {
+			Ok(_) => {}
+			Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+				return Err(ApiError::EmailTaken)
+			}
+			Err(err) => return Err(err.into()),
+		}
+		// This should be literally impossible
+		User::get_user_by_email(new_user.email, conn)?.ok_or(ApiError::Internal)
+	}
+
+	pub fn check_password(user: &User, password: &str) -> Result<(), ApiError> {
+		let parsed_hash =
+			PasswordHash::new(&user.password).map_err(|_| ApiError::InvalidCredentials)?;
+		Argon2::default()
+			.verify_password(password.as_bytes(), &parsed_hash)
+			.map_err(|_| ApiError::InvalidCredentials)
+	}
+
+	// Unknown email and wrong password look the same to the client, in the
+	// response and in the time it takes. Accounts are locked for the client's
+	// IP after repeated failures, unknown emails as well.
+	pub fn login(
+		credentials: &LoginUser,
+		client_ip: Option<&str>,
+		throttle: &LoginThrottle,
+		conn: &mut PgConnection,
+	) -> Result<User, ApiError> {
+		let account = credentials.email.trim().to_lowercase();
+		throttle
+			.check(&account, client_ip)
+			.map_err(|wait| ApiError::RateLimited(retry_after(wait)))?;
+		let user = User::get_user_by_email(credentials.email.clone(), conn)?;
+		let checked = match &user {
+			Some(user) => UserService::check_password(user, &credentials.password),
+			None => UserService::check_against_dummy(&credentials.password),
+		};
+		match (user, checked) {
+			(Some(user), Ok(())) => {
+				throttle.record_success(&account, client_ip);
+				Ok(user)
+			}
+			_ => {
+				throttle.record_failure(&account, client_ip);
+				Err(ApiError::InvalidCredentials)
+			}
+		}
+	}
+
+	// As slow as checking a real password, always fails
+	fn check_against_dummy(password: &str) -> Result<(), ApiError> {
+		static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+		let hash = DUMMY_HASH.get_or_init(|| {
+			UserService::hash_password("not the password").expect("hashing works")
+		});
+		let parsed_hash = PasswordHash::new(hash).map_err(|_| ApiError::Internal)?;
+		let _ = Argon2::default().verify_password(password.as_bytes(), &parsed_hash);
+		Err(ApiError::InvalidCredentials)
+	}
+
+	pub fn me(user_id: i32, conn: &mut PgConnection) -> Result<Me, ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		Ok(Me {
+			id: user.id,
+			email_verified: user.email_verified_at.is_some(),
+			pending_email: user.pending_email,
+			two_factor_enabled: TotpCredential::is_enabled(user.id, conn)?,
+			email: user.email,
+			first_name: user.first_name,
+			last_name: user.last_name,
+		})
+	}
+
+	pub fn check_new_password(password: &str, rep_password: &str) -> Result<(), ApiError> {
+		if rep_password != password {
+			return Err(ApiError::invalid_field("password", "passwords dont match"));
+		}
+		Ok(())
+	}
+
+	// Sends a link that proves the user can read mail sent to their address
+	pub fn send_verification(
+		user: &User,
+		mailer: &dyn MailTransport,
+		frontend: &Frontend,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		if user.email_verified_at.is_some() {
+			return Ok(());
+		}
+		let token = UserToken::issue(
+			user.id,
+			TOKEN_VERIFY_EMAIL,
+			Duration::hours(VERIFY_EMAIL_VALID_HOURS),
+			conn,
+		)?;
+		let email = Email {
+			to: user.email.clone(),
+			subject: "Confirm your email address".to_string(),
+			body: format!(
+				"Hi {},\n\nopen this link to confirm your email address:\n{}\n",
+				user.first_name,
+				frontend.link("verify-email", &token)
+			),
+		};
+		mailer.send(&email).map_err(|err| {
+			println!("{}", err);
+			ApiError::EmailUnavailable
+		})
+	}
+
+	pub fn resend_verification(
+		user_id: i32,
+		mailer: &dyn MailTransport,
+		frontend: &Frontend,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		UserService::send_verification(&user, mailer, frontend, conn)
+	}
+
+	pub fn verify_email(token: &str, conn: &mut PgConnection) -> Result<(), ApiError> {
+		let user_id =
+			UserToken::consume(token, TOKEN_VERIFY_EMAIL, conn)?.ok_or(ApiError::InvalidToken)?;
+		User::mark_email_verified(user_id, conn)?;
+		Ok(())
+	}
+
+	// Unknown addresses are silently ignored and failures to send the email are
+	// only logged, so this can't be used to find out who has an account
+	pub fn request_password_reset(
+		email: &str,
+		mailer: &dyn MailTransport,
+		frontend: &Frontend,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let user = match User::get_user_by_email(email.to_string(), conn)? {
+			Some(user) => user,
+			None => return Ok(()),
+		};
+		if let Err(err) = UserService::send_password_reset(&user, mailer, frontend, conn) {
+			println!("password reset email for user {} not sent: {:?}", user.id, err);
+		}
+		Ok(())
+	}
+
+	fn send_password_reset(
+		user: &User,
+		mailer: &dyn MailTransport,
+		frontend: &Frontend,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let token = UserToken::issue(
+			user.id,
+			TOKEN_RESET_PASSWORD,
+			Duration::minutes(RESET_PASSWORD_VALID_MINUTES),
+			conn,
+		)?;
+		let email = Email {
+			to: user.email.clone(),
+			subject: "Reset your password".to_string(),
+			body: format!(
+				"Hi {},\n\nopen this link within {} minutes to choose a new password:\n{}\n\n\
+				 If you didn't ask for this you can ignore this email.\n",
+				user.first_name,
+				RESET_PASSWORD_VALID_MINUTES,
+				frontend.link("reset-password", &token)
+			),
+		};
+		mailer.send(&email).map_err(|err| {
+			println!("{}", err);
+			ApiError::EmailUnavailable
+		})
+	}
+
+	// Sets the new password and ends every session and API token, whoever knew
+	// the old password is logged out
+	pub fn reset_password(
+		token: &str,
+		password: &str,
+		rep_password: &str,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		UserService::check_new_password(password, rep_password)?;
+		let hash = UserService::hash_password(password)?;
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			let user_id = UserToken::consume(token, TOKEN_RESET_PASSWORD, local_conn)?
+				.ok_or(ApiError::InvalidToken)?;
+			User::update_password(user_id, hash, local_conn)?;
+			Session::revoke_all(user_id, local_conn)?;
+			ApiToken::revoke_all(user_id, local_conn)?;
+			// The link went to the inbox, so the address is verified as well
+			User::mark_email_verified(user_id, local_conn)?;
+			Ok(())
+		})
+	}
+
+	// Other sessions and every API token are ended, the session changing the
+	// password stays logged in
+	pub fn change_password(
+		user_id: i32,
+		session_id: Uuid,
+		change: &ChangePassword,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		UserService::check_password(&user, &change.current_password)?;
+		UserService::check_new_password(&change.password, &change.rep_password)?;
+		let hash = UserService::hash_password(&change.password)?;
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			User::update_password(user_id, hash, local_conn)?;
+			Session::revoke_others(session_id, user_id, local_conn)?;
+			ApiToken::revoke_all(user_id, local_conn)?;
+			Ok(())
+		})
+	}
+
+	// Names change right away. A new email address only replaces the current
+	// one once the link sent to it is opened, see confirm_email_change. Nothing
+	// is changed when any field is invalid or the email can't be sent.
+	pub fn update_profile(
+		user_id: i32,
+		profile: &UpdateProfile,
+		mailer: &dyn MailTransport,
+		frontend: &Frontend,
+		conn: &mut PgConnection,
+	) -> Result<Me, ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		let first_name = profile.first_name.as_deref().unwrap_or(&user.first_name).trim();
+		let last_name = profile.last_name.as_deref().unwrap_or(&user.last_name).trim();
+		if first_name.is_empty() {
+			return Err(ApiError::invalid_field("first_name", "first name can't be empty"));
+		}
+		if last_name.is_empty() {
+			return Err(ApiError::invalid_field("last_name", "last name can't be empty"));
+		}
+		let new_email = profile
+			.email
+			.as_deref()
+			.map(str::trim)
+			.filter(|email| *email != user.email);
+		if let Some(email) = new_email {
+			UserService::check_new_email(email, conn)?;
+		}
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			User::update_name(user_id, first_name, last_name, local_conn)?;
+			match new_email {
+				Some(email) => {
+					UserService::request_email_change(&user, email, mailer, frontend, local_conn)
+				}
+				None => Ok(()),
+			}
+		})?;
+		UserService::me(user_id, conn)
+	}
+
+	fn check_new_email(email: &str, conn: &mut PgConnection) -> Result<(), ApiError> {
+		if !email.contains('@') {
+			return Err(ApiError::invalid_field("email", "not an email address"));
+		}
+		if User::get_user_by_email(email.to_string(), conn)?.is_some() {
+			return Err(ApiError::EmailTaken);
+		}
+		Ok(())
+	}
+
+	// Sent last, so a failure rolls back the pending address with the token
+	fn request_email_change(
+		user: &User,
+		email: &str,
+		mailer: &dyn MailTransport,
+		frontend: &Frontend,
+		conn: &mut PgConnection,
+	) -> Result<(), ApiError> {
+		User::set_pending_email(user.id, email, conn)?;
+		let token = UserToken::issue(
+			user.id,
+			TOKEN_CHANGE_EMAIL,
+			Duration::hours(VERIFY_EMAIL_VALID_HOURS),
+			conn,
+		)?;
+		let email = Email {
+			to: email.to_string(),
+			subject: "Confirm your new email address".to_string(),
+			body: format!(
+				"Hi {},\n\nopen this link to use this address for your account:\n{}\n",
+				user.first_name,
+				frontend.link("confirm-email", &token)
+			),
+		};
+		mailer.send(&email).map_err(|err| {
+			println!("{}", err);
+			ApiError::EmailUnavailable
+		})
+	}
+
+	pub fn confirm_email_change(token: &str, conn: &mut PgConnection) -> Result<(), ApiError> {
+		let applied = conn.transaction::<_, DieselError, _>(|local_conn| {
+			match UserToken::consume(token, TOKEN_CHANGE_EMAIL, local_conn)? {
+				Some(user_id) => User::apply_pending_email(user_id, local_conn),
+				None => Ok(None),
+			}
+		});
+		match applied {
+			Ok(Some(_)) => Ok(()),
+			Ok(None) => Err(ApiError::InvalidToken),
+			// Someone registered with the address in the meantime
+			Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+				Err(ApiError::EmailTaken)
+			}
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	// Removes the user's devices like remove_device does, then everything else
+	// of the user. Returns how many devices didn't acknowledge the wipe, those
+	// stay pending removal and are retried on startup.
+	pub fn delete_account(
+		user_id: i32,
+		password: &str,
+		gateway: &dyn DeviceGateway,
+		effects: &EffectRunner,
+		conn: &mut PgConnection,
+	) -> Result<usize, ApiError> {
+		let user = User::get_user_by_id(user_id, conn)?.ok_or(ApiError::UserNotFound)?;
+		UserService::check_password(&user, password)?;
+		let mut pending = 0;
+		for device in Device::get_devices_by_user(user_id, conn)? {
+			let status = DeviceService::remove(&device, gateway, effects, conn)?;
+			if status == DecommissionStatus::PendingRemoval {
+				pending += 1;
+			}
+		}
+		conn.transaction::<_, ApiError, _>(|local_conn| {
+			for transfer in DeviceTransfer::get_outgoing(user_id, local_conn)? {
+				DeviceTransfer::resolve(transfer.id, TRANSFER_CANCELLED, local_conn)?;
+			}
+			for transfer in DeviceTransfer::get_incoming(&user.email, local_conn)? {
+				DeviceTransfer::resolve(transfer.id, TRANSFER_DECLINED, local_conn)?;
+			}
+			DeviceGroup::remove_groups_by_user(user_id, local_conn)?;
+			User::delete_user(user_id, local_conn)?;
+			Ok(())
+		})?;
+		Ok(pending)
+	}
+}
@@ -0,0 +1,129 @@
+// Time based one time passwords (RFC 6238) as generated by authenticator apps:
+// HMAC-SHA1 over the number of 30 second steps since the epoch, 6 digits.
+// Secrets are shown to the user base32 encoded, which is what the apps expect.
+use openssl::{error::ErrorStack, hash::MessageDigest, pkey::PKey, sign::Signer};
+use rand_core::{OsRng, RngCore};
+
+pub const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_BYTES: usize = 20;
+// Codes from one step before and after are accepted as well, phone clocks drift
+const ALLOWED_DRIFT: i64 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+	let mut secret = vec![0u8; SECRET_BYTES];
+	OsRng.fill_bytes(&mut secret);
+	secret
+}
+
+// RFC 4648 base32 without padding
+pub fn base32_encode(bytes: &[u8]) -> String {
+	let mut encoded = String::new();
+	let mut buffer: u32 = 0;
+	let mut bits = 0;
+	for byte in bytes {
+		buffer = (buffer << 8) | *byte as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+	}
+	encoded
+}
+
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+	let mut bytes = vec![];
+	let mut buffer: u32 = 0;
+	let mut bits = 0;
+	for c in encoded.trim_end_matches('=').bytes() {
+		let value = BASE32_ALPHABET
+			.iter()
+			.position(|&letter| letter == c.to_ascii_uppercase())?;
+		buffer = (buffer << 5) | value as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			bytes.push((buffer >> bits) as u8);
+		}
+	}
+	Some(bytes)
+}
+
+pub fn step_at(unix_time: i64) -> i64 {
+	unix_time.div_euclid(STEP_SECONDS)
+}
+
+pub fn code_at_step(secret: &[u8], step: i64) -> Result<String, ErrorStack> {
+	let key = PKey::hmac(secret)?;
+	let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+	signer.update(&(step as u64).to_be_bytes())?;
+	let mac = signer.sign_to_vec()?;
+	// Dynamic truncation, RFC 4226 section 5.3
+	let offset = (mac[mac.len() - 1] & 0xf) as usize;
+	let bytes = [mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]];
+	let binary = u32::from_be_bytes(bytes) & 0x7fff_ffff;
+	Ok(format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize))
+}
+
+// The step the code belongs to when it is valid at the given time, so callers
+// can refuse to accept the same code twice
+pub fn verify(secret: &[u8], code: &str, unix_time: i64) -> Option<i64> {
+	let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+	if code.len() != DIGITS as usize {
+		return None;
+	}
+	let now = step_at(unix_time);
+	(now - ALLOWED_DRIFT..=now + ALLOWED_DRIFT).find(|&step| {
+		code_at_step(secret, step)
+			.map(|expected| openssl::memcmp::eq(expected.as_bytes(), code.as_bytes()))
+			.unwrap_or(false)
+	})
+}
+
+fn percent_encode(value: &str) -> String {
+	value
+		.bytes()
+		.map(|byte| match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+				(byte as char).to_string()
+			}
+			_ => format!("%{:02X}", byte),
+		})
+		.collect()
+}
+
+// What the QR code shown during enrollment contains
+pub fn otpauth_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+	format!(
+		"otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+		percent_encode(issuer),
+		percent_encode(account),
+		base32_encode(secret),
+		percent_encode(issuer),
+		DIGITS,
+		STEP_SECONDS
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn totp_matches_the_rfc_6238_test_vectors() {
+		let secret = b"12345678901234567890";
+		for (time, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924")] {
+			assert_eq!(code_at_step(secret, step_at(time)).unwrap(), code);
+		}
+		assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+		assert_eq!(base32_decode("gezdgnbvgy3tqojqgezdgnbvgy3tqojq").unwrap(), secret);
+		// One step of clock drift either way is accepted
+		assert_eq!(verify(secret, "287 082", 59 + 30), Some(1));
+		assert_eq!(verify(secret, "287082", 59 + 60), None);
+	}
+}
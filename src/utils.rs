@@ -1,219 +1,134 @@
-#![allow(dead_code)]
-
 use std::env;
 
-use base64::{alphabet::URL_SAFE, engine::general_purpose, Engine};
-use coap_client::{backend::Tokio, ClientOptions, HostOptions, RequestOptions, TokioClient};
-use coap_lite::Packet;
+use base64::{engine::general_purpose, Engine};
 use diesel::{Connection, PgConnection};
 use dotenv::dotenv;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Verifier};
-use rocket::http::{Cookie, Cookies};
-use serde_json::Value;
+use futures::future::join_all;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use rand_core::{OsRng, RngCore};
 use tokio::runtime::Runtime;
 use uuid::Uuid;
 
 use crate::{
-	models::{device::Device, light::LightState},
-	routes::SESSION_STRING,
-	JWT_SECRET,
+	constants::DEVICE_ACTIVE,
+	decommission::retry_pending_removals,
+	effects::EffectRunner,
+	keyring::KeyRing,
+	models::device::Device,
+	services::gateway::{with_timeout, DeviceGateway},
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
 	pub sub: String,
 	pub exp: usize,
+	// Id of the session, only in session cookies
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub jti: Option<String>,
 }
 
-pub fn jwt_from_id(user_id: String, timestamp: usize) -> String {
+// A token that isn't tied to a session, only the tests need those
+#[cfg(test)]
+pub fn jwt_from_id(keys: &KeyRing, user_id: String, timestamp: usize) -> String {
 	let claims = Claims {
 		sub: user_id,
 		exp: timestamp,
+		jti: None,
 	};
-	let token = encode(
-		&Header::default(),
-		&claims,
-		&EncodingKey::from_secret(JWT_SECRET.as_ref()),
-	);
-	return token.unwrap();
+	keys.sign(&claims)
 }
 
-pub fn claim_form_jwt(jwt: String) -> Option<Claims> {
-	let token = decode::<Claims>(
-		&jwt,
-		&DecodingKey::from_secret(JWT_SECRET.as_ref()),
-		&Validation::default(),
-	);
-	if token.is_err() {
-		return None;
-	}
-	return Some(token.unwrap().claims);
+// The session cookie, only valid while the session is
+pub fn session_jwt(keys: &KeyRing, user_id: i32, session_id: Uuid, timestamp: usize) -> String {
+	let claims = Claims {
+		sub: user_id.to_string(),
+		exp: timestamp,
+		jti: Some(session_id.to_string()),
+	};
+	keys.sign(&claims)
 }
-pub fn get_user_id_from_cookie(cookie: Option<&Cookie>) -> Option<i32> {
-	if cookie.is_none() {
-		return None;
-	}
-	let cookie_value = cookie.unwrap().value();
-	let claim = claim_form_jwt(cookie_value.to_string());
-	if claim.is_none() {
-		return None;
-	}
-	let return_value = claim.unwrap().sub.parse::<i32>();
-	if return_value.is_err() {
-		return None;
-	}
-	return Some(return_value.unwrap());
+
+pub fn claim_form_jwt(keys: &KeyRing, jwt: String) -> Option<Claims> {
+	keys.verify::<Claims>(&jwt)
+}
+// Generates a fresh per-device symmetric key, base64 encoded
+pub fn generate_device_key() -> String {
+	let mut key = [0u8; 32];
+	OsRng.fill_bytes(&mut key);
+	general_purpose::URL_SAFE.encode(key)
 }
-pub fn verify_secret(secret: &str, data: String) -> Result<bool, Box<dyn std::error::Error>> {
-	let engine = general_purpose::GeneralPurpose::new(&URL_SAFE, general_purpose::PAD);
-	let signature = &engine.decode(secret)?;
-	let device_secret_key = Rsa::private_key_from_pem(
-		dotenv::var("DEVICE_SECRET_KEY")
-			.expect("set DEVICE_SECRET_KEY")
-			.as_bytes(),
-	)?;
-	let keypair = PKey::from_rsa(device_secret_key).unwrap();
-	let mut verifier = Verifier::new(MessageDigest::sha256(), &keypair).unwrap();
-	verifier.update(data.as_bytes()).unwrap();
-	Ok(verifier.verify(&signature).unwrap())
+
+// HMAC-SHA256 over "<device_id>.<timestamp>.<nonce>.<body>" keyed with the
+// device key. The same construction is used for device messages and for our
+// replies to them, so both sides can check that the other one holds the key. A
+// reply carries the nonce of the request it answers.
+pub fn sign_device_message(
+	device_key: &str,
+	device_id: Uuid,
+	timestamp: i64,
+	nonce: &str,
+	body: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+	let key = PKey::hmac(&general_purpose::URL_SAFE.decode(device_key)?)?;
+	let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+	signer.update(format!("{}.{}.{}.", device_id, timestamp, nonce).as_bytes())?;
+	signer.update(body.as_bytes())?;
+	Ok(general_purpose::URL_SAFE.encode(signer.sign_to_vec()?))
 }
 
-pub fn handle_startup() {
+pub fn verify_device_message(
+	device_key: &str,
+	device_id: Uuid,
+	timestamp: i64,
+	nonce: &str,
+	body: &str,
+	signature: &str,
+) -> bool {
+	let expected = match sign_device_message(device_key, device_id, timestamp, nonce, body) {
+		Ok(expected) => expected,
+		Err(_) => return false,
+	};
+	expected.len() == signature.len() && memcmp::eq(expected.as_bytes(), signature.as_bytes())
+}
+
+// Makes the gateway accept messages for every active device again and retries
+// the removals that didn't finish before the last shutdown
+pub fn handle_startup(gateway: &dyn DeviceGateway, effects: &EffectRunner) {
 	dotenv().ok();
 	let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 	let mut connection = &mut PgConnection::establish(&database_url)
 		.unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
-	let devices = Device::get_all_devices(&mut connection);
-	insert_devices_into_coap_server(devices);
-}
-
-pub fn insert_devices_into_coap_server(devices: Vec<Device>) {
-	let mut host_opts = HostOptions::default();
-	let ip = env::var("COAP_IP").expect("set COAP_IP");
-	let port = env::var("COAP_PORT").expect("set COAP_PORT");
-	host_opts.host = ip;
-	host_opts.port = port.parse().unwrap();
-	let mut req_opts = RequestOptions::default();
-	req_opts.non_confirmable = false;
+	let (active, pending_removal): (Vec<Device>, Vec<Device>) =
+		Device::get_all_devices(&mut connection)
+			.expect("failed to load devices")
+			.into_iter()
+			.partition(|device| device.state == DEVICE_ACTIVE);
 	let rt = Runtime::new().unwrap();
-	rt.block_on(async {
-		let mut client = TokioClient::connect(host_opts, &ClientOptions::default()).await;
-		for device in devices {
-			let resp = client
-				.as_mut()
-				.unwrap()
-				.put(
-					"/lights/create",
-					Some(device.id.to_string().as_bytes()),
-					&req_opts,
-				)
-				.await;
-			// TODO handle errors
-			if resp.is_err() {
-				//something went wrong when sending the request
-			} else {
-				// let resp_payload = String::from_utf8(resp.unwrap_or_default());
-				if resp.unwrap_or_default().len() != 0 {
-				} else {
-					// println!("{}", resp_payload.unwrap());
-				}
-			}
+	let created = rt.block_on(join_all(
+		active
+			.iter()
+			.map(|device| with_timeout(gateway.create(device.id, &device.type_))),
+	));
+	for (device, result) in active.iter().zip(created) {
+		if let Err(err) = result {
+			println!("gateway didn't accept device {}: {:?}", device.id, err);
 		}
-	});
-}
-
-pub fn is_user_logged_in(cookies: Cookies) -> Option<i32> {
-	let cookie = cookies.get(SESSION_STRING);
-	let user_id = get_user_id_from_cookie(cookie);
-	println!("{:?}", user_id);
-	user_id
+	}
+	retry_pending_removals(pending_removal, gateway, effects, &mut connection);
 }
-async fn create_client() -> coap_client::Client<std::io::Error, Tokio> {
-	let mut host_opts = HostOptions::default();
-	let ip = env::var("COAP_IP").expect("set COAP_IP");
-	let port = env::var("COAP_PORT").expect("set COAP_PORT");
-	host_opts.host = ip;
-	host_opts.port = port.parse().unwrap();
 
-	return TokioClient::connect(host_opts, &ClientOptions::default())
-		.await
-		.unwrap();
-}
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-pub async fn send_device_command(
-	light_state: LightState,
-	device_id: Uuid,
-) -> Result<Packet, coap_client::Error<std::io::Error>> {
-	let mut client = create_client().await;
-	let mut req_opts = RequestOptions::default();
-	req_opts.non_confirmable = false;
-	client
-		.put_and_get_packet(
-			format!("/lights/{}", device_id.to_string()).as_str(),
-			Some(serde_json::to_string(&light_state).unwrap().as_bytes()),
-			&req_opts,
-		)
-		.await
-}
-pub async fn remove_coap_device(
-	device_id: Uuid,
-) -> Result<Packet, coap_client::Error<std::io::Error>> {
-	let mut client = create_client().await;
-	let mut req_opts = RequestOptions::default();
-	req_opts.non_confirmable = false;
-	client
-		.put_and_get_packet(
-			format!("/lights/remove/{}", device_id.to_string()).as_str(),
-			None,
-			&req_opts,
-		)
-		.await
-}
-pub async fn check_device_online(device_id: String) -> Option<bool> {
-	// TODO handle errrors
-	let mut client = create_client().await;
-	let mut req_opts = RequestOptions::default();
-	req_opts.non_confirmable = false;
-	let response = client
-		.get(
-			format!("/lights/is_online/{}", device_id.to_string()).as_str(),
-			&req_opts,
-		)
-		.await;
-	if response.is_err() {
-		return None;
+	// The nonce is part of what is signed, a replay with a new one doesn't verify
+	#[test]
+	fn device_signatures_cover_the_nonce() {
+		let key = generate_device_key();
+		let id = Uuid::new_v4();
+		let signature = sign_device_message(&key, id, 1000, "n1", "{}").unwrap();
+		assert!(verify_device_message(&key, id, 1000, "n1", "{}", &signature));
+		assert!(!verify_device_message(&key, id, 1000, "n2", "{}", &signature));
+		assert!(!verify_device_message(&key, id, 1001, "n1", "{}", &signature));
 	}
-	let message = String::from_utf8(response.unwrap());
-	let json_data = serde_json::from_str::<Value>(&message.unwrap());
-	json_data.unwrap()["isOnline"].as_bool()
-	// 	client
-	// 		.get(
-	// 			format!("/lights/is_online/{}", device_id.to_string()).as_str(),
-	// 			&req_opts,
-	// 		)
-	// 		.await
-}
-
-pub async fn create_coap_device(
-	device_id: Uuid,
-	device_type: &str,
-) -> Result<Packet, coap_client::Error<std::io::Error>> {
-	let mut host_opts = HostOptions::default();
-	let ip = env::var("COAP_IP").expect("set COAP_IP");
-	let port = env::var("COAP_PORT").expect("set COAP_PORT");
-	host_opts.host = ip;
-	host_opts.port = port.parse().unwrap();
-	let mut req_opts = RequestOptions::default();
-	req_opts.non_confirmable = false;
-	let mut client = TokioClient::connect(host_opts, &ClientOptions::default())
-		.await
-		.unwrap();
-	client
-		.put_and_get_packet(
-			format!("/{}/create", device_type).as_str(),
-			Some(device_id.to_string().as_bytes()),
-			&req_opts,
-		)
-		.await
 }